@@ -2,96 +2,604 @@ use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
 
-use bin_file::BinFile;
 use clock::Subscriber;
+use device::Device;
 use memory::Memory;
 
+pub mod adc;
+pub mod analog;
+pub mod analog_comparator;
+pub mod boot_loader;
 mod clock;
+pub mod debugger;
+pub mod device;
+pub mod disassembler;
+pub mod eeprom;
+pub mod elf;
+pub mod external_interrupt;
+pub mod fuses;
+pub mod gdb;
+pub mod gpio;
+pub mod i2c;
 pub mod instruction;
 pub mod instruction_executor;
 pub mod interrupt_handler;
 pub mod memory;
+pub mod monitor;
+pub mod number;
+pub mod observer;
+pub mod power_reduction;
+pub mod prescaler;
+pub mod reset;
+pub mod serial;
+pub mod shadow;
+pub mod spi;
+pub mod stack;
+pub mod symbols;
+pub mod system_clock_prescaler;
+#[cfg(test)]
+mod test_utils;
 pub mod timer;
+pub mod trace;
+pub mod twi;
+pub mod usart;
+pub mod vcd;
+pub mod watchdog;
 
 pub struct AVREmulator {
     memory: Arc<Mutex<Memory>>,
+    device: &'static Device,
     frequency: i64,
     stop_program: Arc<AtomicBool>,
+    ports: Vec<Arc<Mutex<gpio::PortPins>>>,
+    serial_ports: Vec<Arc<Mutex<Box<dyn serial::SerialBackend>>>>,
+    spi_slaves: Arc<Mutex<Vec<spi::SpiSlave>>>,
+    i2c_bus: Arc<Mutex<i2c::I2cBus>>,
+    analog_inputs: Arc<Mutex<analog::AnalogInputs>>,
+    eeprom: Arc<Mutex<eeprom::EepromMemory>>,
+    fuses: fuses::Fuses,
+    preserve_sram: bool,
+    power_report: Arc<Mutex<power_reduction::PowerReport>>,
+    debugger: Option<Arc<debugger::Debugger>>,
+    symbols: Arc<symbols::SymbolTable>,
+    tracer: Option<Arc<Mutex<trace::Tracer>>>,
+    vcd: Option<Arc<Mutex<Box<dyn Subscriber>>>>,
+    cycles: Arc<clock::Cycles>,
+    observers: Vec<observer::SharedObserver>,
 }
 
 impl AVREmulator {
     pub fn new(
         hex_dump: Vec<u8>,
         memory_size: usize,
+        device: &'static Device,
         frequency: i64,
         stop_program: Arc<AtomicBool>,
     ) -> Self {
+        let fuses = device.fuses.defaults;
+
+        let mut memory = Memory::new(memory_size, hex_dump).unwrap();
+        reset::reset(&mut memory, device, &fuses, reset::ResetKind::PowerOn, false);
+
         Self {
-            memory: Arc::new(Mutex::new(Memory::new(memory_size, hex_dump).unwrap())),
-            frequency: frequency,
-            stop_program: stop_program,
+            memory: Arc::new(Mutex::new(memory)),
+            device,
+            frequency,
+            stop_program,
+            ports: device
+                .ports
+                .iter()
+                .map(|_| Arc::new(Mutex::new(gpio::PortPins::default())))
+                .collect(),
+            serial_ports: device
+                .usarts
+                .iter()
+                .map(|_| {
+                    let serial: Box<dyn serial::SerialBackend> = Box::new(serial::Disconnected);
+                    Arc::new(Mutex::new(serial))
+                })
+                .collect(),
+            spi_slaves: Arc::new(Mutex::new(vec![])),
+            i2c_bus: Arc::new(Mutex::new(i2c::I2cBus::default())),
+            analog_inputs: Arc::new(Mutex::new(analog::AnalogInputs::default())),
+            eeprom: Arc::new(Mutex::new(eeprom::EepromMemory::new(device.eeprom.size))),
+            fuses,
+            preserve_sram: false,
+            power_report: Arc::new(Mutex::new(power_reduction::PowerReport::default())),
+            debugger: None,
+            symbols: Arc::new(symbols::SymbolTable::default()),
+            tracer: None,
+            vcd: None,
+            cycles: Arc::default(),
+            observers: vec![],
         }
     }
 
-    pub fn run(&self) -> Vec<JoinHandle<()>> {
-        let stop_program1 = self.stop_program.clone();
-        let stop_program2 = self.stop_program.clone();
-        let stop_program3 = self.stop_program.clone();
-        let stop_program4 = self.stop_program.clone();
+    pub fn get_port_pins(&self, name: char) -> Option<Arc<Mutex<gpio::PortPins>>> {
+        self.device
+            .ports
+            .iter()
+            .position(|port| port.name.eq_ignore_ascii_case(&name))
+            .map(|index| self.ports[index].clone())
+    }
+
+    pub fn set_serial_backend(&self, usart: usize, backend: Box<dyn serial::SerialBackend>) {
+        *self.serial_ports[usart].lock().unwrap() = backend;
+    }
+
+    // The chip select is an active low pin given as port name and pin number
+    pub fn attach_spi_device(
+        &self,
+        spi_device: Box<dyn spi::SpiDevice>,
+        chip_select: Option<(char, u8)>,
+    ) -> Result<(), String> {
+        let chip_select = match chip_select {
+            Some((port, pin)) => match self.device.find_port(port) {
+                Some(registers) if pin < 8 => Some(device::RegisterBit::new(registers.pin, pin)),
+                _ => return Err(format!("invalid chip select pin P{}{}", port, pin)),
+            },
+            None => None,
+        };
+
+        self.spi_slaves
+            .lock()
+            .unwrap()
+            .push(spi::SpiSlave::new(spi_device, chip_select));
+
+        Ok(())
+    }
+
+    pub fn get_i2c_bus(&self) -> Arc<Mutex<i2c::I2cBus>> {
+        self.i2c_bus.clone()
+    }
+
+    pub fn get_analog_inputs(&self) -> Arc<Mutex<analog::AnalogInputs>> {
+        self.analog_inputs.clone()
+    }
+
+    pub fn get_eeprom(&self) -> Arc<Mutex<eeprom::EepromMemory>> {
+        self.eeprom.clone()
+    }
+
+    pub fn get_fuses(&self) -> fuses::Fuses {
+        self.fuses
+    }
+
+    // The fuses are read at power-on, the core restarts from the reset vector they select
+    pub fn set_fuses(&mut self, fuses: fuses::Fuses) {
+        self.fuses = fuses;
+
+        reset::reset(
+            &mut self.memory.lock().unwrap(),
+            self.device,
+            &self.fuses,
+            reset::ResetKind::PowerOn,
+            false,
+        );
+    }
+
+    // Cycles the peripherals with a PRR bit were clocked for since run was called
+    pub fn get_power_report(&self) -> Arc<Mutex<power_reduction::PowerReport>> {
+        self.power_report.clone()
+    }
 
-        let instruction_executor: Arc<Mutex<Box<dyn Subscriber>>> = Arc::new(Mutex::new(Box::new(
-            instruction_executor::InstructionExecutor::new(self.memory.clone()),
+    // Breakpoints, watchpoints and run control shared with the gdb server and the monitor.
+    // Once requested, the core starts halted and reports why it stopped.
+    pub fn get_debugger(&mut self) -> Arc<debugger::Debugger> {
+        self.debugger
+            .get_or_insert_with(|| Arc::new(debugger::Debugger::new(true)))
+            .clone()
+    }
+
+    // The core is halted until a gdb client connected to localhost:port continues it
+    pub fn start_gdb_server(&mut self, port: u16) -> Result<(), String> {
+        gdb::GdbServer::new(
+            self.memory.clone(),
+            self.eeprom.clone(),
+            self.get_debugger(),
+            self.stop_program.clone(),
+            self.device.boot_loader.flash_size,
+        )
+        .listen(port)
+    }
+
+    // Commands read from stdin control the core, which is halted until continued
+    pub fn start_monitor(&mut self) {
+        monitor::Monitor::new(
+            self.memory.clone(),
+            self.get_debugger(),
+            self.symbols.clone(),
+            self.device,
+            self.frequency,
+            self.cycles.clone(),
+            self.stop_program.clone(),
+        )
+        .start();
+    }
+
+    // Peripherals with a PRR bit only run while it is cleared
+    fn gate(
+        &self,
+        subscriber: Box<dyn Subscriber>,
+        power_reduction: Option<device::RegisterBit>,
+        registers: Vec<usize>,
+        name: &str,
+    ) -> Arc<Mutex<Box<dyn Subscriber>>> {
+        match power_reduction {
+            Some(bit) => Arc::new(Mutex::new(Box::new(power_reduction::PowerGate::new(
+                self.memory.clone(),
+                bit,
+                registers,
+                self.power_report.clone(),
+                name,
+                subscriber,
+            )))),
+            None => Arc::new(Mutex::new(subscriber)),
+        }
+    }
+
+    // Symbols of the firmware for traces and debugging
+    pub fn set_symbols(&mut self, symbols: symbols::SymbolTable) {
+        self.symbols = Arc::new(symbols);
+    }
+
+    pub fn get_symbols(&self) -> Arc<symbols::SymbolTable> {
+        self.symbols.clone()
+    }
+
+    // Observers are notified of the executed instructions, their memory accesses, interrupts
+    // and resets. Without observers the core does not record any of it.
+    pub fn add_observer(&mut self, observer: observer::SharedObserver) {
+        self.observers.push(observer);
+    }
+
+    // Reports SP dropping below the limit, or below the heap and .bss of the symbols set
+    // before without a limit
+    pub fn check_stack(&mut self, limit: Option<u16>) -> Arc<Mutex<stack::StackGuard>> {
+        let guard = Arc::new(Mutex::new(stack::StackGuard::new(
+            self.memory.clone(),
+            self.symbols.clone(),
+            limit,
         )));
+        self.add_observer(guard.clone());
+        guard
+    }
+
+    // Every executed instruction is recorded by the tracer
+    pub fn set_tracer(&mut self, tracer: Arc<Mutex<trace::Tracer>>) {
+        self.tracer = Some(tracer);
+    }
+
+    // Dumps the pin levels, TCNT0, SREG, the given io registers and whether an interrupt
+    // routine runs every system clock cycle
+    pub fn record_vcd(
+        &mut self,
+        writer: Box<dyn std::io::Write + Send + Sync>,
+        registers: &[(String, usize)],
+    ) -> Result<(), String> {
+        let sram_size = self.memory.lock().unwrap().get_sram_size();
+        if let Some((name, address)) = registers.iter().find(|(_, address)| *address >= sram_size) {
+            return Err(format!(
+                "{} at {:#06x} is outside the data space of {:#06x} bytes",
+                name, address, sram_size
+            ));
+        }
+
+        let mut vcd = vcd::VcdWriter::new(
+            self.memory.clone(),
+            writer,
+            self.device.name,
+            self.frequency as f64,
+        );
+
+        for (registers, pins) in self.device.ports.iter().zip(&self.ports) {
+            vcd.add_port(registers.name, pins.clone());
+        }
+        vcd.add_register("TCNT0", self.device.timer0.counter);
+        vcd.add_register("SREG", 0x5f);
+        for (name, address) in registers {
+            vcd.add_register(name, *address);
+        }
+        vcd.add_interrupt_active();
 
-        let timer: Arc<Mutex<Box<dyn Subscriber>>> =
-            Arc::new(Mutex::new(Box::new(timer::Timer::new(self.memory.clone()))));
+        self.vcd = Some(Arc::new(Mutex::new(Box::new(vcd))));
+        Ok(())
+    }
+
+    // Keep registers and sram contents across all but power-on resets
+    pub fn set_preserve_sram(&mut self, preserve_sram: bool) {
+        self.preserve_sram = preserve_sram;
+    }
+
+    // Registers and sram are filled with a pattern or random data at power-on instead of zeros
+    pub fn set_sram_fill(&mut self, sram_fill: memory::SramFill) {
+        if let memory::SramFill::Random(seed) = sram_fill {
+            log::info!("filling sram with random data, seed {}", seed);
+        }
+        self.memory.lock().unwrap().set_sram_fill(sram_fill);
+
+        reset::reset(
+            &mut self.memory.lock().unwrap(),
+            self.device,
+            &self.fuses,
+            reset::ResetKind::PowerOn,
+            false,
+        );
+    }
 
-        let interrupt_handler: Arc<Mutex<Box<dyn Subscriber>>> = Arc::new(Mutex::new(Box::new(
-            interrupt_handler::InterruptHandler::new(self.memory.clone()),
+    // Warns about reads of data sram never written since it was cleared, set the symbols and
+    // preserve_sram before
+    pub fn check_uninitialised_reads(&mut self) -> Arc<Mutex<shadow::ShadowMemory>> {
+        let shadow = Arc::new(Mutex::new(shadow::ShadowMemory::new(
+            self.symbols.clone(),
+            self.device.sram_start,
+            self.memory.lock().unwrap().get_sram_size(),
+            self.preserve_sram,
         )));
+        self.add_observer(shadow.clone());
+        shadow
+    }
 
-        let clock = Arc::new(Mutex::new(clock::Clock::new(self.frequency as f64)));
+    // The reset is performed between two clock cycles of the running emulator
+    pub fn reset(&self, kind: reset::ResetKind) {
+        self.memory.lock().unwrap().request_reset(kind);
+    }
 
-        clock
+    pub fn run(&self) -> Vec<JoinHandle<()>> {
+        let mut instruction_executor = instruction_executor::InstructionExecutor::new(
+            self.memory.clone(),
+            self.debugger.clone(),
+        );
+        if let Some(tracer) = &self.tracer {
+            instruction_executor.set_tracer(tracer.clone());
+        }
+        for observer in &self.observers {
+            instruction_executor.add_observer(observer.clone());
+        }
+        self.memory
             .lock()
             .unwrap()
-            .subscribe(instruction_executor.clone());
-        clock.lock().unwrap().subscribe(timer.clone());
-        clock.lock().unwrap().subscribe(interrupt_handler.clone());
+            .set_record_events(!self.observers.is_empty());
+        let instruction_executor: Arc<Mutex<Box<dyn Subscriber>>> =
+            Arc::new(Mutex::new(Box::new(instruction_executor)));
 
-        let instruction_executor_thread = std::thread::spawn(move || loop {
-            instruction_executor.lock().unwrap().run();
-            if stop_program1.load(std::sync::atomic::Ordering::Relaxed) {
-                break;
-            }
-        });
+        let timer0: Box<dyn prescaler::Subscriber> =
+            Box::new(timer::Timer::new(self.memory.clone(), &self.device.timer0));
+        let timer0: Arc<Mutex<Box<dyn prescaler::Subscriber>>> =
+            match self.device.timer0.power_reduction {
+                Some(bit) => Arc::new(Mutex::new(Box::new(power_reduction::PowerGate::new(
+                    self.memory.clone(),
+                    bit,
+                    vec![self.device.timer0.control, self.device.timer0.counter],
+                    self.power_report.clone(),
+                    "TIMER0",
+                    timer0,
+                )))),
+                None => Arc::new(Mutex::new(timer0)),
+            };
 
-        let clock_thread = std::thread::spawn(move || loop {
-            clock.lock().unwrap().run();
-            if stop_program2.load(std::sync::atomic::Ordering::Relaxed) {
-                break;
-            }
-        });
+        let mut synchronous_prescaler =
+            prescaler::Prescaler::new(self.memory.clone(), &self.device.synchronous_prescaler);
+        synchronous_prescaler.subscribe(timer0);
 
-        let timer_thread = std::thread::spawn(move || loop {
-            timer.lock().unwrap().run();
-            if stop_program3.load(std::sync::atomic::Ordering::Relaxed) {
-                break;
-            }
-        });
+        let synchronous_prescaler: Arc<Mutex<Box<dyn Subscriber>>> =
+            Arc::new(Mutex::new(Box::new(synchronous_prescaler)));
+
+        let asynchronous_prescaler: Arc<Mutex<Box<dyn Subscriber>>> =
+            Arc::new(Mutex::new(Box::new(prescaler::Prescaler::new(
+                self.memory.clone(),
+                &self.device.asynchronous_prescaler,
+            ))));
+
+        let mut interrupt_handler =
+            interrupt_handler::InterruptHandler::new(self.memory.clone(), self.device, self.fuses);
+        for observer in &self.observers {
+            interrupt_handler.add_observer(observer.clone());
+        }
+        let interrupt_handler: Arc<Mutex<Box<dyn Subscriber>>> =
+            Arc::new(Mutex::new(Box::new(interrupt_handler)));
+
+        let external_interrupt: Arc<Mutex<Box<dyn Subscriber>>> = Arc::new(Mutex::new(Box::new(
+            external_interrupt::ExternalInterrupt::new(self.memory.clone(), self.device),
+        )));
+
+        let mut clock = clock::Clock::new(self.frequency as f64);
+        clock.set_division(self.memory.lock().unwrap().get_clock_division());
+        let clock = Arc::new(Mutex::new(clock));
+
+        let mut subscribers = vec![
+            instruction_executor,
+            synchronous_prescaler,
+            asynchronous_prescaler,
+            external_interrupt,
+            interrupt_handler,
+        ];
 
-        let interrupt_handler_thread = std::thread::spawn(move || loop {
-            interrupt_handler.lock().unwrap().run();
-            if stop_program4.load(std::sync::atomic::Ordering::Relaxed) {
+        if let Some(vcd) = &self.vcd {
+            subscribers.push(vcd.clone());
+        }
+
+        for (registers, pins) in self.device.ports.iter().zip(&self.ports) {
+            subscribers.push(Arc::new(Mutex::new(Box::new(gpio::Gpio::new(
+                self.memory.clone(),
+                registers,
+                self.device.pull_up_disable,
+                self.device.pin_write_toggles_port,
+                pins.clone(),
+            )))));
+        }
+
+        for (index, (registers, serial)) in self
+            .device
+            .usarts
+            .iter()
+            .zip(&self.serial_ports)
+            .enumerate()
+        {
+            subscribers.push(self.gate(
+                Box::new(usart::Usart::new(
+                    self.memory.clone(),
+                    registers,
+                    serial.clone(),
+                )),
+                registers.power_reduction,
+                vec![
+                    registers.data,
+                    registers.control_status_a,
+                    registers.control_status_b,
+                    registers.control_status_c,
+                    registers.baud_rate_low,
+                    registers.baud_rate_high,
+                ],
+                &format!("USART{}", index),
+            ));
+        }
+
+        let spi = &self.device.spi;
+        subscribers.push(self.gate(
+            Box::new(spi::Spi::new(
+                self.memory.clone(),
+                spi,
+                self.spi_slaves.clone(),
+            )),
+            spi.power_reduction,
+            vec![spi.control, spi.status, spi.data],
+            "SPI",
+        ));
+
+        let twi = &self.device.twi;
+        subscribers.push(self.gate(
+            Box::new(twi::Twi::new(
+                self.memory.clone(),
+                twi,
+                self.i2c_bus.clone(),
+            )),
+            twi.power_reduction,
+            vec![twi.bit_rate, twi.status, twi.address, twi.data, twi.control],
+            "TWI",
+        ));
+
+        let adc = &self.device.adc;
+        let adc_registers = [adc.multiplexer, adc.control_status_a]
+            .into_iter()
+            .chain(adc.control_status_b)
+            .collect();
+        subscribers.push(self.gate(
+            Box::new(adc::Adc::new(
+                self.memory.clone(),
+                adc,
+                self.analog_inputs.clone(),
+            )),
+            adc.power_reduction,
+            adc_registers,
+            "ADC",
+        ));
+
+        subscribers.push(Arc::new(Mutex::new(Box::new(
+            analog_comparator::AnalogComparator::new(
+                self.memory.clone(),
+                self.device,
+                self.analog_inputs.clone(),
+            ),
+        ))));
+
+        subscribers.push(Arc::new(Mutex::new(Box::new(eeprom::Eeprom::new(
+            self.memory.clone(),
+            &self.device.eeprom,
+            self.eeprom.clone(),
+            self.frequency,
+        )))));
+
+        subscribers.push(Arc::new(Mutex::new(Box::new(watchdog::Watchdog::new(
+            self.memory.clone(),
+            &self.device.watchdog,
+            self.frequency,
+        )))));
+
+        subscribers.push(Arc::new(Mutex::new(Box::new(boot_loader::BootLoader::new(
+            self.memory.clone(),
+            self.device,
+            self.fuses,
+            self.frequency,
+        )))));
+
+        if let Some(register) = self.device.clock_prescaler {
+            subscribers.push(Arc::new(Mutex::new(Box::new(
+                system_clock_prescaler::SystemClockPrescaler::new(self.memory.clone(), register),
+            ))));
+        }
+
+        let mut threads = vec![];
+
+        for subscriber in subscribers {
+            clock.lock().unwrap().subscribe(subscriber.clone());
+
+            let stop_program = self.stop_program.clone();
+            threads.push(std::thread::spawn(move || loop {
+                subscriber.lock().unwrap().run();
+                std::thread::yield_now();
+                if stop_program.load(std::sync::atomic::Ordering::Relaxed) {
+                    break;
+                }
+            }));
+        }
+
+        let stop_program = self.stop_program.clone();
+        let memory = self.memory.clone();
+        let device = self.device;
+        let fuses = self.fuses;
+        let preserve_sram = self.preserve_sram;
+        let debugger = self.debugger.clone();
+        let cycles = self.cycles.clone();
+        let observers = self.observers.clone();
+        let symbols = self.symbols.clone();
+        threads.push(std::thread::spawn(move || loop {
+            // the clock stands still while the core is halted, resets are still performed
+            let running = debugger.as_ref().is_none_or(|debugger| {
+                debugger.wait_until_running(std::time::Duration::from_millis(10))
+            });
+            if running {
+                let clock_division = memory.lock().unwrap().get_clock_division();
+                clock.lock().unwrap().run();
+                cycles.add(clock_division);
+            }
+            if !observers.is_empty() {
+                let events = memory.lock().unwrap().take_events();
+                observer::notify(&observers, |observer| {
+                    for event in &events {
+                        observer.on_peripheral_event(event.peripheral, &event.event);
+                    }
+                });
+            }
+            let (reset_request, stack_fault) = {
+                let mut memory = memory.lock().unwrap();
+                (memory.take_reset_request(), memory.take_stack_fault())
+            };
+            if let Some((address, pc)) = stack_fault {
+                log::error!(
+                    "stopping at {}: stack access at SP {:#06x} outside the data space",
+                    symbols.format_address(pc as u32 * 2),
+                    address
+                );
+                stop_program.store(true, std::sync::atomic::Ordering::Relaxed);
+            }
+            if let Some(kind) = reset_request {
+                log::info!("{:?} reset", kind);
+                // a watchdog reset is a firmware failing to kick it, shown at any log level
+                if kind == reset::ResetKind::Watchdog {
+                    eprintln!("watchdog reset");
+                }
+                reset::reset(&mut memory.lock().unwrap(), device, &fuses, kind, preserve_sram);
+                clock.lock().unwrap().reset();
+                observer::notify(&observers, |observer| observer.on_reset(kind));
+            }
+            let division = memory.lock().unwrap().get_clock_division();
+            clock.lock().unwrap().set_division(division);
+            if stop_program.load(std::sync::atomic::Ordering::Relaxed) {
                 break;
             }
-        });
-
-        vec![
-            instruction_executor_thread,
-            clock_thread,
-            timer_thread,
-            interrupt_handler_thread,
-        ]
+        }));
+
+        threads
     }
 }
@@ -0,0 +1,475 @@
+use std::sync::{Arc, Mutex};
+
+use crate::avr_emulator::analog::{AnalogInput, AnalogInputs};
+use crate::avr_emulator::clock;
+use crate::avr_emulator::device::{AdcChannel, AdcRegisters, AdcTriggerSource};
+use crate::avr_emulator::memory::Memory;
+
+// ADMUX
+const REFS: u8 = 0b1100_0000;
+const ADLAR: u8 = 1 << 5;
+const MUX: u8 = 0b0000_1111;
+
+// ADCSRA
+const ADEN: u8 = 1 << 7;
+const ADSC: u8 = 1 << 6;
+const ADATE: u8 = 1 << 5;
+const ADIF: u8 = 1 << 4;
+const ADPS: u8 = 0b0000_0111;
+
+// ADCSRB
+const ADTS: u8 = 0b0000_0111;
+
+// Conversion length in adc clock cycles, the first one after enabling initialises the analog circuitry
+const FIRST_CONVERSION_CYCLES: u32 = 25;
+const CONVERSION_CYCLES: u32 = 13;
+
+const MAX_RESULT: u16 = 1023;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Conversion {
+    remaining_cycles: u32,
+    result: u16,
+}
+
+pub struct Adc {
+    rising_edge_notified: std::sync::atomic::AtomicBool,
+    memory: Arc<Mutex<Memory>>,
+    registers: &'static AdcRegisters,
+    analog_inputs: Arc<Mutex<AnalogInputs>>,
+    cycle: u64,
+    conversion: Option<Conversion>,
+    first_conversion: bool,
+    last_trigger_level: bool,
+    // reading ADCL blocks result updates until ADCH is read
+    data_locked: bool,
+}
+
+impl Adc {
+    pub fn new(
+        memory: Arc<Mutex<Memory>>,
+        registers: &'static AdcRegisters,
+        analog_inputs: Arc<Mutex<AnalogInputs>>,
+    ) -> Self {
+        Self {
+            rising_edge_notified: std::sync::atomic::AtomicBool::new(false),
+            memory,
+            registers,
+            analog_inputs,
+            cycle: 0,
+            conversion: None,
+            first_conversion: true,
+            last_trigger_level: false,
+            data_locked: false,
+        }
+    }
+
+    fn get_prescaler(memory: &Memory, registers: &AdcRegisters) -> u32 {
+        match memory.peek(registers.control_status_a) & ADPS {
+            0 => 2,
+            prescaler_select => 1 << prescaler_select,
+        }
+    }
+
+    fn get_reference_voltage(&self, memory: &Memory, analog_inputs: &AnalogInputs) -> f64 {
+        match (memory.peek(self.registers.multiplexer) & REFS) >> 6 {
+            0 => analog_inputs.get_voltage(AnalogInput::Aref, self.cycle),
+            1 => analog_inputs.get_voltage(AnalogInput::Avcc, self.cycle),
+            3 => self.registers.internal_reference,
+            _ => {
+                log::warn!("Adc reserved reference selection, using AVCC");
+                analog_inputs.get_voltage(AnalogInput::Avcc, self.cycle)
+            }
+        }
+    }
+
+    fn sample(&self, memory: &Memory) -> u16 {
+        let analog_inputs = self.analog_inputs.lock().unwrap();
+
+        let channel = memory.peek(self.registers.multiplexer) & MUX;
+        let input_voltage = match self.registers.channels[channel as usize] {
+            AdcChannel::Input(input) => analog_inputs.get_voltage(input, self.cycle),
+            AdcChannel::Voltage(volts) => volts,
+            AdcChannel::Reserved => {
+                log::warn!("Adc reserved channel {} selected", channel);
+                0.0
+            }
+        };
+
+        let reference_voltage = self.get_reference_voltage(memory, &analog_inputs);
+        if reference_voltage <= 0.0 {
+            return 0;
+        }
+
+        let result = (input_voltage * 1024.0 / reference_voltage).floor();
+
+        result.clamp(0.0, MAX_RESULT as f64) as u16
+    }
+
+    fn start_conversion(&mut self, memory: &mut Memory) {
+        let adc_cycles = if self.first_conversion {
+            FIRST_CONVERSION_CYCLES
+        } else {
+            CONVERSION_CYCLES
+        };
+        self.first_conversion = false;
+
+        // the input is sampled at the beginning of the conversion
+        self.conversion = Some(Conversion {
+            remaining_cycles: adc_cycles * Self::get_prescaler(memory, self.registers),
+            result: self.sample(memory),
+        });
+
+        let control = memory.peek(self.registers.control_status_a);
+        memory.poke(self.registers.control_status_a, control | ADSC);
+    }
+
+    fn handle_register_accesses(&mut self, memory: &mut Memory) {
+        if let Some(write) = memory.take_io_write(self.registers.control_status_a) {
+            // ADIF is cleared by writing one, writing zero to ADSC has no effect
+            let flag = write.old & ADIF & !write.new;
+            let start = (write.old | write.new) & ADSC;
+            let mut control = (write.new & !(ADIF | ADSC)) | flag | start;
+
+            if write.new & ADEN == 0 {
+                control &= !ADSC;
+                self.conversion = None;
+            } else if write.old & ADEN == 0 {
+                self.first_conversion = true;
+            }
+
+            memory.poke(self.registers.control_status_a, control);
+        }
+
+        if memory.take_io_read(self.registers.data_low) {
+            self.data_locked = true;
+        }
+        if memory.take_io_read(self.registers.data_high) {
+            self.data_locked = false;
+        }
+    }
+
+    fn get_trigger_source(&self, memory: &Memory) -> Option<AdcTriggerSource> {
+        let control = memory.peek(self.registers.control_status_a);
+        if control & (ADEN | ADATE) != ADEN | ADATE {
+            return None;
+        }
+
+        let select = self
+            .registers
+            .control_status_b
+            .map_or(0, |address| memory.peek(address) & ADTS);
+
+        self.registers.trigger_sources.get(select as usize).copied()
+    }
+
+    fn handle_auto_trigger(&mut self, memory: &mut Memory) {
+        let Some(AdcTriggerSource::Flag(flag)) = self.get_trigger_source(memory) else {
+            self.last_trigger_level = false;
+            return;
+        };
+
+        let level = memory.get_bit(&flag);
+        if level && !self.last_trigger_level && self.conversion.is_none() {
+            self.start_conversion(memory);
+        }
+        self.last_trigger_level = level;
+    }
+
+    fn write_result(&self, memory: &mut Memory, result: u16) {
+        let left_adjust = memory.peek(self.registers.multiplexer) & ADLAR != 0;
+
+        let value = if left_adjust { result << 6 } else { result };
+
+        memory.poke(self.registers.data_low, value as u8);
+        memory.poke(self.registers.data_high, (value >> 8) as u8);
+    }
+
+    fn run_conversion(&mut self, memory: &mut Memory) {
+        let control = memory.peek(self.registers.control_status_a);
+        if control & ADEN == 0 {
+            return;
+        }
+
+        if self.conversion.is_none() && control & ADSC != 0 {
+            self.start_conversion(memory);
+        }
+
+        let Some(mut conversion) = self.conversion.take() else {
+            return;
+        };
+
+        conversion.remaining_cycles -= 1;
+        if conversion.remaining_cycles > 0 {
+            self.conversion = Some(conversion);
+            return;
+        }
+
+        if self.data_locked {
+            log::warn!("Adc result lost, ADCL was read without reading ADCH");
+        } else {
+            self.write_result(memory, conversion.result);
+        }
+
+        let control = memory.peek(self.registers.control_status_a);
+        memory.poke(self.registers.control_status_a, (control & !ADSC) | ADIF);
+        memory.raise_event(
+            "Adc",
+            format_args!("conversion complete {:#05x}", conversion.result),
+        );
+
+        if self.get_trigger_source(memory) == Some(AdcTriggerSource::FreeRunning) {
+            self.start_conversion(memory);
+        }
+    }
+
+    fn update(&mut self) {
+        let memory = self.memory.clone();
+        let mut memory = memory.lock().unwrap();
+
+        self.handle_register_accesses(&mut memory);
+        self.handle_auto_trigger(&mut memory);
+        self.run_conversion(&mut memory);
+
+        // the script of the analog inputs runs in real time, CLKPR slows the conversions but not
+        // the voltages applied to the pins
+        self.cycle += memory.get_clock_division() as u64;
+    }
+}
+
+impl clock::Subscriber for Adc {
+    fn notify_rising_edge(&self) {
+        log::debug!("Adc rising edge notified");
+
+        if self
+            .rising_edge_notified
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            log::warn!("Adc did not finish handling previous rising edge!");
+        }
+
+        self.rising_edge_notified
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+    fn notify_falling_edge(&self) {}
+
+    fn run(&mut self) {
+        if self
+            .rising_edge_notified
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            self.update();
+
+            self.rising_edge_notified
+                .store(false, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.conversion = None;
+        self.first_conversion = true;
+        self.last_trigger_level = false;
+        self.data_locked = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avr_emulator::device::{self, Device};
+    use crate::avr_emulator::test_utils::clock_cycles;
+
+    fn set_up(device: &'static Device) -> (Arc<Mutex<Memory>>, Adc, Arc<Mutex<AnalogInputs>>) {
+        let memory = Arc::new(Mutex::new(Memory::new(300, vec![]).unwrap()));
+        let analog_inputs = Arc::new(Mutex::new(AnalogInputs::default()));
+
+        let adc = Adc::new(memory.clone(), &device.adc, analog_inputs.clone());
+
+        (memory, adc, analog_inputs)
+    }
+
+    fn get_result(memory: &Arc<Mutex<Memory>>) -> u16 {
+        let memory = memory.lock().unwrap();
+        memory.peek(0x24) as u16 | (memory.peek(0x25) as u16) << 8
+    }
+
+    #[test]
+    fn test_prescaler() {
+        let (memory, _, _) = set_up(&device::ATMEGA8);
+
+        let prescaler = |select: u8| {
+            let mut memory = memory.lock().unwrap();
+            memory.poke(0x26, select);
+            Adc::get_prescaler(&memory, &device::ATMEGA8.adc)
+        };
+
+        assert_eq!(prescaler(0), 2);
+        assert_eq!(prescaler(1), 2);
+        assert_eq!(prescaler(2), 4);
+        assert_eq!(prescaler(6), 64);
+        assert_eq!(prescaler(7), 128);
+    }
+
+    #[test]
+    fn test_single_conversion_timing() {
+        let (memory, mut sut, analog_inputs) = set_up(&device::ATMEGA8);
+        analog_inputs
+            .lock()
+            .unwrap()
+            .set_voltage(AnalogInput::Adc(2), 2.5);
+
+        // AVCC reference, ADC2, prescaler 2
+        memory.lock().unwrap().set_sram(0x27, 0b0100_0010);
+        memory.lock().unwrap().set_sram(0x26, ADEN | ADSC);
+
+        clock_cycles(&mut sut, 2 * 25 - 1);
+        assert_eq!(memory.lock().unwrap().peek(0x26) & (ADSC | ADIF), ADSC);
+
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x26) & (ADSC | ADIF), ADIF);
+        assert_eq!(get_result(&memory), 512);
+
+        // following conversions take 13 adc cycles
+        memory.lock().unwrap().set_sram(0x26, ADEN | ADSC | ADIF);
+        clock_cycles(&mut sut, 2 * 13 - 1);
+        assert_eq!(memory.lock().unwrap().peek(0x26) & (ADSC | ADIF), ADSC);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x26) & (ADSC | ADIF), ADIF);
+    }
+
+    #[test]
+    fn test_references_and_channels() {
+        let (memory, sut, analog_inputs) = set_up(&device::ATMEGA8);
+        analog_inputs
+            .lock()
+            .unwrap()
+            .set_voltage(AnalogInput::Adc(0), 1.28);
+        analog_inputs
+            .lock()
+            .unwrap()
+            .set_voltage(AnalogInput::Aref, 2.56);
+
+        let sample = |multiplexer: u8| {
+            let mut memory = memory.lock().unwrap();
+            memory.poke(0x27, multiplexer);
+            sut.sample(&memory)
+        };
+
+        assert_eq!(sample(0b0000_0000), 512);
+        assert_eq!(sample(0b0100_0000), 262);
+        assert_eq!(sample(0b1100_0000), 512);
+        assert_eq!(sample(0b1100_1110), 520);
+        assert_eq!(sample(0b0000_1111), 0);
+
+        analog_inputs
+            .lock()
+            .unwrap()
+            .set_voltage(AnalogInput::Adc(0), 6.0);
+        assert_eq!(sample(0b0100_0000), 1023);
+    }
+
+    #[test]
+    fn test_scripted_input_is_sampled_at_conversion_start() {
+        let (memory, mut sut, analog_inputs) = set_up(&device::ATMEGA8);
+        analog_inputs
+            .lock()
+            .unwrap()
+            .schedule_voltage(AnalogInput::Adc(0), 10, 5.0);
+        memory.lock().unwrap().set_sram(0x27, 0b0100_0000);
+        memory.lock().unwrap().set_sram(0x26, ADEN | ADSC);
+
+        clock_cycles(&mut sut, 50);
+        assert_eq!(get_result(&memory), 0);
+
+        memory.lock().unwrap().set_sram(0x26, ADEN | ADSC | ADIF);
+        clock_cycles(&mut sut, 26);
+        assert_eq!(get_result(&memory), 1023);
+    }
+
+    #[test]
+    fn test_left_adjust() {
+        let (memory, mut sut, analog_inputs) = set_up(&device::ATMEGA8);
+        analog_inputs
+            .lock()
+            .unwrap()
+            .set_voltage(AnalogInput::Adc(0), 5.0);
+        memory.lock().unwrap().set_sram(0x27, 0b0110_0000);
+        memory.lock().unwrap().set_sram(0x26, ADEN | ADSC);
+
+        clock_cycles(&mut sut, 50);
+
+        assert_eq!(memory.lock().unwrap().peek(0x25), 0xff);
+        assert_eq!(memory.lock().unwrap().peek(0x24), 0xc0);
+    }
+
+    #[test]
+    fn test_result_is_locked_until_high_byte_is_read() {
+        let (memory, mut sut, analog_inputs) = set_up(&device::ATMEGA8);
+        analog_inputs
+            .lock()
+            .unwrap()
+            .set_voltage(AnalogInput::Adc(0), 5.0);
+        memory.lock().unwrap().set_sram(0x27, 0b0100_0000);
+
+        memory.lock().unwrap().get_sram(0x24).unwrap();
+        memory.lock().unwrap().set_sram(0x26, ADEN | ADSC);
+        clock_cycles(&mut sut, 50);
+        assert_eq!(get_result(&memory), 0);
+        assert_eq!(memory.lock().unwrap().peek(0x26) & ADIF, ADIF);
+
+        memory.lock().unwrap().get_sram(0x25).unwrap();
+        memory.lock().unwrap().set_sram(0x26, ADEN | ADSC);
+        clock_cycles(&mut sut, 26);
+        assert_eq!(get_result(&memory), 1023);
+    }
+
+    #[test]
+    fn test_disabling_aborts_conversion() {
+        let (memory, mut sut, _) = set_up(&device::ATMEGA8);
+        memory.lock().unwrap().set_sram(0x26, ADEN | ADSC);
+        clock_cycles(&mut sut, 10);
+
+        memory.lock().unwrap().set_sram(0x26, 0);
+        clock_cycles(&mut sut, 100);
+
+        assert_eq!(memory.lock().unwrap().peek(0x26), 0);
+    }
+
+    #[test]
+    fn test_free_running() {
+        let (memory, mut sut, _) = set_up(&device::ATMEGA8);
+        memory.lock().unwrap().set_sram(0x26, ADEN | ADSC | ADATE);
+
+        clock_cycles(&mut sut, 50);
+        assert_eq!(
+            memory.lock().unwrap().peek(0x26) & (ADSC | ADIF),
+            ADSC | ADIF
+        );
+
+        memory.lock().unwrap().set_sram(0x26, ADEN | ADATE | ADIF);
+        clock_cycles(&mut sut, 26);
+        assert_eq!(memory.lock().unwrap().peek(0x26) & ADIF, ADIF);
+    }
+
+    #[test]
+    fn test_auto_trigger_on_flag_rising_edge() {
+        let (memory, mut sut, _) = set_up(&device::ATMEGA88);
+        // timer0 overflow
+        memory.lock().unwrap().set_sram(0x7b, 4);
+        memory.lock().unwrap().set_sram(0x7a, ADEN | ADATE);
+
+        clock_cycles(&mut sut, 100);
+        assert_eq!(memory.lock().unwrap().peek(0x7a) & (ADSC | ADIF), 0);
+
+        memory.lock().unwrap().poke(0x35, 1);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x7a) & ADSC, ADSC);
+
+        clock_cycles(&mut sut, 50);
+        assert_eq!(memory.lock().unwrap().peek(0x7a) & (ADSC | ADIF), ADIF);
+
+        // the flag has to be cleared before it triggers again
+        clock_cycles(&mut sut, 100);
+        assert_eq!(memory.lock().unwrap().peek(0x7a) & ADSC, 0);
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AnalogInput {
+    Adc(u8),
+    Ain0,
+    Ain1,
+    Aref,
+    Avcc,
+}
+
+impl std::str::FromStr for AnalogInput {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let name = name.to_ascii_uppercase();
+
+        match name.as_str() {
+            "AIN0" => Ok(AnalogInput::Ain0),
+            "AIN1" => Ok(AnalogInput::Ain1),
+            "AREF" => Ok(AnalogInput::Aref),
+            "AVCC" => Ok(AnalogInput::Avcc),
+            _ => name
+                .strip_prefix("ADC")
+                .and_then(|channel| channel.parse::<u8>().ok())
+                .filter(|channel| *channel < 8)
+                .map(AnalogInput::Adc)
+                .ok_or(format!("unknown analog input: {}", name)),
+        }
+    }
+}
+
+// Voltages of the analog inputs over simulated time, every input keeps its voltage until
+// the next scheduled change. Unset inputs are at 0 V, AREF and AVCC at 5 V.
+#[derive(Debug, Default)]
+pub struct AnalogInputs {
+    changes: HashMap<AnalogInput, Vec<(u64, f64)>>,
+}
+
+impl AnalogInputs {
+    const DEFAULT_SUPPLY_VOLTAGE: f64 = 5.0;
+
+    pub fn set_voltage(&mut self, input: AnalogInput, volts: f64) {
+        self.changes.insert(input, vec![(0, volts)]);
+    }
+
+    pub fn schedule_voltage(&mut self, input: AnalogInput, cycle: u64, volts: f64) {
+        let changes = self.changes.entry(input).or_default();
+
+        let position = changes.partition_point(|(change_cycle, _)| *change_cycle <= cycle);
+        changes.insert(position, (cycle, volts));
+    }
+
+    pub fn get_voltage(&self, input: AnalogInput, cycle: u64) -> f64 {
+        let default = match input {
+            AnalogInput::Aref | AnalogInput::Avcc => Self::DEFAULT_SUPPLY_VOLTAGE,
+            _ => 0.0,
+        };
+
+        self.changes
+            .get(&input)
+            .and_then(|changes| {
+                changes
+                    .iter()
+                    .take_while(|(change_cycle, _)| *change_cycle <= cycle)
+                    .last()
+            })
+            .map_or(default, |(_, volts)| *volts)
+    }
+
+    // Each line of the script holds the time in seconds, the input name and the voltage,
+    // e.g. "0.002 ADC3 1.25". Empty lines and lines starting with # are ignored.
+    pub fn load_script(&mut self, path: &Path, frequency: i64) -> Result<(), String> {
+        let script = std::fs::read_to_string(path)
+            .map_err(|error| format!("failed to read {}: {}", path.display(), error))?;
+
+        self.parse_script(&script, frequency)
+    }
+
+    fn parse_script(&mut self, script: &str, frequency: i64) -> Result<(), String> {
+        for (number, line) in script.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let error = |message: &str| format!("analog script line {}: {}", number + 1, message);
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [time, input, volts] = fields[..] else {
+                return Err(error("expected <time_s> <input> <volts>"));
+            };
+
+            let time: f64 = time.parse().map_err(|_| error("invalid time"))?;
+            let input: AnalogInput = input.parse().map_err(|message: String| error(&message))?;
+            let volts: f64 = volts.parse().map_err(|_| error("invalid voltage"))?;
+
+            if time < 0.0 {
+                return Err(error("negative time"));
+            }
+
+            self.schedule_voltage(input, (time * frequency as f64).round() as u64, volts);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_input_names() {
+        assert_eq!("adc3".parse::<AnalogInput>(), Ok(AnalogInput::Adc(3)));
+        assert_eq!("AIN1".parse::<AnalogInput>(), Ok(AnalogInput::Ain1));
+        assert_eq!("Aref".parse::<AnalogInput>(), Ok(AnalogInput::Aref));
+        assert!("ADC8".parse::<AnalogInput>().is_err());
+        assert!("PB1".parse::<AnalogInput>().is_err());
+    }
+
+    #[test]
+    fn test_default_voltages() {
+        let sut = AnalogInputs::default();
+
+        assert_eq!(sut.get_voltage(AnalogInput::Adc(0), 100), 0.0);
+        assert_eq!(sut.get_voltage(AnalogInput::Avcc, 100), 5.0);
+        assert_eq!(sut.get_voltage(AnalogInput::Aref, 100), 5.0);
+    }
+
+    #[test]
+    fn test_scheduled_voltages() {
+        let mut sut = AnalogInputs::default();
+        sut.schedule_voltage(AnalogInput::Adc(1), 200, 3.0);
+        sut.schedule_voltage(AnalogInput::Adc(1), 100, 1.0);
+
+        assert_eq!(sut.get_voltage(AnalogInput::Adc(1), 99), 0.0);
+        assert_eq!(sut.get_voltage(AnalogInput::Adc(1), 100), 1.0);
+        assert_eq!(sut.get_voltage(AnalogInput::Adc(1), 199), 1.0);
+        assert_eq!(sut.get_voltage(AnalogInput::Adc(1), 5000), 3.0);
+
+        sut.set_voltage(AnalogInput::Adc(1), 2.0);
+        assert_eq!(sut.get_voltage(AnalogInput::Adc(1), 5000), 2.0);
+    }
+
+    #[test]
+    fn test_parse_script() {
+        let mut sut = AnalogInputs::default();
+
+        sut.parse_script(
+            "# battery\n0 ADC0 4.2\n\n0.5 adc0 3.3\n0.001 AREF 2.5\n",
+            1000,
+        )
+        .unwrap();
+
+        assert_eq!(sut.get_voltage(AnalogInput::Adc(0), 0), 4.2);
+        assert_eq!(sut.get_voltage(AnalogInput::Adc(0), 500), 3.3);
+        assert_eq!(sut.get_voltage(AnalogInput::Aref, 0), 5.0);
+        assert_eq!(sut.get_voltage(AnalogInput::Aref, 1), 2.5);
+    }
+
+    #[test]
+    fn test_parse_script_errors() {
+        let mut sut = AnalogInputs::default();
+
+        assert!(sut.parse_script("0 ADC0", 1000).is_err());
+        assert!(sut.parse_script("x ADC0 1.0", 1000).is_err());
+        assert!(sut.parse_script("0 ADC9 1.0", 1000).is_err());
+        assert!(sut.parse_script("0 ADC0 high", 1000).is_err());
+        assert!(sut.parse_script("-1 ADC0 1.0", 1000).is_err());
+    }
+}
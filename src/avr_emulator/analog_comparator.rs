@@ -0,0 +1,354 @@
+use std::sync::{Arc, Mutex};
+
+use crate::avr_emulator::analog::{AnalogInput, AnalogInputs};
+use crate::avr_emulator::clock;
+use crate::avr_emulator::device::Device;
+use crate::avr_emulator::memory::Memory;
+
+// ACSR
+const ACD: u8 = 1 << 7;
+const ACBG: u8 = 1 << 6;
+const ACO: u8 = 1 << 5;
+const ACI: u8 = 1 << 4;
+const ACIC: u8 = 1 << 2;
+const ACIS: u8 = 0b0000_0011;
+
+// ADCSRA and ADMUX
+const ADEN: u8 = 1 << 7;
+const MUX: u8 = 0b0000_0111;
+
+#[derive(Debug, PartialEq)]
+enum InterruptMode {
+    Toggle,
+    Reserved,
+    FallingEdge,
+    RisingEdge,
+}
+
+pub struct AnalogComparator {
+    rising_edge_notified: std::sync::atomic::AtomicBool,
+    memory: Arc<Mutex<Memory>>,
+    device: &'static Device,
+    analog_inputs: Arc<Mutex<AnalogInputs>>,
+    cycle: u64,
+    last_output: bool,
+}
+
+impl AnalogComparator {
+    pub fn new(
+        memory: Arc<Mutex<Memory>>,
+        device: &'static Device,
+        analog_inputs: Arc<Mutex<AnalogInputs>>,
+    ) -> Self {
+        Self {
+            rising_edge_notified: std::sync::atomic::AtomicBool::new(false),
+            memory,
+            device,
+            analog_inputs,
+            cycle: 0,
+            last_output: false,
+        }
+    }
+
+    fn get_interrupt_mode(control: u8) -> InterruptMode {
+        match control & ACIS {
+            0 => InterruptMode::Toggle,
+            1 => InterruptMode::Reserved,
+            2 => InterruptMode::FallingEdge,
+            _ => InterruptMode::RisingEdge,
+        }
+    }
+
+    fn get_negative_input(&self, memory: &Memory) -> AnalogInput {
+        let multiplexed = memory.get_bit(&self.device.analog_comparator.multiplexer_enable)
+            && memory.peek(self.device.adc.control_status_a) & ADEN == 0;
+
+        if multiplexed {
+            AnalogInput::Adc(memory.peek(self.device.adc.multiplexer) & MUX)
+        } else {
+            AnalogInput::Ain1
+        }
+    }
+
+    fn compare(&self, memory: &Memory) -> bool {
+        let analog_inputs = self.analog_inputs.lock().unwrap();
+        let control = memory.peek(self.device.analog_comparator.control_status);
+
+        let positive = if control & ACBG != 0 {
+            self.device.analog_comparator.bandgap_voltage
+        } else {
+            analog_inputs.get_voltage(AnalogInput::Ain0, self.cycle)
+        };
+        let negative = analog_inputs.get_voltage(self.get_negative_input(memory), self.cycle);
+
+        positive > negative
+    }
+
+    fn handle_register_writes(&self, memory: &mut Memory) {
+        let control_status = self.device.analog_comparator.control_status;
+
+        // ACO is read only, ACI is cleared by writing one
+        if let Some(write) = memory.take_io_write(control_status) {
+            let output = write.old & ACO;
+            let flag = write.old & ACI & !write.new;
+            memory.poke(control_status, (write.new & !(ACO | ACI)) | output | flag);
+        }
+    }
+
+    fn capture(&self, memory: &mut Memory, output: bool) {
+        let input_capture = &self.device.analog_comparator.input_capture;
+
+        if memory.get_bit(&input_capture.edge_select) != output {
+            return;
+        }
+
+        let counter_low = memory.peek(input_capture.counter);
+        let counter_high = memory.peek(input_capture.counter + 1);
+        memory.poke(input_capture.capture, counter_low);
+        memory.poke(input_capture.capture + 1, counter_high);
+
+        memory.set_bit(&input_capture.flag, true);
+    }
+
+    fn update(&mut self) {
+        let memory = self.memory.clone();
+        let mut memory = memory.lock().unwrap();
+
+        self.handle_register_writes(&mut memory);
+
+        let control_status = self.device.analog_comparator.control_status;
+        let control = memory.peek(control_status);
+
+        if control & ACD == 0 {
+            let output = self.compare(&memory);
+
+            if output != self.last_output {
+                let triggered = match Self::get_interrupt_mode(control) {
+                    InterruptMode::Toggle => true,
+                    InterruptMode::Reserved => false,
+                    InterruptMode::FallingEdge => !output,
+                    InterruptMode::RisingEdge => output,
+                };
+
+                let output_bit = if output { ACO } else { 0 };
+                let flag = if triggered { ACI } else { 0 };
+                memory.poke(control_status, (control & !ACO) | output_bit | flag);
+
+                if control & ACIC != 0 {
+                    self.capture(&mut memory, output);
+                }
+
+                self.last_output = output;
+            }
+        }
+
+        // a divided system clock samples the comparator inputs less often, their script still
+        // changes at the times it was written for
+        self.cycle += memory.get_clock_division() as u64;
+    }
+}
+
+impl clock::Subscriber for AnalogComparator {
+    fn notify_rising_edge(&self) {
+        log::debug!("AnalogComparator rising edge notified");
+
+        if self
+            .rising_edge_notified
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            log::warn!("AnalogComparator did not finish handling previous rising edge!");
+        }
+
+        self.rising_edge_notified
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+    fn notify_falling_edge(&self) {}
+
+    fn run(&mut self) {
+        if self
+            .rising_edge_notified
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            self.update();
+
+            self.rising_edge_notified
+                .store(false, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.last_output = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avr_emulator::device;
+    use crate::avr_emulator::test_utils::clock_cycles;
+
+    fn set_up(
+        device: &'static Device,
+    ) -> (
+        Arc<Mutex<Memory>>,
+        AnalogComparator,
+        Arc<Mutex<AnalogInputs>>,
+    ) {
+        let memory = Arc::new(Mutex::new(Memory::new(300, vec![]).unwrap()));
+        let analog_inputs = Arc::new(Mutex::new(AnalogInputs::default()));
+
+        let comparator = AnalogComparator::new(memory.clone(), device, analog_inputs.clone());
+
+        (memory, comparator, analog_inputs)
+    }
+
+    #[test]
+    fn test_output_follows_inputs() {
+        let (memory, mut sut, analog_inputs) = set_up(&device::ATMEGA8);
+        analog_inputs
+            .lock()
+            .unwrap()
+            .schedule_voltage(AnalogInput::Ain0, 5, 2.0);
+        analog_inputs
+            .lock()
+            .unwrap()
+            .schedule_voltage(AnalogInput::Ain1, 10, 3.0);
+
+        clock_cycles(&mut sut, 5);
+        assert_eq!(memory.lock().unwrap().peek(0x28) & ACO, 0);
+
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x28) & ACO, ACO);
+
+        clock_cycles(&mut sut, 5);
+        assert_eq!(memory.lock().unwrap().peek(0x28) & ACO, 0);
+    }
+
+    #[test]
+    fn test_interrupt_modes() {
+        let (memory, mut sut, analog_inputs) = set_up(&device::ATMEGA8);
+        let set_ain0 = |volts: f64| {
+            analog_inputs
+                .lock()
+                .unwrap()
+                .set_voltage(AnalogInput::Ain0, volts)
+        };
+
+        // rising edge
+        memory.lock().unwrap().set_sram(0x28, 0b0000_0011);
+        set_ain0(1.0);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x28) & ACI, ACI);
+
+        // writing one clears the flag
+        memory.lock().unwrap().set_sram(0x28, 0b0001_0011);
+        set_ain0(0.0);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x28) & ACI, 0);
+
+        // falling edge
+        memory.lock().unwrap().set_sram(0x28, 0b0000_0010);
+        set_ain0(1.0);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x28) & ACI, 0);
+        set_ain0(0.0);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x28) & ACI, ACI);
+
+        // toggle
+        memory.lock().unwrap().set_sram(0x28, 0b0001_0000);
+        set_ain0(1.0);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x28) & ACI, ACI);
+    }
+
+    #[test]
+    fn test_output_is_read_only() {
+        let (memory, mut sut, analog_inputs) = set_up(&device::ATMEGA8);
+        analog_inputs
+            .lock()
+            .unwrap()
+            .set_voltage(AnalogInput::Ain0, 1.0);
+        clock_cycles(&mut sut, 1);
+
+        memory.lock().unwrap().set_sram(0x28, 0);
+        clock_cycles(&mut sut, 1);
+
+        assert_eq!(memory.lock().unwrap().peek(0x28), ACO | ACI);
+    }
+
+    #[test]
+    fn test_bandgap_and_multiplexed_inputs() {
+        let (memory, mut sut, analog_inputs) = set_up(&device::ATMEGA88);
+        analog_inputs
+            .lock()
+            .unwrap()
+            .set_voltage(AnalogInput::Ain1, 1.0);
+        analog_inputs
+            .lock()
+            .unwrap()
+            .set_voltage(AnalogInput::Adc(3), 2.0);
+
+        // bandgap of 1.1 V against AIN1
+        memory.lock().unwrap().set_sram(0x50, ACBG);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x50) & ACO, ACO);
+
+        // bandgap against ADC3 through the multiplexer
+        memory.lock().unwrap().poke(0x7c, 3);
+        memory.lock().unwrap().poke(0x7b, 1 << 6);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x50) & ACO, 0);
+
+        // the multiplexer is not available while the ADC is enabled
+        memory.lock().unwrap().poke(0x7a, ADEN);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x50) & ACO, ACO);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let (memory, mut sut, analog_inputs) = set_up(&device::ATMEGA8);
+        memory.lock().unwrap().set_sram(0x28, ACD);
+        analog_inputs
+            .lock()
+            .unwrap()
+            .set_voltage(AnalogInput::Ain0, 1.0);
+
+        clock_cycles(&mut sut, 10);
+
+        assert_eq!(memory.lock().unwrap().peek(0x28), ACD);
+    }
+
+    #[test]
+    fn test_input_capture() {
+        let (memory, mut sut, analog_inputs) = set_up(&device::ATMEGA8);
+        // capture on rising edge
+        memory.lock().unwrap().set_sram(0x28, ACIC);
+        memory.lock().unwrap().poke(0x4e, 1 << 6);
+        memory.lock().unwrap().poke(0x4c, 0x34);
+        memory.lock().unwrap().poke(0x4d, 0x12);
+
+        analog_inputs
+            .lock()
+            .unwrap()
+            .set_voltage(AnalogInput::Ain0, 1.0);
+        clock_cycles(&mut sut, 1);
+
+        assert_eq!(memory.lock().unwrap().peek(0x46), 0x34);
+        assert_eq!(memory.lock().unwrap().peek(0x47), 0x12);
+        assert_eq!(memory.lock().unwrap().peek(0x58), 1 << 5);
+
+        // ICF1 is cleared by the timer, the falling edge is not captured
+        memory.lock().unwrap().poke(0x58, 0);
+        memory.lock().unwrap().poke(0x4c, 0x56);
+        analog_inputs
+            .lock()
+            .unwrap()
+            .set_voltage(AnalogInput::Ain0, 0.0);
+        clock_cycles(&mut sut, 1);
+
+        assert_eq!(memory.lock().unwrap().peek(0x46), 0x34);
+        assert_eq!(memory.lock().unwrap().peek(0x58), 0);
+    }
+}
@@ -0,0 +1,444 @@
+use std::sync::{Arc, Mutex};
+
+use crate::avr_emulator::clock;
+use crate::avr_emulator::device::Device;
+use crate::avr_emulator::fuses::Fuses;
+use crate::avr_emulator::memory::{Memory, SpmRequest};
+
+// SPMCR / SPMCSR
+const RWWSB: u8 = 1 << 6;
+const RWWSRE: u8 = 1 << 4;
+const BLBSET: u8 = 1 << 3;
+const PGWRT: u8 = 1 << 2;
+const PGERS: u8 = 1 << 1;
+const SPMEN: u8 = 1 << 0;
+const OPERATION: u8 = RWWSRE | BLBSET | PGWRT | PGERS | SPMEN;
+
+// SPM has to follow within four cycles after setting SPMEN and IVSEL after setting IVCE,
+// afterwards the enable bits are cleared by hardware
+const CHANGE_ENABLE_CYCLES: u8 = 4;
+
+const ERASED: u8 = 0xff;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PageOperation {
+    address: usize,
+    erase: bool,
+    write_time: clock::Countdown,
+}
+
+// Self programming through SPM and the interrupt vector select of the boot loader section.
+// SPM only works from the boot loader section and page operations honour the boot lock bits.
+// The cpu is halted while a page in the NRWW section is programmed, which is emulated by
+// completing the operation at once. Pages in the RWW section are programmed in the background
+// and RWWSB stays set until the section is re-enabled with RWWSRE.
+pub struct BootLoader {
+    rising_edge_notified: std::sync::atomic::AtomicBool,
+    memory: Arc<Mutex<Memory>>,
+    device: &'static Device,
+    fuses: Fuses,
+    frequency: i64,
+    vector_change_cycles: u8,
+    spm_enable_cycles: u8,
+    page_buffer: Vec<u8>,
+    page_operation: Option<PageOperation>,
+}
+
+impl BootLoader {
+    pub fn new(
+        memory: Arc<Mutex<Memory>>,
+        device: &'static Device,
+        fuses: Fuses,
+        frequency: i64,
+    ) -> Self {
+        Self {
+            rising_edge_notified: std::sync::atomic::AtomicBool::new(false),
+            memory,
+            device,
+            fuses,
+            frequency,
+            vector_change_cycles: 0,
+            spm_enable_cycles: 0,
+            page_buffer: vec![ERASED; device.boot_loader.page_size],
+            page_operation: None,
+        }
+    }
+
+    fn handle_vector_select_write(&mut self, memory: &mut Memory) {
+        let vector_select = self.device.boot_loader.vector_select;
+        let vector_change_enable = self.device.boot_loader.vector_change_enable;
+
+        let Some(write) = memory.take_io_write(vector_select.address) else {
+            return;
+        };
+
+        let select = vector_select.mask();
+        let mut value = (write.new & !select) | (write.old & select);
+
+        if write.new & vector_change_enable.mask() != 0 {
+            self.vector_change_cycles = CHANGE_ENABLE_CYCLES;
+        } else if self.vector_change_cycles > 0 {
+            value = write.new;
+            self.vector_change_cycles = 0;
+        } else if (write.new ^ write.old) & select != 0 {
+            log::warn!("BootLoader IVSEL changed without IVCE, ignored");
+        }
+
+        memory.poke(vector_select.address, value);
+    }
+
+    fn update_vector_change_enable(&mut self, memory: &mut Memory) {
+        if self.vector_change_cycles > 0 {
+            self.vector_change_cycles -= 1;
+
+            if self.vector_change_cycles == 0 {
+                memory.set_bit(&self.device.boot_loader.vector_change_enable, false);
+            }
+        }
+    }
+
+    fn handle_control_write(&mut self, memory: &mut Memory) {
+        let control = self.device.boot_loader.control;
+
+        let Some(write) = memory.take_io_write(control) else {
+            return;
+        };
+
+        let mut value = (write.new & !RWWSB) | (write.old & RWWSB);
+
+        if self.page_operation.is_some() {
+            // only SPMIE can change until the page operation completes
+            value = (value & !(OPERATION | RWWSB)) | (write.old & (OPERATION | RWWSB));
+        } else if write.new & SPMEN != 0 {
+            self.spm_enable_cycles = CHANGE_ENABLE_CYCLES;
+        }
+
+        memory.poke(control, value);
+    }
+
+    fn finish_operation(&self, memory: &mut Memory) {
+        let control = self.device.boot_loader.control;
+        memory.poke(control, memory.peek(control) & !OPERATION);
+    }
+
+    fn fill_page_buffer(&mut self, request: &SpmRequest) {
+        let offset = (request.address as usize % self.page_buffer.len()) & !1;
+
+        self.page_buffer[offset] = request.data as u8;
+        self.page_buffer[offset + 1] = (request.data >> 8) as u8;
+    }
+
+    fn start_page_operation(&mut self, memory: &mut Memory, request: &SpmRequest, erase: bool) {
+        let registers = &self.device.boot_loader;
+        let address =
+            (request.address as usize % registers.flash_size) & !(registers.page_size - 1);
+
+        let lock = if address >= self.fuses.get_boot_loader_start(self.device) {
+            self.fuses.get_boot_loader_lock()
+        } else {
+            self.fuses.get_application_lock()
+        };
+
+        if !lock.allows_spm_write() {
+            log::warn!(
+                "BootLoader SPM write to locked page {:#06x}, ignored",
+                address
+            );
+            self.finish_operation(memory);
+            return;
+        }
+
+        let operation = PageOperation {
+            address,
+            erase,
+            write_time: clock::Countdown::new(registers.write_time, self.frequency),
+        };
+
+        if address >= registers.no_read_while_write_start {
+            self.complete_page_operation(memory, operation);
+        } else {
+            memory.poke(registers.control, memory.peek(registers.control) | RWWSB);
+            self.page_operation = Some(operation);
+        }
+    }
+
+    fn complete_page_operation(&mut self, memory: &mut Memory, operation: PageOperation) {
+        for offset in 0..self.page_buffer.len() {
+            let value = if operation.erase {
+                ERASED
+            } else {
+                self.page_buffer[offset]
+            };
+            memory.set_flash(operation.address + offset, value);
+        }
+
+        if !operation.erase {
+            self.page_buffer.fill(ERASED);
+        }
+
+        self.finish_operation(memory);
+    }
+
+    fn handle_spm_request(&mut self, memory: &mut Memory) {
+        let Some(request) = memory.take_spm_request() else {
+            return;
+        };
+
+        if self.spm_enable_cycles == 0 || self.page_operation.is_some() {
+            log::warn!("BootLoader SPM without SPMEN, ignored");
+            return;
+        }
+        self.spm_enable_cycles = 0;
+
+        if (request.pc as usize) * 2 < self.fuses.get_boot_loader_start(self.device) {
+            log::warn!("BootLoader SPM executed from the application section, ignored");
+            self.finish_operation(memory);
+            return;
+        }
+
+        let control = self.device.boot_loader.control;
+
+        match memory.peek(control) & (OPERATION & !SPMEN) {
+            0 => self.fill_page_buffer(&request),
+            PGERS => return self.start_page_operation(memory, &request, true),
+            PGWRT => return self.start_page_operation(memory, &request, false),
+            BLBSET => self.fuses.program_boot_lock_bits(request.data as u8),
+            RWWSRE => {
+                memory.poke(control, memory.peek(control) & !RWWSB);
+                self.page_buffer.fill(ERASED);
+            }
+            operation => log::warn!("BootLoader invalid SPM operation {:#04x}", operation),
+        }
+
+        self.finish_operation(memory);
+    }
+
+    fn run_page_operation(&mut self, memory: &mut Memory) {
+        let Some(mut operation) = self.page_operation.take() else {
+            return;
+        };
+
+        if !operation.write_time.tick(memory.get_clock_division()) {
+            self.page_operation = Some(operation);
+            return;
+        }
+
+        self.complete_page_operation(memory, operation);
+    }
+
+    fn update_spm_enable(&mut self, memory: &mut Memory) {
+        if self.spm_enable_cycles > 0 {
+            self.spm_enable_cycles -= 1;
+
+            if self.spm_enable_cycles == 0 {
+                self.finish_operation(memory);
+            }
+        }
+    }
+
+    fn update(&mut self) {
+        let memory = self.memory.clone();
+        let mut memory = memory.lock().unwrap();
+
+        self.handle_vector_select_write(&mut memory);
+        self.handle_control_write(&mut memory);
+        self.handle_spm_request(&mut memory);
+        self.run_page_operation(&mut memory);
+
+        self.update_vector_change_enable(&mut memory);
+        self.update_spm_enable(&mut memory);
+    }
+}
+
+impl clock::Subscriber for BootLoader {
+    fn notify_rising_edge(&self) {
+        log::debug!("BootLoader rising edge notified");
+
+        if self
+            .rising_edge_notified
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            log::warn!("BootLoader did not finish handling previous rising edge!");
+        }
+
+        self.rising_edge_notified
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+    fn notify_falling_edge(&self) {}
+
+    fn run(&mut self) {
+        if self
+            .rising_edge_notified
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            self.update();
+
+            self.rising_edge_notified
+                .store(false, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    // The boot lock bits keep their programmed state
+    fn reset(&mut self) {
+        self.vector_change_cycles = 0;
+        self.spm_enable_cycles = 0;
+        self.page_buffer.fill(ERASED);
+        self.page_operation = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avr_emulator::device;
+    use crate::avr_emulator::test_utils::clock_cycles;
+
+    // BOOTSZ 00, the boot loader section starts at 0x1800
+    fn set_up(device: &'static Device, frequency: i64) -> (Arc<Mutex<Memory>>, BootLoader) {
+        let memory = Arc::new(Mutex::new(Memory::new(300, vec![]).unwrap()));
+
+        let boot_loader = BootLoader::new(memory.clone(), device, device.fuses.defaults, frequency);
+
+        (memory, boot_loader)
+    }
+
+    fn spm(memory: &Arc<Mutex<Memory>>, pc: u16, address: u16, data: u16) {
+        memory
+            .lock()
+            .unwrap()
+            .request_spm(SpmRequest { pc, address, data });
+    }
+
+    #[test]
+    fn test_vector_select_timed_sequence() {
+        let (memory, mut sut) = set_up(&device::ATMEGA8, 1_000_000);
+
+        // IVSEL alone is ignored
+        memory.lock().unwrap().set_sram(0x5b, 0b0100_0010);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x5b), 0b0100_0000);
+
+        memory.lock().unwrap().set_sram(0x5b, 0b0100_0001);
+        clock_cycles(&mut sut, 1);
+        memory.lock().unwrap().set_sram(0x5b, 0b0100_0010);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x5b), 0b0100_0010);
+    }
+
+    #[test]
+    fn test_vector_change_enable_times_out() {
+        let (memory, mut sut) = set_up(&device::ATMEGA88, 1_000_000);
+
+        memory.lock().unwrap().set_sram(0x55, 0b0000_0001);
+        clock_cycles(&mut sut, 4);
+        assert_eq!(memory.lock().unwrap().peek(0x55), 0);
+
+        memory.lock().unwrap().set_sram(0x55, 0b0000_0010);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x55), 0);
+    }
+
+    #[test]
+    fn test_nrww_page_erase_and_write() {
+        let (memory, mut sut) = set_up(&device::ATMEGA8, 1_000_000);
+        memory.lock().unwrap().set_flash(0x1c02, 0x00);
+
+        memory.lock().unwrap().set_sram(0x57, PGERS | SPMEN);
+        spm(&memory, 0xc00, 0x1c00, 0);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().get_flash(0x1c02), ERASED);
+        assert_eq!(memory.lock().unwrap().peek(0x57), 0);
+
+        memory.lock().unwrap().set_sram(0x57, SPMEN);
+        spm(&memory, 0xc00, 0x1c02, 0xabcd);
+        clock_cycles(&mut sut, 1);
+
+        memory.lock().unwrap().set_sram(0x57, PGWRT | SPMEN);
+        spm(&memory, 0xc00, 0x1c00, 0);
+        clock_cycles(&mut sut, 1);
+
+        assert_eq!(memory.lock().unwrap().get_flash(0x1c02), 0xcd);
+        assert_eq!(memory.lock().unwrap().get_flash(0x1c03), 0xab);
+        assert_eq!(memory.lock().unwrap().get_flash(0x1c04), ERASED);
+        assert_eq!(memory.lock().unwrap().peek(0x57), 0);
+    }
+
+    #[test]
+    fn test_rww_page_write_takes_write_time() {
+        let (memory, mut sut) = set_up(&device::ATMEGA88, 1000);
+        memory.lock().unwrap().set_flash(0x0040, 0x00);
+
+        memory.lock().unwrap().set_sram(0x57, SPMEN);
+        spm(&memory, 0xc00, 0x0040, 0x1234);
+        clock_cycles(&mut sut, 1);
+
+        memory.lock().unwrap().set_sram(0x57, PGWRT | SPMEN);
+        spm(&memory, 0xc00, 0x0040, 0);
+        clock_cycles(&mut sut, 4);
+        assert_eq!(memory.lock().unwrap().peek(0x57), RWWSB | PGWRT | SPMEN);
+        assert_eq!(memory.lock().unwrap().get_flash(0x0040), 0x00);
+
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().get_flash(0x0040), 0x34);
+        assert_eq!(memory.lock().unwrap().peek(0x57), RWWSB);
+
+        // RWWSB is read only and cleared through RWWSRE
+        memory.lock().unwrap().set_sram(0x57, 0);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x57), RWWSB);
+
+        memory.lock().unwrap().set_sram(0x57, RWWSRE | SPMEN);
+        spm(&memory, 0xc00, 0, 0);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x57), 0);
+    }
+
+    #[test]
+    fn test_spm_from_application_section_is_ignored() {
+        let (memory, mut sut) = set_up(&device::ATMEGA8, 1_000_000);
+
+        memory.lock().unwrap().set_sram(0x57, PGERS | SPMEN);
+        spm(&memory, 0x100, 0x1c00, 0);
+        clock_cycles(&mut sut, 1);
+
+        assert_eq!(memory.lock().unwrap().peek(0x57), 0);
+        assert!(sut.page_operation.is_none());
+    }
+
+    #[test]
+    fn test_spm_without_spmen_is_ignored() {
+        let (memory, mut sut) = set_up(&device::ATMEGA8, 1_000_000);
+
+        memory.lock().unwrap().set_sram(0x57, PGERS | SPMEN);
+        clock_cycles(&mut sut, 4);
+        assert_eq!(memory.lock().unwrap().peek(0x57), 0);
+
+        spm(&memory, 0xc00, 0x0000, 0);
+        clock_cycles(&mut sut, 1);
+        assert!(sut.page_operation.is_none());
+    }
+
+    #[test]
+    fn test_boot_lock_bits() {
+        let (memory, mut sut) = set_up(&device::ATMEGA8, 1_000_000);
+
+        // BLB11 programmed: SPM can not write the boot loader section
+        memory.lock().unwrap().set_sram(0x57, BLBSET | SPMEN);
+        spm(&memory, 0xc00, 0x0001, 0xffef);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(sut.fuses.lock, 0xef);
+
+        memory.lock().unwrap().set_flash(0x1c00, 0x00);
+        memory.lock().unwrap().set_sram(0x57, PGERS | SPMEN);
+        spm(&memory, 0xc00, 0x1c00, 0);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().get_flash(0x1c00), 0x00);
+        assert_eq!(memory.lock().unwrap().peek(0x57), 0);
+
+        // the application section is still writable
+        memory.lock().unwrap().set_sram(0x57, PGERS | SPMEN);
+        spm(&memory, 0xc00, 0x0000, 0);
+        clock_cycles(&mut sut, 1);
+        assert!(sut.page_operation.is_some());
+    }
+}
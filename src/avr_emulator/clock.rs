@@ -1,12 +1,64 @@
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 
 pub trait Subscriber: Send + Sync {
     fn notify_rising_edge(&self);
     fn notify_falling_edge(&self);
     fn run(&mut self);
+
+    // Called after the core has been reset, peripherals drop their internal state
+    fn reset(&mut self) {}
+}
+
+// Cycles run since start, time is measured in clock source cycles as the system clock may be
+// divided
+#[derive(Debug, Default)]
+pub struct Cycles {
+    system: AtomicU64,
+    source: AtomicU64,
+}
+
+impl Cycles {
+    pub fn add(&self, division: u16) {
+        self.system.fetch_add(1, Ordering::Relaxed);
+        self.source.fetch_add(division as u64, Ordering::Relaxed);
+    }
+
+    pub fn get_system(&self) -> u64 {
+        self.system.load(Ordering::Relaxed)
+    }
+
+    pub fn get_source(&self) -> u64 {
+        self.source.load(Ordering::Relaxed)
+    }
 }
 
+// A time from the datasheet like the write time of the eeprom. It passes in clock source
+// cycles, so a system clock cycle takes the clock division off it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Countdown {
+    remaining_cycles: u64,
+}
+
+impl Countdown {
+    // At least one cycle long
+    pub fn new(time_s: f64, frequency: i64) -> Self {
+        Self {
+            remaining_cycles: ((time_s * frequency as f64).round() as u64).max(1),
+        }
+    }
+
+    // Counts a system clock cycle, true once the time has passed
+    pub fn tick(&mut self, division: u16) -> bool {
+        self.remaining_cycles = self.remaining_cycles.saturating_sub(division as u64);
+        self.remaining_cycles == 0
+    }
+}
+
+// The system clock is the clock source frequency divided by the system clock prescaler
 pub struct Clock {
+    frequency_hz: f64,
+    division: u16,
     half_cycle_time_s: f64,
     subscribers: Vec<Arc<Mutex<Box<dyn Subscriber>>>>,
 }
@@ -14,11 +66,20 @@ pub struct Clock {
 impl Clock {
     pub fn new(frequency_hz: f64) -> Self {
         Self {
+            frequency_hz,
+            division: 1,
             half_cycle_time_s: 1f64 / frequency_hz / 2f64,
             subscribers: vec![],
         }
     }
 
+    pub fn set_division(&mut self, division: u16) {
+        if division != self.division {
+            self.division = division;
+            self.half_cycle_time_s = division as f64 / self.frequency_hz / 2f64;
+        }
+    }
+
     pub fn run(&self) {
         std::thread::sleep(std::time::Duration::from_secs_f64(self.half_cycle_time_s));
 
@@ -41,6 +102,12 @@ impl Clock {
         }
     }
 
+    pub fn reset(&self) {
+        for subscriber in &self.subscribers {
+            subscriber.lock().unwrap().reset();
+        }
+    }
+
     pub fn subscribe(&mut self, subscriber: Arc<Mutex<Box<dyn Subscriber>>>) {
         self.subscribers.push(subscriber);
     }
@@ -48,7 +115,7 @@ impl Clock {
 
 #[cfg(test)]
 mod tests {
-    use super::{Clock, Subscriber};
+    use super::{Clock, Countdown, Subscriber};
     use std::sync::{Arc, Mutex};
 
     struct MockSubscriber {
@@ -112,4 +179,33 @@ mod tests {
         clock.run(); // run single clock cycle
         mock_subscriber.lock().unwrap().run(); // check if it was as expected
     }
+
+    #[test]
+    fn test_run_with_division() {
+        let mock_subscriber: Arc<Mutex<Box<dyn Subscriber>>> =
+            Arc::new(Mutex::new(Box::new(MockSubscriber {
+                rising_edge_timestamp_ms: std::sync::atomic::AtomicI64::new(0),
+                falling_edge_timestamp_ms: std::sync::atomic::AtomicI64::new(0),
+                expected_frequency_hz: 1.0,
+            })));
+
+        let mut clock = Clock::new(4.0);
+        clock.set_division(4);
+        clock.subscribe(mock_subscriber.clone());
+
+        clock.run();
+        mock_subscriber.lock().unwrap().run();
+    }
+
+    #[test]
+    fn test_countdown() {
+        // 3.4 ms at 1 MHz
+        let mut sut = Countdown::new(0.0034, 1_000_000);
+        assert!(!sut.tick(8 * 424));
+        assert!(sut.tick(8));
+
+        // shorter than a cycle
+        let mut sut = Countdown::new(0.0, 1_000_000);
+        assert!(sut.tick(1));
+    }
 }
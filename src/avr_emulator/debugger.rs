@@ -0,0 +1,525 @@
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use crate::avr_emulator::memory::{AccessKind, Memory, MemoryAccess};
+use crate::avr_emulator::number::parse_number;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RunState {
+    Running,
+    Stepping,
+    Halted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    // two character operators first, so that <= is not taken for <
+    const OPERATORS: [(&'static str, Comparison); 6] = [
+        ("==", Comparison::Equal),
+        ("!=", Comparison::NotEqual),
+        ("<=", Comparison::LessOrEqual),
+        (">=", Comparison::GreaterOrEqual),
+        ("<", Comparison::Less),
+        (">", Comparison::Greater),
+    ];
+
+    fn get_operator(&self) -> &'static str {
+        Self::OPERATORS
+            .iter()
+            .find(|(_, comparison)| comparison == self)
+            .map(|(operator, _)| *operator)
+            .unwrap()
+    }
+}
+
+// A general purpose register compared with a constant like r24==5, breakpoints with
+// conditions only halt when all of them are met
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Condition {
+    pub register: usize,
+    pub comparison: Comparison,
+    pub value: u8,
+}
+
+impl Condition {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid condition: {}", value);
+
+        let (register, comparison, constant) = Comparison::OPERATORS
+            .iter()
+            .find_map(|(operator, comparison)| {
+                let (register, constant) = value.split_once(operator)?;
+                Some((register.trim(), *comparison, constant.trim()))
+            })
+            .ok_or_else(invalid)?;
+
+        let register = register
+            .strip_prefix('r')
+            .and_then(|register| register.parse().ok())
+            .filter(|register| *register < Memory::REGISTERS_SIZE)
+            .ok_or_else(invalid)?;
+
+        let value = parse_number(constant).ok_or_else(invalid)?;
+
+        Ok(Self {
+            register,
+            comparison,
+            value,
+        })
+    }
+
+    fn is_met(&self, memory: &Memory) -> bool {
+        let register = memory.peek(self.register);
+
+        match self.comparison {
+            Comparison::Equal => register == self.value,
+            Comparison::NotEqual => register != self.value,
+            Comparison::Less => register < self.value,
+            Comparison::LessOrEqual => register <= self.value,
+            Comparison::Greater => register > self.value,
+            Comparison::GreaterOrEqual => register >= self.value,
+        }
+    }
+}
+
+impl std::fmt::Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "r{}{}{}",
+            self.register,
+            self.comparison.get_operator(),
+            self.value
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+impl WatchKind {
+    fn matches(&self, kind: AccessKind) -> bool {
+        match self {
+            WatchKind::Read => kind == AccessKind::Read,
+            WatchKind::Write => kind == AccessKind::Write,
+            WatchKind::Access => true,
+        }
+    }
+}
+
+// Data space addresses execution halts at after an instruction accessed them
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watchpoint {
+    pub kind: WatchKind,
+    pub range: RangeInclusive<usize>,
+}
+
+// Why the core last halted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopReason {
+    Halted,
+    Step,
+    // word address
+    Breakpoint(u16),
+    Watchpoint {
+        kind: WatchKind,
+        access: MemoryAccess,
+    },
+}
+
+#[derive(Debug)]
+struct State {
+    run_state: RunState,
+    stop_reason: StopReason,
+    // Word addresses execution halts at before executing the instruction
+    breakpoints: BTreeMap<u16, Vec<Condition>>,
+    watchpoints: Vec<Watchpoint>,
+    // The instruction at a breakpoint is executed when resuming from it
+    skip_breakpoint: bool,
+}
+
+// Run control of the core. While halted the clock is stopped, so the peripherals
+// stand still together with the core.
+#[derive(Debug)]
+pub struct Debugger {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl Debugger {
+    pub fn new(halted: bool) -> Self {
+        Self {
+            state: Mutex::new(State {
+                run_state: if halted {
+                    RunState::Halted
+                } else {
+                    RunState::Running
+                },
+                stop_reason: StopReason::Halted,
+                breakpoints: BTreeMap::new(),
+                watchpoints: vec![],
+                skip_breakpoint: false,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    fn set_run_state(&self, run_state: RunState) {
+        let mut state = self.state.lock().unwrap();
+        state.run_state = run_state;
+        state.skip_breakpoint = run_state != RunState::Halted;
+        if run_state == RunState::Halted {
+            state.stop_reason = StopReason::Halted;
+        }
+        self.changed.notify_all();
+    }
+
+    fn stop(&self, state: &mut State, stop_reason: StopReason) {
+        state.run_state = RunState::Halted;
+        state.stop_reason = stop_reason;
+        self.changed.notify_all();
+    }
+
+    pub fn add_breakpoint(&self, pc: u16) {
+        self.add_conditional_breakpoint(pc, vec![]);
+    }
+
+    // Replaces the conditions of an existing breakpoint at pc
+    pub fn add_conditional_breakpoint(&self, pc: u16, conditions: Vec<Condition>) {
+        self.state
+            .lock()
+            .unwrap()
+            .breakpoints
+            .insert(pc, conditions);
+    }
+
+    pub fn remove_breakpoint(&self, pc: u16) {
+        self.state.lock().unwrap().breakpoints.remove(&pc);
+    }
+
+    pub fn get_breakpoints(&self) -> Vec<(u16, Vec<Condition>)> {
+        self.state
+            .lock()
+            .unwrap()
+            .breakpoints
+            .iter()
+            .map(|(pc, conditions)| (*pc, conditions.clone()))
+            .collect()
+    }
+
+    pub fn add_watchpoint(&self, kind: WatchKind, range: RangeInclusive<usize>) {
+        let watchpoint = Watchpoint { kind, range };
+        let mut state = self.state.lock().unwrap();
+
+        if !state.watchpoints.contains(&watchpoint) {
+            state.watchpoints.push(watchpoint);
+        }
+    }
+
+    pub fn remove_watchpoint(&self, kind: WatchKind, range: RangeInclusive<usize>) {
+        let watchpoint = Watchpoint { kind, range };
+
+        self.state
+            .lock()
+            .unwrap()
+            .watchpoints
+            .retain(|existing| *existing != watchpoint);
+    }
+
+    pub fn get_watchpoints(&self) -> Vec<Watchpoint> {
+        self.state.lock().unwrap().watchpoints.clone()
+    }
+
+    // The core only records its memory accesses while there are watchpoints
+    pub fn has_watchpoints(&self) -> bool {
+        !self.state.lock().unwrap().watchpoints.is_empty()
+    }
+
+    pub fn resume(&self) {
+        self.set_run_state(RunState::Running);
+    }
+
+    // Executes a single instruction and halts again
+    pub fn step(&self) {
+        self.set_run_state(RunState::Stepping);
+    }
+
+    pub fn halt(&self) {
+        self.set_run_state(RunState::Halted);
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.state.lock().unwrap().run_state == RunState::Halted
+    }
+
+    pub fn get_stop_reason(&self) -> StopReason {
+        self.state.lock().unwrap().stop_reason
+    }
+
+    // Blocks the clock while halted, returns whether the core runs after at most the timeout
+    pub fn wait_until_running(&self, timeout: Duration) -> bool {
+        let state = self.state.lock().unwrap();
+
+        self.changed
+            .wait_timeout_while(state, timeout, |state| state.run_state == RunState::Halted)
+            .unwrap()
+            .0
+            .run_state
+            != RunState::Halted
+    }
+
+    // Returns whether the core halted within the timeout
+    pub fn wait_until_halted(&self, timeout: Duration) -> bool {
+        let state = self.state.lock().unwrap();
+
+        self.changed
+            .wait_timeout_while(state, timeout, |state| state.run_state != RunState::Halted)
+            .unwrap()
+            .0
+            .run_state
+            == RunState::Halted
+    }
+
+    // Called by the core before executing the instruction at the pc
+    pub fn should_execute(&self, memory: &Memory) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let pc = memory.get_pc();
+
+        if state.run_state == RunState::Halted {
+            return false;
+        }
+
+        let hit = state
+            .breakpoints
+            .get(&pc)
+            .is_some_and(|conditions| conditions.iter().all(|condition| condition.is_met(memory)));
+
+        if !std::mem::take(&mut state.skip_breakpoint) && hit {
+            log::info!("Debugger breakpoint at {:#06x}", pc as u32 * 2);
+            self.stop(&mut state, StopReason::Breakpoint(pc));
+            return false;
+        }
+
+        true
+    }
+
+    // Called by the core after executing an instruction with its memory accesses
+    pub fn executed(&self, accesses: &[MemoryAccess]) {
+        let mut state = self.state.lock().unwrap();
+
+        let hit = accesses.iter().find_map(|access| {
+            state
+                .watchpoints
+                .iter()
+                .find(|watchpoint| {
+                    watchpoint.kind.matches(access.kind)
+                        && watchpoint.range.contains(&access.address)
+                })
+                .map(|watchpoint| (watchpoint.kind, *access))
+        });
+
+        if let Some((kind, access)) = hit {
+            log::info!(
+                "Debugger watchpoint {:?} {:#06x}: {:#04x} -> {:#04x}",
+                access.kind,
+                access.address,
+                access.old,
+                access.new
+            );
+            self.stop(&mut state, StopReason::Watchpoint { kind, access });
+        } else if state.run_state == RunState::Stepping {
+            self.stop(&mut state, StopReason::Step);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set_up() -> Memory {
+        Memory::new(100, vec![]).unwrap()
+    }
+
+    fn at(memory: &mut Memory, pc: u16) -> &Memory {
+        memory.set_pc(pc);
+        memory
+    }
+
+    fn access(kind: AccessKind, address: usize, old: u8, new: u8) -> MemoryAccess {
+        MemoryAccess {
+            kind,
+            address,
+            old,
+            new,
+        }
+    }
+
+    #[test]
+    fn test_breakpoint() {
+        let sut = Debugger::new(false);
+        let mut memory = set_up();
+        sut.add_breakpoint(0x10);
+
+        assert!(sut.should_execute(at(&mut memory, 0x0f)));
+        assert!(!sut.should_execute(at(&mut memory, 0x10)));
+        assert!(sut.is_halted());
+        assert_eq!(sut.get_stop_reason(), StopReason::Breakpoint(0x10));
+        assert!(!sut.should_execute(&memory));
+
+        // resuming executes the instruction at the breakpoint
+        sut.resume();
+        assert!(sut.should_execute(&memory));
+        assert!(sut.should_execute(at(&mut memory, 0x11)));
+
+        sut.remove_breakpoint(0x10);
+        assert!(sut.should_execute(at(&mut memory, 0x10)));
+        assert!(sut.get_breakpoints().is_empty());
+    }
+
+    #[test]
+    fn test_conditional_breakpoint() {
+        let sut = Debugger::new(false);
+        let mut memory = set_up();
+        let conditions = vec![
+            Condition::parse("r24==5").unwrap(),
+            Condition::parse("r25 >= 0x10").unwrap(),
+        ];
+        sut.add_conditional_breakpoint(0x10, conditions.clone());
+        assert_eq!(sut.get_breakpoints(), [(0x10, conditions)]);
+
+        memory.poke(24, 5);
+        assert!(sut.should_execute(at(&mut memory, 0x10)));
+
+        memory.poke(25, 0x10);
+        assert!(!sut.should_execute(&memory));
+        assert_eq!(sut.get_stop_reason(), StopReason::Breakpoint(0x10));
+    }
+
+    #[test]
+    fn test_parse_condition() {
+        assert_eq!(
+            Condition::parse("r0<=0xff"),
+            Ok(Condition {
+                register: 0,
+                comparison: Comparison::LessOrEqual,
+                value: 0xff
+            })
+        );
+        assert_eq!(Condition::parse("r31 != 7").unwrap().to_string(), "r31!=7");
+        assert_eq!(
+            Condition::parse("r1<2").unwrap().comparison,
+            Comparison::Less
+        );
+        assert!(Condition::parse("r32==1").is_err());
+        assert!(Condition::parse("r24==256").is_err());
+        assert!(Condition::parse("sp==1").is_err());
+        assert!(Condition::parse("r24=1").is_err());
+    }
+
+    #[test]
+    fn test_step() {
+        let sut = Debugger::new(true);
+        let memory = set_up();
+        assert!(!sut.should_execute(&memory));
+
+        sut.step();
+        assert!(sut.should_execute(&memory));
+        sut.executed(&[]);
+
+        assert!(sut.is_halted());
+        assert_eq!(sut.get_stop_reason(), StopReason::Step);
+        assert!(sut.wait_until_halted(Duration::ZERO));
+        assert!(!sut.should_execute(&memory));
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let sut = Debugger::new(false);
+        assert!(!sut.has_watchpoints());
+
+        sut.add_watchpoint(WatchKind::Write, 0x60..=0x61);
+        sut.add_watchpoint(WatchKind::Read, 0x70..=0x70);
+        sut.add_watchpoint(WatchKind::Read, 0x70..=0x70);
+        assert_eq!(sut.get_watchpoints().len(), 2);
+        assert!(sut.has_watchpoints());
+
+        sut.executed(&[
+            access(AccessKind::Read, 0x60, 1, 1),
+            access(AccessKind::Write, 0x62, 1, 2),
+        ]);
+        assert!(!sut.is_halted());
+
+        // writes of an unchanged value halt as well
+        let write = access(AccessKind::Write, 0x61, 3, 3);
+        sut.executed(&[write]);
+        assert_eq!(
+            sut.get_stop_reason(),
+            StopReason::Watchpoint {
+                kind: WatchKind::Write,
+                access: write
+            }
+        );
+
+        sut.resume();
+        let read = access(AccessKind::Read, 0x70, 4, 4);
+        sut.executed(&[read]);
+        assert_eq!(
+            sut.get_stop_reason(),
+            StopReason::Watchpoint {
+                kind: WatchKind::Read,
+                access: read
+            }
+        );
+
+        sut.remove_watchpoint(WatchKind::Write, 0x60..=0x61);
+        sut.remove_watchpoint(WatchKind::Read, 0x70..=0x71);
+        assert_eq!(
+            sut.get_watchpoints(),
+            [Watchpoint {
+                kind: WatchKind::Read,
+                range: 0x70..=0x70
+            }]
+        );
+    }
+
+    #[test]
+    fn test_access_watchpoint() {
+        let sut = Debugger::new(false);
+        sut.add_watchpoint(WatchKind::Access, 0x60..=0x60);
+
+        sut.executed(&[access(AccessKind::Read, 0x60, 0, 0)]);
+        assert!(sut.is_halted());
+
+        sut.resume();
+        sut.executed(&[access(AccessKind::Write, 0x60, 0, 1)]);
+        assert!(sut.is_halted());
+    }
+
+    #[test]
+    fn test_wait_until_running() {
+        let sut = Debugger::new(true);
+
+        assert!(!sut.wait_until_running(Duration::from_millis(1)));
+        assert!(sut.is_halted());
+
+        sut.resume();
+        assert!(sut.wait_until_running(Duration::ZERO));
+        assert!(!sut.wait_until_halted(Duration::from_millis(1)));
+    }
+}
@@ -0,0 +1,941 @@
+use crate::avr_emulator::analog::AnalogInput;
+use crate::avr_emulator::fuses::Fuses;
+use crate::avr_emulator::interrupt_handler::Interrupt;
+use crate::avr_emulator::timer::ClockSource;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RegisterBit {
+    pub address: usize,
+    pub bit: u8,
+}
+
+impl RegisterBit {
+    pub const fn new(address: usize, bit: u8) -> Self {
+        Self { address, bit }
+    }
+
+    pub fn mask(&self) -> u8 {
+        1 << self.bit
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FuseByte {
+    Low,
+    High,
+    Extended,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FuseBit {
+    pub byte: FuseByte,
+    pub bit: u8,
+}
+
+impl FuseBit {
+    pub const fn new(byte: FuseByte, bit: u8) -> Self {
+        Self { byte, bit }
+    }
+}
+
+// CKSEL is four bits wide starting at clock_select, BOOTSZ two bits starting at boot_size.
+// The internal oscillators map CKSEL values to their nominal frequency, all other values
+// select an external clock, crystal or resonator.
+#[derive(Debug)]
+pub struct FuseLayout {
+    pub defaults: Fuses,
+    pub clock_select: FuseBit,
+    pub clock_divide: Option<FuseBit>,
+    pub internal_oscillators: &'static [(u8, f64)],
+    pub boot_reset: FuseBit,
+    pub boot_size: FuseBit,
+    // signature bytes 0 to 2
+    pub signature: [u8; 3],
+}
+
+// Flash addresses and sizes are in bytes. The boot loader section ends with the flash,
+// its size is given per BOOTSZ value. IVCE and IVSEL share the register.
+#[derive(Debug)]
+pub struct BootLoaderRegisters {
+    pub control: usize,
+    pub vector_select: RegisterBit,
+    pub vector_change_enable: RegisterBit,
+    pub flash_size: usize,
+    pub page_size: usize,
+    pub no_read_while_write_start: usize,
+    pub boot_sizes: [usize; 4],
+    pub write_time: f64,
+}
+
+#[derive(Debug)]
+pub struct PrescalerRegisters {
+    pub reset: RegisterBit,
+    pub synchronization_mode: Option<RegisterBit>,
+}
+
+#[derive(Debug)]
+pub struct TimerRegisters {
+    pub control: usize,
+    pub counter: usize,
+    pub clock_sources: &'static [ClockSource],
+    pub overflow_flag: RegisterBit,
+    pub external_clock_pin: RegisterBit,
+    // PRR bit stopping the clock of the peripheral
+    pub power_reduction: Option<RegisterBit>,
+}
+
+#[derive(Debug)]
+pub struct PortRegisters {
+    pub name: char,
+    pub pin: usize,
+    pub ddr: usize,
+    pub port: usize,
+}
+
+// Sense control is two bits wide starting at sense_control: low level, any change, falling, rising
+#[derive(Debug)]
+pub struct ExternalInterruptRegisters {
+    pub pin: RegisterBit,
+    pub sense_control: RegisterBit,
+    pub flag: RegisterBit,
+}
+
+#[derive(Debug)]
+pub struct PinChangeInterruptRegisters {
+    pub pins: usize,
+    pub mask: usize,
+    pub flag: RegisterBit,
+}
+
+// The UCSRC register shares its address with UBRRH on parts that have the URSEL bit
+#[derive(Debug)]
+pub struct UsartRegisters {
+    pub data: usize,
+    pub control_status_a: usize,
+    pub control_status_b: usize,
+    pub control_status_c: usize,
+    pub baud_rate_low: usize,
+    pub baud_rate_high: usize,
+    pub register_select: Option<RegisterBit>,
+    pub power_reduction: Option<RegisterBit>,
+}
+
+// The SS pin is seen through its PIN and DDR bits
+#[derive(Debug)]
+pub struct SpiRegisters {
+    pub control: usize,
+    pub status: usize,
+    pub data: usize,
+    pub slave_select_pin: RegisterBit,
+    pub slave_select_direction: RegisterBit,
+    pub power_reduction: Option<RegisterBit>,
+}
+
+#[derive(Debug)]
+pub struct TwiRegisters {
+    pub bit_rate: usize,
+    pub status: usize,
+    pub address: usize,
+    pub data: usize,
+    pub control: usize,
+    pub power_reduction: Option<RegisterBit>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AdcChannel {
+    Input(AnalogInput),
+    Voltage(f64),
+    Reserved,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AdcTriggerSource {
+    FreeRunning,
+    // conversion starts on the rising edge of the interrupt flag
+    Flag(RegisterBit),
+}
+
+// Parts without ADCSRB only support free running mode through the ADFR bit
+#[derive(Debug)]
+pub struct AdcRegisters {
+    pub multiplexer: usize,
+    pub control_status_a: usize,
+    pub control_status_b: Option<usize>,
+    pub data_low: usize,
+    pub data_high: usize,
+    pub channels: &'static [AdcChannel; 16],
+    pub internal_reference: f64,
+    pub trigger_sources: &'static [AdcTriggerSource],
+    pub power_reduction: Option<RegisterBit>,
+}
+
+// Timer1 input capture unit, TCNT1 is latched into ICR1 on the edge selected by ICES1
+#[derive(Debug)]
+pub struct InputCaptureRegisters {
+    pub control: usize,
+    pub counter: usize,
+    pub capture: usize,
+    pub edge_select: RegisterBit,
+    pub flag: RegisterBit,
+}
+
+// The negative input is taken from the ADC multiplexer when ACME is set and the ADC is off
+#[derive(Debug)]
+pub struct AnalogComparatorRegisters {
+    pub control_status: usize,
+    pub multiplexer_enable: RegisterBit,
+    pub bandgap_voltage: f64,
+    pub input_capture: InputCaptureRegisters,
+}
+
+// Write times in seconds per EEPM programming mode: erase and write, erase only, write only.
+// Parts without EEPM bits always erase and write.
+#[derive(Debug)]
+pub struct EepromRegisters {
+    pub control: usize,
+    pub data: usize,
+    pub address_low: usize,
+    pub address_high: usize,
+    pub size: usize,
+    pub write_times: &'static [f64],
+}
+
+// Timeouts in watchdog oscillator cycles per WDP value. Parts with the WDTCSR interrupt mode
+// also need the timed sequence to change the prescaler and keep WDE set while WDRF is set.
+#[derive(Debug)]
+pub struct WatchdogRegisters {
+    pub control: usize,
+    pub reset_status: usize,
+    pub oscillator_frequency: f64,
+    pub timeouts: &'static [u32],
+    pub interrupt_mode: bool,
+}
+
+#[derive(Debug)]
+pub struct InterruptVector {
+    pub interrupt: Interrupt,
+    pub enable: Option<RegisterBit>,
+    pub flag: Option<RegisterBit>,
+    // Status flags like RXC or UDRE are only cleared by the peripheral itself
+    pub clear_flag_on_entry: bool,
+    // Ready interrupts like EE_RDY are pending while their busy flag is clear
+    pub flag_active_low: bool,
+}
+
+impl InterruptVector {
+    const fn new(interrupt: Interrupt) -> Self {
+        Self {
+            interrupt,
+            enable: None,
+            flag: None,
+            clear_flag_on_entry: false,
+            flag_active_low: false,
+        }
+    }
+
+    const fn with_flag(interrupt: Interrupt, enable: RegisterBit, flag: RegisterBit) -> Self {
+        Self {
+            interrupt,
+            enable: Some(enable),
+            flag: Some(flag),
+            clear_flag_on_entry: true,
+            flag_active_low: false,
+        }
+    }
+
+    const fn with_status_flag(
+        interrupt: Interrupt,
+        enable: RegisterBit,
+        flag: RegisterBit,
+    ) -> Self {
+        Self {
+            interrupt,
+            enable: Some(enable),
+            flag: Some(flag),
+            clear_flag_on_entry: false,
+            flag_active_low: false,
+        }
+    }
+
+    const fn with_busy_flag(interrupt: Interrupt, enable: RegisterBit, busy: RegisterBit) -> Self {
+        Self {
+            interrupt,
+            enable: Some(enable),
+            flag: Some(busy),
+            clear_flag_on_entry: false,
+            flag_active_low: true,
+        }
+    }
+}
+
+// All register addresses are data space addresses, i.e. io register + 0x20
+#[derive(Debug)]
+pub struct Device {
+    pub name: &'static str,
+    // variants emulated by this device, e.g. the A versions
+    pub aliases: &'static [&'static str],
+    // io registers end where sram starts
+    pub sram_start: usize,
+    // MCUSR / MCUCSR
+    pub reset_status: usize,
+    // registers not cleared on reset
+    pub io_reset_values: &'static [(usize, u8)],
+    // CLKPR
+    pub clock_prescaler: Option<usize>,
+    pub synchronous_prescaler: PrescalerRegisters,
+    pub asynchronous_prescaler: PrescalerRegisters,
+    pub timer0: TimerRegisters,
+    // interrupt flags of all timer/counters, cleared by writing one
+    pub timer_flag_registers: &'static [usize],
+    pub ports: &'static [PortRegisters],
+    pub pull_up_disable: RegisterBit,
+    pub pin_write_toggles_port: bool,
+    pub external_interrupts: &'static [ExternalInterruptRegisters],
+    pub pin_change_interrupts: &'static [PinChangeInterruptRegisters],
+    pub usarts: &'static [UsartRegisters],
+    pub spi: SpiRegisters,
+    pub twi: TwiRegisters,
+    pub adc: AdcRegisters,
+    pub analog_comparator: AnalogComparatorRegisters,
+    pub eeprom: EepromRegisters,
+    pub watchdog: WatchdogRegisters,
+    pub fuses: FuseLayout,
+    pub boot_loader: BootLoaderRegisters,
+    pub interrupt_vectors: &'static [InterruptVector],
+}
+
+const TIMER0_CLOCK_SOURCES: [ClockSource; 8] = [
+    ClockSource::Stopped,
+    ClockSource::Prescaled(1),
+    ClockSource::Prescaled(8),
+    ClockSource::Prescaled(64),
+    ClockSource::Prescaled(256),
+    ClockSource::Prescaled(1024),
+    ClockSource::ExternalFalling,
+    ClockSource::ExternalRising,
+];
+
+const ADC_INPUTS: [AdcChannel; 8] = [
+    AdcChannel::Input(AnalogInput::Adc(0)),
+    AdcChannel::Input(AnalogInput::Adc(1)),
+    AdcChannel::Input(AnalogInput::Adc(2)),
+    AdcChannel::Input(AnalogInput::Adc(3)),
+    AdcChannel::Input(AnalogInput::Adc(4)),
+    AdcChannel::Input(AnalogInput::Adc(5)),
+    AdcChannel::Input(AnalogInput::Adc(6)),
+    AdcChannel::Input(AnalogInput::Adc(7)),
+];
+
+const ATMEGA8_ADC_CHANNELS: [AdcChannel; 16] = [
+    ADC_INPUTS[0],
+    ADC_INPUTS[1],
+    ADC_INPUTS[2],
+    ADC_INPUTS[3],
+    ADC_INPUTS[4],
+    ADC_INPUTS[5],
+    ADC_INPUTS[6],
+    ADC_INPUTS[7],
+    AdcChannel::Reserved,
+    AdcChannel::Reserved,
+    AdcChannel::Reserved,
+    AdcChannel::Reserved,
+    AdcChannel::Reserved,
+    AdcChannel::Reserved,
+    AdcChannel::Voltage(1.30),
+    AdcChannel::Voltage(0.0),
+];
+
+// the temperature sensor reads about 314 mV at 25 degrees
+const ATMEGA88_ADC_CHANNELS: [AdcChannel; 16] = [
+    ADC_INPUTS[0],
+    ADC_INPUTS[1],
+    ADC_INPUTS[2],
+    ADC_INPUTS[3],
+    ADC_INPUTS[4],
+    ADC_INPUTS[5],
+    ADC_INPUTS[6],
+    ADC_INPUTS[7],
+    AdcChannel::Voltage(0.314),
+    AdcChannel::Reserved,
+    AdcChannel::Reserved,
+    AdcChannel::Reserved,
+    AdcChannel::Reserved,
+    AdcChannel::Reserved,
+    AdcChannel::Voltage(1.1),
+    AdcChannel::Voltage(0.0),
+];
+
+pub static ATMEGA8: Device = Device {
+    name: "atmega8",
+    aliases: &["atmega8a"],
+    sram_start: 0x60,
+    reset_status: 0x54,
+    io_reset_values: &[
+        (0x2b, 0x20),
+        (0x40, 0x86),
+        (0x21, 0xf8),
+        (0x22, 0xfe),
+        (0x23, 0xff),
+    ],
+    clock_prescaler: None,
+    synchronous_prescaler: PrescalerRegisters {
+        reset: RegisterBit::new(0x50, 0),
+        synchronization_mode: None,
+    },
+    asynchronous_prescaler: PrescalerRegisters {
+        reset: RegisterBit::new(0x50, 1),
+        synchronization_mode: None,
+    },
+    timer0: TimerRegisters {
+        control: 0x53,
+        counter: 0x52,
+        clock_sources: &TIMER0_CLOCK_SOURCES,
+        overflow_flag: RegisterBit::new(0x58, 0),
+        external_clock_pin: RegisterBit::new(0x30, 4),
+        power_reduction: None,
+    },
+    // TIFR
+    timer_flag_registers: &[0x58],
+    ports: &[
+        PortRegisters {
+            name: 'B',
+            pin: 0x36,
+            ddr: 0x37,
+            port: 0x38,
+        },
+        PortRegisters {
+            name: 'C',
+            pin: 0x33,
+            ddr: 0x34,
+            port: 0x35,
+        },
+        PortRegisters {
+            name: 'D',
+            pin: 0x30,
+            ddr: 0x31,
+            port: 0x32,
+        },
+    ],
+    pull_up_disable: RegisterBit::new(0x50, 2),
+    pin_write_toggles_port: false,
+    external_interrupts: &[
+        ExternalInterruptRegisters {
+            pin: RegisterBit::new(0x30, 2),
+            sense_control: RegisterBit::new(0x55, 0),
+            flag: RegisterBit::new(0x5a, 6),
+        },
+        ExternalInterruptRegisters {
+            pin: RegisterBit::new(0x30, 3),
+            sense_control: RegisterBit::new(0x55, 2),
+            flag: RegisterBit::new(0x5a, 7),
+        },
+    ],
+    pin_change_interrupts: &[],
+    usarts: &[UsartRegisters {
+        data: 0x2c,
+        control_status_a: 0x2b,
+        control_status_b: 0x2a,
+        control_status_c: 0x40,
+        baud_rate_low: 0x29,
+        baud_rate_high: 0x40,
+        register_select: Some(RegisterBit::new(0x40, 7)),
+        power_reduction: None,
+    }],
+    spi: SpiRegisters {
+        control: 0x2d,
+        status: 0x2e,
+        data: 0x2f,
+        slave_select_pin: RegisterBit::new(0x36, 2),
+        slave_select_direction: RegisterBit::new(0x37, 2),
+        power_reduction: None,
+    },
+    twi: TwiRegisters {
+        bit_rate: 0x20,
+        status: 0x21,
+        address: 0x22,
+        data: 0x23,
+        control: 0x56,
+        power_reduction: None,
+    },
+    adc: AdcRegisters {
+        multiplexer: 0x27,
+        control_status_a: 0x26,
+        control_status_b: None,
+        data_low: 0x24,
+        data_high: 0x25,
+        channels: &ATMEGA8_ADC_CHANNELS,
+        internal_reference: 2.56,
+        trigger_sources: &[AdcTriggerSource::FreeRunning],
+        power_reduction: None,
+    },
+    analog_comparator: AnalogComparatorRegisters {
+        control_status: 0x28,
+        multiplexer_enable: RegisterBit::new(0x50, 3),
+        bandgap_voltage: 1.30,
+        input_capture: InputCaptureRegisters {
+            control: 0x4e,
+            counter: 0x4c,
+            capture: 0x46,
+            edge_select: RegisterBit::new(0x4e, 6),
+            flag: RegisterBit::new(0x58, 5),
+        },
+    },
+    eeprom: EepromRegisters {
+        control: 0x3c,
+        data: 0x3d,
+        address_low: 0x3e,
+        address_high: 0x3f,
+        size: 512,
+        write_times: &[8.5e-3],
+    },
+    watchdog: WatchdogRegisters {
+        control: 0x41,
+        reset_status: 0x54,
+        oscillator_frequency: 1_000_000.0,
+        timeouts: &[
+            16 << 10,
+            32 << 10,
+            64 << 10,
+            128 << 10,
+            256 << 10,
+            512 << 10,
+            1024 << 10,
+            2048 << 10,
+        ],
+        interrupt_mode: false,
+    },
+    fuses: FuseLayout {
+        defaults: Fuses {
+            low: 0xe1,
+            high: 0xd9,
+            extended: 0xff,
+            lock: 0xff,
+        },
+        clock_select: FuseBit::new(FuseByte::Low, 0),
+        clock_divide: None,
+        internal_oscillators: &[(1, 1e6), (2, 2e6), (3, 4e6), (4, 8e6)],
+        boot_reset: FuseBit::new(FuseByte::High, 0),
+        boot_size: FuseBit::new(FuseByte::High, 1),
+        signature: [0x1e, 0x93, 0x07],
+    },
+    boot_loader: BootLoaderRegisters {
+        control: 0x57,
+        vector_select: RegisterBit::new(0x5b, 1),
+        vector_change_enable: RegisterBit::new(0x5b, 0),
+        flash_size: 8192,
+        page_size: 64,
+        no_read_while_write_start: 0x1800,
+        boot_sizes: [2048, 1024, 512, 256],
+        write_time: 4.5e-3,
+    },
+    interrupt_vectors: &[
+        InterruptVector::new(Interrupt::Reset),
+        InterruptVector::with_flag(
+            Interrupt::Int0,
+            RegisterBit::new(0x5b, 6),
+            RegisterBit::new(0x5a, 6),
+        ),
+        InterruptVector::with_flag(
+            Interrupt::Int1,
+            RegisterBit::new(0x5b, 7),
+            RegisterBit::new(0x5a, 7),
+        ),
+        InterruptVector::new(Interrupt::Timer2Comp),
+        InterruptVector::new(Interrupt::Timer2Ovf),
+        InterruptVector::with_flag(
+            Interrupt::Timer1Capt,
+            RegisterBit::new(0x59, 5),
+            RegisterBit::new(0x58, 5),
+        ),
+        InterruptVector::new(Interrupt::Timer1CompA),
+        InterruptVector::new(Interrupt::Timer1ComB),
+        InterruptVector::new(Interrupt::Timer1Ovf),
+        InterruptVector::with_flag(
+            Interrupt::Timer0Ovf,
+            RegisterBit::new(0x59, 0),
+            RegisterBit::new(0x58, 0),
+        ),
+        InterruptVector::with_flag(
+            Interrupt::SPISTC,
+            RegisterBit::new(0x2d, 7),
+            RegisterBit::new(0x2e, 7),
+        ),
+        InterruptVector::with_status_flag(
+            Interrupt::USARTRXC,
+            RegisterBit::new(0x2a, 7),
+            RegisterBit::new(0x2b, 7),
+        ),
+        InterruptVector::with_status_flag(
+            Interrupt::USARTUDRE,
+            RegisterBit::new(0x2a, 5),
+            RegisterBit::new(0x2b, 5),
+        ),
+        InterruptVector::with_flag(
+            Interrupt::USARTTXC,
+            RegisterBit::new(0x2a, 6),
+            RegisterBit::new(0x2b, 6),
+        ),
+        InterruptVector::with_flag(
+            Interrupt::ADC,
+            RegisterBit::new(0x26, 3),
+            RegisterBit::new(0x26, 4),
+        ),
+        InterruptVector::with_busy_flag(
+            Interrupt::EERDY,
+            RegisterBit::new(0x3c, 3),
+            RegisterBit::new(0x3c, 1),
+        ),
+        InterruptVector::with_flag(
+            Interrupt::ANACOMP,
+            RegisterBit::new(0x28, 3),
+            RegisterBit::new(0x28, 4),
+        ),
+        InterruptVector::with_status_flag(
+            Interrupt::TWI,
+            RegisterBit::new(0x56, 0),
+            RegisterBit::new(0x56, 7),
+        ),
+        InterruptVector::with_busy_flag(
+            Interrupt::SPMRdy,
+            RegisterBit::new(0x57, 7),
+            RegisterBit::new(0x57, 0),
+        ),
+    ],
+};
+
+pub static ATMEGA88: Device = Device {
+    name: "atmega88",
+    aliases: &["atmega88a"],
+    sram_start: 0x100,
+    reset_status: 0x54,
+    io_reset_values: &[
+        (0x5d, 0xff),
+        (0x5e, 0x04),
+        (0xc0, 0x20),
+        (0xc2, 0x06),
+        (0xb9, 0xf8),
+        (0xba, 0xfe),
+        (0xbb, 0xff),
+    ],
+    clock_prescaler: Some(0x61),
+    synchronous_prescaler: PrescalerRegisters {
+        reset: RegisterBit::new(0x43, 0),
+        synchronization_mode: Some(RegisterBit::new(0x43, 7)),
+    },
+    asynchronous_prescaler: PrescalerRegisters {
+        reset: RegisterBit::new(0x43, 1),
+        synchronization_mode: Some(RegisterBit::new(0x43, 7)),
+    },
+    timer0: TimerRegisters {
+        control: 0x45,
+        counter: 0x46,
+        clock_sources: &TIMER0_CLOCK_SOURCES,
+        overflow_flag: RegisterBit::new(0x35, 0),
+        external_clock_pin: RegisterBit::new(0x29, 4),
+        power_reduction: Some(RegisterBit::new(0x64, 5)),
+    },
+    // TIFR0, TIFR1, TIFR2
+    timer_flag_registers: &[0x35, 0x36, 0x37],
+    ports: &[
+        PortRegisters {
+            name: 'B',
+            pin: 0x23,
+            ddr: 0x24,
+            port: 0x25,
+        },
+        PortRegisters {
+            name: 'C',
+            pin: 0x26,
+            ddr: 0x27,
+            port: 0x28,
+        },
+        PortRegisters {
+            name: 'D',
+            pin: 0x29,
+            ddr: 0x2a,
+            port: 0x2b,
+        },
+    ],
+    pull_up_disable: RegisterBit::new(0x55, 4),
+    pin_write_toggles_port: true,
+    external_interrupts: &[
+        ExternalInterruptRegisters {
+            pin: RegisterBit::new(0x29, 2),
+            sense_control: RegisterBit::new(0x69, 0),
+            flag: RegisterBit::new(0x3c, 0),
+        },
+        ExternalInterruptRegisters {
+            pin: RegisterBit::new(0x29, 3),
+            sense_control: RegisterBit::new(0x69, 2),
+            flag: RegisterBit::new(0x3c, 1),
+        },
+    ],
+    pin_change_interrupts: &[
+        PinChangeInterruptRegisters {
+            pins: 0x23,
+            mask: 0x6b,
+            flag: RegisterBit::new(0x3b, 0),
+        },
+        PinChangeInterruptRegisters {
+            pins: 0x26,
+            mask: 0x6c,
+            flag: RegisterBit::new(0x3b, 1),
+        },
+        PinChangeInterruptRegisters {
+            pins: 0x29,
+            mask: 0x6d,
+            flag: RegisterBit::new(0x3b, 2),
+        },
+    ],
+    usarts: &[UsartRegisters {
+        data: 0xc6,
+        control_status_a: 0xc0,
+        control_status_b: 0xc1,
+        control_status_c: 0xc2,
+        baud_rate_low: 0xc4,
+        baud_rate_high: 0xc5,
+        register_select: None,
+        power_reduction: Some(RegisterBit::new(0x64, 1)),
+    }],
+    spi: SpiRegisters {
+        control: 0x4c,
+        status: 0x4d,
+        data: 0x4e,
+        slave_select_pin: RegisterBit::new(0x23, 2),
+        slave_select_direction: RegisterBit::new(0x24, 2),
+        power_reduction: Some(RegisterBit::new(0x64, 2)),
+    },
+    twi: TwiRegisters {
+        bit_rate: 0xb8,
+        status: 0xb9,
+        address: 0xba,
+        data: 0xbb,
+        control: 0xbc,
+        power_reduction: Some(RegisterBit::new(0x64, 7)),
+    },
+    adc: AdcRegisters {
+        multiplexer: 0x7c,
+        control_status_a: 0x7a,
+        control_status_b: Some(0x7b),
+        data_low: 0x78,
+        data_high: 0x79,
+        channels: &ATMEGA88_ADC_CHANNELS,
+        internal_reference: 1.1,
+        trigger_sources: &[
+            AdcTriggerSource::FreeRunning,
+            AdcTriggerSource::Flag(RegisterBit::new(0x50, 4)),
+            AdcTriggerSource::Flag(RegisterBit::new(0x3c, 0)),
+            AdcTriggerSource::Flag(RegisterBit::new(0x35, 1)),
+            AdcTriggerSource::Flag(RegisterBit::new(0x35, 0)),
+            AdcTriggerSource::Flag(RegisterBit::new(0x36, 2)),
+            AdcTriggerSource::Flag(RegisterBit::new(0x36, 0)),
+            AdcTriggerSource::Flag(RegisterBit::new(0x36, 5)),
+        ],
+        power_reduction: Some(RegisterBit::new(0x64, 0)),
+    },
+    analog_comparator: AnalogComparatorRegisters {
+        control_status: 0x50,
+        multiplexer_enable: RegisterBit::new(0x7b, 6),
+        bandgap_voltage: 1.1,
+        input_capture: InputCaptureRegisters {
+            control: 0x81,
+            counter: 0x84,
+            capture: 0x86,
+            edge_select: RegisterBit::new(0x81, 6),
+            flag: RegisterBit::new(0x36, 5),
+        },
+    },
+    eeprom: EepromRegisters {
+        control: 0x3f,
+        data: 0x40,
+        address_low: 0x41,
+        address_high: 0x42,
+        size: 512,
+        write_times: &[3.4e-3, 1.8e-3, 1.8e-3],
+    },
+    watchdog: WatchdogRegisters {
+        control: 0x60,
+        reset_status: 0x54,
+        oscillator_frequency: 128_000.0,
+        timeouts: &[
+            2 << 10,
+            4 << 10,
+            8 << 10,
+            16 << 10,
+            32 << 10,
+            64 << 10,
+            128 << 10,
+            256 << 10,
+            512 << 10,
+            1024 << 10,
+        ],
+        interrupt_mode: true,
+    },
+    fuses: FuseLayout {
+        defaults: Fuses {
+            low: 0x62,
+            high: 0xdf,
+            extended: 0xf9,
+            lock: 0xff,
+        },
+        clock_select: FuseBit::new(FuseByte::Low, 0),
+        clock_divide: Some(FuseBit::new(FuseByte::Low, 7)),
+        internal_oscillators: &[(2, 8e6), (3, 128e3)],
+        boot_reset: FuseBit::new(FuseByte::Extended, 0),
+        boot_size: FuseBit::new(FuseByte::Extended, 1),
+        signature: [0x1e, 0x93, 0x0a],
+    },
+    boot_loader: BootLoaderRegisters {
+        control: 0x57,
+        vector_select: RegisterBit::new(0x55, 1),
+        vector_change_enable: RegisterBit::new(0x55, 0),
+        flash_size: 8192,
+        page_size: 64,
+        no_read_while_write_start: 0x1800,
+        boot_sizes: [2048, 1024, 512, 256],
+        write_time: 4.5e-3,
+    },
+    interrupt_vectors: &[
+        InterruptVector::new(Interrupt::Reset),
+        InterruptVector::with_flag(
+            Interrupt::Int0,
+            RegisterBit::new(0x3d, 0),
+            RegisterBit::new(0x3c, 0),
+        ),
+        InterruptVector::with_flag(
+            Interrupt::Int1,
+            RegisterBit::new(0x3d, 1),
+            RegisterBit::new(0x3c, 1),
+        ),
+        InterruptVector::with_flag(
+            Interrupt::PCInt0,
+            RegisterBit::new(0x68, 0),
+            RegisterBit::new(0x3b, 0),
+        ),
+        InterruptVector::with_flag(
+            Interrupt::PCInt1,
+            RegisterBit::new(0x68, 1),
+            RegisterBit::new(0x3b, 1),
+        ),
+        InterruptVector::with_flag(
+            Interrupt::PCInt2,
+            RegisterBit::new(0x68, 2),
+            RegisterBit::new(0x3b, 2),
+        ),
+        InterruptVector::with_flag(
+            Interrupt::WDT,
+            RegisterBit::new(0x60, 6),
+            RegisterBit::new(0x60, 7),
+        ),
+        InterruptVector::new(Interrupt::Timer2CompA),
+        InterruptVector::new(Interrupt::Timer2CompB),
+        InterruptVector::new(Interrupt::Timer2Ovf),
+        InterruptVector::with_flag(
+            Interrupt::Timer1Capt,
+            RegisterBit::new(0x6f, 5),
+            RegisterBit::new(0x36, 5),
+        ),
+        InterruptVector::new(Interrupt::Timer1CompA),
+        InterruptVector::new(Interrupt::Timer1ComB),
+        InterruptVector::new(Interrupt::Timer1Ovf),
+        InterruptVector::new(Interrupt::Timer0CompA),
+        InterruptVector::new(Interrupt::Timer0CompB),
+        InterruptVector::with_flag(
+            Interrupt::Timer0Ovf,
+            RegisterBit::new(0x6e, 0),
+            RegisterBit::new(0x35, 0),
+        ),
+        InterruptVector::with_flag(
+            Interrupt::SPISTC,
+            RegisterBit::new(0x4c, 7),
+            RegisterBit::new(0x4d, 7),
+        ),
+        InterruptVector::with_status_flag(
+            Interrupt::USARTRXC,
+            RegisterBit::new(0xc1, 7),
+            RegisterBit::new(0xc0, 7),
+        ),
+        InterruptVector::with_status_flag(
+            Interrupt::USARTUDRE,
+            RegisterBit::new(0xc1, 5),
+            RegisterBit::new(0xc0, 5),
+        ),
+        InterruptVector::with_flag(
+            Interrupt::USARTTXC,
+            RegisterBit::new(0xc1, 6),
+            RegisterBit::new(0xc0, 6),
+        ),
+        InterruptVector::with_flag(
+            Interrupt::ADC,
+            RegisterBit::new(0x7a, 3),
+            RegisterBit::new(0x7a, 4),
+        ),
+        InterruptVector::with_busy_flag(
+            Interrupt::EERDY,
+            RegisterBit::new(0x3f, 3),
+            RegisterBit::new(0x3f, 1),
+        ),
+        InterruptVector::with_flag(
+            Interrupt::ANACOMP,
+            RegisterBit::new(0x50, 3),
+            RegisterBit::new(0x50, 4),
+        ),
+        InterruptVector::with_status_flag(
+            Interrupt::TWI,
+            RegisterBit::new(0xbc, 0),
+            RegisterBit::new(0xbc, 7),
+        ),
+        InterruptVector::with_busy_flag(
+            Interrupt::SPMRdy,
+            RegisterBit::new(0x57, 7),
+            RegisterBit::new(0x57, 0),
+        ),
+    ],
+};
+
+impl Device {
+    pub fn find_port(&self, name: char) -> Option<&'static PortRegisters> {
+        self.ports
+            .iter()
+            .find(|port| port.name.eq_ignore_ascii_case(&name))
+    }
+}
+
+static DEVICES: [&Device; 2] = [&ATMEGA8, &ATMEGA88];
+
+pub fn find_device(name: &str) -> Option<&'static Device> {
+    DEVICES
+        .iter()
+        .find(|device| {
+            std::iter::once(&device.name)
+                .chain(device.aliases)
+                .any(|device_name| device_name.eq_ignore_ascii_case(name))
+        })
+        .copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_device() {
+        assert_eq!(find_device("atmega8").unwrap().name, "atmega8");
+        assert_eq!(find_device("ATmega88").unwrap().name, "atmega88");
+        assert_eq!(find_device("atmega8a").unwrap().name, "atmega8");
+        assert!(find_device("attiny13").is_none());
+    }
+
+    #[test]
+    fn test_timer0_overflow_vector() {
+        let position = |device: &Device| {
+            device
+                .interrupt_vectors
+                .iter()
+                .position(|vector| vector.interrupt == Interrupt::Timer0Ovf)
+        };
+
+        assert_eq!(position(&ATMEGA8), Some(9));
+        assert_eq!(position(&ATMEGA88), Some(16));
+    }
+}
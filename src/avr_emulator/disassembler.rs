@@ -0,0 +1,261 @@
+use std::collections::BTreeMap;
+
+use crate::avr_emulator::instruction;
+use crate::avr_emulator::symbols::{SymbolTable, DATA_OFFSET};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisassembledInstruction {
+    // byte address
+    pub address: u32,
+    pub bytes: Vec<u8>,
+    // None when the decoder does not understand the opcode
+    pub text: Option<String>,
+}
+
+impl DisassembledInstruction {
+    fn get_words(&self) -> Vec<u16> {
+        self.bytes
+            .chunks(2)
+            .map(|word| u16::from_le_bytes([word[0], *word.get(1).unwrap_or(&0xff)]))
+            .collect()
+    }
+
+    // Unprogrammed flash
+    pub fn is_erased(&self) -> bool {
+        self.bytes.iter().all(|byte| *byte == 0xff)
+    }
+}
+
+// Decodes the instruction at the byte address, which needs at least one word of flash
+pub fn disassemble_at(flash: &[u8], address: usize) -> DisassembledInstruction {
+    let opcode = u16::from_le_bytes([flash[address], flash[address + 1]]);
+    let size = (instruction::get_size(opcode) as usize * 2).min(flash.len() - address);
+    let bytes = flash[address..address + size].to_vec();
+
+    let text = instruction::get_instruction(opcode).map(|mut instruction| {
+        if let [_, _, low, high] = bytes[..] {
+            instruction.set_operand(u16::from_le_bytes([low, high]));
+        }
+        instruction.str()
+    });
+
+    DisassembledInstruction {
+        address: address as u32,
+        bytes,
+        text,
+    }
+}
+
+// Decodes flash word by word with the decoder used for execution, operands of the 32-bit
+// instructions are skipped
+pub struct Disassembly {
+    instructions: Vec<DisassembledInstruction>,
+    labels: BTreeMap<u32, Vec<String>>,
+}
+
+impl Disassembly {
+    pub fn new(flash: &[u8], symbols: &SymbolTable) -> Self {
+        let mut labels: BTreeMap<u32, Vec<String>> = BTreeMap::new();
+        for symbol in symbols
+            .get_symbols()
+            .iter()
+            .filter(|symbol| symbol.address < DATA_OFFSET)
+        {
+            labels
+                .entry(symbol.address)
+                .or_default()
+                .push(symbol.name.clone());
+        }
+
+        let mut instructions = vec![];
+        let mut address = 0;
+
+        while address + 1 < flash.len() {
+            let instruction = disassemble_at(flash, address);
+            address += instruction.bytes.len();
+            instructions.push(instruction);
+        }
+
+        Self {
+            instructions,
+            labels,
+        }
+    }
+
+    pub fn get_instructions(&self) -> &[DisassembledInstruction] {
+        &self.instructions
+    }
+
+    // Instructions the decoder does not understand, erased flash is left out
+    pub fn get_unsupported(&self) -> impl Iterator<Item = &DisassembledInstruction> {
+        self.instructions
+            .iter()
+            .filter(|instruction| instruction.text.is_none() && !instruction.is_erased())
+    }
+}
+
+// A listing with symbol labels, runs of erased flash collapsed to "..." and a summary of
+// the unsupported opcodes
+impl std::fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut previous_erased = false;
+
+        for instruction in &self.instructions {
+            if instruction.is_erased() {
+                if !previous_erased {
+                    writeln!(f, "        ...")?;
+                }
+                previous_erased = true;
+                continue;
+            }
+            previous_erased = false;
+
+            for label in self.labels.get(&instruction.address).into_iter().flatten() {
+                writeln!(f, "\n{}:", label)?;
+            }
+
+            let bytes = instruction
+                .bytes
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            let text = match &instruction.text {
+                Some(text) => text.clone(),
+                None => {
+                    let words = instruction
+                        .get_words()
+                        .iter()
+                        .map(|word| format!("{:#06x}", word))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    format!(".word {:<16} ; unsupported", words)
+                }
+            };
+
+            writeln!(f, "{:#06x}:  {:<11}  {}", instruction.address, bytes, text)?;
+        }
+
+        let mut unsupported: BTreeMap<u16, usize> = BTreeMap::new();
+        for instruction in self.get_unsupported() {
+            *unsupported.entry(instruction.get_words()[0]).or_default() += 1;
+        }
+
+        let total = self
+            .instructions
+            .iter()
+            .filter(|instruction| !instruction.is_erased())
+            .count();
+
+        writeln!(
+            f,
+            "\n{} instructions, {} not supported by the decoder",
+            total,
+            unsupported.values().sum::<usize>()
+        )?;
+        for (opcode, count) in unsupported {
+            writeln!(f, "  {:#06x}  {}x", opcode, count)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avr_emulator::symbols::{Symbol, SymbolKind};
+
+    fn to_bytes(words: &[u16]) -> Vec<u8> {
+        words.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+
+    fn set_up() -> Disassembly {
+        let symbols = SymbolTable::new(vec![
+            Symbol {
+                name: "__vectors".to_owned(),
+                address: 0,
+                size: 0,
+                kind: SymbolKind::Other,
+            },
+            Symbol {
+                name: "main".to_owned(),
+                address: 0x0c,
+                size: 4,
+                kind: SymbolKind::Function,
+            },
+            Symbol {
+                name: "counter".to_owned(),
+                address: DATA_OFFSET + 0x60,
+                size: 1,
+                kind: SymbolKind::Object,
+            },
+        ]);
+
+        // jmp 0xc; lds r24, 0x60; erased; ldi r24, 5; rjmp -1
+        Disassembly::new(
+            &to_bytes(&[
+                0x940c, 0x0006, 0x9180, 0x0060, 0xffff, 0xffff, 0xe085, 0xcfff,
+            ]),
+            &symbols,
+        )
+    }
+
+    #[test]
+    fn test_decode() {
+        let sut = set_up();
+
+        let texts: Vec<_> = sut
+            .get_instructions()
+            .iter()
+            .map(|instruction| (instruction.address, instruction.text.clone()))
+            .collect();
+        assert_eq!(
+            texts,
+            [
+                (0x00, None),
+                (0x04, Some("lds r24, 96".to_owned())),
+                (0x08, None),
+                (0x0a, None),
+                (0x0c, Some("ldi r24, 5".to_owned())),
+                (0x0e, Some("rjmp -1".to_owned())),
+            ]
+        );
+
+        let unsupported: Vec<_> = sut
+            .get_unsupported()
+            .map(|instruction| instruction.address)
+            .collect();
+        assert_eq!(unsupported, [0x00]);
+    }
+
+    #[test]
+    fn test_listing() {
+        let sut = set_up();
+
+        assert_eq!(
+            sut.to_string(),
+            "\n__vectors:\n\
+             0x0000:  0c 94 06 00  .word 0x940c, 0x0006   ; unsupported\n\
+             0x0004:  80 91 60 00  lds r24, 96\n\
+             \x20       ...\n\
+             \n\
+             main:\n\
+             0x000c:  85 e0        ldi r24, 5\n\
+             0x000e:  ff cf        rjmp -1\n\
+             \n\
+             4 instructions, 1 not supported by the decoder\n\
+             \x20 0x940c  1x\n"
+        );
+    }
+
+    #[test]
+    fn test_odd_length() {
+        let sut = Disassembly::new(&[0x85, 0xe0, 0x0c, 0x94, 0x06], &SymbolTable::default());
+
+        assert_eq!(sut.get_instructions().len(), 2);
+        assert_eq!(sut.get_instructions()[1].bytes, [0x0c, 0x94, 0x06]);
+        assert_eq!(sut.get_unsupported().count(), 1);
+    }
+}
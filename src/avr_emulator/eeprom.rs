@@ -0,0 +1,542 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::avr_emulator::clock;
+use crate::avr_emulator::device::EepromRegisters;
+use crate::avr_emulator::memory::Memory;
+
+// EECR
+const EEPM: u8 = 0b0011_0000;
+const EEMWE: u8 = 1 << 2;
+const EEWE: u8 = 1 << 1;
+const EERE: u8 = 1 << 0;
+
+// EEWE has to be set within four cycles after EEMWE, afterwards EEMWE is cleared by hardware
+const MASTER_WRITE_ENABLE_CYCLES: u8 = 4;
+
+const ERASED: u8 = 0xff;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ProgrammingMode {
+    EraseAndWrite,
+    EraseOnly,
+    WriteOnly,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Write {
+    address: usize,
+    data: u8,
+    mode: ProgrammingMode,
+    write_time: clock::Countdown,
+}
+
+// Contents of the EEPROM, optionally backed by an Intel HEX (.hex, .eep) or raw binary file
+// which is rewritten after every completed write
+#[derive(Debug)]
+pub struct EepromMemory {
+    data: Vec<u8>,
+    backing_file: Option<PathBuf>,
+}
+
+impl EepromMemory {
+    pub fn new(size: usize) -> Self {
+        Self {
+            data: vec![ERASED; size],
+            backing_file: None,
+        }
+    }
+
+    pub fn get_data(&self) -> &[u8] {
+        &self.data
+    }
+
+    // Initial contents like the .eeprom section of the firmware
+    pub fn load(&mut self, contents: &[u8]) -> Result<(), String> {
+        if contents.len() > self.data.len() {
+            return Err(format!(
+                "{} bytes of eeprom data do not fit into the {} byte eeprom",
+                contents.len(),
+                self.data.len()
+            ));
+        }
+
+        self.data[..contents.len()].copy_from_slice(contents);
+        Ok(())
+    }
+
+    // Direct write bypassing the timed programming, as done by a programmer or debugger
+    pub fn set_byte(&mut self, address: usize, value: u8) {
+        self.data[address] = value;
+    }
+
+    fn is_intel_hex(path: &Path) -> bool {
+        path.extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| {
+                ["hex", "eep", "ihex"].contains(&extension.to_ascii_lowercase().as_str())
+            })
+    }
+
+    // A missing file is created with the erased contents on the first write
+    pub fn set_backing_file(&mut self, path: &Path) -> Result<(), String> {
+        if path.exists() {
+            let contents = if Self::is_intel_hex(path) {
+                bin_file::BinFile::from_file(path)
+                    .and_then(|file| file.to_bytes(.., Some(ERASED)))
+                    .map_err(|error| format!("failed to load {}: {:?}", path.display(), error))?
+            } else {
+                std::fs::read(path)
+                    .map_err(|error| format!("failed to load {}: {}", path.display(), error))?
+            };
+
+            if contents.len() > self.data.len() {
+                return Err(format!(
+                    "{} holds {} bytes but the eeprom has only {}",
+                    path.display(),
+                    contents.len(),
+                    self.data.len()
+                ));
+            }
+
+            self.data[..contents.len()].copy_from_slice(&contents);
+        }
+
+        self.backing_file = Some(path.to_path_buf());
+        Ok(())
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let Some(path) = &self.backing_file else {
+            return Ok(());
+        };
+
+        let contents = if Self::is_intel_hex(path) {
+            let mut file = bin_file::BinFile::new();
+            file.add_bytes(&self.data, None, false)
+                .and_then(|_| file.to_ihex(None, bin_file::IHexFormat::IHex16))
+                .map(|records| records.join("\n") + "\n")
+                .map_err(|error| format!("failed to save {}: {:?}", path.display(), error))?
+                .into_bytes()
+        } else {
+            self.data.clone()
+        };
+
+        std::fs::write(path, contents)
+            .map_err(|error| format!("failed to save {}: {}", path.display(), error))
+    }
+
+    fn program(&mut self, address: usize, data: u8, mode: ProgrammingMode) {
+        self.data[address] = match mode {
+            ProgrammingMode::EraseAndWrite => data,
+            ProgrammingMode::EraseOnly => ERASED,
+            ProgrammingMode::WriteOnly => self.data[address] & data,
+        };
+    }
+}
+
+pub struct Eeprom {
+    rising_edge_notified: std::sync::atomic::AtomicBool,
+    memory: Arc<Mutex<Memory>>,
+    registers: &'static EepromRegisters,
+    eeprom_memory: Arc<Mutex<EepromMemory>>,
+    frequency: i64,
+    master_write_enable_cycles: u8,
+    write: Option<Write>,
+}
+
+impl Eeprom {
+    pub fn new(
+        memory: Arc<Mutex<Memory>>,
+        registers: &'static EepromRegisters,
+        eeprom_memory: Arc<Mutex<EepromMemory>>,
+        frequency: i64,
+    ) -> Self {
+        Self {
+            rising_edge_notified: std::sync::atomic::AtomicBool::new(false),
+            memory,
+            registers,
+            eeprom_memory,
+            frequency,
+            master_write_enable_cycles: 0,
+            write: None,
+        }
+    }
+
+    fn get_address(&self, memory: &Memory) -> usize {
+        let address = memory.peek(self.registers.address_low) as usize
+            | (memory.peek(self.registers.address_high) as usize) << 8;
+
+        address % self.registers.size
+    }
+
+    fn get_programming_mode(&self, control: u8) -> Option<ProgrammingMode> {
+        if self.registers.write_times.len() == 1 {
+            return Some(ProgrammingMode::EraseAndWrite);
+        }
+
+        match (control & EEPM) >> 4 {
+            0 => Some(ProgrammingMode::EraseAndWrite),
+            1 => Some(ProgrammingMode::EraseOnly),
+            2 => Some(ProgrammingMode::WriteOnly),
+            _ => None,
+        }
+    }
+
+    fn start_write(&mut self, memory: &Memory, control: u8) -> bool {
+        let Some(mode) = self.get_programming_mode(control) else {
+            log::warn!("Eeprom reserved programming mode, write ignored");
+            return false;
+        };
+
+        let write_time = self.registers.write_times[mode as usize];
+
+        self.write = Some(Write {
+            address: self.get_address(memory),
+            data: memory.peek(self.registers.data),
+            mode,
+            write_time: clock::Countdown::new(write_time, self.frequency),
+        });
+        true
+    }
+
+    fn read(&self, memory: &mut Memory) {
+        let address = self.get_address(memory);
+        let data = self.eeprom_memory.lock().unwrap().get_data()[address];
+
+        memory.poke(self.registers.data, data);
+    }
+
+    fn handle_control_write(&mut self, memory: &mut Memory) {
+        let Some(write) = memory.take_io_write(self.registers.control) else {
+            return;
+        };
+
+        let writing = self.write.is_some();
+        let mut control = write.new & !(EEWE | EERE);
+
+        if write.new & EEMWE != 0 && write.old & EEMWE == 0 {
+            self.master_write_enable_cycles = MASTER_WRITE_ENABLE_CYCLES;
+        }
+
+        if writing {
+            // EEWE stays set and the programming mode can not change until the write completes
+            control = (control & !EEPM) | (write.old & EEPM) | EEWE;
+        } else if write.new & EEWE != 0 {
+            if self.master_write_enable_cycles > 0 && write.old & EEMWE != 0 {
+                if self.start_write(memory, write.new) {
+                    control |= EEWE;
+                }
+            } else {
+                log::warn!("Eeprom EEWE set without EEMWE, write ignored");
+            }
+        }
+
+        if write.new & EERE != 0 {
+            if writing {
+                log::warn!("Eeprom read during write, read ignored");
+            } else {
+                self.read(memory);
+            }
+        }
+
+        memory.poke(self.registers.control, control);
+    }
+
+    fn run_write(&mut self, memory: &mut Memory) {
+        let Some(mut write) = self.write.take() else {
+            return;
+        };
+
+        if !write.write_time.tick(memory.get_clock_division()) {
+            self.write = Some(write);
+            return;
+        }
+
+        let mut eeprom_memory = self.eeprom_memory.lock().unwrap();
+        eeprom_memory.program(write.address, write.data, write.mode);
+        if let Err(error) = eeprom_memory.save() {
+            log::error!("{}", error);
+        }
+
+        let control = memory.peek(self.registers.control);
+        memory.poke(self.registers.control, control & !EEWE);
+        memory.raise_event(
+            "Eeprom",
+            format_args!("write complete {:#06x}", write.address),
+        );
+    }
+
+    fn update(&mut self) {
+        let memory = self.memory.clone();
+        let mut memory = memory.lock().unwrap();
+
+        self.handle_control_write(&mut memory);
+        self.run_write(&mut memory);
+
+        if self.master_write_enable_cycles > 0 {
+            self.master_write_enable_cycles -= 1;
+
+            if self.master_write_enable_cycles == 0 {
+                let control = memory.peek(self.registers.control);
+                memory.poke(self.registers.control, control & !EEMWE);
+            }
+        }
+    }
+}
+
+impl clock::Subscriber for Eeprom {
+    fn notify_rising_edge(&self) {
+        log::debug!("Eeprom rising edge notified");
+
+        if self
+            .rising_edge_notified
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            log::warn!("Eeprom did not finish handling previous rising edge!");
+        }
+
+        self.rising_edge_notified
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+    fn notify_falling_edge(&self) {}
+
+    fn run(&mut self) {
+        if self
+            .rising_edge_notified
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            self.update();
+
+            self.rising_edge_notified
+                .store(false, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.master_write_enable_cycles = 0;
+
+        // a write in progress is completed
+        if self.write.is_some() {
+            let mut memory = self.memory.lock().unwrap();
+            let control = memory.peek(self.registers.control);
+            memory.poke(self.registers.control, control | EEWE);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avr_emulator::clock::Subscriber;
+    use crate::avr_emulator::device::{self, Device};
+    use crate::avr_emulator::test_utils::clock_cycles;
+
+    // 1 MHz, an ATmega8 write takes 8500 cycles
+    const FREQUENCY: i64 = 1_000_000;
+
+    fn set_up(device: &'static Device) -> (Arc<Mutex<Memory>>, Eeprom, Arc<Mutex<EepromMemory>>) {
+        let memory = Arc::new(Mutex::new(Memory::new(300, vec![]).unwrap()));
+        let eeprom_memory = Arc::new(Mutex::new(EepromMemory::new(device.eeprom.size)));
+
+        let eeprom = Eeprom::new(
+            memory.clone(),
+            &device.eeprom,
+            eeprom_memory.clone(),
+            FREQUENCY,
+        );
+
+        (memory, eeprom, eeprom_memory)
+    }
+
+    fn start_write(memory: &Arc<Mutex<Memory>>, eeprom: &mut Eeprom, address: u16, data: u8) {
+        memory.lock().unwrap().set_sram(0x3e, address as u8);
+        memory.lock().unwrap().set_sram(0x3f, (address >> 8) as u8);
+        memory.lock().unwrap().set_sram(0x3d, data);
+
+        memory.lock().unwrap().set_sram(0x3c, EEMWE);
+        clock_cycles(eeprom, 2);
+        memory.lock().unwrap().set_sram(0x3c, EEMWE | EEWE);
+        clock_cycles(eeprom, 1);
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("avr-eeprom-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_write() {
+        let (memory, mut sut, eeprom_memory) = set_up(&device::ATMEGA8);
+
+        start_write(&memory, &mut sut, 0x123, 0x42);
+        assert_eq!(memory.lock().unwrap().peek(0x3c), EEMWE | EEWE);
+
+        clock_cycles(&mut sut, 8499 - 1);
+        assert_eq!(memory.lock().unwrap().peek(0x3c), EEWE);
+        assert_eq!(eeprom_memory.lock().unwrap().get_data()[0x123], 0xff);
+
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x3c), 0);
+        assert_eq!(eeprom_memory.lock().unwrap().get_data()[0x123], 0x42);
+    }
+
+    #[test]
+    fn test_master_write_enable_times_out() {
+        let (memory, mut sut, eeprom_memory) = set_up(&device::ATMEGA8);
+
+        memory.lock().unwrap().set_sram(0x3c, EEMWE);
+        clock_cycles(&mut sut, 3);
+        assert_eq!(memory.lock().unwrap().peek(0x3c), EEMWE);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x3c), 0);
+
+        memory.lock().unwrap().set_sram(0x3c, EEWE);
+        clock_cycles(&mut sut, 10000);
+
+        assert_eq!(memory.lock().unwrap().peek(0x3c), 0);
+        assert_eq!(eeprom_memory.lock().unwrap().get_data()[0], 0xff);
+    }
+
+    #[test]
+    fn test_setting_both_enables_at_once_does_not_write() {
+        let (memory, mut sut, _) = set_up(&device::ATMEGA8);
+
+        memory.lock().unwrap().set_sram(0x3c, EEMWE | EEWE);
+        clock_cycles(&mut sut, 1);
+
+        assert_eq!(memory.lock().unwrap().peek(0x3c), EEMWE);
+    }
+
+    #[test]
+    fn test_read() {
+        let (memory, mut sut, eeprom_memory) = set_up(&device::ATMEGA8);
+        eeprom_memory
+            .lock()
+            .unwrap()
+            .program(0x10, 0x5a, ProgrammingMode::EraseAndWrite);
+
+        memory.lock().unwrap().set_sram(0x3e, 0x10);
+        memory.lock().unwrap().set_sram(0x3c, EERE);
+        clock_cycles(&mut sut, 1);
+
+        assert_eq!(memory.lock().unwrap().peek(0x3d), 0x5a);
+        assert_eq!(memory.lock().unwrap().peek(0x3c), 0);
+    }
+
+    #[test]
+    fn test_write_completes_across_reset() {
+        let (memory, mut sut, eeprom_memory) = set_up(&device::ATMEGA8);
+        start_write(&memory, &mut sut, 0x10, 0x42);
+
+        memory.lock().unwrap().reset(0x60);
+        sut.reset();
+        assert_eq!(memory.lock().unwrap().peek(0x3c), EEWE);
+
+        clock_cycles(&mut sut, 8500);
+        assert_eq!(memory.lock().unwrap().peek(0x3c), 0);
+        assert_eq!(eeprom_memory.lock().unwrap().get_data()[0x10], 0x42);
+    }
+
+    #[test]
+    fn test_read_is_ignored_while_writing() {
+        let (memory, mut sut, _) = set_up(&device::ATMEGA8);
+        start_write(&memory, &mut sut, 0x10, 0x42);
+
+        memory.lock().unwrap().set_sram(0x3d, 0);
+        memory.lock().unwrap().set_sram(0x3c, EERE);
+        clock_cycles(&mut sut, 1);
+
+        assert_eq!(memory.lock().unwrap().peek(0x3d), 0);
+        assert_eq!(memory.lock().unwrap().peek(0x3c), EEWE);
+    }
+
+    #[test]
+    fn test_programming_modes() {
+        let (memory, mut sut, eeprom_memory) = set_up(&device::ATMEGA88);
+        let write = |sut: &mut Eeprom, mode: u8, data: u8| {
+            memory.lock().unwrap().set_sram(0x41, 0x20);
+            memory.lock().unwrap().set_sram(0x40, data);
+            memory.lock().unwrap().set_sram(0x3f, mode | EEMWE);
+            clock_cycles(sut, 1);
+            memory.lock().unwrap().set_sram(0x3f, mode | EEMWE | EEWE);
+            clock_cycles(sut, 1);
+        };
+
+        // write only clears bits
+        write(&mut sut, 0b10 << 4, 0xf0);
+        clock_cycles(&mut sut, 1800);
+        assert_eq!(eeprom_memory.lock().unwrap().get_data()[0x20], 0xf0);
+        write(&mut sut, 0b10 << 4, 0x3c);
+        clock_cycles(&mut sut, 1800);
+        assert_eq!(eeprom_memory.lock().unwrap().get_data()[0x20], 0x30);
+
+        // erase only
+        write(&mut sut, 0b01 << 4, 0x00);
+        clock_cycles(&mut sut, 1800);
+        assert_eq!(eeprom_memory.lock().unwrap().get_data()[0x20], 0xff);
+
+        // erase and write takes longer
+        write(&mut sut, 0, 0x12);
+        clock_cycles(&mut sut, 1800);
+        assert_eq!(memory.lock().unwrap().peek(0x3f) & EEWE, EEWE);
+        clock_cycles(&mut sut, 1600);
+        assert_eq!(memory.lock().unwrap().peek(0x3f) & EEWE, 0);
+        assert_eq!(eeprom_memory.lock().unwrap().get_data()[0x20], 0x12);
+    }
+
+    #[test]
+    fn test_raw_backing_file() {
+        let path = temp_path("raw.bin");
+        std::fs::write(&path, [1, 2, 3]).unwrap();
+
+        let mut sut = EepromMemory::new(512);
+        sut.set_backing_file(&path).unwrap();
+        assert_eq!(&sut.get_data()[..4], &[1, 2, 3, 0xff]);
+
+        sut.program(3, 4, ProgrammingMode::EraseAndWrite);
+        sut.save().unwrap();
+
+        let saved = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(saved.len(), 512);
+        assert_eq!(&saved[..4], &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_intel_hex_backing_file() {
+        let path = temp_path("contents.eep");
+
+        let mut sut = EepromMemory::new(512);
+        sut.set_backing_file(&path).unwrap();
+        sut.program(0x100, 0xab, ProgrammingMode::EraseAndWrite);
+        sut.save().unwrap();
+
+        let mut loaded = EepromMemory::new(512);
+        loaded.set_backing_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.get_data(), sut.get_data());
+    }
+
+    #[test]
+    fn test_backing_file_larger_than_eeprom() {
+        let path = temp_path("large.bin");
+        std::fs::write(&path, [0; 1024]).unwrap();
+
+        let mut sut = EepromMemory::new(512);
+        let result = sut.set_backing_file(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_load() {
+        let mut sut = EepromMemory::new(4);
+
+        assert!(sut.load(&[0x12, 0x34]).is_ok());
+        assert_eq!(sut.get_data(), &[0x12, 0x34, 0xff, 0xff]);
+
+        assert!(sut.load(&[0; 5]).is_err());
+    }
+}
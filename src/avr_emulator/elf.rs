@@ -0,0 +1,569 @@
+use std::path::Path;
+
+use crate::avr_emulator::symbols::{Symbol, SymbolKind, SymbolTable, DATA_OFFSET};
+
+const MAGIC: &[u8] = b"\x7fELF";
+const CLASS_32: u8 = 1;
+const LITTLE_ENDIAN: u8 = 1;
+
+const HEADER_SIZE: usize = 0x34;
+const PROGRAM_HEADER_SIZE: usize = 0x20;
+const SECTION_HEADER_SIZE: usize = 0x28;
+const SYMBOL_SIZE: usize = 0x10;
+
+const SEGMENT_TYPE_LOAD: u32 = 1;
+
+const SECTION_TYPE_PROGRAM_BITS: u32 = 1;
+const SECTION_TYPE_SYMBOL_TABLE: u32 = 2;
+// Sections without contents in the file like .bss
+const SECTION_TYPE_NO_BITS: u32 = 8;
+
+// Sections occupying memory of the device, unlike debug information
+const SECTION_FLAG_ALLOCATE: u32 = 2;
+
+const SYMBOL_TYPE_OBJECT: u8 = 1;
+const SYMBOL_TYPE_FUNCTION: u8 = 2;
+const SYMBOL_TYPE_SECTION: u8 = 3;
+const SYMBOL_TYPE_FILE: u8 = 4;
+const SYMBOL_UNDEFINED: u16 = 0;
+
+// simavr's .mmcu section is a list of tag, length and value records
+const MMCU_TAG_END: u8 = 0;
+const MMCU_TAG_NAME: u8 = 1;
+
+const ERASED: u8 = 0xff;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section {
+    pub name: String,
+    pub section_type: u32,
+    pub address: u32,
+    flags: u32,
+    offset: usize,
+    size: usize,
+    link: usize,
+}
+
+// Program header of a loadable segment, its physical address is where it is stored in flash
+#[derive(Debug, Clone, PartialEq)]
+struct Segment {
+    segment_type: u32,
+    offset: usize,
+    physical_address: u32,
+    file_size: usize,
+}
+
+// Reader for the 32 bit little endian ELF files produced by avr-gcc
+#[derive(Debug)]
+pub struct ElfFile {
+    data: Vec<u8>,
+    sections: Vec<Section>,
+    segments: Vec<Segment>,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, String> {
+    data.get(offset..offset + 2)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+        .ok_or("truncated elf file".to_owned())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, String> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .ok_or("truncated elf file".to_owned())
+}
+
+fn read_string(data: &[u8], offset: usize) -> Result<String, String> {
+    let bytes = data.get(offset..).ok_or("truncated elf file")?;
+    let end = bytes
+        .iter()
+        .position(|byte| *byte == 0)
+        .ok_or("unterminated string in elf file")?;
+
+    Ok(String::from_utf8_lossy(&bytes[..end]).into_owned())
+}
+
+impl ElfFile {
+    pub fn is_elf(data: &[u8]) -> bool {
+        data.starts_with(MAGIC)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let data = std::fs::read(path)
+            .map_err(|error| format!("failed to read {}: {}", path.display(), error))?;
+
+        Self::parse(data).map_err(|error| format!("{}: {}", path.display(), error))
+    }
+
+    pub fn parse(data: Vec<u8>) -> Result<Self, String> {
+        if !Self::is_elf(&data) || data.len() < HEADER_SIZE {
+            return Err("not an elf file".to_owned());
+        }
+        if data[4] != CLASS_32 || data[5] != LITTLE_ENDIAN {
+            return Err("only 32 bit little endian elf files are supported".to_owned());
+        }
+
+        let program_headers = read_u32(&data, 0x1c)? as usize;
+        let segment_count = read_u16(&data, 0x2c)? as usize;
+        let section_headers = read_u32(&data, 0x20)? as usize;
+        let section_count = read_u16(&data, 0x30)? as usize;
+        let names_index = read_u16(&data, 0x32)? as usize;
+
+        let mut segments = vec![];
+        for index in 0..segment_count {
+            let header = program_headers + index * PROGRAM_HEADER_SIZE;
+
+            segments.push(Segment {
+                segment_type: read_u32(&data, header)?,
+                offset: read_u32(&data, header + 4)? as usize,
+                physical_address: read_u32(&data, header + 12)?,
+                file_size: read_u32(&data, header + 16)? as usize,
+            });
+        }
+
+        let mut sections = vec![];
+        let mut name_offsets = vec![];
+
+        for index in 0..section_count {
+            let header = section_headers + index * SECTION_HEADER_SIZE;
+
+            name_offsets.push(read_u32(&data, header)? as usize);
+            sections.push(Section {
+                name: String::new(),
+                section_type: read_u32(&data, header + 4)?,
+                flags: read_u32(&data, header + 8)?,
+                address: read_u32(&data, header + 12)?,
+                offset: read_u32(&data, header + 16)? as usize,
+                size: read_u32(&data, header + 20)? as usize,
+                link: read_u32(&data, header + 24)? as usize,
+            });
+        }
+
+        if let Some(names) = sections.get(names_index) {
+            let names_offset = names.offset;
+
+            for (section, name_offset) in sections.iter_mut().zip(name_offsets) {
+                section.name = read_string(&data, names_offset + name_offset)?;
+            }
+        }
+
+        for section in &sections {
+            if section.section_type != SECTION_TYPE_NO_BITS
+                && data
+                    .get(section.offset..section.offset + section.size)
+                    .is_none()
+            {
+                return Err(format!("section {} exceeds the elf file", section.name));
+            }
+        }
+
+        Ok(Self {
+            data,
+            sections,
+            segments,
+        })
+    }
+
+    pub fn get_sections(&self) -> &[Section] {
+        &self.sections
+    }
+
+    fn get_data(&self, section: &Section) -> &[u8] {
+        &self.data[section.offset..section.offset + section.size]
+    }
+
+    pub fn get_section_data(&self, name: &str) -> Option<&[u8]> {
+        self.sections
+            .iter()
+            .find(|section| section.name == name && section.section_type != SECTION_TYPE_NO_BITS)
+            .map(|section| self.get_data(section))
+    }
+
+    // Initialised data is linked to sram but stored in flash behind the code, the segment
+    // holding a section tells where it is stored
+    fn get_load_address(&self, section: &Section) -> u32 {
+        self.segments
+            .iter()
+            .find(|segment| {
+                segment.segment_type == SEGMENT_TYPE_LOAD
+                    && (segment.offset..segment.offset + segment.file_size)
+                        .contains(&section.offset)
+            })
+            .map_or(section.address, |segment| {
+                segment.physical_address + (section.offset - segment.offset) as u32
+            })
+    }
+
+    // Flash contents as avr-objcopy -j .text -j .data would produce them
+    pub fn get_flash(&self) -> Vec<u8> {
+        let mut flash = vec![];
+
+        for section in &self.sections {
+            if section.section_type != SECTION_TYPE_PROGRAM_BITS
+                || section.flags & SECTION_FLAG_ALLOCATE == 0
+                || section.size == 0
+            {
+                continue;
+            }
+
+            let address = self.get_load_address(section);
+            if address >= DATA_OFFSET {
+                continue;
+            }
+
+            let start = address as usize;
+            if flash.len() < start + section.size {
+                flash.resize(start + section.size, ERASED);
+            }
+            flash[start..start + section.size].copy_from_slice(self.get_data(section));
+        }
+
+        flash
+    }
+
+    pub fn get_eeprom(&self) -> Option<&[u8]> {
+        self.get_section_data(".eeprom")
+    }
+
+    // avr-libc stores the signature bytes in reverse order, byte 2 first
+    pub fn get_signature(&self) -> Option<[u8; 3]> {
+        match self.get_section_data(".signature")? {
+            [byte2, byte1, byte0, ..] => Some([*byte0, *byte1, *byte2]),
+            _ => None,
+        }
+    }
+
+    // Device name from the note written by avr-libc or from simavr's .mmcu section
+    pub fn get_mcu(&self) -> Option<String> {
+        self.get_section_data(".note.gnu.avr.deviceinfo")
+            .and_then(|note| Self::parse_device_info(note).ok())
+            .or_else(|| self.get_section_data(".mmcu").and_then(Self::parse_mmcu))
+            .filter(|name| !name.is_empty())
+    }
+
+    // Note header followed by flash, sram and eeprom start and size, the size of the
+    // string offset table, the table itself and the string table, whose first entry is the name
+    fn parse_device_info(note: &[u8]) -> Result<String, String> {
+        let name_size = read_u32(note, 0)? as usize;
+        let description = 12 + name_size.next_multiple_of(4);
+
+        let offset_table_size = read_u32(note, description + 24)? as usize;
+        let name_offset = read_u32(note, description + 28)? as usize;
+        let string_table = description + 28 + offset_table_size;
+
+        read_string(note, string_table + name_offset)
+    }
+
+    fn parse_mmcu(mmcu: &[u8]) -> Option<String> {
+        let mut offset = 0;
+
+        while let [tag, length, ..] = mmcu.get(offset..)? {
+            let value = mmcu.get(offset + 2..offset + 2 + *length as usize)?;
+
+            match *tag {
+                MMCU_TAG_END => return None,
+                MMCU_TAG_NAME => return read_string(value, 0).ok(),
+                _ => offset += 2 + *length as usize,
+            }
+        }
+
+        None
+    }
+
+    // Functions and objects plus the labels and linker symbols like __data_end,
+    // data space symbols are offset by 0x800000
+    pub fn get_symbols(&self) -> Result<SymbolTable, String> {
+        let Some(table) = self
+            .sections
+            .iter()
+            .find(|section| section.section_type == SECTION_TYPE_SYMBOL_TABLE)
+        else {
+            return Ok(SymbolTable::default());
+        };
+
+        let names = self
+            .sections
+            .get(table.link)
+            .ok_or("symbol table without string table")?
+            .offset;
+
+        let mut symbols = vec![];
+
+        for entry in self.get_data(table).chunks_exact(SYMBOL_SIZE) {
+            let name = read_string(&self.data, names + read_u32(entry, 0)? as usize)?;
+            let info = entry[12];
+            let section = read_u16(entry, 14)?;
+
+            let kind = match info & 0x0f {
+                SYMBOL_TYPE_OBJECT => SymbolKind::Object,
+                SYMBOL_TYPE_FUNCTION => SymbolKind::Function,
+                SYMBOL_TYPE_SECTION | SYMBOL_TYPE_FILE => continue,
+                _ => SymbolKind::Other,
+            };
+
+            if name.is_empty() || section == SYMBOL_UNDEFINED {
+                continue;
+            }
+
+            symbols.push(Symbol {
+                name,
+                address: read_u32(entry, 4)?,
+                size: read_u32(entry, 8)?,
+                kind,
+            });
+        }
+
+        Ok(SymbolTable::new(symbols))
+    }
+}
+
+// Builds elf files for the tests: sections with their link and load addresses and a symbol table
+#[cfg(test)]
+#[derive(Default)]
+pub struct Builder {
+    sections: Vec<(String, u32, u32, u32, Vec<u8>)>,
+    symbols: Vec<(String, u32, u32, u8)>,
+}
+
+#[cfg(test)]
+impl Builder {
+    pub fn section(self, name: &str, address: u32, data: &[u8]) -> Self {
+        self.loaded_section(name, address, address, data)
+    }
+
+    pub fn loaded_section(mut self, name: &str, address: u32, load: u32, data: &[u8]) -> Self {
+        self.sections.push((
+            name.to_owned(),
+            address,
+            load,
+            SECTION_TYPE_PROGRAM_BITS,
+            data.to_vec(),
+        ));
+        self
+    }
+
+    pub fn symbol(mut self, name: &str, address: u32, size: u32, symbol_type: u8) -> Self {
+        self.symbols
+            .push((name.to_owned(), address, size, symbol_type));
+        self
+    }
+
+    fn add_symbol_table(&mut self) {
+        let mut names = vec![0];
+        let mut table = vec![0; SYMBOL_SIZE];
+
+        for (name, address, size, symbol_type) in &self.symbols {
+            table.extend_from_slice(&(names.len() as u32).to_le_bytes());
+            table.extend_from_slice(&address.to_le_bytes());
+            table.extend_from_slice(&size.to_le_bytes());
+            table.extend_from_slice(&[0x10 | symbol_type, 0, 1, 0]);
+
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+
+        self.sections
+            .push((".symtab".to_owned(), 0, 0, SECTION_TYPE_SYMBOL_TABLE, table));
+        self.sections.push((".strtab".to_owned(), 0, 0, 3, names));
+    }
+
+    pub fn build(mut self) -> Vec<u8> {
+        if !self.symbols.is_empty() {
+            self.add_symbol_table();
+        }
+        self.sections
+            .push((".shstrtab".to_owned(), 0, 0, 3, vec![]));
+
+        let mut names = vec![0];
+        let mut name_offsets = vec![];
+        for (name, ..) in &self.sections {
+            name_offsets.push(names.len() as u32);
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        self.sections.last_mut().unwrap().4 = names;
+
+        let segment_count = self.sections.len();
+        let contents_start = HEADER_SIZE + segment_count * PROGRAM_HEADER_SIZE;
+
+        let mut contents = vec![];
+        let mut offsets = vec![];
+        for (.., data) in &self.sections {
+            offsets.push((contents_start + contents.len()) as u32);
+            contents.extend_from_slice(data);
+        }
+
+        let section_headers = contents_start + contents.len();
+        let section_count = self.sections.len() + 1;
+
+        let mut data = vec![0; HEADER_SIZE];
+        data[..4].copy_from_slice(MAGIC);
+        data[4] = CLASS_32;
+        data[5] = LITTLE_ENDIAN;
+        data[0x1c..0x20].copy_from_slice(&(HEADER_SIZE as u32).to_le_bytes());
+        data[0x20..0x24].copy_from_slice(&(section_headers as u32).to_le_bytes());
+        data[0x2c..0x2e].copy_from_slice(&(segment_count as u16).to_le_bytes());
+        data[0x30..0x32].copy_from_slice(&(section_count as u16).to_le_bytes());
+        data[0x32..0x34].copy_from_slice(&(section_count as u16 - 1).to_le_bytes());
+
+        for ((_, address, load, section_type, section_data), offset) in
+            self.sections.iter().zip(&offsets)
+        {
+            let mut program_header = vec![0; PROGRAM_HEADER_SIZE];
+            if *section_type == SECTION_TYPE_PROGRAM_BITS {
+                program_header[0..4].copy_from_slice(&SEGMENT_TYPE_LOAD.to_le_bytes());
+            }
+            program_header[4..8].copy_from_slice(&offset.to_le_bytes());
+            program_header[8..12].copy_from_slice(&address.to_le_bytes());
+            program_header[12..16].copy_from_slice(&load.to_le_bytes());
+            program_header[16..20].copy_from_slice(&(section_data.len() as u32).to_le_bytes());
+            data.extend_from_slice(&program_header);
+        }
+
+        data.extend_from_slice(&contents);
+
+        data.extend_from_slice(&[0; SECTION_HEADER_SIZE]);
+        for (index, (_, address, _, section_type, section_data)) in self.sections.iter().enumerate()
+        {
+            let flags = match *section_type {
+                SECTION_TYPE_PROGRAM_BITS => SECTION_FLAG_ALLOCATE,
+                _ => 0,
+            };
+            // the string table follows the symbol table
+            let link = match *section_type {
+                SECTION_TYPE_SYMBOL_TABLE => index as u32 + 2,
+                _ => 0,
+            };
+
+            let mut section_header = vec![0; SECTION_HEADER_SIZE];
+            section_header[0..4].copy_from_slice(&name_offsets[index].to_le_bytes());
+            section_header[4..8].copy_from_slice(&section_type.to_le_bytes());
+            section_header[8..12].copy_from_slice(&flags.to_le_bytes());
+            section_header[12..16].copy_from_slice(&address.to_le_bytes());
+            section_header[16..20].copy_from_slice(&offsets[index].to_le_bytes());
+            section_header[20..24].copy_from_slice(&(section_data.len() as u32).to_le_bytes());
+            section_header[24..28].copy_from_slice(&link.to_le_bytes());
+            data.extend_from_slice(&section_header);
+        }
+
+        data
+    }
+}
+
+// Builds an elf file with the given sections and their addresses
+#[cfg(test)]
+pub fn build(sections: &[(&str, u32, &[u8])]) -> Vec<u8> {
+    sections
+        .iter()
+        .fold(Builder::default(), |builder, (name, address, data)| {
+            builder.section(name, *address, data)
+        })
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_section_data() {
+        let sut = ElfFile::parse(build(&[
+            (".text", 0, &[0x00, 0xc0]),
+            (".fuse", 0x820000, &[0xe2, 0xd9]),
+        ]))
+        .unwrap();
+
+        assert_eq!(sut.get_section_data(".text"), Some(&[0x00, 0xc0][..]));
+        assert_eq!(sut.get_section_data(".fuse"), Some(&[0xe2, 0xd9][..]));
+        assert_eq!(sut.get_section_data(".lock"), None);
+        assert_eq!(sut.get_sections()[2].address, 0x820000);
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        assert!(ElfFile::parse(b":1000000000C0".to_vec()).is_err());
+
+        let mut elf = build(&[(".text", 0, &[0x00, 0xc0])]);
+        elf[4] = 2;
+        assert!(ElfFile::parse(elf).is_err());
+
+        let mut elf = build(&[(".text", 0, &[0x00, 0xc0])]);
+        elf.truncate(HEADER_SIZE + 1);
+        assert!(ElfFile::parse(elf).is_err());
+    }
+
+    #[test]
+    fn test_flash_and_eeprom() {
+        let sut = ElfFile::parse(
+            Builder::default()
+                .section(".text", 0, &[0x0c, 0x94, 0x34, 0x00])
+                .loaded_section(".data", 0x800100, 0x6, &[0x12, 0x34])
+                .loaded_section(".eeprom", 0x810000, 0x810000, &[0x42])
+                .section(".signature", 0x840000, &[0x0a, 0x93, 0x1e])
+                .build(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            sut.get_flash(),
+            vec![0x0c, 0x94, 0x34, 0x00, 0xff, 0xff, 0x12, 0x34]
+        );
+        assert_eq!(sut.get_eeprom(), Some(&[0x42][..]));
+        assert_eq!(sut.get_signature(), Some([0x1e, 0x93, 0x0a]));
+    }
+
+    #[test]
+    fn test_mcu() {
+        let mut note = vec![];
+        for value in [4, 0x28, 1] {
+            note.extend_from_slice(&u32::to_le_bytes(value));
+        }
+        note.extend_from_slice(b"AVR\0");
+        for value in [0, 0x2000, 0x100, 0x400, 0, 0x200, 4, 1] {
+            note.extend_from_slice(&u32::to_le_bytes(value));
+        }
+        note.extend_from_slice(b"\0atmega88\0\0");
+
+        let sut = ElfFile::parse(build(&[(".note.gnu.avr.deviceinfo", 0, &note)])).unwrap();
+        assert_eq!(sut.get_mcu(), Some("atmega88".to_owned()));
+
+        let mmcu = b"\x02\x04\x00\x12\x7a\x00\x01\x09atmega8\0\0\x00\x00";
+        let sut = ElfFile::parse(build(&[(".mmcu", 0x910000, mmcu)])).unwrap();
+        assert_eq!(sut.get_mcu(), Some("atmega8".to_owned()));
+
+        let sut = ElfFile::parse(build(&[(".text", 0, &[0x00, 0xc0])])).unwrap();
+        assert_eq!(sut.get_mcu(), None);
+    }
+
+    #[test]
+    fn test_symbols() {
+        let sut = ElfFile::parse(
+            Builder::default()
+                .section(".text", 0, &[0x00, 0xc0])
+                .symbol("main", 0x34, 0x10, SYMBOL_TYPE_FUNCTION)
+                .symbol("counter", 0x800100, 2, SYMBOL_TYPE_OBJECT)
+                .symbol("main.c", 0, 0, SYMBOL_TYPE_FILE)
+                .symbol("__data_end", 0x800102, 0, 0)
+                .build(),
+        )
+        .unwrap();
+
+        let symbols = sut.get_symbols().unwrap();
+
+        assert_eq!(symbols.get_symbols().len(), 3);
+        assert_eq!(
+            symbols.find("main"),
+            Some(&Symbol {
+                name: "main".to_owned(),
+                address: 0x34,
+                size: 0x10,
+                kind: SymbolKind::Function,
+            })
+        );
+        assert_eq!(symbols.format_address(0x800101), "counter+0x1");
+        assert_eq!(
+            symbols.find("__data_end").map(|symbol| symbol.kind),
+            Some(SymbolKind::Other)
+        );
+    }
+}
@@ -0,0 +1,341 @@
+use std::sync::{Arc, Mutex};
+
+use crate::avr_emulator::clock;
+use crate::avr_emulator::device::{Device, ExternalInterruptRegisters};
+use crate::avr_emulator::memory::Memory;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SenseControl {
+    LowLevel,
+    AnyChange,
+    FallingEdge,
+    RisingEdge,
+}
+
+// Senses INTn and PCINTn pins through the PIN registers kept up to date by the gpio peripheral
+pub struct ExternalInterrupt {
+    rising_edge_notified: std::sync::atomic::AtomicBool,
+    memory: Arc<Mutex<Memory>>,
+    device: &'static Device,
+    last_pin_levels: Vec<bool>,
+    last_port_levels: Vec<u8>,
+}
+
+impl ExternalInterrupt {
+    pub fn new(memory: Arc<Mutex<Memory>>, device: &'static Device) -> Self {
+        let (last_pin_levels, last_port_levels) = {
+            let memory = memory.lock().unwrap();
+            (
+                device
+                    .external_interrupts
+                    .iter()
+                    .map(|interrupt| memory.get_bit(&interrupt.pin))
+                    .collect(),
+                device
+                    .pin_change_interrupts
+                    .iter()
+                    .map(|interrupt| memory.peek(interrupt.pins))
+                    .collect(),
+            )
+        };
+
+        Self {
+            rising_edge_notified: std::sync::atomic::AtomicBool::new(false),
+            memory,
+            device,
+            last_pin_levels,
+            last_port_levels,
+        }
+    }
+
+    fn get_sense_control(memory: &Memory, registers: &ExternalInterruptRegisters) -> SenseControl {
+        let sense_control = registers.sense_control;
+
+        match (memory.peek(sense_control.address) >> sense_control.bit) & 0b11 {
+            0 => SenseControl::LowLevel,
+            1 => SenseControl::AnyChange,
+            2 => SenseControl::FallingEdge,
+            _ => SenseControl::RisingEdge,
+        }
+    }
+
+    fn handle_flag_writes(&self, memory: &mut Memory) {
+        let flag_registers = self
+            .device
+            .external_interrupts
+            .iter()
+            .map(|interrupt| interrupt.flag.address)
+            .chain(
+                self.device
+                    .pin_change_interrupts
+                    .iter()
+                    .map(|interrupt| interrupt.flag.address),
+            );
+
+        let mut handled: Vec<usize> = vec![];
+        for address in flag_registers {
+            if !handled.contains(&address) {
+                memory.handle_flag_register_write(address);
+                handled.push(address);
+            }
+        }
+    }
+
+    fn sense_external_interrupts(&mut self, memory: &mut Memory) {
+        for (number, (registers, last_level)) in self
+            .device
+            .external_interrupts
+            .iter()
+            .zip(&mut self.last_pin_levels)
+            .enumerate()
+        {
+            let level = memory.get_bit(&registers.pin);
+
+            let triggered = match Self::get_sense_control(memory, registers) {
+                SenseControl::LowLevel => !level,
+                SenseControl::AnyChange => level != *last_level,
+                SenseControl::FallingEdge => *last_level && !level,
+                SenseControl::RisingEdge => !*last_level && level,
+            };
+
+            if triggered {
+                memory.set_bit(&registers.flag, true);
+                memory.raise_event("ExternalInterrupt", format_args!("INT{} triggered", number));
+            }
+
+            *last_level = level;
+        }
+    }
+
+    fn sense_pin_change_interrupts(&mut self, memory: &mut Memory) {
+        for (number, (registers, last_levels)) in self
+            .device
+            .pin_change_interrupts
+            .iter()
+            .zip(&mut self.last_port_levels)
+            .enumerate()
+        {
+            let levels = memory.peek(registers.pins);
+
+            if (levels ^ *last_levels) & memory.peek(registers.mask) != 0 {
+                memory.set_bit(&registers.flag, true);
+                memory.raise_event(
+                    "ExternalInterrupt",
+                    format_args!("PCINT{} triggered", number),
+                );
+            }
+
+            *last_levels = levels;
+        }
+    }
+
+    fn sense_pins(&mut self) {
+        let memory = self.memory.clone();
+        let mut memory = memory.lock().unwrap();
+
+        self.handle_flag_writes(&mut memory);
+        self.sense_external_interrupts(&mut memory);
+        self.sense_pin_change_interrupts(&mut memory);
+    }
+}
+
+impl clock::Subscriber for ExternalInterrupt {
+    fn notify_rising_edge(&self) {
+        log::debug!("ExternalInterrupt rising edge notified");
+
+        if self
+            .rising_edge_notified
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            log::warn!("ExternalInterrupt did not finish handling previous rising edge!");
+        }
+
+        self.rising_edge_notified
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+    fn notify_falling_edge(&self) {}
+
+    fn run(&mut self) {
+        if self
+            .rising_edge_notified
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            self.sense_pins();
+
+            self.rising_edge_notified
+                .store(false, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avr_emulator::clock::Subscriber;
+    use crate::avr_emulator::device;
+    use crate::avr_emulator::gpio::{Gpio, PinDrive, PortPins};
+    use crate::avr_emulator::interrupt_handler::InterruptHandler;
+    use crate::avr_emulator::memory::SregBit;
+
+    fn set_up(device: &'static Device) -> (Arc<Mutex<Memory>>, ExternalInterrupt) {
+        let memory = Arc::new(Mutex::new(Memory::new(300, vec![]).unwrap()));
+        let sut = ExternalInterrupt::new(memory.clone(), device);
+
+        (memory, sut)
+    }
+
+    fn clock_cycle(external_interrupt: &mut ExternalInterrupt) {
+        external_interrupt.notify_rising_edge();
+        external_interrupt.run();
+    }
+
+    fn set_pin(memory: &Arc<Mutex<Memory>>, address: usize, bit: u8, level: bool) {
+        memory
+            .lock()
+            .unwrap()
+            .set_bit(&device::RegisterBit::new(address, bit), level);
+    }
+
+    #[test]
+    fn test_run_without_notify() {
+        let (memory, mut sut) = set_up(&device::ATMEGA8);
+
+        sut.run();
+
+        assert_eq!(memory.lock().unwrap().peek(0x5a), 0);
+    }
+
+    #[test]
+    fn test_low_level_sets_flag_while_pin_is_low() {
+        let (memory, mut sut) = set_up(&device::ATMEGA8);
+
+        clock_cycle(&mut sut);
+        assert_eq!(memory.lock().unwrap().peek(0x5a), 0b1100_0000);
+
+        set_pin(&memory, 0x30, 2, true);
+        set_pin(&memory, 0x30, 3, true);
+        memory.lock().unwrap().set_sram(0x5a, 0b1100_0000);
+        clock_cycle(&mut sut);
+        assert_eq!(memory.lock().unwrap().peek(0x5a), 0);
+    }
+
+    #[test]
+    fn test_any_change() {
+        let (memory, mut sut) = set_up(&device::ATMEGA8);
+        memory.lock().unwrap().set_sram(0x55, 0b0000_0001);
+
+        set_pin(&memory, 0x30, 2, true);
+        clock_cycle(&mut sut);
+        assert!(memory
+            .lock()
+            .unwrap()
+            .get_bit(&device::RegisterBit::new(0x5a, 6)));
+
+        memory.lock().unwrap().set_sram(0x5a, 0b0100_0000);
+        clock_cycle(&mut sut);
+        assert!(!memory
+            .lock()
+            .unwrap()
+            .get_bit(&device::RegisterBit::new(0x5a, 6)));
+
+        set_pin(&memory, 0x30, 2, false);
+        clock_cycle(&mut sut);
+        assert!(memory
+            .lock()
+            .unwrap()
+            .get_bit(&device::RegisterBit::new(0x5a, 6)));
+    }
+
+    #[test]
+    fn test_falling_edge() {
+        let (memory, mut sut) = set_up(&device::ATMEGA8);
+        memory.lock().unwrap().set_sram(0x55, 0b0000_1000);
+
+        set_pin(&memory, 0x30, 3, true);
+        clock_cycle(&mut sut);
+        assert_eq!(memory.lock().unwrap().peek(0x5a) & 0b1000_0000, 0);
+
+        set_pin(&memory, 0x30, 3, false);
+        clock_cycle(&mut sut);
+        assert_eq!(memory.lock().unwrap().peek(0x5a) & 0b1000_0000, 0b1000_0000);
+    }
+
+    #[test]
+    fn test_rising_edge() {
+        let (memory, mut sut) = set_up(&device::ATMEGA88);
+        memory.lock().unwrap().set_sram(0x69, 0b0000_0011);
+
+        set_pin(&memory, 0x29, 2, true);
+        clock_cycle(&mut sut);
+        assert_eq!(memory.lock().unwrap().peek(0x3c) & 0b0000_0001, 1);
+
+        memory.lock().unwrap().set_sram(0x3c, 0b0000_0001);
+        set_pin(&memory, 0x29, 2, false);
+        clock_cycle(&mut sut);
+        assert_eq!(memory.lock().unwrap().peek(0x3c) & 0b0000_0001, 0);
+    }
+
+    #[test]
+    fn test_pin_change_respects_mask() {
+        let (memory, mut sut) = set_up(&device::ATMEGA88);
+        memory.lock().unwrap().set_sram(0x6c, 0b0000_0100);
+
+        set_pin(&memory, 0x26, 1, true);
+        clock_cycle(&mut sut);
+        assert_eq!(memory.lock().unwrap().peek(0x3b), 0);
+
+        set_pin(&memory, 0x26, 2, true);
+        clock_cycle(&mut sut);
+        assert_eq!(memory.lock().unwrap().peek(0x3b), 0b0000_0010);
+    }
+
+    #[test]
+    fn test_button_press_runs_int0_routine() {
+        let memory = Arc::new(Mutex::new(Memory::new(300, vec![]).unwrap()));
+        let pins = Arc::new(Mutex::new(PortPins::default()));
+
+        let mut gpio = Gpio::new(
+            memory.clone(),
+            device::ATMEGA8.find_port('D').unwrap(),
+            device::ATMEGA8.pull_up_disable,
+            device::ATMEGA8.pin_write_toggles_port,
+            pins.clone(),
+        );
+        let mut external_interrupt = ExternalInterrupt::new(memory.clone(), &device::ATMEGA8);
+        let mut interrupt_handler = InterruptHandler::new(
+            memory.clone(),
+            &device::ATMEGA8,
+            device::ATMEGA8.fuses.defaults,
+        );
+
+        {
+            let mut memory = memory.lock().unwrap();
+            memory.set_sp(50);
+            memory.set_pc(0x40);
+            // PD2 pull-up, falling edge, INT0 enabled
+            memory.set_sram(0x32, 0b0000_0100);
+            memory.set_sram(0x55, 0b0000_0010);
+            memory.set_sram(0x5b, 0b0100_0000);
+            memory.set_status_register_bit(SregBit::I);
+        }
+
+        let mut clock_cycle = || {
+            gpio.notify_rising_edge();
+            gpio.run();
+            external_interrupt.notify_rising_edge();
+            external_interrupt.run();
+            interrupt_handler.notify_rising_edge();
+            interrupt_handler.run();
+        };
+
+        clock_cycle();
+        clock_cycle();
+        assert_eq!(memory.lock().unwrap().get_pc(), 0x40);
+
+        pins.lock().unwrap().drive(2, PinDrive::Low);
+        clock_cycle();
+
+        assert_eq!(memory.lock().unwrap().get_pc(), 1);
+        assert_eq!(memory.lock().unwrap().peek(0x5a) & 0b0100_0000, 0);
+    }
+}
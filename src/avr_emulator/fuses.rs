@@ -0,0 +1,297 @@
+use std::path::Path;
+
+use crate::avr_emulator::device::{Device, FuseBit, FuseByte};
+use crate::avr_emulator::elf::ElfFile;
+use crate::avr_emulator::number::parse_number;
+
+// Boot lock bits BLB02:BLB01 protect the application section, BLB12:BLB11 the boot loader
+const BLB0_SHIFT: u8 = 2;
+const BLB1_SHIFT: u8 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LockMode {
+    Unrestricted,
+    NoSpmWrite,
+    NoSpmWriteNoLpmRead,
+    NoLpmRead,
+}
+
+impl LockMode {
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0b11 {
+            0b11 => LockMode::Unrestricted,
+            0b10 => LockMode::NoSpmWrite,
+            0b00 => LockMode::NoSpmWriteNoLpmRead,
+            _ => LockMode::NoLpmRead,
+        }
+    }
+
+    pub fn allows_spm_write(&self) -> bool {
+        matches!(self, LockMode::Unrestricted | LockMode::NoLpmRead)
+    }
+}
+
+// Fuse and lock bytes as written by a programmer, programmed bits read as zero
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fuses {
+    pub low: u8,
+    pub high: u8,
+    pub extended: u8,
+    pub lock: u8,
+}
+
+// Fuse values are given like avrdude takes them: 0xe2, 0b1110_0010 or 226
+pub fn parse_byte(value: &str) -> Result<u8, String> {
+    parse_number(&value.replace('_', "")).ok_or_else(|| format!("invalid fuse byte: {}", value))
+}
+
+// The .signature section records the device the firmware was built for
+pub fn check_signature(device: &Device, elf: &ElfFile) -> Result<(), String> {
+    match elf.get_signature() {
+        Some(signature) if signature != device.fuses.signature => Err(format!(
+            "firmware signature {:02x?} does not match the {} signature {:02x?}",
+            signature, device.name, device.fuses.signature
+        )),
+        _ => Ok(()),
+    }
+}
+
+impl Fuses {
+    pub fn get_byte(&self, byte: FuseByte) -> u8 {
+        match byte {
+            FuseByte::Low => self.low,
+            FuseByte::High => self.high,
+            FuseByte::Extended => self.extended,
+        }
+    }
+
+    fn get_bits(&self, first: FuseBit, width: u8) -> u8 {
+        (self.get_byte(first.byte) >> first.bit) & ((1 << width) - 1)
+    }
+
+    fn is_programmed(&self, bit: FuseBit) -> bool {
+        self.get_bits(bit, 1) == 0
+    }
+
+    // avr-libc places the fuse bytes low, high and extended in the .fuse section
+    // and the lock byte in the .lock section
+    pub fn load_elf_sections(&mut self, path: &Path) -> Result<(), String> {
+        self.set_from_elf(&ElfFile::from_file(path)?);
+        Ok(())
+    }
+
+    pub fn set_from_elf(&mut self, elf: &ElfFile) {
+        if let Some(fuse) = elf.get_section_data(".fuse") {
+            let bytes = [&mut self.low, &mut self.high, &mut self.extended];
+            for (byte, value) in bytes.into_iter().zip(fuse) {
+                *byte = *value;
+            }
+        }
+
+        if let Some(lock) = elf.get_section_data(".lock").and_then(|lock| lock.first()) {
+            self.lock = *lock;
+        }
+    }
+
+    // Byte address of the boot loader section selected by BOOTSZ
+    pub fn get_boot_loader_start(&self, device: &Device) -> usize {
+        let boot_size = self.get_bits(device.fuses.boot_size, 2) as usize;
+
+        device.boot_loader.flash_size - device.boot_loader.boot_sizes[boot_size]
+    }
+
+    // Word address execution starts at, the boot loader section when BOOTRST is programmed
+    pub fn get_reset_address(&self, device: &Device) -> u16 {
+        if self.is_programmed(device.fuses.boot_reset) {
+            (self.get_boot_loader_start(device) / 2) as u16
+        } else {
+            0
+        }
+    }
+
+    pub fn get_application_lock(&self) -> LockMode {
+        LockMode::from_bits(self.lock >> BLB0_SHIFT)
+    }
+
+    pub fn get_boot_loader_lock(&self) -> LockMode {
+        LockMode::from_bits(self.lock >> BLB1_SHIFT)
+    }
+
+    // Only BLBSET can program further boot lock bits, programmed bits stay programmed
+    pub fn program_boot_lock_bits(&mut self, bits: u8) {
+        let mask = 0b11 << BLB0_SHIFT | 0b11 << BLB1_SHIFT;
+
+        self.lock &= bits | !mask;
+    }
+
+    // CKDIV8 selects the initial division of the system clock prescaler
+    pub fn is_clock_divided(&self, device: &Device) -> bool {
+        device
+            .fuses
+            .clock_divide
+            .is_some_and(|clock_divide| self.is_programmed(clock_divide))
+    }
+
+    // The clock source is the internal oscillator selected by CKSEL unless its frequency is
+    // given, external clocks and crystals always need it
+    pub fn get_clock_source_frequency(
+        &self,
+        device: &Device,
+        source_frequency: Option<i64>,
+    ) -> Result<i64, String> {
+        let clock_select = self.get_bits(device.fuses.clock_select, 4);
+
+        let internal_oscillator = device
+            .fuses
+            .internal_oscillators
+            .iter()
+            .find(|(select, _)| *select == clock_select)
+            .map(|(_, frequency)| *frequency as i64);
+
+        source_frequency.or(internal_oscillator).ok_or(format!(
+            "CKSEL {:#06b} selects an external clock source, its frequency has to be given",
+            clock_select
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avr_emulator::{device, elf};
+
+    #[test]
+    fn test_parse_byte() {
+        assert_eq!(parse_byte("0xe2"), Ok(0xe2));
+        assert_eq!(parse_byte("0XD9"), Ok(0xd9));
+        assert_eq!(parse_byte("0b1111_1001"), Ok(0xf9));
+        assert_eq!(parse_byte("255"), Ok(0xff));
+        assert!(parse_byte("0x100").is_err());
+        assert!(parse_byte("high").is_err());
+    }
+
+    #[test]
+    fn test_boot_loader_start_and_reset_address() {
+        let mut sut = device::ATMEGA8.fuses.defaults;
+
+        // BOOTSZ 00 and BOOTRST unprogrammed
+        assert_eq!(sut.get_boot_loader_start(&device::ATMEGA8), 0x1800);
+        assert_eq!(sut.get_reset_address(&device::ATMEGA8), 0);
+
+        sut.high = 0xda;
+        assert_eq!(sut.get_boot_loader_start(&device::ATMEGA8), 0x1c00);
+        assert_eq!(sut.get_reset_address(&device::ATMEGA8), 0xe00);
+
+        let mut sut = device::ATMEGA88.fuses.defaults;
+        assert_eq!(sut.get_reset_address(&device::ATMEGA88), 0);
+
+        sut.extended = 0xfe;
+        assert_eq!(sut.get_reset_address(&device::ATMEGA88), 0xf80);
+    }
+
+    #[test]
+    fn test_clock_source_frequency() {
+        let mut sut = device::ATMEGA88.fuses.defaults;
+
+        // internal 8 MHz oscillator, divided by 8 at start
+        assert_eq!(
+            sut.get_clock_source_frequency(&device::ATMEGA88, None),
+            Ok(8_000_000)
+        );
+        assert_eq!(
+            sut.get_clock_source_frequency(&device::ATMEGA88, Some(7_372_800)),
+            Ok(7_372_800)
+        );
+        assert!(sut.is_clock_divided(&device::ATMEGA88));
+
+        // external crystal without CKDIV8
+        sut.low = 0xff;
+        assert!(sut
+            .get_clock_source_frequency(&device::ATMEGA88, None)
+            .is_err());
+        assert!(!sut.is_clock_divided(&device::ATMEGA88));
+        assert_eq!(
+            sut.get_clock_source_frequency(&device::ATMEGA88, Some(16_000_000)),
+            Ok(16_000_000)
+        );
+
+        let mut sut = device::ATMEGA8.fuses.defaults;
+        assert_eq!(
+            sut.get_clock_source_frequency(&device::ATMEGA8, None),
+            Ok(1_000_000)
+        );
+        assert!(!sut.is_clock_divided(&device::ATMEGA8));
+
+        sut.low = 0xe4;
+        assert_eq!(
+            sut.get_clock_source_frequency(&device::ATMEGA8, None),
+            Ok(8_000_000)
+        );
+    }
+
+    #[test]
+    fn test_lock_modes() {
+        let mut sut = device::ATMEGA8.fuses.defaults;
+        assert_eq!(sut.get_application_lock(), LockMode::Unrestricted);
+        assert_eq!(sut.get_boot_loader_lock(), LockMode::Unrestricted);
+
+        sut.lock = 0b1110_1111;
+        assert_eq!(sut.get_application_lock(), LockMode::Unrestricted);
+        assert_eq!(sut.get_boot_loader_lock(), LockMode::NoSpmWrite);
+        assert!(!sut.get_boot_loader_lock().allows_spm_write());
+
+        sut.lock = 0b1111_0011;
+        assert_eq!(sut.get_application_lock(), LockMode::NoSpmWriteNoLpmRead);
+
+        sut.lock = 0b1111_0111;
+        assert_eq!(sut.get_application_lock(), LockMode::NoLpmRead);
+        assert!(sut.get_application_lock().allows_spm_write());
+    }
+
+    #[test]
+    fn test_program_boot_lock_bits() {
+        let mut sut = device::ATMEGA8.fuses.defaults;
+        sut.lock = 0b1111_1100;
+
+        // lock bits 0 and 1 can not be changed and programmed bits stay programmed
+        sut.program_boot_lock_bits(0b1110_1011);
+        sut.program_boot_lock_bits(0xff);
+
+        assert_eq!(sut.lock, 0b1110_1000);
+    }
+
+    #[test]
+    fn test_check_signature() {
+        let elf =
+            ElfFile::parse(elf::build(&[(".signature", 0x840000, &[0x0a, 0x93, 0x1e])])).unwrap();
+
+        assert!(check_signature(&device::ATMEGA88, &elf).is_ok());
+        assert!(check_signature(&device::ATMEGA8, &elf).is_err());
+
+        let elf = ElfFile::parse(elf::build(&[(".text", 0, &[0x00, 0xc0])])).unwrap();
+        assert!(check_signature(&device::ATMEGA8, &elf).is_ok());
+    }
+
+    #[test]
+    fn test_set_from_elf() {
+        let mut sut = device::ATMEGA88.fuses.defaults;
+        let elf = ElfFile::parse(elf::build(&[
+            (".text", 0, &[0x00, 0xc0]),
+            (".fuse", 0x820000, &[0xff, 0xdd, 0xf8]),
+            (".lock", 0x830000, &[0xcf]),
+        ]))
+        .unwrap();
+
+        sut.set_from_elf(&elf);
+
+        assert_eq!(
+            sut,
+            Fuses {
+                low: 0xff,
+                high: 0xdd,
+                extended: 0xf8,
+                lock: 0xcf,
+            }
+        );
+    }
+}
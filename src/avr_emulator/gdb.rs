@@ -0,0 +1,688 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::avr_emulator::debugger::{Debugger, StopReason, WatchKind};
+use crate::avr_emulator::eeprom::EepromMemory;
+use crate::avr_emulator::memory::Memory;
+
+// avr-gdb addresses flash, the data space and the eeprom in a single address space
+const DATA_OFFSET: u32 = 0x80_0000;
+const EEPROM_OFFSET: u32 = 0x81_0000;
+
+// Register numbers of avr-gdb: r0-r31, SREG, SP and PC
+const REGISTER_COUNT: usize = 35;
+const SREG: usize = 32;
+const SP: usize = 33;
+const PC: usize = 34;
+
+const SREG_ADDRESS: usize = 0x5f;
+const SP_ADDRESS: usize = 0x5d;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+// Sent by gdb outside of a packet to interrupt the running target
+const INTERRUPT: u8 = 0x03;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, PartialEq)]
+enum Packet {
+    Command(String),
+    Interrupt,
+}
+
+#[derive(Debug, PartialEq)]
+enum Action {
+    Reply(String),
+    Resume,
+    Detach,
+    Kill,
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn encode_packet(data: &str) -> String {
+    format!("${}#{:02x}", data, checksum(data.as_bytes()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+// "addr,length" as used by the memory and breakpoint packets
+fn parse_address_length(arguments: &str) -> Option<(u32, usize)> {
+    let (address, length) = arguments.split_once(',')?;
+
+    Some((
+        u32::from_str_radix(address, 16).ok()?,
+        usize::from_str_radix(length, 16).ok()?,
+    ))
+}
+
+fn read_byte(stream: &mut impl Read) -> std::io::Result<Option<u8>> {
+    let mut byte = [0];
+    match stream.read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+// Returns None when the connection is closed, packets with a bad checksum are requested again
+fn read_packet(stream: &mut (impl Read + Write)) -> std::io::Result<Option<Packet>> {
+    loop {
+        let Some(byte) = read_byte(stream)? else {
+            return Ok(None);
+        };
+
+        match byte {
+            INTERRUPT => return Ok(Some(Packet::Interrupt)),
+            b'$' => {
+                let mut data = vec![];
+                loop {
+                    match read_byte(stream)? {
+                        None => return Ok(None),
+                        Some(b'#') => break,
+                        Some(byte) => data.push(byte),
+                    }
+                }
+
+                let mut received = vec![];
+                for _ in 0..2 {
+                    match read_byte(stream)? {
+                        None => return Ok(None),
+                        Some(byte) => received.push(byte),
+                    }
+                }
+
+                let received = std::str::from_utf8(&received)
+                    .ok()
+                    .and_then(|received| u8::from_str_radix(received, 16).ok());
+
+                if received == Some(checksum(&data)) {
+                    stream.write_all(b"+")?;
+                    return Ok(Some(Packet::Command(
+                        String::from_utf8_lossy(&data).into_owned(),
+                    )));
+                }
+
+                log::warn!("GdbServer packet with bad checksum");
+                stream.write_all(b"-")?;
+            }
+            // acknowledgements of our packets
+            _ => {}
+        }
+    }
+}
+
+// Remote serial protocol stub for avr-gdb, serving one client at a time
+pub struct GdbServer {
+    memory: Arc<Mutex<Memory>>,
+    eeprom: Arc<Mutex<EepromMemory>>,
+    debugger: Arc<Debugger>,
+    stop_program: Arc<AtomicBool>,
+    // flash writes beyond the loaded program are allowed up to the flash of the device
+    flash_size: usize,
+    signal: u8,
+}
+
+impl GdbServer {
+    pub fn new(
+        memory: Arc<Mutex<Memory>>,
+        eeprom: Arc<Mutex<EepromMemory>>,
+        debugger: Arc<Debugger>,
+        stop_program: Arc<AtomicBool>,
+        flash_size: usize,
+    ) -> Self {
+        Self {
+            memory,
+            eeprom,
+            debugger,
+            stop_program,
+            flash_size,
+            signal: SIGTRAP,
+        }
+    }
+
+    pub fn listen(mut self, port: u16) -> Result<(), String> {
+        let listener = TcpListener::bind(("127.0.0.1", port))
+            .map_err(|error| format!("failed to listen on port {}: {}", port, error))?;
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(error) => {
+                        log::warn!("failed to accept gdb client: {}", error);
+                        continue;
+                    }
+                };
+
+                // without detaching, a disconnecting client leaves the core running or halted
+                if let Err(error) = self.serve(stream) {
+                    log::warn!("gdb connection failed: {}", error);
+                }
+
+                if self.stop_program.load(std::sync::atomic::Ordering::Relaxed) {
+                    return;
+                }
+            }
+        });
+
+        eprintln!("gdb server listening on localhost:{}", port);
+
+        Ok(())
+    }
+
+    fn serve(&mut self, mut stream: TcpStream) -> std::io::Result<()> {
+        let _ = stream.set_nodelay(true);
+
+        loop {
+            let packet = match read_packet(&mut stream)? {
+                Some(packet) => packet,
+                None => return Ok(()),
+            };
+
+            log::debug!("GdbServer received {:?}", packet);
+
+            let action = match packet {
+                Packet::Interrupt => {
+                    self.debugger.halt();
+                    self.signal = SIGINT;
+                    Action::Reply(self.get_stop_reply())
+                }
+                Packet::Command(command) => self.handle_command(&command),
+            };
+
+            match action {
+                Action::Reply(reply) => {
+                    stream.write_all(encode_packet(&reply).as_bytes())?;
+                }
+                Action::Resume => {
+                    if !self.wait_for_stop(&mut stream)? {
+                        return Ok(());
+                    }
+                    stream.write_all(encode_packet(&self.get_stop_reply()).as_bytes())?;
+                }
+                Action::Detach => {
+                    stream.write_all(encode_packet("OK").as_bytes())?;
+                    self.debugger.resume();
+                    return Ok(());
+                }
+                Action::Kill => {
+                    self.stop_program
+                        .store(true, std::sync::atomic::Ordering::Relaxed);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // Waits until the core halts or gdb interrupts it, returns false when gdb disconnected
+    fn wait_for_stop(&mut self, stream: &mut TcpStream) -> std::io::Result<bool> {
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+
+        let stopped = loop {
+            if self.debugger.wait_until_halted(POLL_INTERVAL) {
+                self.signal = SIGTRAP;
+                break true;
+            }
+
+            match read_byte(stream) {
+                Ok(None) => break false,
+                Ok(Some(INTERRUPT)) => {
+                    self.debugger.halt();
+                    self.signal = SIGINT;
+                    break true;
+                }
+                Ok(Some(_)) => {}
+                Err(error)
+                    if matches!(
+                        error.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) => {}
+                Err(error) => return Err(error),
+            }
+        };
+
+        stream.set_read_timeout(None)?;
+        Ok(stopped)
+    }
+
+    // Watchpoint hits are reported with the watched data space address
+    fn get_stop_reply(&self) -> String {
+        match self.debugger.get_stop_reason() {
+            StopReason::Watchpoint { kind, access } if self.signal == SIGTRAP => {
+                let name = match kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                format!(
+                    "T{:02x}{}:{:x};",
+                    self.signal,
+                    name,
+                    access.address as u32 + DATA_OFFSET
+                )
+            }
+            _ => format!("S{:02x}", self.signal),
+        }
+    }
+
+    fn handle_command(&mut self, command: &str) -> Action {
+        let reply = |reply: &str| Action::Reply(reply.to_owned());
+        let (kind, arguments) = command.split_at(command.len().min(1));
+
+        match kind {
+            "?" => Action::Reply(self.get_stop_reply()),
+            "g" => Action::Reply(to_hex(
+                &(0..REGISTER_COUNT)
+                    .flat_map(|register| self.read_register(register))
+                    .collect::<Vec<u8>>(),
+            )),
+            "G" => match self.write_registers(arguments) {
+                Some(()) => reply("OK"),
+                None => reply("E01"),
+            },
+            "p" => usize::from_str_radix(arguments, 16)
+                .ok()
+                .filter(|register| *register < REGISTER_COUNT)
+                .map_or(reply("E01"), |register| {
+                    Action::Reply(to_hex(&self.read_register(register)))
+                }),
+            "P" => match self.write_register(arguments) {
+                Some(()) => reply("OK"),
+                None => reply("E01"),
+            },
+            "m" => parse_address_length(arguments)
+                .and_then(|(address, length)| self.read_memory(address, length))
+                .map_or(reply("E01"), |data| Action::Reply(to_hex(&data))),
+            "M" => match self.write_memory(arguments) {
+                Some(()) => reply("OK"),
+                None => reply("E01"),
+            },
+            "c" | "s" => {
+                if let Ok(address) = u32::from_str_radix(arguments, 16) {
+                    self.memory.lock().unwrap().set_pc((address / 2) as u16);
+                }
+                if kind == "c" {
+                    self.debugger.resume();
+                } else {
+                    self.debugger.step();
+                }
+                Action::Resume
+            }
+            "Z" | "z" => self.handle_breakpoint(kind == "Z", arguments),
+            "H" => reply("OK"),
+            "D" => Action::Detach,
+            "k" => Action::Kill,
+            "q" if arguments.starts_with("Supported") => reply("PacketSize=1000"),
+            "q" if arguments == "Attached" => reply("1"),
+            _ => reply(""),
+        }
+    }
+
+    // Software and hardware breakpoints are the same to the emulator, watchpoints are only
+    // supported in the data space
+    fn handle_breakpoint(&self, insert: bool, arguments: &str) -> Action {
+        let Some((kind, location)) = arguments.split_once(',') else {
+            return Action::Reply("E01".to_owned());
+        };
+        let Some((address, length)) = parse_address_length(location) else {
+            return Action::Reply("E01".to_owned());
+        };
+
+        let watch_kind = match kind {
+            "0" | "1" => {
+                let pc = (address / 2) as u16;
+                if insert {
+                    self.debugger.add_breakpoint(pc);
+                } else {
+                    self.debugger.remove_breakpoint(pc);
+                }
+                return Action::Reply("OK".to_owned());
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return Action::Reply(String::new()),
+        };
+
+        if !(DATA_OFFSET..EEPROM_OFFSET).contains(&address) || length == 0 {
+            return Action::Reply("E01".to_owned());
+        }
+
+        let start = (address - DATA_OFFSET) as usize;
+        let Some(end) = start.checked_add(length - 1) else {
+            return Action::Reply("E01".to_owned());
+        };
+        let range = start..=end;
+        if insert {
+            self.debugger.add_watchpoint(watch_kind, range);
+        } else {
+            self.debugger.remove_watchpoint(watch_kind, range);
+        }
+
+        Action::Reply("OK".to_owned())
+    }
+
+    fn read_register(&self, register: usize) -> Vec<u8> {
+        let memory = self.memory.lock().unwrap();
+
+        match register {
+            SREG => vec![memory.peek(SREG_ADDRESS)],
+            SP => vec![memory.peek(SP_ADDRESS), memory.peek(SP_ADDRESS + 1)],
+            PC => (memory.get_pc() as u32 * 2).to_le_bytes().to_vec(),
+            register => vec![memory.peek(register)],
+        }
+    }
+
+    fn set_register(&self, register: usize, value: &[u8]) {
+        let mut memory = self.memory.lock().unwrap();
+
+        match register {
+            SREG => memory.poke(SREG_ADDRESS, value[0]),
+            SP => {
+                memory.poke(SP_ADDRESS, value[0]);
+                memory.poke(SP_ADDRESS + 1, value[1]);
+            }
+            PC => {
+                let address = u32::from_le_bytes([value[0], value[1], value[2], value[3]]);
+                memory.set_pc((address / 2) as u16);
+            }
+            register => memory.poke(register, value[0]),
+        }
+    }
+
+    fn get_register_size(register: usize) -> usize {
+        match register {
+            SP => 2,
+            PC => 4,
+            _ => 1,
+        }
+    }
+
+    fn write_register(&self, arguments: &str) -> Option<()> {
+        let (register, value) = arguments.split_once('=')?;
+        let register = usize::from_str_radix(register, 16).ok()?;
+        let value = from_hex(value)?;
+
+        if register >= REGISTER_COUNT || value.len() != Self::get_register_size(register) {
+            return None;
+        }
+
+        self.set_register(register, &value);
+        Some(())
+    }
+
+    fn write_registers(&self, arguments: &str) -> Option<()> {
+        let mut values = from_hex(arguments)?;
+
+        for register in 0..REGISTER_COUNT {
+            let size = Self::get_register_size(register);
+            if values.len() < size {
+                break;
+            }
+            let rest = values.split_off(size);
+            self.set_register(register, &values);
+            values = rest;
+        }
+
+        Some(())
+    }
+
+    fn read_memory(&self, address: u32, length: usize) -> Option<Vec<u8>> {
+        if address >= EEPROM_OFFSET {
+            let start = (address - EEPROM_OFFSET) as usize;
+            let eeprom = self.eeprom.lock().unwrap();
+            eeprom
+                .get_data()
+                .get(start..start.checked_add(length)?)
+                .map(<[u8]>::to_vec)
+        } else if address >= DATA_OFFSET {
+            let start = (address - DATA_OFFSET) as usize;
+            let end = start.checked_add(length)?;
+            let memory = self.memory.lock().unwrap();
+            (end <= memory.get_sram_size())
+                .then(|| (start..end).map(|address| memory.peek(address)).collect())
+        } else {
+            let start = address as usize;
+            let end = start.checked_add(length)?;
+            let memory = self.memory.lock().unwrap();
+            (end <= memory.get_flash_size()).then(|| {
+                (start..end)
+                    .map(|address| memory.get_flash(address))
+                    .collect()
+            })
+        }
+    }
+
+    fn write_memory(&self, arguments: &str) -> Option<()> {
+        let (location, data) = arguments.split_once(':')?;
+        let (address, length) = parse_address_length(location)?;
+        let data = from_hex(data)?;
+
+        if data.len() != length {
+            return None;
+        }
+
+        if address >= EEPROM_OFFSET {
+            let start = (address - EEPROM_OFFSET) as usize;
+            let mut eeprom = self.eeprom.lock().unwrap();
+            if start.checked_add(length)? > eeprom.get_data().len() {
+                return None;
+            }
+            for (offset, value) in data.into_iter().enumerate() {
+                eeprom.set_byte(start + offset, value);
+            }
+        } else if address >= DATA_OFFSET {
+            let start = (address - DATA_OFFSET) as usize;
+            let mut memory = self.memory.lock().unwrap();
+            if start.checked_add(length)? > memory.get_sram_size() {
+                return None;
+            }
+            for (offset, value) in data.into_iter().enumerate() {
+                memory.poke(start + offset, value);
+            }
+        } else {
+            let start = address as usize;
+            if start.checked_add(length)? > self.flash_size {
+                return None;
+            }
+            let mut memory = self.memory.lock().unwrap();
+            for (offset, value) in data.into_iter().enumerate() {
+                memory.set_flash(start + offset, value);
+            }
+        }
+
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avr_emulator::memory::{AccessKind, MemoryAccess};
+
+    fn set_up() -> GdbServer {
+        let memory = Arc::new(Mutex::new(
+            Memory::new(0x460, vec![0x0c, 0x94, 0x34, 0x00]).unwrap(),
+        ));
+
+        GdbServer::new(
+            memory,
+            Arc::new(Mutex::new(EepromMemory::new(512))),
+            Arc::new(Debugger::new(true)),
+            Arc::new(AtomicBool::new(false)),
+            0x2000,
+        )
+    }
+
+    fn reply(reply: &str) -> Action {
+        Action::Reply(reply.to_owned())
+    }
+
+    // Input read from and output written to gdb
+    struct MockStream {
+        input: std::io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buffer)
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buffer)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_read_packet() {
+        let mut stream = MockStream {
+            input: std::io::Cursor::new(b"+$g#00$g#67\x03".to_vec()),
+            output: vec![],
+        };
+
+        assert_eq!(
+            read_packet(&mut stream).unwrap(),
+            Some(Packet::Command("g".to_owned()))
+        );
+        assert_eq!(stream.output, b"-+");
+        assert_eq!(read_packet(&mut stream).unwrap(), Some(Packet::Interrupt));
+        assert_eq!(read_packet(&mut stream).unwrap(), None);
+
+        assert_eq!(encode_packet("OK"), "$OK#9a");
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut sut = set_up();
+        {
+            let mut memory = sut.memory.lock().unwrap();
+            memory.poke(24, 0x12);
+            memory.set_sp(0x045f);
+            memory.poke(SREG_ADDRESS, 0x82);
+            memory.set_pc(0x34);
+        }
+
+        let Action::Reply(registers) = sut.handle_command("g") else {
+            panic!("expected a reply");
+        };
+        assert_eq!(registers.len(), 39 * 2);
+        assert_eq!(&registers[48..50], "12");
+        assert_eq!(&registers[64..], "825f0468000000");
+
+        assert_eq!(sut.handle_command("p22"), reply("68000000"));
+        assert_eq!(sut.handle_command("P22=80000000"), reply("OK"));
+        assert_eq!(sut.memory.lock().unwrap().get_pc(), 0x40);
+
+        assert_eq!(sut.handle_command("P1f=ff"), reply("OK"));
+        assert_eq!(sut.memory.lock().unwrap().peek(31), 0xff);
+
+        assert_eq!(sut.handle_command("P21=ff"), reply("E01"));
+        assert_eq!(sut.handle_command("p23"), reply("E01"));
+    }
+
+    #[test]
+    fn test_memory() {
+        let mut sut = set_up();
+        sut.eeprom.lock().unwrap().set_byte(1, 0x42);
+
+        assert_eq!(sut.handle_command("m0,4"), reply("0c943400"));
+        assert_eq!(sut.handle_command("m0,5"), reply("E01"));
+
+        assert_eq!(sut.handle_command("M800100,2:abcd"), reply("OK"));
+        assert_eq!(sut.handle_command("m8000ff,3"), reply("00abcd"));
+        assert_eq!(sut.handle_command("m800460,1"), reply("E01"));
+
+        assert_eq!(sut.handle_command("m810000,2"), reply("ff42"));
+        assert_eq!(sut.handle_command("M810000,1:00"), reply("OK"));
+        assert_eq!(sut.eeprom.lock().unwrap().get_data()[0], 0x00);
+
+        assert_eq!(sut.handle_command("M4,2:ffcf"), reply("OK"));
+        assert_eq!(sut.memory.lock().unwrap().get_flash(5), 0xcf);
+        assert_eq!(sut.handle_command("M1fff,2:ffff"), reply("E01"));
+        assert_eq!(sut.memory.lock().unwrap().get_flash_size(), 6);
+
+        // lengths overflowing the address space
+        assert_eq!(sut.handle_command("m0,ffffffffffffffff"), reply("E01"));
+        assert_eq!(sut.handle_command("m800000,ffffffffffffffff"), reply("E01"));
+        assert_eq!(sut.handle_command("m810000,ffffffffffffffff"), reply("E01"));
+        assert_eq!(
+            sut.handle_command("Z2,800100,ffffffffffffffff"),
+            reply("E01")
+        );
+    }
+
+    #[test]
+    fn test_breakpoints_and_execution() {
+        let mut sut = set_up();
+
+        assert_eq!(sut.handle_command("Z0,68,2"), reply("OK"));
+        assert_eq!(sut.handle_command("Z1,6a,2"), reply("OK"));
+        assert_eq!(sut.handle_command("Z5,68,2"), reply(""));
+        assert_eq!(sut.handle_command("z1,6a,2"), reply("OK"));
+
+        sut.memory.lock().unwrap().set_pc(0x34);
+        assert!(!sut.debugger.should_execute(&sut.memory.lock().unwrap()));
+
+        assert_eq!(sut.handle_command("s"), Action::Resume);
+        assert!(sut.debugger.should_execute(&sut.memory.lock().unwrap()));
+        sut.debugger.executed(&[]);
+        assert!(sut.debugger.is_halted());
+
+        sut.memory.lock().unwrap().set_pc(0x35);
+        assert_eq!(sut.handle_command("c"), Action::Resume);
+        assert!(sut.debugger.should_execute(&sut.memory.lock().unwrap()));
+        assert!(!sut.debugger.is_halted());
+
+        assert_eq!(sut.handle_command("?"), reply("S05"));
+        assert_eq!(sut.handle_command("D"), Action::Detach);
+        assert_eq!(sut.handle_command("vMustReplyEmpty"), reply(""));
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut sut = set_up();
+
+        assert_eq!(sut.handle_command("Z2,800100,2"), reply("OK"));
+        assert_eq!(sut.handle_command("Z3,800060,1"), reply("OK"));
+        assert_eq!(sut.handle_command("Z4,800061,1"), reply("OK"));
+        assert_eq!(sut.handle_command("Z2,100,1"), reply("E01"));
+        assert_eq!(sut.handle_command("z4,800061,1"), reply("OK"));
+        assert_eq!(sut.debugger.get_watchpoints().len(), 2);
+
+        assert_eq!(sut.handle_command("c"), Action::Resume);
+        sut.debugger.executed(&[MemoryAccess {
+            kind: AccessKind::Write,
+            address: 0x101,
+            old: 0,
+            new: 1,
+        }]);
+        assert!(sut.debugger.is_halted());
+        assert_eq!(sut.handle_command("?"), reply("T05watch:800101;"));
+    }
+}
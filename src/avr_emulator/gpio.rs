@@ -0,0 +1,282 @@
+use std::sync::{Arc, Mutex};
+
+use crate::avr_emulator::clock;
+use crate::avr_emulator::device::{PortRegisters, RegisterBit};
+use crate::avr_emulator::memory::Memory;
+
+// What the outside world does to a pin. A floating input without pull-up reads as low.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum PinDrive {
+    #[default]
+    Floating,
+    Low,
+    High,
+}
+
+// State of the pins of a single port shared with the code using the emulator
+#[derive(Debug, Default)]
+pub struct PortPins {
+    drive: [PinDrive; 8],
+    levels: u8,
+}
+
+impl PortPins {
+    pub fn drive(&mut self, pin: u8, drive: PinDrive) {
+        self.drive[pin as usize] = drive;
+    }
+
+    pub fn get_drive(&self, pin: u8) -> PinDrive {
+        self.drive[pin as usize]
+    }
+
+    pub fn get_level(&self, pin: u8) -> bool {
+        self.levels & (1 << pin) != 0
+    }
+
+    pub fn get_levels(&self) -> u8 {
+        self.levels
+    }
+}
+
+pub struct Gpio {
+    rising_edge_notified: std::sync::atomic::AtomicBool,
+    memory: Arc<Mutex<Memory>>,
+    registers: &'static PortRegisters,
+    pull_up_disable: RegisterBit,
+    pin_write_toggles_port: bool,
+    pins: Arc<Mutex<PortPins>>,
+    conflicting_pins: u8,
+}
+
+impl Gpio {
+    pub fn new(
+        memory: Arc<Mutex<Memory>>,
+        registers: &'static PortRegisters,
+        pull_up_disable: RegisterBit,
+        pin_write_toggles_port: bool,
+        pins: Arc<Mutex<PortPins>>,
+    ) -> Self {
+        Self {
+            rising_edge_notified: std::sync::atomic::AtomicBool::new(false),
+            memory,
+            registers,
+            pull_up_disable,
+            pin_write_toggles_port,
+            pins,
+            conflicting_pins: 0,
+        }
+    }
+
+    fn handle_pin_write(&mut self, memory: &mut Memory) {
+        if let Some(write) = memory.take_io_write(self.registers.pin) {
+            if self.pin_write_toggles_port {
+                let port = memory.peek(self.registers.port);
+                memory.poke(self.registers.port, port ^ write.new);
+            }
+        }
+    }
+
+    fn compute_levels(&mut self, memory: &Memory, pins: &PortPins) -> u8 {
+        let ddr = memory.peek(self.registers.ddr);
+        let port = memory.peek(self.registers.port);
+        let pull_ups_disabled = memory.get_bit(&self.pull_up_disable);
+
+        let mut levels = 0;
+        let mut conflicting_pins = 0;
+
+        for pin in 0..8 {
+            let mask = 1 << pin;
+
+            let level = if ddr & mask != 0 {
+                if pins.drive[pin] != PinDrive::Floating
+                    && (pins.drive[pin] == PinDrive::High) != (port & mask != 0)
+                {
+                    conflicting_pins |= mask;
+                    if self.conflicting_pins & mask == 0 {
+                        log::warn!(
+                            "output pin P{}{} is driven externally to the opposite level",
+                            self.registers.name,
+                            pin
+                        );
+                    }
+                }
+                port & mask != 0
+            } else {
+                match pins.drive[pin] {
+                    PinDrive::High => true,
+                    PinDrive::Low => false,
+                    PinDrive::Floating => port & mask != 0 && !pull_ups_disabled,
+                }
+            };
+
+            if level {
+                levels |= mask;
+            }
+        }
+
+        self.conflicting_pins = conflicting_pins;
+
+        levels
+    }
+
+    fn update_pins(&mut self) {
+        let memory = self.memory.clone();
+        let mut memory = memory.lock().unwrap();
+        let pins = self.pins.clone();
+        let mut pins = pins.lock().unwrap();
+
+        self.handle_pin_write(&mut memory);
+
+        let levels = self.compute_levels(&memory, &pins);
+
+        memory.poke(self.registers.pin, levels);
+        pins.levels = levels;
+    }
+}
+
+impl clock::Subscriber for Gpio {
+    fn notify_rising_edge(&self) {
+        log::debug!("Gpio rising edge notified");
+
+        if self
+            .rising_edge_notified
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            log::warn!("Gpio did not finish handling previous rising edge!");
+        }
+
+        self.rising_edge_notified
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+    fn notify_falling_edge(&self) {}
+
+    fn run(&mut self) {
+        if self
+            .rising_edge_notified
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            self.update_pins();
+
+            self.rising_edge_notified
+                .store(false, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avr_emulator::clock::Subscriber;
+    use crate::avr_emulator::device::{self, Device};
+
+    fn set_up(device: &'static Device) -> (Arc<Mutex<Memory>>, Gpio, Arc<Mutex<PortPins>>) {
+        let memory = Arc::new(Mutex::new(Memory::new(300, vec![]).unwrap()));
+        let pins = Arc::new(Mutex::new(PortPins::default()));
+
+        let gpio = Gpio::new(
+            memory.clone(),
+            device.find_port('B').unwrap(),
+            device.pull_up_disable,
+            device.pin_write_toggles_port,
+            pins.clone(),
+        );
+
+        (memory, gpio, pins)
+    }
+
+    fn clock_cycle(gpio: &mut Gpio) {
+        gpio.notify_rising_edge();
+        gpio.run();
+    }
+
+    #[test]
+    fn test_run_without_notify() {
+        let (memory, mut sut, pins) = set_up(&device::ATMEGA8);
+        pins.lock().unwrap().drive(0, PinDrive::High);
+
+        sut.run();
+
+        assert_eq!(memory.lock().unwrap().peek(0x36), 0);
+    }
+
+    #[test]
+    fn test_output_pins_follow_port() {
+        let (memory, mut sut, pins) = set_up(&device::ATMEGA8);
+        memory.lock().unwrap().set_sram(0x37, 0b0000_1111);
+        memory.lock().unwrap().set_sram(0x38, 0b0000_0101);
+
+        clock_cycle(&mut sut);
+
+        assert_eq!(memory.lock().unwrap().peek(0x36), 0b0000_0101);
+        assert_eq!(pins.lock().unwrap().get_levels(), 0b0000_0101);
+        assert!(pins.lock().unwrap().get_level(0));
+        assert!(!pins.lock().unwrap().get_level(1));
+    }
+
+    #[test]
+    fn test_input_pins_follow_external_drive() {
+        let (memory, mut sut, pins) = set_up(&device::ATMEGA8);
+        pins.lock().unwrap().drive(3, PinDrive::High);
+        pins.lock().unwrap().drive(4, PinDrive::Low);
+
+        clock_cycle(&mut sut);
+        assert_eq!(memory.lock().unwrap().peek(0x36), 0b0000_1000);
+
+        pins.lock().unwrap().drive(3, PinDrive::Floating);
+        clock_cycle(&mut sut);
+        assert_eq!(memory.lock().unwrap().peek(0x36), 0);
+    }
+
+    #[test]
+    fn test_pull_ups() {
+        let (memory, mut sut, pins) = set_up(&device::ATMEGA8);
+        memory.lock().unwrap().set_sram(0x38, 0b0000_0011);
+        pins.lock().unwrap().drive(1, PinDrive::Low);
+
+        clock_cycle(&mut sut);
+        assert_eq!(memory.lock().unwrap().peek(0x36), 0b0000_0001);
+
+        memory.lock().unwrap().set_sram(0x50, 0b0000_0100);
+        clock_cycle(&mut sut);
+        assert_eq!(memory.lock().unwrap().peek(0x36), 0);
+    }
+
+    #[test]
+    fn test_output_wins_over_external_drive() {
+        let (memory, mut sut, pins) = set_up(&device::ATMEGA8);
+        memory.lock().unwrap().set_sram(0x37, 0b0000_0001);
+        pins.lock().unwrap().drive(0, PinDrive::High);
+
+        clock_cycle(&mut sut);
+
+        assert_eq!(memory.lock().unwrap().peek(0x36), 0);
+    }
+
+    #[test]
+    fn test_pin_write_is_ignored_on_older_devices() {
+        let (memory, mut sut, _) = set_up(&device::ATMEGA8);
+        memory.lock().unwrap().set_sram(0x37, 0b0000_0001);
+        memory.lock().unwrap().set_sram(0x36, 0b0000_0001);
+
+        clock_cycle(&mut sut);
+
+        assert_eq!(memory.lock().unwrap().peek(0x38), 0);
+        assert_eq!(memory.lock().unwrap().peek(0x36), 0);
+    }
+
+    #[test]
+    fn test_pin_write_toggles_port_on_newer_devices() {
+        let (memory, mut sut, pins) = set_up(&device::ATMEGA88);
+        memory.lock().unwrap().set_sram(0x24, 0b0000_0011);
+        memory.lock().unwrap().set_sram(0x25, 0b0000_0010);
+
+        memory.lock().unwrap().set_sram(0x23, 0b0000_0011);
+        clock_cycle(&mut sut);
+
+        assert_eq!(memory.lock().unwrap().peek(0x25), 0b0000_0001);
+        assert_eq!(pins.lock().unwrap().get_levels(), 0b0000_0001);
+
+        clock_cycle(&mut sut);
+        assert_eq!(memory.lock().unwrap().peek(0x25), 0b0000_0001);
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::VecDeque;
+
+// Slave device attached to the I2C bus at a 7-bit address
+pub trait I2cDevice: Send {
+    // (Repeated) start addressed to the device, returns whether the address is acknowledged
+    fn start(&mut self, read: bool) -> bool;
+
+    // Byte written by the master, returns whether it is acknowledged
+    fn write(&mut self, byte: u8) -> bool;
+
+    // Byte read by the master, master_ack tells whether the master acknowledges it
+    fn read(&mut self, master_ack: bool) -> u8;
+
+    fn stop(&mut self);
+}
+
+// Transaction of an external bus master addressing the emulated mcu as a slave
+#[derive(Debug, Clone, PartialEq)]
+pub enum I2cTransaction {
+    Write { address: u8, data: Vec<u8> },
+    Read { address: u8, length: usize },
+}
+
+impl I2cTransaction {
+    pub fn get_address(&self) -> u8 {
+        match self {
+            I2cTransaction::Write { address, .. } | I2cTransaction::Read { address, .. } => {
+                *address
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct I2cTransactionResult {
+    pub acknowledged: bool,
+    // bytes read from the mcu, empty for writes
+    pub data: Vec<u8>,
+}
+
+#[derive(Default)]
+pub struct I2cBus {
+    devices: Vec<(u8, Box<dyn I2cDevice>)>,
+    pending_transactions: VecDeque<I2cTransaction>,
+    results: Vec<I2cTransactionResult>,
+}
+
+impl I2cBus {
+    pub fn attach(&mut self, address: u8, device: Box<dyn I2cDevice>) {
+        self.devices.push((address & 0x7f, device));
+    }
+
+    pub fn queue_transaction(&mut self, transaction: I2cTransaction) {
+        self.pending_transactions.push_back(transaction);
+    }
+
+    pub fn take_results(&mut self) -> Vec<I2cTransactionResult> {
+        std::mem::take(&mut self.results)
+    }
+
+    pub(crate) fn next_transaction(&mut self) -> Option<I2cTransaction> {
+        self.pending_transactions.pop_front()
+    }
+
+    pub(crate) fn push_result(&mut self, result: I2cTransactionResult) {
+        self.results.push(result);
+    }
+
+    fn find_device(&mut self, address: u8) -> Option<&mut Box<dyn I2cDevice>> {
+        self.devices
+            .iter_mut()
+            .find(|(device_address, _)| *device_address == address)
+            .map(|(_, device)| device)
+    }
+
+    // Nobody acknowledges an address without a device
+    pub(crate) fn start(&mut self, address: u8, read: bool) -> bool {
+        self.find_device(address)
+            .is_some_and(|device| device.start(read))
+    }
+
+    pub(crate) fn write(&mut self, address: u8, byte: u8) -> bool {
+        self.find_device(address)
+            .is_some_and(|device| device.write(byte))
+    }
+
+    // SDA is pulled up, reading from a missing device returns all ones
+    pub(crate) fn read(&mut self, address: u8, master_ack: bool) -> u8 {
+        self.find_device(address)
+            .map_or(0xff, |device| device.read(master_ack))
+    }
+
+    pub(crate) fn stop(&mut self, address: u8) {
+        if let Some(device) = self.find_device(address) {
+            device.stop();
+        }
+    }
+}
+
+// Serial EEPROM like the 24C02: a write sets the word address followed by data bytes,
+// reads continue sequentially from the current address
+#[derive(Debug)]
+pub struct I2cEeprom {
+    memory: Vec<u8>,
+    address: usize,
+    address_received: bool,
+}
+
+impl I2cEeprom {
+    // The word address wraps around the memory, which needs at least one byte
+    pub fn new(size: usize) -> Result<Self, String> {
+        if size == 0 {
+            return Err("I2cEeprom needs at least one byte of memory".to_owned());
+        }
+
+        Ok(Self {
+            memory: vec![0xff; size],
+            address: 0,
+            address_received: false,
+        })
+    }
+
+    pub fn get_memory(&self) -> &[u8] {
+        &self.memory
+    }
+}
+
+impl I2cDevice for I2cEeprom {
+    fn start(&mut self, _read: bool) -> bool {
+        self.address_received = false;
+        true
+    }
+
+    fn write(&mut self, byte: u8) -> bool {
+        if self.address_received {
+            self.memory[self.address] = byte;
+            self.address = (self.address + 1) % self.memory.len();
+        } else {
+            self.address = byte as usize % self.memory.len();
+            self.address_received = true;
+        }
+        true
+    }
+
+    fn read(&mut self, _master_ack: bool) -> u8 {
+        let byte = self.memory[self.address];
+        self.address = (self.address + 1) % self.memory.len();
+        byte
+    }
+
+    fn stop(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_device_does_not_acknowledge() {
+        let mut sut = I2cBus::default();
+
+        assert!(!sut.start(0x50, false));
+        assert!(!sut.write(0x50, 0x12));
+        assert_eq!(sut.read(0x50, false), 0xff);
+    }
+
+    #[test]
+    fn test_eeprom() {
+        let mut sut = I2cBus::default();
+        sut.attach(0x50, Box::new(I2cEeprom::new(256).unwrap()));
+
+        assert!(sut.start(0x50, false));
+        assert!(sut.write(0x50, 0x10));
+        assert!(sut.write(0x50, 0xab));
+        assert!(sut.write(0x50, 0xcd));
+        sut.stop(0x50);
+
+        assert!(sut.start(0x50, false));
+        assert!(sut.write(0x50, 0x10));
+        assert!(sut.start(0x50, true));
+        assert_eq!(sut.read(0x50, true), 0xab);
+        assert_eq!(sut.read(0x50, false), 0xcd);
+        sut.stop(0x50);
+    }
+
+    #[test]
+    fn test_eeprom_without_memory() {
+        assert!(I2cEeprom::new(0).is_err());
+    }
+}
@@ -28,12 +28,13 @@ mod pop;
 mod push;
 mod rcall;
 mod ret;
-mod reti;
+pub(crate) mod reti;
 mod rjmp;
 mod sbc;
 mod sbci;
 mod sbiw;
 mod sbr;
+mod spm;
 mod st_x_plus;
 mod st_y_plus;
 mod st_z;
@@ -43,11 +44,15 @@ mod std_z;
 mod sts;
 mod sub;
 mod subi;
+mod wdr;
 
 pub trait Instruction {
     fn process(&self, memory: &mut Memory) -> ();
     fn str(&self) -> String;
 
+    // The word following the opcode of the 32-bit instructions
+    fn set_operand(&mut self, _operand: u16) {}
+
     fn get_instruction_codes() -> Vec<u16>
     where
         Self: Sized;
@@ -76,6 +81,19 @@ pub trait Instruction {
     }
 }
 
+// Length in words of the instruction starting with the opcode, LDS, STS, JMP and CALL are
+// followed by an address
+pub fn get_size(opcode: u16) -> u16 {
+    let two_words = opcode & 0b1111_1100_0000_1111 == 0b1001_0000_0000_0000
+        || opcode & 0b1111_1110_0000_1100 == 0b1001_0100_0000_1100;
+
+    if two_words {
+        2
+    } else {
+        1
+    }
+}
+
 //TODO: refactor
 pub fn get_instruction(opcode: u16) -> Option<Box<dyn Instruction>> {
     if nop::NOP::eq(opcode) {
@@ -207,6 +225,12 @@ pub fn get_instruction(opcode: u16) -> Option<Box<dyn Instruction>> {
     if st_y_plus::StYPlus::eq(opcode) {
         return Some(Box::new(st_y_plus::StYPlus::new(opcode)));
     }
+    if wdr::WDR::eq(opcode) {
+        return Some(Box::new(wdr::WDR::new(opcode)));
+    }
+    if spm::SPM::eq(opcode) {
+        return Some(Box::new(spm::SPM::new(opcode)));
+    }
 
     None
 }
@@ -263,6 +287,17 @@ mod tests {
         assert!(get_instruction(0xffff).is_none());
     }
 
+    #[test]
+    fn test_get_size() {
+        assert_eq!(get_size(0x0000), 1);
+        assert_eq!(get_size(0x9180), 2); // lds r24
+        assert_eq!(get_size(0x9380), 2); // sts r24
+        assert_eq!(get_size(0x940c), 2); // jmp
+        assert_eq!(get_size(0x940e), 2); // call
+        assert_eq!(get_size(0x9181), 1); // ld r24, Z+
+        assert_eq!(get_size(0x9508), 1); // ret
+    }
+
     #[test]
     fn test_get_instruction_returns_nop_for_nop_opcode() {
         assert_eq!(get_instruction(0x0000).unwrap().str(), "nop");
@@ -483,6 +518,16 @@ mod tests {
     fn test_get_instruction_returns_st_y_plus_for_st_y_plus_opcode() {
         assert_eq!(get_instruction(0x9209).unwrap().str(), "st y+, r0");
     }
+
+    #[test]
+    fn test_get_instruction_returns_wdr_for_wdr_opcode() {
+        assert_eq!(get_instruction(0x95a8).unwrap().str(), "wdr");
+    }
+
+    #[test]
+    fn test_get_instruction_returns_spm_for_spm_opcode() {
+        assert_eq!(get_instruction(0x95e8).unwrap().str(), "spm");
+    }
 }
 
 #[cfg(test)]
@@ -23,6 +23,9 @@ impl Instruction for LDS {
     fn str(&self) -> String {
         return format!("lds r{}, {}", self.d, self.k).to_owned();
     }
+    fn set_operand(&mut self, operand: u16) {
+        self.k = operand;
+    }
     fn get_instruction_codes() -> Vec<u16> {
         vec![0b1001_0000_0000_0000]
     }
@@ -81,4 +84,11 @@ mod tests {
         let lds = LDS::new(0x90f0);
         assert_eq!(lds.str(), "lds r15, 0");
     }
+
+    #[test]
+    fn test_str_with_operand() {
+        let mut lds = LDS::new(0x90f0);
+        lds.set_operand(0x0100);
+        assert_eq!(lds.str(), "lds r15, 256");
+    }
 }
@@ -6,10 +6,10 @@ pub struct POP {
 
 impl Instruction for POP {
     fn process(&self, memory: &mut Memory) {
-        memory.set_sp(memory.get_sp() + 1);
+        memory.set_sp(memory.get_sp().wrapping_add(1));
         memory.set_register(
             self.d as usize,
-            memory.get_stack(memory.get_sp() as usize).unwrap(),
+            memory.get_stack(memory.get_sp() as usize).unwrap_or(0),
         );
         memory.set_pc(memory.get_pc() + 1);
     }
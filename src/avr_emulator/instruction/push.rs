@@ -10,8 +10,8 @@ impl Instruction for PUSH {
             memory.get_sp() as usize,
             memory.get_register(self.r as usize).unwrap(),
         );
-        memory.set_sp(memory.get_sp() - 1);
-        memory.set_pc(memory.get_pc() +1)
+        memory.set_sp(memory.get_sp().wrapping_sub(1));
+        memory.set_pc(memory.get_pc() + 1)
     }
     fn str(&self) -> String {
         return format!("push r{}", self.r).to_owned();
@@ -7,14 +7,14 @@ pub struct RCALL {
 impl Instruction for RCALL {
     fn process(&self, memory: &mut Memory) {
         memory.set_stack(
-            (memory.get_sp() - 1) as usize,
+            memory.get_sp().wrapping_sub(1) as usize,
             (((memory.get_pc() + 1) & 0xff00) >> 8) as u8,
         );
         memory.set_stack(
             memory.get_sp() as usize,
             ((memory.get_pc() + 1) & 0x00ff) as u8,
         );
-        memory.set_sp(memory.get_sp() - 2);
+        memory.set_sp(memory.get_sp().wrapping_sub(2));
         memory.set_pc(memory.get_pc().checked_add_signed(self.k + 1).unwrap());
     }
     fn str(&self) -> String {
@@ -4,11 +4,13 @@ pub struct RET {}
 
 impl Instruction for RET {
     fn process(&self, memory: &mut Memory) {
-        memory.set_sp(memory.get_sp() + 2);
-        memory.set_pc(
-            ((memory.get_stack((memory.get_sp() - 1) as usize).unwrap() as u16) << 8)
-                | (memory.get_stack(memory.get_sp() as usize).unwrap() as u16),
-        );
+        let sp = memory.get_sp().wrapping_add(2);
+        memory.set_sp(sp);
+
+        // a stack outside the data space is reported by memory, the return address reads as 0
+        let high = memory.get_stack(sp.wrapping_sub(1) as usize).unwrap_or(0);
+        let low = memory.get_stack(sp as usize).unwrap_or(0);
+        memory.set_pc(u16::from_be_bytes([high, low]));
     }
     fn str(&self) -> String {
         return format!("ret").to_owned();
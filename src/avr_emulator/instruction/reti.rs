@@ -1,22 +1,27 @@
 use crate::avr_emulator::{instruction::Instruction, memory::Memory, memory::SregBit};
 
+pub const OPCODE: u16 = 0b1001_0101_0001_1000;
+
 pub struct RETI {}
 
 impl Instruction for RETI {
     fn process(&self, memory: &mut Memory) {
-        memory.set_sp(memory.get_sp() + 2);
-        memory.set_pc(
-            ((memory.get_stack((memory.get_sp() - 1) as usize).unwrap() as u16) << 8)
-                | (memory.get_stack(memory.get_sp() as usize).unwrap() as u16),
-        );
+        let sp = memory.get_sp().wrapping_add(2);
+        memory.set_sp(sp);
+
+        // a stack outside the data space is reported by memory, the return address reads as 0
+        let high = memory.get_stack(sp.wrapping_sub(1) as usize).unwrap_or(0);
+        let low = memory.get_stack(sp as usize).unwrap_or(0);
+        memory.set_pc(u16::from_be_bytes([high, low]));
 
         memory.set_status_register_bit(SregBit::I);
+        memory.leave_interrupt();
     }
     fn str(&self) -> String {
         return format!("reti").to_owned();
     }
     fn get_instruction_codes() -> Vec<u16> {
-        vec![0b1001_0101_0001_1000]
+        vec![OPCODE]
     }
     fn get_instruction_mask() -> u16 {
         0b1111_1111_1111_1111
@@ -51,10 +56,13 @@ mod tests {
         expected_registers.set_stack(expected_sp as usize, expected_pc as u8);
         expected_registers.set_status_register_bit(SregBit::I);
 
+        test_registers.enter_interrupt();
+
         let reti = RETI::new(0b1001_0101_0001_1000);
         reti.process(&mut test_registers);
 
         assert_eq!(test_registers, expected_registers);
+        assert_eq!(test_registers.get_interrupt_depth(), 0);
     }
 
     #[test]
@@ -0,0 +1,81 @@
+use crate::avr_emulator::instruction::Instruction;
+use crate::avr_emulator::memory::{Memory, SpmRequest};
+
+pub struct SPM {}
+
+impl Instruction for SPM {
+    fn process(&self, memory: &mut Memory) {
+        let data =
+            (memory.get_register(1).unwrap() as u16) << 8 | memory.get_register(0).unwrap() as u16;
+
+        memory.request_spm(SpmRequest {
+            pc: memory.get_pc(),
+            address: memory.get_z_register(),
+            data,
+        });
+        memory.set_pc(memory.get_pc() + 1);
+    }
+    fn str(&self) -> String {
+        "spm".to_owned()
+    }
+    fn get_instruction_codes() -> Vec<u16> {
+        vec![0b1001_0101_1110_1000]
+    }
+    fn get_instruction_mask() -> u16 {
+        0b1111_1111_1111_1111
+    }
+}
+
+impl SPM {
+    pub fn new(_opcode: u16) -> Self {
+        Self {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::avr_emulator::instruction::Instruction;
+    use crate::avr_emulator::memory::{Memory, SpmRequest};
+
+    use super::SPM;
+
+    #[test]
+    fn test_process() {
+        let mut test_registers = Memory::new(100, vec![]).unwrap();
+        test_registers.set_pc(0xf80);
+        test_registers.set_z_register(0x1234);
+        test_registers.set_register(0, 0xcd);
+        test_registers.set_register(1, 0xab);
+
+        let spm = SPM::new(0x95e8);
+        spm.process(&mut test_registers);
+
+        assert_eq!(test_registers.get_pc(), 0xf81);
+        assert_eq!(
+            test_registers.take_spm_request(),
+            Some(SpmRequest {
+                pc: 0xf80,
+                address: 0x1234,
+                data: 0xabcd,
+            })
+        );
+        assert_eq!(test_registers.take_spm_request(), None);
+    }
+
+    #[test]
+    fn test_get_instruction_codes() {
+        assert_eq!(SPM::get_instruction_codes(), vec![0x95e8]);
+    }
+
+    #[test]
+    fn test_get_instruction_mask() {
+        assert_eq!(SPM::get_instruction_mask(), 0xffff);
+    }
+
+    #[test]
+    fn test_str() {
+        let spm = SPM::new(0x95e8);
+
+        assert_eq!(spm.str(), "spm");
+    }
+}
@@ -23,6 +23,9 @@ impl Instruction for STS {
     fn str(&self) -> String {
         return format!("sts {}, r{}", self.k, self.r).to_owned();
     }
+    fn set_operand(&mut self, operand: u16) {
+        self.k = operand;
+    }
     fn get_instruction_codes() -> Vec<u16> {
         vec![0b1001_0010_0000_0000]
     }
@@ -83,4 +86,11 @@ mod tests {
         let sts = STS::new(0x92f0);
         assert_eq!(sts.str(), "sts 0, r15");
     }
+
+    #[test]
+    fn test_str_with_operand() {
+        let mut sts = STS::new(0x92f0);
+        sts.set_operand(0x0100);
+        assert_eq!(sts.str(), "sts 256, r15");
+    }
 }
@@ -0,0 +1,63 @@
+use crate::avr_emulator::{instruction::Instruction, memory::Memory};
+
+pub struct WDR {}
+
+impl Instruction for WDR {
+    fn process(&self, memory: &mut Memory) {
+        memory.kick_watchdog();
+        memory.set_pc(memory.get_pc() + 1);
+    }
+    fn str(&self) -> String {
+        "wdr".to_owned()
+    }
+    fn get_instruction_codes() -> Vec<u16> {
+        vec![0b1001_0101_1010_1000]
+    }
+    fn get_instruction_mask() -> u16 {
+        0b1111_1111_1111_1111
+    }
+}
+
+impl WDR {
+    pub fn new(_opcode: u16) -> Self {
+        Self {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::avr_emulator::{instruction::Instruction, memory::Memory};
+
+    use super::WDR;
+
+    #[test]
+    fn test_process() {
+        let mut test_registers = Memory::new(100, vec![]).unwrap();
+
+        let mut expected_registers = Memory::new(100, vec![]).unwrap();
+        expected_registers.set_pc(1);
+
+        let wdr = WDR::new(0x95a8);
+        wdr.process(&mut test_registers);
+
+        assert_eq!(test_registers, expected_registers);
+        assert!(test_registers.take_watchdog_kick());
+    }
+
+    #[test]
+    fn test_get_instruction_codes() {
+        assert_eq!(WDR::get_instruction_codes(), vec![0x95a8]);
+    }
+
+    #[test]
+    fn test_get_instruction_mask() {
+        assert_eq!(WDR::get_instruction_mask(), 0xffff);
+    }
+
+    #[test]
+    fn test_str() {
+        let wdr = WDR::new(0x95a8);
+
+        assert_eq!(wdr.str(), "wdr");
+    }
+}
@@ -1,19 +1,85 @@
 use std::sync::{Arc, Mutex};
 
 use crate::avr_emulator::clock;
+use crate::avr_emulator::debugger::Debugger;
 use crate::avr_emulator::instruction;
-use crate::avr_emulator::memory::Memory;
+use crate::avr_emulator::instruction::reti;
+use crate::avr_emulator::memory::{AccessKind, Memory, MemoryAccess};
+use crate::avr_emulator::observer::{self, SharedObserver};
+use crate::avr_emulator::trace::{TraceRecord, Tracer};
 
 pub struct InstructionExecutor {
     rising_edge_notified: std::sync::atomic::AtomicBool,
     memory: Arc<Mutex<Memory>>,
+    debugger: Option<Arc<Debugger>>,
+    tracer: Option<Arc<Mutex<Tracer>>>,
+    observers: Vec<SharedObserver>,
+    cycles: u64,
 }
 
 impl InstructionExecutor {
-    pub fn new(memory: Arc<Mutex<Memory>>) -> Self {
+    pub fn new(memory: Arc<Mutex<Memory>>, debugger: Option<Arc<Debugger>>) -> Self {
         Self {
             rising_edge_notified: std::sync::atomic::AtomicBool::new(false),
             memory: memory,
+            debugger,
+            tracer: None,
+            observers: vec![],
+            cycles: 0,
+        }
+    }
+
+    pub fn set_tracer(&mut self, tracer: Arc<Mutex<Tracer>>) {
+        self.tracer = Some(tracer);
+    }
+
+    pub fn add_observer(&mut self, observer: SharedObserver) {
+        self.observers.push(observer);
+    }
+
+    fn notify_observers(&self, pc: u16, opcode: u16, accesses: &[MemoryAccess]) {
+        observer::notify(&self.observers, |observer| {
+            observer.on_instruction(pc as u32 * 2, opcode);
+
+            for access in accesses {
+                match access.kind {
+                    AccessKind::Read => observer.on_mem_read(access.address, access.new),
+                    AccessKind::Write => observer.on_mem_write(access.address, access.new),
+                }
+            }
+
+            if opcode == reti::OPCODE {
+                observer.on_reti();
+            }
+        });
+    }
+
+    fn trace(
+        &self,
+        tracer: &Mutex<Tracer>,
+        pc: u16,
+        opcode: u16,
+        instruction: &dyn instruction::Instruction,
+        registers: &[u8],
+    ) {
+        let memory = self.memory.lock().unwrap();
+        let address = pc as usize * 2;
+
+        let record = TraceRecord {
+            cycle: self.cycles,
+            pc: address as u32,
+            opcode: (address..address + instruction::get_size(opcode) as usize * 2)
+                .filter(|address| *address < memory.get_flash_size())
+                .map(|address| memory.get_flash(address))
+                .collect(),
+            disassembly: instruction.str(),
+            changes: TraceRecord::find_changes(registers, &memory.get_all_registers()),
+            sreg: memory.get_status_register(),
+        };
+        drop(memory);
+
+        if let Err(error) = tracer.lock().unwrap().record(&record) {
+            log::error!("failed to write trace: {}", error);
         }
     }
 
@@ -26,13 +92,25 @@ impl InstructionExecutor {
         (b << 8 | a) as u16
     }
 
+    fn get_current_instruction_operand(&self) -> u16 {
+        let offset = ((self.memory.lock().unwrap().get_pc() + 1) * 2) as usize;
+
+        let a = self.memory.lock().unwrap().get_flash(offset) as u16;
+        let b = self.memory.lock().unwrap().get_flash(offset + 1) as u16;
+
+        b << 8 | a
+    }
+
     fn find_instruction_from_opcode(&mut self, opcode: u16) -> Box<dyn instruction::Instruction> {
         match instruction::get_instruction(opcode) {
             None => {
                 log::error!("unknown opcode: {:#06x}", opcode);
                 std::process::exit(2); // TODO: this should be handled elsewhere ...
             }
-            Some(instruction) => {
+            Some(mut instruction) => {
+                if instruction::get_size(opcode) == 2 {
+                    instruction.set_operand(self.get_current_instruction_operand());
+                }
                 log::info!("instruction: {}", instruction.str());
                 instruction
             }
@@ -61,9 +139,56 @@ impl<'a> clock::Subscriber for InstructionExecutor {
             .rising_edge_notified
             .load(std::sync::atomic::Ordering::Relaxed)
         {
-            let current_instruction_opcode = self.get_current_instruction_opcode();
-            let current_instruction = self.find_instruction_from_opcode(current_instruction_opcode);
-            current_instruction.process(&mut self.memory.lock().unwrap());
+            self.cycles += 1;
+            let pc = self.memory.lock().unwrap().get_pc();
+
+            if self
+                .debugger
+                .as_ref()
+                .is_none_or(|debugger| debugger.should_execute(&self.memory.lock().unwrap()))
+            {
+                let current_instruction_opcode = self.get_current_instruction_opcode();
+                let current_instruction =
+                    self.find_instruction_from_opcode(current_instruction_opcode);
+
+                let tracer = self
+                    .tracer
+                    .clone()
+                    .filter(|tracer| tracer.lock().unwrap().is_traced(pc as u32 * 2));
+                let registers = tracer
+                    .as_ref()
+                    .map(|_| self.memory.lock().unwrap().get_all_registers());
+                let observing = !self.observers.is_empty();
+                let watching = self
+                    .debugger
+                    .as_ref()
+                    .is_some_and(|debugger| debugger.has_watchpoints());
+
+                let accesses = {
+                    let mut memory = self.memory.lock().unwrap();
+                    memory.set_record_accesses(watching || observing);
+                    current_instruction.process(&mut memory);
+                    memory.take_accesses()
+                };
+
+                if let (Some(tracer), Some(registers)) = (tracer, registers) {
+                    self.trace(
+                        &tracer,
+                        pc,
+                        current_instruction_opcode,
+                        current_instruction.as_ref(),
+                        &registers,
+                    );
+                }
+
+                if observing {
+                    self.notify_observers(pc, current_instruction_opcode, &accesses);
+                }
+
+                if let Some(debugger) = &self.debugger {
+                    debugger.executed(&accesses);
+                }
+            }
 
             self.rising_edge_notified
                 .store(false, std::sync::atomic::Ordering::Relaxed);
@@ -75,14 +200,21 @@ impl<'a> clock::Subscriber for InstructionExecutor {
 mod tests {
     use super::*;
     use crate::avr_emulator::clock::Subscriber;
+    use crate::avr_emulator::debugger::{StopReason, WatchKind};
+    use crate::avr_emulator::observer::Observer;
+    use crate::avr_emulator::symbols::SymbolTable;
+    use crate::avr_emulator::test_utils::Output;
+    use crate::avr_emulator::trace::TraceFormat;
     use std::sync::{Arc, Mutex};
 
     #[test]
     fn test_run_without_notify() {
         let empty_registers = Arc::new(Mutex::new(Memory::new(100, vec![0, 0]).unwrap()));
 
-        let mut sut =
-            InstructionExecutor::new(Arc::new(Mutex::new(Memory::new(100, vec![0, 0]).unwrap())));
+        let mut sut = InstructionExecutor::new(
+            Arc::new(Mutex::new(Memory::new(100, vec![0, 0]).unwrap())),
+            None,
+        );
 
         sut.run();
 
@@ -96,8 +228,10 @@ mod tests {
     fn test_run_with_falling_edge_notify() {
         let empty_registers = Arc::new(Mutex::new(Memory::new(100, vec![0, 0]).unwrap()));
 
-        let mut sut =
-            InstructionExecutor::new(Arc::new(Mutex::new(Memory::new(100, vec![0, 0]).unwrap())));
+        let mut sut = InstructionExecutor::new(
+            Arc::new(Mutex::new(Memory::new(100, vec![0, 0]).unwrap())),
+            None,
+        );
 
         sut.notify_falling_edge();
         sut.run();
@@ -113,8 +247,10 @@ mod tests {
         let expected_registers = Arc::new(Mutex::new(Memory::new(100, vec![0, 0]).unwrap()));
         expected_registers.lock().unwrap().set_pc(1);
 
-        let mut sut =
-            InstructionExecutor::new(Arc::new(Mutex::new(Memory::new(100, vec![0, 0]).unwrap())));
+        let mut sut = InstructionExecutor::new(
+            Arc::new(Mutex::new(Memory::new(100, vec![0, 0]).unwrap())),
+            None,
+        );
 
         sut.notify_rising_edge();
         sut.run();
@@ -125,13 +261,156 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_run_halts_at_breakpoint() {
+        let debugger = Arc::new(Debugger::new(false));
+        debugger.add_breakpoint(1);
+
+        let mut sut = InstructionExecutor::new(
+            Arc::new(Mutex::new(
+                Memory::new(100, vec![0, 0, 0, 0, 0, 0]).unwrap(),
+            )),
+            Some(debugger.clone()),
+        );
+
+        for _ in 0..3 {
+            sut.notify_rising_edge();
+            sut.run();
+        }
+        assert_eq!(sut.memory.lock().unwrap().get_pc(), 1);
+        assert!(debugger.is_halted());
+
+        debugger.step();
+        sut.notify_rising_edge();
+        sut.run();
+        assert_eq!(sut.memory.lock().unwrap().get_pc(), 2);
+        assert!(debugger.is_halted());
+    }
+
+    #[test]
+    fn test_run_halts_at_watchpoint() {
+        let debugger = Arc::new(Debugger::new(false));
+        debugger.add_watchpoint(WatchKind::Read, 0x60..=0x60);
+
+        // nop; lds r24, 0x60; nop
+        let mut sut = InstructionExecutor::new(
+            Arc::new(Mutex::new(
+                Memory::new(200, vec![0, 0, 0x80, 0x91, 0x60, 0, 0, 0]).unwrap(),
+            )),
+            Some(debugger.clone()),
+        );
+        sut.memory.lock().unwrap().poke(0x60, 7);
+
+        for _ in 0..3 {
+            sut.notify_rising_edge();
+            sut.run();
+        }
+        assert_eq!(sut.memory.lock().unwrap().get_pc(), 3);
+        assert_eq!(
+            debugger.get_stop_reason(),
+            StopReason::Watchpoint {
+                kind: WatchKind::Read,
+                access: MemoryAccess {
+                    kind: AccessKind::Read,
+                    address: 0x60,
+                    old: 7,
+                    new: 7
+                }
+            }
+        );
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Vec<String>,
+    }
+
+    impl Observer for Recorder {
+        fn on_instruction(&mut self, pc: u32, opcode: u16) {
+            self.events.push(format!("{:#x}: {:#06x}", pc, opcode));
+        }
+
+        fn on_mem_write(&mut self, address: usize, value: u8) {
+            self.events.push(format!("write {:#x} {}", address, value));
+        }
+
+        fn on_reti(&mut self) {
+            self.events.push("reti".to_owned());
+        }
+    }
+
+    #[test]
+    fn test_run_notifies_observers() {
+        // ldi r24, 5; reti
+        let mut sut = InstructionExecutor::new(
+            Arc::new(Mutex::new(
+                Memory::new(200, vec![0x85, 0xe0, 0x18, 0x95]).unwrap(),
+            )),
+            None,
+        );
+        sut.memory.lock().unwrap().set_sp(0x10);
+        let recorder = Arc::new(Mutex::new(Recorder::default()));
+        sut.add_observer(recorder.clone());
+
+        for _ in 0..2 {
+            sut.notify_rising_edge();
+            sut.run();
+        }
+
+        assert_eq!(
+            recorder.lock().unwrap().events,
+            [
+                "0x0: 0xe085",
+                "write 0x18 5",
+                "0x2: 0x9518",
+                "write 0x5e 0",
+                "write 0x5d 18",
+                "write 0x5f 128",
+                "reti"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_run_traces_instructions() {
+        let output = Output::default();
+        let tracer = Tracer::new(
+            Box::new(output.clone()),
+            TraceFormat::Csv,
+            Arc::new(SymbolTable::default()),
+        );
+
+        // ldi r24, 5; nop
+        let mut sut = InstructionExecutor::new(
+            Arc::new(Mutex::new(
+                Memory::new(100, vec![0x85, 0xe0, 0, 0]).unwrap(),
+            )),
+            None,
+        );
+        sut.set_tracer(Arc::new(Mutex::new(tracer)));
+
+        for _ in 0..2 {
+            sut.notify_rising_edge();
+            sut.run();
+        }
+
+        assert_eq!(
+            output.text(),
+            "cycle,pc,symbol,opcode,disassembly,changes,sreg\n\
+             1,0x0000,,85 e0,\"ldi r24, 5\",r24=0x05,--------\n\
+             2,0x0002,,00 00,nop,,--------\n"
+        );
+    }
+
     #[test]
     fn find_instruction_from_opcode() {
         let expected_registers = Arc::new(Mutex::new(Memory::new(100, vec![0, 0]).unwrap()));
         expected_registers.lock().unwrap().set_pc(1);
 
-        let mut sut =
-            InstructionExecutor::new(Arc::new(Mutex::new(Memory::new(100, vec![0, 0]).unwrap())));
+        let mut sut = InstructionExecutor::new(
+            Arc::new(Mutex::new(Memory::new(100, vec![0, 0]).unwrap())),
+            None,
+        );
 
         sut.notify_rising_edge();
         sut.run();
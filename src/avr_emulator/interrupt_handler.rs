@@ -1,28 +1,39 @@
-use strum::IntoEnumIterator;
-use strum_macros::EnumIter;
-
 use std::sync::{Arc, Mutex};
 
 use crate::avr_emulator::clock;
-use crate::avr_emulator::memory::{Memory, SregBit};
+use crate::avr_emulator::device::{Device, InterruptVector, RegisterBit};
+use crate::avr_emulator::fuses::Fuses;
+use crate::avr_emulator::memory::{AccessKind, Memory, MemoryAccess, SregBit};
+use crate::avr_emulator::observer::{self, SharedObserver};
 
 pub struct InterruptHandler {
     rising_edge_notified: std::sync::atomic::AtomicBool,
     memory: Arc<Mutex<Memory>>,
+    device: &'static Device,
+    fuses: Fuses,
+    observers: Vec<SharedObserver>,
 }
 
-// TODO: this is for atmega8. Is it valid for all AVRs? - probably not - Make it generic
-#[derive(EnumIter)]
-enum Interrupt {
+// Vector numbers and priorities are defined per device in device::Device::interrupt_vectors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interrupt {
     Reset,
     Int0,
     Int1,
+    PCInt0,
+    PCInt1,
+    PCInt2,
+    WDT,
     Timer2Comp,
+    Timer2CompA,
+    Timer2CompB,
     Timer2Ovf,
     Timer1Capt,
     Timer1CompA,
     Timer1ComB,
     Timer1Ovf,
+    Timer0CompA,
+    Timer0CompB,
     Timer0Ovf,
     SPISTC,
     USARTRXC,
@@ -58,20 +69,27 @@ impl clock::Subscriber for InterruptHandler {
             .rising_edge_notified
             .load(std::sync::atomic::Ordering::Relaxed)
         {
+            self.handle_flag_register_writes();
+
             if self.are_interrupts_enabled() {
                 let current_interrupt_maybe = self.get_current_interrupt();
 
-                if current_interrupt_maybe.is_some() {
+                if let Some((vector_number, current_interrupt)) = current_interrupt_maybe {
                     self.disable_interrupts();
 
-                    let current_interrupt = current_interrupt_maybe.unwrap();
+                    self.clear_interrupt_flag(current_interrupt); // TODO: is it in the right place?
 
-                    let interrupt_routine_address =
-                        self.get_interrupt_routine_address(&current_interrupt);
+                    log::info!("executing {:?} interrupt", current_interrupt.interrupt);
 
-                    self.clear_interrupt_flag(&current_interrupt); // TODO: is it in the right place?
+                    let accesses =
+                        self.execute_interrupt_routine(self.get_vector_address(vector_number));
 
-                    self.execute_interrupt_routine(interrupt_routine_address);
+                    observer::notify(&self.observers, |observer| {
+                        for access in accesses.iter().filter(|a| a.kind == AccessKind::Write) {
+                            observer.on_mem_write(access.address, access.new);
+                        }
+                        observer.on_interrupt(vector_number)
+                    });
                 }
             }
             self.rising_edge_notified
@@ -81,10 +99,38 @@ impl clock::Subscriber for InterruptHandler {
 }
 
 impl InterruptHandler {
-    pub fn new(memory: Arc<Mutex<Memory>>) -> Self {
+    pub fn new(memory: Arc<Mutex<Memory>>, device: &'static Device, fuses: Fuses) -> Self {
         Self {
             rising_edge_notified: std::sync::atomic::AtomicBool::new(false),
-            memory: memory,
+            memory,
+            device,
+            fuses,
+            observers: vec![],
+        }
+    }
+
+    pub fn add_observer(&mut self, observer: SharedObserver) {
+        self.observers.push(observer);
+    }
+
+    // Timer flags are cleared here rather than by a timer, which a PRR bit or a prescaler reset
+    // can stop, otherwise writing one to clear a flag would set it
+    fn handle_flag_register_writes(&self) {
+        let mut memory = self.memory.lock().unwrap();
+
+        for address in self.device.timer_flag_registers {
+            memory.handle_flag_register_write(*address);
+        }
+    }
+
+    // IVSEL moves the vector table to the start of the boot loader section
+    fn get_vector_address(&self, vector_number: usize) -> u16 {
+        let vector_select = self.device.boot_loader.vector_select;
+
+        if self.memory.lock().unwrap().get_bit(&vector_select) {
+            (self.fuses.get_boot_loader_start(self.device) / 2 + vector_number) as u16
+        } else {
+            vector_number as u16
         }
     }
 
@@ -95,16 +141,9 @@ impl InterruptHandler {
             .clear_status_register_bit(SregBit::I);
     }
 
-    fn clear_interrupt_flag(&mut self, interrupt: &Interrupt) {
-        match interrupt {
-            Interrupt::Timer0Ovf => {
-                let register_value = self.memory.lock().unwrap().get_io(56).unwrap();
-                self.memory
-                    .lock()
-                    .unwrap()
-                    .set_io(56, register_value & (!1u8));
-            }
-            _ => (),
+    fn clear_interrupt_flag(&mut self, interrupt: &InterruptVector) {
+        if let Some(flag) = interrupt.flag.filter(|_| interrupt.clear_flag_on_entry) {
+            self.memory.lock().unwrap().set_bit(&flag, false);
         }
     }
 
@@ -115,55 +154,47 @@ impl InterruptHandler {
             .get_status_register_bit(SregBit::I)
     }
 
-    fn get_current_interrupt(&self) -> Option<Interrupt> {
-        for interrupt in Interrupt::iter() {
-            if self.is_enabled(&interrupt) && self.occurred(&interrupt) {
-                return Some(interrupt);
-            }
-        }
-        None
+    fn get_current_interrupt(&self) -> Option<(usize, &'static InterruptVector)> {
+        self.device
+            .interrupt_vectors
+            .iter()
+            .enumerate()
+            .find(|(_, interrupt)| self.is_enabled(interrupt) && self.occurred(interrupt))
     }
 
-    fn get_interrupt_routine_address(&self, interrupt: &Interrupt) -> u16 {
-        match interrupt {
-            Interrupt::Timer0Ovf => 0x0009,
-            _ => 0,
-        }
+    fn is_bit_set(&self, register_bit: Option<RegisterBit>) -> bool {
+        register_bit.is_some_and(|register_bit| self.memory.lock().unwrap().get_bit(&register_bit))
     }
 
-    fn is_enabled(&self, interrupt: &Interrupt) -> bool {
-        match interrupt {
-            Interrupt::Timer0Ovf => (self.memory.lock().unwrap().get_io(57).unwrap() & 1) == 1,
-            _ => false,
-        }
+    fn is_enabled(&self, interrupt: &InterruptVector) -> bool {
+        self.is_bit_set(interrupt.enable)
     }
 
-    fn occurred(&self, interrupt: &Interrupt) -> bool {
-        match interrupt {
-            Interrupt::Timer0Ovf => (self.memory.lock().unwrap().get_io(56).unwrap() & 1) == 1,
-            _ => false,
-        }
+    fn occurred(&self, interrupt: &InterruptVector) -> bool {
+        interrupt.flag.is_some() && self.is_bit_set(interrupt.flag) != interrupt.flag_active_low
     }
 
-    fn execute_interrupt_routine(&mut self, routine_address: u16) {
+    // Returns the pushes of the return address for the observers
+    fn execute_interrupt_routine(&mut self, routine_address: u16) -> Vec<MemoryAccess> {
         log::error!("executing {} interrupt", routine_address);
 
-        let sp = self.memory.lock().unwrap().get_sp();
-        let pc = self.memory.lock().unwrap().get_pc();
+        let mut memory = self.memory.lock().unwrap();
+        memory.set_record_accesses(!self.observers.is_empty());
 
-        self.memory
-            .lock()
-            .unwrap()
-            .set_stack(sp as usize, (pc & (0xff00 >> 8)) as u8);
+        let sp = memory.get_sp();
+        let pc = memory.get_pc();
 
-        self.memory
-            .lock()
-            .unwrap()
-            .set_stack(sp as usize, (pc & (0x00ff)) as u8);
+        // the return address is pushed low byte first like rcall does, reti pops it
+        memory.set_stack(sp as usize, (pc & 0x00ff) as u8);
+        memory.set_stack(sp.wrapping_sub(1) as usize, (pc >> 8) as u8);
+        memory.set_sp(sp.wrapping_sub(2));
 
-        self.memory.lock().unwrap().set_sp(sp - 2);
+        memory.set_pc(routine_address);
+        memory.enter_interrupt();
 
-        self.memory.lock().unwrap().set_pc(routine_address);
+        let accesses = memory.take_accesses();
+        memory.set_record_accesses(false);
+        accesses
     }
 }
 
@@ -172,6 +203,8 @@ mod tests {
     use clock::Subscriber;
 
     use super::*;
+    use crate::avr_emulator::device;
+    use crate::avr_emulator::instruction_executor::InstructionExecutor;
 
     use std::sync::{Arc, Mutex};
 
@@ -180,16 +213,21 @@ mod tests {
         let memory = Arc::new(Mutex::new(Memory::new(200, vec![]).unwrap()));
         memory.lock().unwrap().set_status_register_bit(SregBit::I);
         memory.lock().unwrap().set_io(57, 1);
-        memory.lock().unwrap().set_io(56, 1);
+        memory.lock().unwrap().poke(0x58, 1);
         memory.lock().unwrap().set_sp(50);
         memory.lock().unwrap().set_pc(30);
 
-        let mut sut = InterruptHandler::new(memory.clone());
+        let mut sut = InterruptHandler::new(
+            memory.clone(),
+            &device::ATMEGA8,
+            device::ATMEGA8.fuses.defaults,
+        );
         sut.notify_rising_edge();
         sut.run();
 
         assert!(!memory.lock().unwrap().get_status_register_bit(SregBit::I));
         assert!(!memory.lock().unwrap().get_sp() != 50);
+        assert_eq!(memory.lock().unwrap().get_interrupt_depth(), 1);
     }
 
     #[test]
@@ -197,7 +235,11 @@ mod tests {
         let memory = Arc::new(Mutex::new(Memory::new(200, vec![]).unwrap()));
         memory.lock().unwrap().set_status_register_bit(SregBit::I);
 
-        let mut sut = InterruptHandler::new(memory.clone());
+        let mut sut = InterruptHandler::new(
+            memory.clone(),
+            &device::ATMEGA8,
+            device::ATMEGA8.fuses.defaults,
+        );
         sut.notify_rising_edge();
         sut.run();
 
@@ -209,14 +251,197 @@ mod tests {
         let memory = Arc::new(Mutex::new(Memory::new(200, vec![]).unwrap()));
         memory.lock().unwrap().clear_status_register_bit(SregBit::I);
         memory.lock().unwrap().set_io(57, 1);
-        memory.lock().unwrap().set_io(56, 1);
+        memory.lock().unwrap().poke(0x58, 1);
         memory.lock().unwrap().set_sp(50);
         memory.lock().unwrap().set_pc(40);
 
-        let mut sut = InterruptHandler::new(memory.clone());
+        let mut sut = InterruptHandler::new(
+            memory.clone(),
+            &device::ATMEGA8,
+            device::ATMEGA8.fuses.defaults,
+        );
         sut.notify_rising_edge();
         sut.run();
 
         assert_eq!(memory.lock().unwrap().get_sp(), 50);
     }
+
+    #[test]
+    fn test_interrupt_routine_address_depends_on_device() {
+        let memory = Arc::new(Mutex::new(Memory::new(200, vec![]).unwrap()));
+        memory.lock().unwrap().set_status_register_bit(SregBit::I);
+        memory.lock().unwrap().set_sram(0x6e, 1);
+        memory.lock().unwrap().poke(0x35, 1);
+        memory.lock().unwrap().set_sp(50);
+        memory.lock().unwrap().set_pc(30);
+
+        let mut sut = InterruptHandler::new(
+            memory.clone(),
+            &device::ATMEGA88,
+            device::ATMEGA88.fuses.defaults,
+        );
+        sut.notify_rising_edge();
+        sut.run();
+
+        assert_eq!(memory.lock().unwrap().get_pc(), 16);
+        assert_eq!(memory.lock().unwrap().get_sram(0x35).unwrap(), 0);
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        vectors: Vec<usize>,
+        writes: Vec<usize>,
+    }
+
+    impl observer::Observer for Recorder {
+        fn on_mem_write(&mut self, address: usize, _value: u8) {
+            self.writes.push(address);
+        }
+
+        fn on_interrupt(&mut self, vector: usize) {
+            self.vectors.push(vector);
+        }
+    }
+
+    #[test]
+    fn test_observers_are_notified() {
+        let memory = Arc::new(Mutex::new(Memory::new(200, vec![]).unwrap()));
+        memory.lock().unwrap().set_status_register_bit(SregBit::I);
+        memory.lock().unwrap().set_sram(0x6e, 1);
+        memory.lock().unwrap().poke(0x35, 1);
+        memory.lock().unwrap().set_sp(50);
+
+        let mut sut = InterruptHandler::new(
+            memory.clone(),
+            &device::ATMEGA88,
+            device::ATMEGA88.fuses.defaults,
+        );
+        let recorder = Arc::new(Mutex::new(Recorder::default()));
+        sut.add_observer(recorder.clone());
+
+        sut.notify_rising_edge();
+        sut.run();
+        sut.notify_rising_edge();
+        sut.run();
+
+        let recorder = recorder.lock().unwrap();
+        assert_eq!(recorder.vectors, [16]);
+        // the return address and SP
+        assert!(recorder.writes.contains(&(Memory::STACK_START + 50)));
+        assert!(recorder.writes.contains(&0x5d));
+    }
+
+    #[test]
+    fn test_reti_reads_the_pushed_return_address() {
+        // reti at vector 16 of the atmega88
+        let mut flash = vec![0; 34];
+        flash[32..].copy_from_slice(&[0x18, 0x95]);
+        let memory = Arc::new(Mutex::new(Memory::new(0x300, flash).unwrap()));
+        memory.lock().unwrap().set_status_register_bit(SregBit::I);
+        memory.lock().unwrap().set_sram(0x6e, 1);
+        memory.lock().unwrap().poke(0x35, 1);
+        memory.lock().unwrap().set_sp(0x200);
+        memory.lock().unwrap().set_pc(0x123);
+
+        let mut sut = InterruptHandler::new(
+            memory.clone(),
+            &device::ATMEGA88,
+            device::ATMEGA88.fuses.defaults,
+        );
+        let mut executor = InstructionExecutor::new(memory.clone(), None);
+
+        sut.notify_rising_edge();
+        sut.run();
+        // high byte below the low byte like rcall pushes it
+        assert_eq!(memory.lock().unwrap().get_pc(), 16);
+        assert_eq!(memory.lock().unwrap().get_sp(), 0x1fe);
+        assert_eq!(memory.lock().unwrap().get_stack(0x200), Ok(0x23));
+        assert_eq!(memory.lock().unwrap().get_stack(0x1ff), Ok(0x01));
+
+        executor.notify_rising_edge();
+        executor.run();
+
+        assert_eq!(memory.lock().unwrap().get_pc(), 0x123);
+        assert_eq!(memory.lock().unwrap().get_sp(), 0x200);
+    }
+
+    #[test]
+    fn test_timer_flags_are_cleared_by_writing_one() {
+        for (device, address) in [(&device::ATMEGA8, 0x58), (&device::ATMEGA88, 0x35)] {
+            let memory = Arc::new(Mutex::new(Memory::new(0x200, vec![]).unwrap()));
+            memory.lock().unwrap().poke(address, 0b0000_0011);
+            memory.lock().unwrap().set_sram(address, 0b0000_0001);
+
+            let mut sut = InterruptHandler::new(memory.clone(), device, device.fuses.defaults);
+            sut.notify_rising_edge();
+            sut.run();
+
+            assert_eq!(memory.lock().unwrap().peek(address), 0b0000_0010);
+        }
+    }
+
+    #[test]
+    fn test_timer_flags_are_cleared_with_timer0_powered_down() {
+        let memory = Arc::new(Mutex::new(Memory::new(0x200, vec![]).unwrap()));
+        memory.lock().unwrap().set_status_register_bit(SregBit::I);
+        // PRTIM0 set, ICIE1 enabled
+        memory.lock().unwrap().poke(0x64, 0b0010_0000);
+        memory.lock().unwrap().poke(0x6f, 0b0010_0000);
+        memory.lock().unwrap().set_sp(50);
+        memory.lock().unwrap().set_pc(30);
+
+        let mut sut = InterruptHandler::new(
+            memory.clone(),
+            &device::ATMEGA88,
+            device::ATMEGA88.fuses.defaults,
+        );
+        // writing one to a cleared ICF1 leaves it cleared
+        memory.lock().unwrap().set_sram(0x36, 0b0010_0000);
+        sut.notify_rising_edge();
+        sut.run();
+
+        assert_eq!(memory.lock().unwrap().peek(0x36), 0);
+        assert_eq!(memory.lock().unwrap().get_pc(), 30);
+    }
+
+    #[test]
+    fn test_status_flag_is_not_cleared_on_entry() {
+        let memory = Arc::new(Mutex::new(Memory::new(200, vec![]).unwrap()));
+        memory.lock().unwrap().set_status_register_bit(SregBit::I);
+        memory.lock().unwrap().set_sram(0x2a, 0b1000_0000);
+        memory.lock().unwrap().set_sram(0x2b, 0b1000_0000);
+        memory.lock().unwrap().set_sp(50);
+        memory.lock().unwrap().set_pc(30);
+
+        let mut sut = InterruptHandler::new(
+            memory.clone(),
+            &device::ATMEGA8,
+            device::ATMEGA8.fuses.defaults,
+        );
+        sut.notify_rising_edge();
+        sut.run();
+
+        assert_eq!(memory.lock().unwrap().get_pc(), 11);
+        assert_eq!(memory.lock().unwrap().get_sram(0x2b).unwrap(), 0b1000_0000);
+    }
+
+    #[test]
+    fn test_vector_table_in_boot_loader_section() {
+        let memory = Arc::new(Mutex::new(Memory::new(200, vec![]).unwrap()));
+        memory.lock().unwrap().set_status_register_bit(SregBit::I);
+        memory.lock().unwrap().set_sram(0x59, 1);
+        memory.lock().unwrap().poke(0x58, 1);
+        memory.lock().unwrap().set_sram(0x5b, 0b0000_0010);
+        memory.lock().unwrap().set_sp(50);
+
+        // BOOTSZ 01, the boot loader section starts at word 0xe00
+        let mut fuses = device::ATMEGA8.fuses.defaults;
+        fuses.high = 0xdb;
+
+        let mut sut = InterruptHandler::new(memory.clone(), &device::ATMEGA8, fuses);
+        sut.notify_rising_edge();
+        sut.run();
+
+        assert_eq!(memory.lock().unwrap().get_pc(), 0xe09);
+    }
 }
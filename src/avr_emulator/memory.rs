@@ -1,8 +1,154 @@
-#[derive(Debug, PartialEq, Clone)]
+use std::cell::{Cell, RefCell};
+
+use crate::avr_emulator::device::RegisterBit;
+use crate::avr_emulator::reset::ResetKind;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IoWrite {
+    pub old: u8,
+    pub new: u8,
+}
+
+// Store program memory operation of the SPM instruction at pc with Z and R1:R0,
+// performed by the boot loader
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpmRequest {
+    pub pc: u16,
+    pub address: u16,
+    pub data: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+// A data space access of the cpu, old and new are the same for reads
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryAccess {
+    pub kind: AccessKind,
+    pub address: usize,
+    pub old: u8,
+    pub new: u8,
+}
+
+// Something a peripheral did, like a transmitted byte or a completed conversion
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeripheralEvent {
+    pub peripheral: &'static str,
+    pub event: String,
+}
+
+// Contents of the registers and sram after a power-on reset. Real devices start with
+// whatever the cells settle to, a pattern or random data finds reads of memory never written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SramFill {
+    Zero,
+    Pattern(u8),
+    // state of the xorshift generator, seeded by the user or the current time
+    Random(u64),
+}
+
+impl SramFill {
+    fn next(&mut self) -> u8 {
+        match self {
+            SramFill::Zero => 0,
+            SramFill::Pattern(value) => *value,
+            SramFill::Random(state) => {
+                *state ^= *state << 13;
+                *state ^= *state >> 7;
+                *state ^= *state << 17;
+                (*state >> 32) as u8
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for SramFill {
+    type Err = String;
+
+    // zero, random, random:<seed> or a byte value
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let seed = match value.to_ascii_lowercase().as_str() {
+            "zero" => return Ok(SramFill::Zero),
+            "random" => std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(1, |time| time.as_nanos() as u64),
+            value => match value.strip_prefix("random:") {
+                Some(seed) => seed
+                    .parse()
+                    .map_err(|_| format!("invalid seed: {}", seed))?,
+                None => {
+                    return crate::avr_emulator::fuses::parse_byte(value)
+                        .map(SramFill::Pattern)
+                        .map_err(|_| format!("invalid sram fill: {}", value))
+                }
+            },
+        };
+
+        // xorshift never leaves a zero state
+        Ok(SramFill::Random(seed.max(1)))
+    }
+}
+
+// Io accesses done by the cpu since they were last taken by the peripheral owning the register.
+// Peripherals themselves use peek/poke which are not logged.
+#[derive(Clone)]
+struct IoAccessLog {
+    writes: Vec<Option<IoWrite>>,
+    reads: Vec<Cell<bool>>,
+}
+
+impl IoAccessLog {
+    fn new() -> Self {
+        Self {
+            writes: vec![None; Memory::IO_END - Memory::IO_START],
+            reads: vec![Cell::new(false); Memory::IO_END - Memory::IO_START],
+        }
+    }
+
+    fn index(address: usize) -> Option<usize> {
+        if (Memory::IO_START..Memory::IO_END).contains(&address) {
+            Some(address - Memory::IO_START)
+        } else {
+            None
+        }
+    }
+}
+
+impl std::fmt::Debug for IoAccessLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IoAccessLog").finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Memory {
     sram: Vec<u8>,
     pc: u16,
     flash: Vec<u8>,
+    io_access_log: IoAccessLog,
+    watchdog_kicked: bool,
+    reset_request: Option<ResetKind>,
+    spm_request: Option<SpmRequest>,
+    clock_division: u16,
+    interrupt_depth: u8,
+    // cpu accesses are only recorded while the debugger needs them
+    record_accesses: bool,
+    accesses: RefCell<Vec<MemoryAccess>>,
+    // peripheral events are only queued while observers take them
+    record_events: bool,
+    events: Vec<PeripheralEvent>,
+    sram_fill: SramFill,
+    // SP and PC of the first stack access outside the data space
+    stack_fault: Cell<Option<(usize, u16)>>,
+}
+
+impl PartialEq for Memory {
+    fn eq(&self, other: &Self) -> bool {
+        self.sram == other.sram && self.pc == other.pc && self.flash == other.flash
+    }
 }
 
 pub enum SregBit {
@@ -25,6 +171,9 @@ impl Memory {
 
     pub const STACK_START: usize = Self::IO_START + Self::IO_SIZE;
 
+    // end of the extended io space of the newer devices, it overlaps with the stack on older ones
+    pub const IO_END: usize = 0x100;
+
     pub fn new(size: usize, flash: Vec<u8>) -> Result<Self, String> {
         if size < Self::STACK_START {
             return Err("Size to small".to_owned());
@@ -33,6 +182,18 @@ impl Memory {
             sram: vec![0; size],
             pc: 0,
             flash: flash,
+            io_access_log: IoAccessLog::new(),
+            watchdog_kicked: false,
+            reset_request: None,
+            spm_request: None,
+            clock_division: 1,
+            interrupt_depth: 0,
+            record_accesses: false,
+            accesses: RefCell::new(vec![]),
+            record_events: false,
+            events: vec![],
+            sram_fill: SramFill::Zero,
+            stack_fault: Cell::new(None),
         })
     }
 
@@ -82,18 +243,42 @@ impl Memory {
         self.get_sram(io + Self::IO_START)
     }
 
+    // A stack pointer run past either end of the data space is a fault of the firmware, the
+    // access is dropped and the emulator is stopped once the fault is taken
+    fn is_stack_address(&self, address: usize) -> bool {
+        if address < self.sram.len() - Self::STACK_START {
+            return true;
+        }
+
+        if self.stack_fault.get().is_none() {
+            self.stack_fault.set(Some((address, self.pc)));
+        }
+        false
+    }
+
     pub fn set_stack(&mut self, address: usize, value: u8) {
-        if address >= self.sram.len() - Self::STACK_START {
-            panic!("Trying to access stack memory out of bounds");
+        if !self.is_stack_address(address) {
+            return;
         }
+        self.record_access(AccessKind::Write, address + Self::STACK_START, value);
         self.sram[address + Self::STACK_START] = value;
     }
 
     pub fn get_stack(&self, address: usize) -> Result<u8, String> {
-        if address >= self.sram.len() - Self::STACK_START {
+        if !self.is_stack_address(address) {
             return Err("Trying to access stack memory out of bounds".to_owned());
         }
-        Ok(self.sram[address + Self::STACK_START])
+        let value = self.sram[address + Self::STACK_START];
+        self.record_access(AccessKind::Read, address + Self::STACK_START, value);
+        Ok(value)
+    }
+
+    pub fn get_sram_size(&self) -> usize {
+        self.sram.len()
+    }
+
+    pub fn get_flash_size(&self) -> usize {
+        self.flash.len()
     }
 
     pub fn get_flash(&self, address: usize) -> u8 {
@@ -103,10 +288,26 @@ impl Memory {
         self.flash[address]
     }
 
+    // Flash beyond the loaded program reads as erased once it gets programmed
+    pub fn set_flash(&mut self, address: usize, value: u8) {
+        if address >= self.flash.len() {
+            self.flash.resize(address + 1, 0xff);
+        }
+        self.flash[address] = value;
+    }
+
     pub fn set_sram(&mut self, address: usize, value: u8) {
         if address >= self.sram.len() {
             panic!("Trying to access sram memory out of bounds: {}", address);
         }
+        if let Some(index) = IoAccessLog::index(address) {
+            let old = match self.io_access_log.writes[index] {
+                Some(write) => write.old,
+                None => self.sram[address],
+            };
+            self.io_access_log.writes[index] = Some(IoWrite { old, new: value });
+        }
+        self.record_access(AccessKind::Write, address, value);
         self.sram[address] = value;
     }
 
@@ -114,9 +315,171 @@ impl Memory {
         if address >= self.sram.len() {
             return Err("Trying to access sram memory out of bounds".to_owned());
         }
+        if let Some(index) = IoAccessLog::index(address) {
+            self.io_access_log.reads[index].set(true);
+        }
+        self.record_access(AccessKind::Read, address, self.sram[address]);
         Ok(self.sram[address])
     }
 
+    fn record_access(&self, kind: AccessKind, address: usize, new: u8) {
+        if self.record_accesses {
+            self.accesses.borrow_mut().push(MemoryAccess {
+                kind,
+                address,
+                old: self.sram[address],
+                new,
+            });
+        }
+    }
+
+    // Records the data space accesses of the instructions until they are taken
+    pub fn set_record_accesses(&mut self, record_accesses: bool) {
+        self.record_accesses = record_accesses;
+        self.accesses.get_mut().clear();
+    }
+
+    pub fn take_accesses(&mut self) -> Vec<MemoryAccess> {
+        std::mem::take(self.accesses.get_mut())
+    }
+
+    pub fn set_record_events(&mut self, record_events: bool) {
+        self.record_events = record_events;
+        self.events.clear();
+    }
+
+    pub fn raise_event(&mut self, peripheral: &'static str, event: std::fmt::Arguments) {
+        if self.record_events {
+            self.events.push(PeripheralEvent {
+                peripheral,
+                event: event.to_string(),
+            });
+        }
+    }
+
+    pub fn take_events(&mut self) -> Vec<PeripheralEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    pub fn peek(&self, address: usize) -> u8 {
+        if address >= self.sram.len() {
+            panic!("Trying to access sram memory out of bounds: {}", address);
+        }
+        self.sram[address]
+    }
+
+    pub fn poke(&mut self, address: usize, value: u8) {
+        if address >= self.sram.len() {
+            panic!("Trying to access sram memory out of bounds: {}", address);
+        }
+        self.sram[address] = value;
+    }
+
+    pub fn take_io_write(&mut self, address: usize) -> Option<IoWrite> {
+        IoAccessLog::index(address).and_then(|index| self.io_access_log.writes[index].take())
+    }
+
+    pub fn take_io_read(&mut self, address: usize) -> bool {
+        IoAccessLog::index(address).is_some_and(|index| self.io_access_log.reads[index].take())
+    }
+
+    // Set by the WDR instruction, taken by the watchdog timer
+    pub fn kick_watchdog(&mut self) {
+        self.watchdog_kicked = true;
+    }
+
+    pub fn take_watchdog_kick(&mut self) -> bool {
+        std::mem::take(&mut self.watchdog_kicked)
+    }
+
+    pub fn request_spm(&mut self, request: SpmRequest) {
+        self.spm_request = Some(request);
+    }
+
+    pub fn take_spm_request(&mut self) -> Option<SpmRequest> {
+        self.spm_request.take()
+    }
+
+    // System clock division selected through CLKPR, applied to the clock by the emulator.
+    // Peripherals timed in seconds count this many clock source cycles per system clock cycle.
+    pub fn set_clock_division(&mut self, division: u16) {
+        self.clock_division = division;
+    }
+
+    pub fn get_clock_division(&self) -> u16 {
+        self.clock_division
+    }
+
+    // Nesting depth of interrupt routines, entered by the interrupt handler and left by RETI
+    pub fn enter_interrupt(&mut self) {
+        self.interrupt_depth = self.interrupt_depth.saturating_add(1);
+    }
+
+    pub fn leave_interrupt(&mut self) {
+        self.interrupt_depth = self.interrupt_depth.saturating_sub(1);
+    }
+
+    pub fn get_interrupt_depth(&self) -> u8 {
+        self.interrupt_depth
+    }
+
+    // Resets are performed between clock cycles by the emulator
+    pub fn request_reset(&mut self, kind: ResetKind) {
+        self.reset_request = Some(kind);
+    }
+
+    pub fn take_reset_request(&mut self) -> Option<ResetKind> {
+        self.reset_request.take()
+    }
+
+    pub fn take_stack_fault(&mut self) -> Option<(usize, u16)> {
+        self.stack_fault.take()
+    }
+
+    pub fn is_reset_requested(&self) -> bool {
+        self.reset_request.is_some()
+    }
+
+    // Restarts execution at address 0 with the io registers up to io_end cleared,
+    // the general purpose registers and sram keep their contents
+    pub fn reset(&mut self, io_end: usize) {
+        self.pc = 0;
+        self.sram[Self::IO_START..io_end].fill(0);
+        self.io_access_log = IoAccessLog::new();
+        self.interrupt_depth = 0;
+    }
+
+    pub fn set_sram_fill(&mut self, sram_fill: SramFill) {
+        self.sram_fill = sram_fill;
+    }
+
+    // Fills the general purpose registers, io registers and sram as set by set_sram_fill,
+    // the io registers get their reset values afterwards
+    pub fn clear(&mut self) {
+        let mut sram_fill = self.sram_fill;
+        self.sram.fill_with(|| sram_fill.next());
+        self.sram_fill = sram_fill;
+    }
+
+    // Interrupt flags are cleared by writing a logical one to them
+    pub fn handle_flag_register_write(&mut self, address: usize) {
+        if let Some(write) = self.take_io_write(address) {
+            self.poke(address, write.old & !write.new);
+        }
+    }
+
+    pub fn get_bit(&self, register_bit: &RegisterBit) -> bool {
+        self.peek(register_bit.address) & register_bit.mask() != 0
+    }
+    pub fn set_bit(&mut self, register_bit: &RegisterBit, value: bool) {
+        let register_value = self.peek(register_bit.address);
+        if value {
+            self.poke(register_bit.address, register_value | register_bit.mask());
+        } else {
+            self.poke(register_bit.address, register_value & !register_bit.mask());
+        }
+    }
+
     pub fn get_as_16bit(&self, address: usize) -> Result<u16, String> {
         let msb = self.get_sram(address + 1);
         let lsb = self.get_sram(address);
@@ -423,18 +786,23 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
     fn test_set_stack_out_of_bounds() {
         let mut memory = Memory::new(100, vec![]).unwrap();
+        memory.set_pc(0x20);
 
         memory.set_stack(101, 0);
+        memory.set_stack(0xffff, 0);
+
+        assert_eq!(memory.take_stack_fault(), Some((101, 0x20)));
+        assert_eq!(memory.take_stack_fault(), None);
     }
 
     #[test]
     fn test_get_stack_out_of_bounds() {
-        let memory = Memory::new(100, vec![]).unwrap();
+        let mut memory = Memory::new(100, vec![]).unwrap();
 
         assert!(memory.get_stack(101).is_err());
+        assert_eq!(memory.take_stack_fault(), Some((101, 0)));
     }
 
     #[test]
@@ -452,6 +820,151 @@ mod tests {
         assert_eq!(memory.get_flash(1), 2);
     }
 
+    #[test]
+    fn test_get_bit() {
+        let mut memory = Memory::new(100, vec![]).unwrap();
+        memory.set_sram(0x58, 0b0000_0100);
+
+        assert!(memory.get_bit(&RegisterBit::new(0x58, 2)));
+        assert!(!memory.get_bit(&RegisterBit::new(0x58, 3)));
+    }
+
+    #[test]
+    fn test_set_bit() {
+        let mut memory = Memory::new(100, vec![]).unwrap();
+        memory.set_sram(0x58, 0b1000_0001);
+
+        memory.set_bit(&RegisterBit::new(0x58, 2), true);
+        assert_eq!(memory.get_sram(0x58).unwrap(), 0b1000_0101);
+
+        memory.set_bit(&RegisterBit::new(0x58, 7), false);
+        assert_eq!(memory.get_sram(0x58).unwrap(), 0b0000_0101);
+    }
+
+    #[test]
+    fn test_take_io_write() {
+        let mut memory = Memory::new(300, vec![]).unwrap();
+        memory.set_sram(0x38, 0x0f);
+        memory.set_sram(0x38, 0xf0);
+
+        assert_eq!(
+            memory.take_io_write(0x38),
+            Some(IoWrite {
+                old: 0x00,
+                new: 0xf0
+            })
+        );
+        assert_eq!(memory.take_io_write(0x38), None);
+    }
+
+    #[test]
+    fn test_take_io_write_ignores_registers_stack_and_poke() {
+        let mut memory = Memory::new(300, vec![]).unwrap();
+        memory.set_register(5, 1);
+        memory.set_sram(0x100, 1);
+        memory.poke(0x38, 1);
+
+        assert_eq!(memory.take_io_write(5), None);
+        assert_eq!(memory.take_io_write(0x100), None);
+        assert_eq!(memory.take_io_write(0x38), None);
+        assert_eq!(memory.peek(0x38), 1);
+    }
+
+    #[test]
+    fn test_take_io_read() {
+        let mut memory = Memory::new(300, vec![]).unwrap();
+        memory.peek(0x2c);
+        assert!(!memory.take_io_read(0x2c));
+
+        memory.get_io(0x0c).unwrap();
+        assert!(memory.take_io_read(0x2c));
+        assert!(!memory.take_io_read(0x2c));
+    }
+
+    #[test]
+    fn test_record_accesses() {
+        let mut memory = Memory::new(300, vec![]).unwrap();
+        memory.set_sram(0x60, 1);
+        assert!(memory.take_accesses().is_empty());
+
+        memory.set_record_accesses(true);
+        memory.set_sram(0x60, 2);
+        memory.get_register(3).unwrap();
+        memory.set_stack(1, 4);
+        memory.poke(0x62, 5);
+
+        let access = |kind, address, old, new| MemoryAccess {
+            kind,
+            address,
+            old,
+            new,
+        };
+        assert_eq!(
+            memory.take_accesses(),
+            [
+                access(AccessKind::Write, 0x60, 1, 2),
+                access(AccessKind::Read, 3, 0, 0),
+                access(AccessKind::Write, Memory::STACK_START + 1, 0, 4),
+            ]
+        );
+        assert!(memory.take_accesses().is_empty());
+    }
+
+    #[test]
+    fn test_record_events() {
+        let mut memory = Memory::new(100, vec![]).unwrap();
+        memory.raise_event("Timer", format_args!("overflow"));
+        assert!(memory.take_events().is_empty());
+
+        memory.set_record_events(true);
+        memory.raise_event("Usart", format_args!("transmitted {:#04x}", 0x41));
+        assert_eq!(
+            memory.take_events(),
+            [PeripheralEvent {
+                peripheral: "Usart",
+                event: "transmitted 0x41".to_owned()
+            }]
+        );
+        assert!(memory.take_events().is_empty());
+    }
+
+    #[test]
+    fn test_set_flash_beyond_program() {
+        let mut memory = Memory::new(100, vec![0x12, 0x34]).unwrap();
+
+        memory.set_flash(1, 0x56);
+        memory.set_flash(4, 0x78);
+
+        assert_eq!(memory.get_flash(1), 0x56);
+        assert_eq!(memory.get_flash(3), 0xff);
+        assert_eq!(memory.get_flash(4), 0x78);
+    }
+
+    #[test]
+    fn test_handle_flag_register_write() {
+        let mut memory = Memory::new(100, vec![]).unwrap();
+        memory.poke(0x58, 0b0000_0011);
+        memory.set_sram(0x58, 0b0000_0001);
+
+        memory.handle_flag_register_write(0x58);
+        assert_eq!(memory.peek(0x58), 0b0000_0010);
+
+        memory.handle_flag_register_write(0x58);
+        assert_eq!(memory.peek(0x58), 0b0000_0010);
+    }
+
+    #[test]
+    fn test_eq_ignores_io_access_log() {
+        let mut memory = Memory::new(100, vec![]).unwrap();
+        memory.set_io(5, 1);
+        memory.get_io(6).unwrap();
+
+        let mut other = Memory::new(100, vec![]).unwrap();
+        other.poke(Memory::IO_START + 5, 1);
+
+        assert_eq!(memory, other);
+    }
+
     #[test]
     fn test_get_sp() {
         let lsb: u8 = 0xa5;
@@ -732,4 +1245,62 @@ mod tests {
         assert_eq!(Memory::to_bit_position(SregBit::Z), 1);
         assert_eq!(Memory::to_bit_position(SregBit::C), 0);
     }
+
+    #[test]
+    fn test_reset() {
+        let mut memory = Memory::new(300, vec![]).unwrap();
+        memory.set_register(5, 0x12);
+        memory.set_pc(0x100);
+        memory.set_sram(0x58, 0xff);
+        memory.set_sram(0xff, 0xff);
+        memory.set_sram(0x100, 0x34);
+
+        memory.reset(0x100);
+
+        assert_eq!(memory.get_pc(), 0);
+        assert_eq!(memory.peek(0x58), 0);
+        assert_eq!(memory.peek(0xff), 0);
+        assert_eq!(memory.take_io_write(0x58), None);
+        assert_eq!(memory.get_register(5), Ok(0x12));
+        assert_eq!(memory.peek(0x100), 0x34);
+    }
+
+    #[test]
+    fn test_clear_with_sram_fill() {
+        let mut memory = Memory::new(0x100, vec![]).unwrap();
+        memory.set_sram(0x70, 0x12);
+        memory.clear();
+        assert!(memory.get_all_stack().iter().all(|value| *value == 0));
+
+        memory.set_sram_fill("0xa5".parse().unwrap());
+        memory.clear();
+        assert!(memory.get_all_stack().iter().all(|value| *value == 0xa5));
+
+        memory.set_sram_fill("random:42".parse().unwrap());
+        memory.clear();
+        let first = memory.get_all_stack();
+        assert!(first.iter().any(|value| *value != first[0]));
+
+        // the generator continues, the next power-on starts with other contents
+        memory.clear();
+        assert_ne!(memory.get_all_stack(), first);
+
+        assert!("random".parse::<SramFill>().is_ok());
+        assert!("random:x".parse::<SramFill>().is_err());
+        assert!("0x100".parse::<SramFill>().is_err());
+    }
+
+    #[test]
+    fn test_requests_are_taken_once() {
+        let mut memory = Memory::new(100, vec![]).unwrap();
+        memory.kick_watchdog();
+        memory.request_reset(ResetKind::External);
+
+        assert!(memory.take_watchdog_kick());
+        assert!(!memory.take_watchdog_kick());
+        assert!(memory.is_reset_requested());
+        assert_eq!(memory.take_reset_request(), Some(ResetKind::External));
+        assert_eq!(memory.take_reset_request(), None);
+        assert!(!memory.is_reset_requested());
+    }
 }
@@ -0,0 +1,792 @@
+use std::io::{BufRead, Write};
+use std::ops::RangeInclusive;
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::avr_emulator::clock::Cycles;
+use crate::avr_emulator::debugger::{Condition, Debugger, StopReason, WatchKind, Watchpoint};
+use crate::avr_emulator::device::Device;
+use crate::avr_emulator::disassembler;
+use crate::avr_emulator::memory::{AccessKind, Memory};
+use crate::avr_emulator::number::parse_number;
+use crate::avr_emulator::reset::ResetKind;
+use crate::avr_emulator::symbols::{SymbolTable, DATA_OFFSET};
+use crate::avr_emulator::trace;
+
+const SREG_ADDRESS: usize = 0x5f;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+const HELP: &str = "\
+step [n]                  execute n instructions (s)
+continue                  run until a breakpoint, a watchpoint or enter (c)
+break [addr|symbol] [if r24==5 ...]
+                          halt before the instruction at the flash address, list without one (b)
+delete <addr|symbol>      remove a breakpoint
+watch [addr|symbol|a-b]   halt after the data space was written, list without one (w)
+rwatch <addr|symbol|a-b>  halt after the data space was read
+awatch <addr|symbol|a-b>  halt after the data space was read or written
+unwatch <addr|symbol|a-b> remove the watchpoints
+regs                      registers, SREG, SP and PC
+io                        io registers
+x/<n>b <addr|symbol>      n bytes of the data space
+set <rN|sp|pc|sreg|addr|symbol> <value>
+cycles                    clock cycles since start
+reset                     external reset
+quit                      stop the emulator (q)";
+
+#[derive(Debug, PartialEq)]
+enum Action {
+    Print(String),
+    Step(usize),
+    Continue,
+    Reset,
+    Quit,
+}
+
+fn format_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Interactive console on stdin and stdout to run, halt and inspect the core
+pub struct Monitor {
+    memory: Arc<Mutex<Memory>>,
+    debugger: Arc<Debugger>,
+    symbols: Arc<SymbolTable>,
+    device: &'static Device,
+    frequency: i64,
+    cycles: Arc<Cycles>,
+    stop_program: Arc<AtomicBool>,
+}
+
+impl Monitor {
+    pub fn new(
+        memory: Arc<Mutex<Memory>>,
+        debugger: Arc<Debugger>,
+        symbols: Arc<SymbolTable>,
+        device: &'static Device,
+        frequency: i64,
+        cycles: Arc<Cycles>,
+        stop_program: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
+            memory,
+            debugger,
+            symbols,
+            device,
+            frequency,
+            cycles,
+            stop_program,
+        }
+    }
+
+    pub fn start(self) {
+        let (sender, receiver) = std::sync::mpsc::channel();
+
+        // lines are read separately, so that enter can halt the running core
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lock().lines() {
+                let Ok(line) = line else {
+                    return;
+                };
+                if sender.send(line).is_err() {
+                    return;
+                }
+            }
+        });
+
+        std::thread::spawn(move || {
+            println!("monitor: core halted, type help for the commands");
+            println!("{}", self.get_location());
+            self.serve(receiver);
+            self.stop_program
+                .store(true, std::sync::atomic::Ordering::Relaxed);
+        });
+    }
+
+    fn serve(&self, lines: Receiver<String>) {
+        loop {
+            print!("(avr) ");
+            let _ = std::io::stdout().flush();
+
+            // end of input quits like the quit command
+            let Ok(line) = lines.recv() else {
+                println!();
+                return;
+            };
+
+            match self.execute(&line) {
+                Ok(Action::Print(text)) if text.is_empty() => {}
+                Ok(Action::Print(text)) => println!("{}", text),
+                Ok(Action::Step(count)) => match self.step(count) {
+                    Some(stop) => println!("{}", stop),
+                    None => return,
+                },
+                Ok(Action::Continue) => match self.resume(&lines) {
+                    Some(stop) => println!("{}", stop),
+                    None => return,
+                },
+                Ok(Action::Reset) => match self.reset() {
+                    Some(location) => println!("{}", location),
+                    None => return,
+                },
+                Ok(Action::Quit) => return,
+                Err(error) => println!("error: {}", error),
+            }
+        }
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.stop_program.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    // Returns None when the emulator stopped meanwhile
+    fn wait_until_halted(&self) -> Option<()> {
+        while !self.debugger.wait_until_halted(POLL_INTERVAL) {
+            if self.is_stopped() {
+                return None;
+            }
+        }
+        Some(())
+    }
+
+    // Stops early at a breakpoint or watchpoint
+    fn step(&self, count: usize) -> Option<String> {
+        for _ in 0..count {
+            self.debugger.step();
+            self.wait_until_halted()?;
+
+            if self.debugger.get_stop_reason() != StopReason::Step {
+                break;
+            }
+        }
+        Some(self.get_stop())
+    }
+
+    fn resume(&self, lines: &Receiver<String>) -> Option<String> {
+        self.debugger.resume();
+
+        while !self.debugger.wait_until_halted(POLL_INTERVAL) {
+            if self.is_stopped() {
+                return None;
+            }
+            match lines.recv_timeout(Duration::ZERO) {
+                Ok(_) => self.debugger.halt(),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+        Some(self.get_stop())
+    }
+
+    // The clock thread performs the reset, also while the core is halted
+    fn reset(&self) -> Option<String> {
+        self.memory
+            .lock()
+            .unwrap()
+            .request_reset(ResetKind::External);
+
+        while self.memory.lock().unwrap().is_reset_requested() {
+            if self.is_stopped() {
+                return None;
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+        Some(format!("external reset\n{}", self.get_location()))
+    }
+
+    fn get_stop(&self) -> String {
+        let reason = match self.debugger.get_stop_reason() {
+            StopReason::Halted => "halted".to_owned(),
+            StopReason::Step => String::new(),
+            StopReason::Breakpoint(_) => "breakpoint".to_owned(),
+            StopReason::Watchpoint { access, .. } if access.kind == AccessKind::Read => format!(
+                "watchpoint, read {}: {:#04x}",
+                self.format_data_address(access.address),
+                access.old
+            ),
+            StopReason::Watchpoint { access, .. } => format!(
+                "watchpoint, write {}: {:#04x} -> {:#04x}",
+                self.format_data_address(access.address),
+                access.old,
+                access.new
+            ),
+        };
+
+        if reason.is_empty() {
+            self.get_location()
+        } else {
+            format!("{}\n{}", reason, self.get_location())
+        }
+    }
+
+    // The current instruction with its symbolized address
+    fn get_location(&self) -> String {
+        let memory = self.memory.lock().unwrap();
+        let address = memory.get_pc() as usize * 2;
+
+        if address + 1 >= memory.get_flash_size() {
+            return format!("{:#06x}  outside of flash", address);
+        }
+
+        let bytes: Vec<u8> = (address..(address + 4).min(memory.get_flash_size()))
+            .map(|address| memory.get_flash(address))
+            .collect();
+        let instruction = disassembler::disassemble_at(&bytes, 0);
+
+        let text = instruction
+            .text
+            .unwrap_or_else(|| format!("{} ; unsupported", format_bytes(&instruction.bytes)));
+
+        match self.symbols.symbolize(address as u32) {
+            Some(symbol) => format!("{:#06x} <{}>:  {}", address, symbol, text),
+            None => format!("{:#06x}:  {}", address, text),
+        }
+    }
+
+    fn format_data_address(&self, address: usize) -> String {
+        match self.symbols.symbolize(address as u32 + DATA_OFFSET) {
+            Some(symbol) => format!("{:#06x} <{}>", address, symbol),
+            None => format!("{:#06x}", address),
+        }
+    }
+
+    fn format_watchpoint(&self, watchpoint: &Watchpoint) -> String {
+        let kind = match watchpoint.kind {
+            WatchKind::Read => "read",
+            WatchKind::Write => "write",
+            WatchKind::Access => "access",
+        };
+        let (start, end) = (*watchpoint.range.start(), *watchpoint.range.end());
+
+        if start == end {
+            format!("{} {}", kind, self.format_data_address(start))
+        } else {
+            format!("{} {}-{:#06x}", kind, self.format_data_address(start), end)
+        }
+    }
+
+    // Flash byte address of a number or function
+    fn parse_flash_address(&self, value: &str) -> Result<u32, String> {
+        let address = match parse_number(value) {
+            Some(address) => address,
+            None => match self.symbols.find(value) {
+                Some(symbol) if symbol.address < DATA_OFFSET => symbol.address,
+                Some(_) => return Err(format!("{} is not in flash", value)),
+                None => return Err(format!("unknown address or symbol: {}", value)),
+            },
+        };
+
+        if address % 2 != 0 || address as usize >= self.memory.lock().unwrap().get_flash_size() {
+            return Err(format!("invalid flash address: {:#06x}", address));
+        }
+        Ok(address)
+    }
+
+    // Data space address of a number or variable, avr-gdb's 0x800000 offset is accepted
+    fn parse_data_address(&self, value: &str) -> Result<usize, String> {
+        let address = match parse_number(value) {
+            Some(address) if address >= DATA_OFFSET => address - DATA_OFFSET,
+            Some(address) => address,
+            None => match self.symbols.find(value) {
+                Some(symbol) if symbol.address >= DATA_OFFSET => symbol.address - DATA_OFFSET,
+                Some(_) => return Err(format!("{} is not in the data space", value)),
+                None => return Err(format!("unknown address or symbol: {}", value)),
+            },
+        } as usize;
+
+        if address >= self.memory.lock().unwrap().get_sram_size() {
+            return Err(format!("invalid data space address: {:#06x}", address));
+        }
+        Ok(address)
+    }
+
+    // An address, start-end or all bytes of a variable
+    fn parse_data_range(&self, value: &str) -> Result<RangeInclusive<usize>, String> {
+        if let Some((start, end)) = value.split_once('-') {
+            let (start, end) = (
+                self.parse_data_address(start)?,
+                self.parse_data_address(end)?,
+            );
+            if start > end {
+                return Err(format!("invalid range: {}", value));
+            }
+            return Ok(start..=end);
+        }
+
+        let start = self.parse_data_address(value)?;
+        let size = match self.symbols.find(value) {
+            Some(symbol) if symbol.size > 1 => symbol.size as usize,
+            _ => 1,
+        };
+        let end = (start + size).min(self.memory.lock().unwrap().get_sram_size()) - 1;
+
+        Ok(start..=end)
+    }
+
+    fn execute(&self, line: &str) -> Result<Action, String> {
+        let mut arguments = line.split_whitespace();
+        let Some(command) = arguments.next() else {
+            return Ok(Action::Print(String::new()));
+        };
+        let arguments: Vec<&str> = arguments.collect();
+
+        let argument = |index: usize| {
+            arguments.get(index).copied().ok_or(format!(
+                "missing argument, usage: {}",
+                self.get_usage(command)
+            ))
+        };
+
+        match command {
+            "help" | "h" | "?" => Ok(Action::Print(HELP.to_owned())),
+            "step" | "s" => match arguments.first() {
+                Some(count) => match parse_number::<usize>(count) {
+                    Some(count) if count > 0 => Ok(Action::Step(count)),
+                    _ => Err(format!("invalid count: {}", count)),
+                },
+                None => Ok(Action::Step(1)),
+            },
+            "continue" | "c" => Ok(Action::Continue),
+            "break" | "b" if arguments.is_empty() => Ok(Action::Print(
+                self.debugger
+                    .get_breakpoints()
+                    .iter()
+                    .map(|(pc, conditions)| {
+                        let location = self.symbols.format_address(*pc as u32 * 2);
+                        match &conditions[..] {
+                            [] => location,
+                            conditions => format!(
+                                "{} if {}",
+                                location,
+                                conditions
+                                    .iter()
+                                    .map(Condition::to_string)
+                                    .collect::<Vec<_>>()
+                                    .join(" ")
+                            ),
+                        }
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            )),
+            "break" | "b" => {
+                let address = self.parse_flash_address(argument(0)?)?;
+                let conditions = match arguments.get(1) {
+                    Some(&"if") if arguments.len() > 2 => arguments[2..]
+                        .iter()
+                        .map(|condition| Condition::parse(condition))
+                        .collect::<Result<Vec<_>, _>>()?,
+                    Some(_) => return Err(format!("usage: {}", self.get_usage(command))),
+                    None => vec![],
+                };
+
+                self.debugger
+                    .add_conditional_breakpoint((address / 2) as u16, conditions);
+                Ok(Action::Print(format!(
+                    "breakpoint at {}",
+                    self.symbols.format_address(address)
+                )))
+            }
+            "delete" | "d" => {
+                let address = self.parse_flash_address(argument(0)?)?;
+                self.debugger.remove_breakpoint((address / 2) as u16);
+                Ok(Action::Print(String::new()))
+            }
+            "watch" | "w" if arguments.is_empty() => Ok(Action::Print(
+                self.debugger
+                    .get_watchpoints()
+                    .iter()
+                    .map(|watchpoint| self.format_watchpoint(watchpoint))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            )),
+            "watch" | "w" | "rwatch" | "awatch" => {
+                let watchpoint = Watchpoint {
+                    kind: match command {
+                        "rwatch" => WatchKind::Read,
+                        "awatch" => WatchKind::Access,
+                        _ => WatchKind::Write,
+                    },
+                    range: self.parse_data_range(argument(0)?)?,
+                };
+
+                self.debugger
+                    .add_watchpoint(watchpoint.kind, watchpoint.range.clone());
+                Ok(Action::Print(format!(
+                    "watchpoint, {}",
+                    self.format_watchpoint(&watchpoint)
+                )))
+            }
+            "unwatch" => {
+                let range = self.parse_data_range(argument(0)?)?;
+                for kind in [WatchKind::Read, WatchKind::Write, WatchKind::Access] {
+                    self.debugger.remove_watchpoint(kind, range.clone());
+                }
+                Ok(Action::Print(String::new()))
+            }
+            "regs" | "r" => Ok(Action::Print(self.get_registers())),
+            "io" => Ok(Action::Print(
+                self.dump(Memory::IO_START, self.device.sram_start - Memory::IO_START),
+            )),
+            "set" => {
+                self.set(argument(0)?, argument(1)?)?;
+                Ok(Action::Print(String::new()))
+            }
+            "cycles" => Ok(Action::Print(format!(
+                "{} cycles, {:.6} s",
+                self.cycles.get_system(),
+                self.cycles.get_source() as f64 / self.frequency as f64
+            ))),
+            "reset" => Ok(Action::Reset),
+            "quit" | "q" => Ok(Action::Quit),
+            _ => match command.strip_prefix('x') {
+                Some(format) => {
+                    let count = match format.strip_prefix('/') {
+                        Some(format) => format
+                            .strip_suffix('b')
+                            .unwrap_or(format)
+                            .parse()
+                            .map_err(|_| format!("invalid format: {}", command))?,
+                        None if format.is_empty() => 16,
+                        None => return Err(format!("unknown command: {}", command)),
+                    };
+
+                    let address = self.parse_data_address(argument(0)?)?;
+                    let count = count.min(self.memory.lock().unwrap().get_sram_size() - address);
+                    Ok(Action::Print(self.dump(address, count)))
+                }
+                None => Err(format!("unknown command: {}, try help", command)),
+            },
+        }
+    }
+
+    fn get_usage(&self, command: &str) -> &'static str {
+        HELP.lines()
+            .find(|line| line.split(['/', ' ']).next() == command.split('/').next())
+            .unwrap_or("see help")
+    }
+
+    fn get_registers(&self) -> String {
+        let memory = self.memory.lock().unwrap();
+        let registers = memory.get_all_registers();
+
+        let mut lines: Vec<String> = registers
+            .chunks(8)
+            .enumerate()
+            .map(|(row, registers)| {
+                format!(
+                    "r{:<2}-r{:<2}  {}",
+                    row * 8,
+                    row * 8 + 7,
+                    format_bytes(registers)
+                )
+            })
+            .collect();
+
+        let sreg = memory.peek(SREG_ADDRESS);
+        let pc = memory.get_pc() as u32 * 2;
+        lines.push(format!(
+            "SREG     {:#04x} {}",
+            sreg,
+            trace::format_sreg(sreg)
+        ));
+        lines.push(format!("SP       {:#06x}", memory.get_sp()));
+        lines.push(format!(
+            "PC       {:#06x} {}",
+            pc,
+            self.symbols.symbolize(pc).unwrap_or_default()
+        ));
+
+        lines.join("\n").trim_end().to_owned()
+    }
+
+    // Rows of 16 bytes of the data space
+    fn dump(&self, start: usize, count: usize) -> String {
+        let memory = self.memory.lock().unwrap();
+
+        (start..start + count)
+            .step_by(16)
+            .map(|row| {
+                let bytes: Vec<u8> = (row..(row + 16).min(start + count))
+                    .map(|address| memory.peek(address))
+                    .collect();
+                format!("{:#06x}  {}", row, format_bytes(&bytes))
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn set(&self, target: &str, value: &str) -> Result<(), String> {
+        let value = parse_number::<u32>(value).ok_or(format!("invalid value: {}", value))?;
+        let byte = || u8::try_from(value).map_err(|_| format!("{:#x} exceeds a byte", value));
+
+        let register = target
+            .strip_prefix('r')
+            .and_then(|register| register.parse::<usize>().ok())
+            .filter(|register| *register < Memory::REGISTERS_SIZE);
+
+        match (target, register) {
+            (_, Some(register)) => self.memory.lock().unwrap().poke(register, byte()?),
+            ("sreg", _) => self.memory.lock().unwrap().poke(SREG_ADDRESS, byte()?),
+            ("sp", _) => {
+                let sp = u16::try_from(value).map_err(|_| format!("invalid sp: {:#x}", value))?;
+                self.memory.lock().unwrap().set_sp(sp);
+            }
+            ("pc", _) => {
+                let address = self.parse_flash_address(&value.to_string())?;
+                self.memory.lock().unwrap().set_pc((address / 2) as u16);
+            }
+            _ => {
+                let address = self.parse_data_address(target)?;
+                self.memory.lock().unwrap().poke(address, byte()?);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avr_emulator::device::find_device;
+    use crate::avr_emulator::memory::MemoryAccess;
+    use crate::avr_emulator::symbols::{Symbol, SymbolKind};
+
+    fn set_up() -> Monitor {
+        // rjmp 0; ldi r24, 5
+        let memory = Memory::new(0x460, vec![0xff, 0xcf, 0x85, 0xe0]).unwrap();
+        // the system clock divided by 8
+        let cycles = Cycles::default();
+        (0..800).for_each(|_| cycles.add(8));
+        let symbols = SymbolTable::new(vec![
+            Symbol {
+                name: "main".to_owned(),
+                address: 0,
+                size: 4,
+                kind: SymbolKind::Function,
+            },
+            Symbol {
+                name: "counter".to_owned(),
+                address: DATA_OFFSET + 0x60,
+                size: 1,
+                kind: SymbolKind::Object,
+            },
+            Symbol {
+                name: "buffer".to_owned(),
+                address: DATA_OFFSET + 0x70,
+                size: 8,
+                kind: SymbolKind::Object,
+            },
+        ]);
+
+        Monitor::new(
+            Arc::new(Mutex::new(memory)),
+            Arc::new(Debugger::new(true)),
+            Arc::new(symbols),
+            find_device("atmega8").unwrap(),
+            8_000_000,
+            Arc::new(cycles),
+            Arc::new(AtomicBool::new(false)),
+        )
+    }
+
+    fn print(text: &str) -> Result<Action, String> {
+        Ok(Action::Print(text.to_owned()))
+    }
+
+    #[test]
+    fn test_run_control() {
+        let sut = set_up();
+
+        assert_eq!(sut.execute("step"), Ok(Action::Step(1)));
+        assert_eq!(sut.execute("s 0x10"), Ok(Action::Step(16)));
+        assert!(sut.execute("step 0").is_err());
+        assert_eq!(sut.execute("c"), Ok(Action::Continue));
+        assert_eq!(sut.execute("quit"), Ok(Action::Quit));
+        assert_eq!(sut.execute("  "), print(""));
+        assert!(sut.execute("jump 0").is_err());
+    }
+
+    #[test]
+    fn test_breakpoints_and_watchpoints() {
+        let sut = set_up();
+
+        assert_eq!(sut.execute("break 2"), print("breakpoint at main+0x2"));
+        assert_eq!(sut.execute("b main"), print("breakpoint at main"));
+        assert!(sut.execute("b 3").is_err());
+        assert!(sut.execute("b counter").is_err());
+        assert!(sut.execute("b").is_ok());
+        assert_eq!(sut.debugger.get_breakpoints().len(), 2);
+        assert_eq!(sut.execute("delete main"), print(""));
+        assert_eq!(
+            sut.execute("b 2 if r24==5 r25<0x10"),
+            print("breakpoint at main+0x2")
+        );
+        assert!(sut.execute("b 2 r24==5").is_err());
+        assert!(sut.execute("b 2 if r24=5").is_err());
+        assert_eq!(sut.execute("break"), print("main+0x2 if r24==5 r25<16"));
+
+        assert_eq!(
+            sut.execute("watch counter"),
+            print("watchpoint, write 0x0060 <counter>")
+        );
+        assert_eq!(
+            sut.execute("rwatch 0x800061-0x64"),
+            print("watchpoint, read 0x0061-0x0064")
+        );
+        assert_eq!(
+            sut.execute("awatch buffer"),
+            print("watchpoint, access 0x0070 <buffer>-0x0077")
+        );
+        assert!(sut.execute("watch 0x460").is_err());
+        assert!(sut.execute("watch main").is_err());
+        assert!(sut.execute("watch 0x64-0x61").is_err());
+        assert_eq!(sut.execute("unwatch 0x61-0x64"), print(""));
+        assert_eq!(
+            sut.execute("w"),
+            print("write 0x0060 <counter>\naccess 0x0070 <buffer>-0x0077")
+        );
+    }
+
+    #[test]
+    fn test_stop() {
+        let sut = set_up();
+        let access = MemoryAccess {
+            kind: AccessKind::Write,
+            address: 0x60,
+            old: 1,
+            new: 2,
+        };
+        sut.debugger.add_watchpoint(WatchKind::Access, 0x60..=0x60);
+        sut.debugger.resume();
+
+        sut.debugger.executed(&[access]);
+        assert_eq!(
+            sut.get_stop(),
+            "watchpoint, write 0x0060 <counter>: 0x01 -> 0x02\n0x0000 <main>:  rjmp -1"
+        );
+
+        sut.debugger.resume();
+        sut.debugger.executed(&[MemoryAccess {
+            kind: AccessKind::Read,
+            ..access
+        }]);
+        assert_eq!(
+            sut.get_stop(),
+            "watchpoint, read 0x0060 <counter>: 0x01\n0x0000 <main>:  rjmp -1"
+        );
+    }
+
+    #[test]
+    fn test_inspect() {
+        let sut = set_up();
+        {
+            let mut memory = sut.memory.lock().unwrap();
+            memory.poke(24, 0x12);
+            memory.poke(0x61, 0xab);
+            memory.set_sp(0x045f);
+            memory.poke(SREG_ADDRESS, 0x82);
+            memory.set_pc(1);
+        }
+
+        assert_eq!(
+            sut.execute("regs"),
+            print(
+                "r0 -r7   00 00 00 00 00 00 00 00\n\
+                 r8 -r15  00 00 00 00 00 00 00 00\n\
+                 r16-r23  00 00 00 00 00 00 00 00\n\
+                 r24-r31  12 00 00 00 00 00 00 00\n\
+                 SREG     0x82 I-----Z-\n\
+                 SP       0x045f\n\
+                 PC       0x0002 main+0x2"
+            )
+        );
+
+        assert_eq!(sut.execute("x/2b counter"), print("0x0060  00 ab"));
+        assert_eq!(
+            sut.execute("x/20 0x50"),
+            print(
+                "0x0050  00 00 00 00 00 00 00 00 00 00 00 00 00 5f 04 82\n\
+                 0x0060  00 ab 00 00"
+            )
+        );
+        assert!(sut.execute("x/zb 0x60").is_err());
+        assert_eq!(sut.execute("x 0x45c"), print("0x045c  00 00 00 00"));
+
+        let Ok(Action::Print(io)) = sut.execute("io") else {
+            panic!("expected io registers");
+        };
+        assert_eq!(io.lines().count(), 4);
+        assert!(io.starts_with("0x0020  "));
+
+        assert_eq!(sut.execute("cycles"), print("800 cycles, 0.000800 s"));
+        assert_eq!(sut.get_location(), "0x0002 <main+0x2>:  ldi r24, 5");
+    }
+
+    #[test]
+    fn test_set() {
+        let sut = set_up();
+
+        assert_eq!(sut.execute("set r24 5"), print(""));
+        assert_eq!(sut.execute("set sp 0x400"), print(""));
+        assert_eq!(sut.execute("set pc 2"), print(""));
+        assert_eq!(sut.execute("set sreg 0x80"), print(""));
+        assert_eq!(sut.execute("set counter 7"), print(""));
+        assert!(sut.execute("set r24 256").is_err());
+        assert!(sut.execute("set pc 3").is_err());
+        assert!(sut.execute("set r24").is_err());
+
+        let memory = sut.memory.lock().unwrap();
+        assert_eq!(memory.peek(24), 5);
+        assert_eq!(memory.get_sp(), 0x400);
+        assert_eq!(memory.get_pc(), 1);
+        assert_eq!(memory.get_status_register(), 0x80);
+        assert_eq!(memory.peek(0x60), 7);
+    }
+
+    #[test]
+    fn test_step() {
+        let sut = set_up();
+        let debugger = sut.debugger.clone();
+        let memory = sut.memory.clone();
+
+        // stands in for the instruction executor
+        let core = std::thread::spawn(move || {
+            while !debugger.wait_until_running(Duration::from_secs(1)) {}
+            let mut memory = memory.lock().unwrap();
+            assert!(debugger.should_execute(&memory));
+            memory.set_pc(1);
+            debugger.executed(&[]);
+        });
+
+        assert_eq!(
+            sut.step(1),
+            Some("0x0002 <main+0x2>:  ldi r24, 5".to_owned())
+        );
+        core.join().unwrap();
+
+        // stands in for the clock
+        let memory = sut.memory.clone();
+        let clock = std::thread::spawn(move || loop {
+            let mut memory = memory.lock().unwrap();
+            if memory.take_reset_request() == Some(ResetKind::External) {
+                memory.set_pc(0);
+                return;
+            }
+        });
+
+        assert_eq!(sut.execute("reset"), Ok(Action::Reset));
+        assert_eq!(
+            sut.reset(),
+            Some("external reset\n0x0000 <main>:  rjmp -1".to_owned())
+        );
+        clock.join().unwrap();
+    }
+}
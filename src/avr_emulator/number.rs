@@ -0,0 +1,29 @@
+// Numbers in options and commands are given as hex with 0x, binary with 0b or decimal, values
+// not fitting T are rejected
+pub fn parse_number<T: TryFrom<u64>>(value: &str) -> Option<T> {
+    let parsed = if let Some(hex) = value.strip_prefix("0x").or(value.strip_prefix("0X")) {
+        u64::from_str_radix(hex, 16)
+    } else if let Some(binary) = value.strip_prefix("0b").or(value.strip_prefix("0B")) {
+        u64::from_str_radix(binary, 2)
+    } else {
+        value.parse()
+    };
+
+    parsed.ok().and_then(|number| T::try_from(number).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number::<u16>("0x1ff"), Some(0x1ff));
+        assert_eq!(parse_number::<u8>("0XD9"), Some(0xd9));
+        assert_eq!(parse_number::<u8>("0b11111001"), Some(0xf9));
+        assert_eq!(parse_number::<u32>("256"), Some(256));
+        assert_eq!(parse_number::<u8>("0x100"), None);
+        assert_eq!(parse_number::<u32>("0x"), None);
+        assert_eq!(parse_number::<usize>("high"), None);
+    }
+}
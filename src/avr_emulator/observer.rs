@@ -0,0 +1,32 @@
+use std::sync::{Arc, Mutex};
+
+use crate::avr_emulator::reset::ResetKind;
+
+// Instrumentation notified of what the emulated chip does. All callbacks default to doing
+// nothing, so an observer only implements the ones it is interested in.
+pub trait Observer: Send {
+    // Called after the instruction at the byte address pc was executed
+    fn on_instruction(&mut self, _pc: u32, _opcode: u16) {}
+
+    // Data space accesses of the instruction, including registers, io and the stack. The
+    // return address pushed when entering an interrupt is written before on_interrupt.
+    fn on_mem_read(&mut self, _address: usize, _value: u8) {}
+    fn on_mem_write(&mut self, _address: usize, _value: u8) {}
+
+    fn on_interrupt(&mut self, _vector: usize) {}
+    fn on_reti(&mut self) {}
+
+    fn on_reset(&mut self, _kind: ResetKind) {}
+
+    // Raised by the peripherals, e.g. "Usart" with "transmitted 0x41"
+    fn on_peripheral_event(&mut self, _peripheral: &str, _event: &str) {}
+}
+
+// Observers are shared with the threads of the core, the interrupt handler and the clock
+pub type SharedObserver = Arc<Mutex<dyn Observer>>;
+
+pub fn notify(observers: &[SharedObserver], mut callback: impl FnMut(&mut dyn Observer)) {
+    for observer in observers {
+        callback(&mut *observer.lock().unwrap());
+    }
+}
@@ -0,0 +1,277 @@
+use std::sync::{Arc, Mutex};
+
+use crate::avr_emulator::clock;
+use crate::avr_emulator::device::RegisterBit;
+use crate::avr_emulator::memory::Memory;
+use crate::avr_emulator::prescaler;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeripheralUsage {
+    pub name: String,
+    pub cycles: u64,
+    pub enabled_cycles: u64,
+}
+
+// Clock cycles each gated peripheral has seen and how many of them it was enabled for
+#[derive(Debug, Default)]
+pub struct PowerReport {
+    peripherals: Vec<PeripheralUsage>,
+}
+
+impl PowerReport {
+    fn add(&mut self, name: &str) -> usize {
+        self.peripherals.push(PeripheralUsage {
+            name: name.to_owned(),
+            cycles: 0,
+            enabled_cycles: 0,
+        });
+        self.peripherals.len() - 1
+    }
+
+    fn count_cycle(&mut self, index: usize, enabled: bool) {
+        let usage = &mut self.peripherals[index];
+        usage.cycles += 1;
+        if enabled {
+            usage.enabled_cycles += 1;
+        }
+    }
+
+    pub fn get_peripherals(&self) -> &[PeripheralUsage] {
+        &self.peripherals
+    }
+}
+
+impl std::fmt::Display for PowerReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for usage in &self.peripherals {
+            let percentage = if usage.cycles == 0 {
+                0.0
+            } else {
+                usage.enabled_cycles as f64 * 100.0 / usage.cycles as f64
+            };
+
+            writeln!(
+                f,
+                "{:<8} enabled {} of {} cycles ({:.1}%)",
+                usage.name, usage.enabled_cycles, usage.cycles, percentage
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Runs a peripheral only while its PRR bit is cleared. A stopped peripheral keeps its state
+// and register writes to it are undone.
+pub struct PowerGate<S: ?Sized> {
+    rising_edge_notified: std::sync::atomic::AtomicBool,
+    memory: Arc<Mutex<Memory>>,
+    bit: RegisterBit,
+    registers: Vec<usize>,
+    report: Arc<Mutex<PowerReport>>,
+    index: usize,
+    subscriber: Box<S>,
+}
+
+impl<S: ?Sized> PowerGate<S> {
+    pub fn new(
+        memory: Arc<Mutex<Memory>>,
+        bit: RegisterBit,
+        registers: Vec<usize>,
+        report: Arc<Mutex<PowerReport>>,
+        name: &str,
+        subscriber: Box<S>,
+    ) -> Self {
+        let index = report.lock().unwrap().add(name);
+
+        Self {
+            rising_edge_notified: std::sync::atomic::AtomicBool::new(false),
+            memory,
+            bit,
+            registers,
+            report,
+            index,
+            subscriber,
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        let memory = self.memory.clone();
+        let mut memory = memory.lock().unwrap();
+
+        let enabled = !memory.get_bit(&self.bit);
+
+        if !enabled {
+            for register in &self.registers {
+                if let Some(write) = memory.take_io_write(*register) {
+                    log::debug!("PowerGate write to {:#04x} ignored", register);
+                    memory.poke(*register, write.old);
+                }
+            }
+        }
+
+        self.report.lock().unwrap().count_cycle(self.index, enabled);
+
+        enabled
+    }
+}
+
+impl clock::Subscriber for PowerGate<dyn clock::Subscriber> {
+    fn notify_rising_edge(&self) {
+        log::debug!("PowerGate rising edge notified");
+
+        if self
+            .rising_edge_notified
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            log::warn!("PowerGate did not finish handling previous rising edge!");
+        }
+
+        self.rising_edge_notified
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+    fn notify_falling_edge(&self) {}
+
+    fn run(&mut self) {
+        if self
+            .rising_edge_notified
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            if self.is_enabled() {
+                self.subscriber.notify_rising_edge();
+                self.subscriber.run();
+            }
+
+            self.rising_edge_notified
+                .store(false, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.subscriber.reset();
+    }
+}
+
+// Timers are gated on the prescaler ticks
+impl prescaler::Subscriber for PowerGate<dyn prescaler::Subscriber> {
+    fn get_external_clock_pin(&self) -> Option<RegisterBit> {
+        self.subscriber.get_external_clock_pin()
+    }
+
+    fn notify_tick(&mut self, tick: &prescaler::PrescalerTick) {
+        if self.is_enabled() {
+            self.subscriber.notify_tick(tick);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avr_emulator::test_utils::clock_cycles;
+
+    struct MockSubscriber {
+        rising_edge_notified: std::sync::atomic::AtomicBool,
+        memory: Arc<Mutex<Memory>>,
+        cycles: Arc<Mutex<u32>>,
+    }
+
+    impl clock::Subscriber for MockSubscriber {
+        fn notify_rising_edge(&self) {
+            self.rising_edge_notified
+                .store(true, std::sync::atomic::Ordering::Relaxed);
+        }
+        fn notify_falling_edge(&self) {}
+
+        fn run(&mut self) {
+            if self
+                .rising_edge_notified
+                .swap(false, std::sync::atomic::Ordering::Relaxed)
+            {
+                *self.cycles.lock().unwrap() += 1;
+
+                let mut memory = self.memory.lock().unwrap();
+                if let Some(write) = memory.take_io_write(0x4c) {
+                    memory.poke(0x4c, write.new | 0x01);
+                }
+            }
+        }
+    }
+
+    fn set_up() -> (Arc<Mutex<u32>>, PowerGate<dyn clock::Subscriber>) {
+        let memory = Arc::new(Mutex::new(Memory::new(300, vec![]).unwrap()));
+        let cycles = Arc::new(Mutex::new(0));
+        let report = Arc::new(Mutex::new(PowerReport::default()));
+
+        let sut = PowerGate::new(
+            memory.clone(),
+            RegisterBit::new(0x64, 2),
+            vec![0x4c, 0x4d, 0x4e],
+            report,
+            "SPI",
+            Box::new(MockSubscriber {
+                rising_edge_notified: std::sync::atomic::AtomicBool::new(false),
+                memory: memory.clone(),
+                cycles: cycles.clone(),
+            }) as Box<dyn clock::Subscriber>,
+        );
+
+        (cycles, sut)
+    }
+
+    #[test]
+    fn test_gated_peripheral_is_not_clocked() {
+        let (cycles, mut sut) = set_up();
+        let memory = sut.memory.clone();
+
+        clock_cycles(&mut sut, 3);
+        assert_eq!(*cycles.lock().unwrap(), 3);
+
+        memory.lock().unwrap().set_sram(0x64, 0b0000_0100);
+        clock_cycles(&mut sut, 5);
+        assert_eq!(*cycles.lock().unwrap(), 3);
+
+        memory.lock().unwrap().set_sram(0x64, 0);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(*cycles.lock().unwrap(), 4);
+    }
+
+    #[test]
+    fn test_register_writes_are_ignored_while_gated() {
+        let (_, mut sut) = set_up();
+        let memory = sut.memory.clone();
+        memory.lock().unwrap().poke(0x4c, 0x50);
+        memory.lock().unwrap().set_sram(0x64, 0b0000_0100);
+
+        memory.lock().unwrap().set_sram(0x4c, 0x40);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x4c), 0x50);
+
+        memory.lock().unwrap().set_sram(0x64, 0);
+        memory.lock().unwrap().set_sram(0x4c, 0x40);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x4c), 0x41);
+    }
+
+    #[test]
+    fn test_report() {
+        let (_, mut sut) = set_up();
+        let memory = sut.memory.clone();
+
+        clock_cycles(&mut sut, 1);
+        memory.lock().unwrap().set_sram(0x64, 0b0000_0100);
+        clock_cycles(&mut sut, 3);
+
+        assert_eq!(
+            sut.report.lock().unwrap().get_peripherals(),
+            &[PeripheralUsage {
+                name: "SPI".to_owned(),
+                cycles: 4,
+                enabled_cycles: 1,
+            }]
+        );
+        assert_eq!(
+            sut.report.lock().unwrap().to_string(),
+            "SPI      enabled 1 of 4 cycles (25.0%)\n"
+        );
+    }
+}
@@ -0,0 +1,315 @@
+use std::sync::{Arc, Mutex};
+
+use crate::avr_emulator::clock;
+use crate::avr_emulator::device::{PrescalerRegisters, RegisterBit};
+use crate::avr_emulator::memory::Memory;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum PinEdge {
+    #[default]
+    None,
+    Rising,
+    Falling,
+}
+
+// Single clock cycle as seen by a timer: the prescaler counter value and the edges detected
+// on the external clock pins of the subscribed timers
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PrescalerTick {
+    pub counter: u16,
+    pub external_pin_edge: PinEdge,
+}
+
+impl PrescalerTick {
+    pub fn is_tap(&self, divisor: u16) -> bool {
+        divisor != 0 && self.counter.is_multiple_of(divisor)
+    }
+}
+
+pub trait Subscriber: Send + Sync {
+    fn get_external_clock_pin(&self) -> Option<RegisterBit>;
+    fn notify_tick(&mut self, tick: &PrescalerTick);
+}
+
+struct PrescalerSubscriber {
+    subscriber: Arc<Mutex<Box<dyn Subscriber>>>,
+    last_pin_level: bool,
+}
+
+pub struct Prescaler {
+    rising_edge_notified: std::sync::atomic::AtomicBool,
+    memory: Arc<Mutex<Memory>>,
+    registers: &'static PrescalerRegisters,
+    counter: u16,
+    subscribers: Vec<PrescalerSubscriber>,
+}
+
+impl Prescaler {
+    const COUNTER_MASK: u16 = 0x03ff;
+
+    pub fn new(memory: Arc<Mutex<Memory>>, registers: &'static PrescalerRegisters) -> Self {
+        Self {
+            rising_edge_notified: std::sync::atomic::AtomicBool::new(false),
+            memory,
+            registers,
+            counter: 0,
+            subscribers: vec![],
+        }
+    }
+
+    pub fn subscribe(&mut self, subscriber: Arc<Mutex<Box<dyn Subscriber>>>) {
+        self.subscribers.push(PrescalerSubscriber {
+            subscriber,
+            last_pin_level: false,
+        });
+    }
+
+    // Returns true when the prescaler is in reset for this cycle
+    fn handle_reset(&mut self) -> bool {
+        let mut memory = self.memory.lock().unwrap();
+
+        if !memory.get_bit(&self.registers.reset) {
+            return false;
+        }
+
+        self.counter = 0;
+
+        let synchronization_mode = self
+            .registers
+            .synchronization_mode
+            .is_some_and(|tsm| memory.get_bit(&tsm));
+
+        if !synchronization_mode {
+            memory.set_bit(&self.registers.reset, false);
+        }
+
+        true
+    }
+
+    fn tick(&mut self) {
+        self.counter = (self.counter + 1) & Self::COUNTER_MASK;
+
+        for subscriber in &mut self.subscribers {
+            let mut timer = subscriber.subscriber.lock().unwrap();
+
+            let external_pin_edge = match timer.get_external_clock_pin() {
+                Some(pin) => {
+                    let level = self.memory.lock().unwrap().get_bit(&pin);
+                    let edge = match (subscriber.last_pin_level, level) {
+                        (false, true) => PinEdge::Rising,
+                        (true, false) => PinEdge::Falling,
+                        _ => PinEdge::None,
+                    };
+                    subscriber.last_pin_level = level;
+                    edge
+                }
+                None => PinEdge::None,
+            };
+
+            timer.notify_tick(&PrescalerTick {
+                counter: self.counter,
+                external_pin_edge,
+            });
+        }
+    }
+}
+
+impl clock::Subscriber for Prescaler {
+    fn notify_rising_edge(&self) {
+        log::debug!("Prescaler rising edge notified");
+
+        if self
+            .rising_edge_notified
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            log::warn!("Prescaler did not finish handling previous rising edge!");
+        }
+
+        self.rising_edge_notified
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+    fn notify_falling_edge(&self) {}
+
+    fn run(&mut self) {
+        if self
+            .rising_edge_notified
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            if !self.handle_reset() {
+                self.tick();
+            }
+
+            self.rising_edge_notified
+                .store(false, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.counter = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avr_emulator::clock::Subscriber as ClockSubscriber;
+    use crate::avr_emulator::device;
+
+    struct MockTimer {
+        pin: Option<RegisterBit>,
+        ticks: Arc<Mutex<Vec<PrescalerTick>>>,
+    }
+
+    impl Subscriber for MockTimer {
+        fn get_external_clock_pin(&self) -> Option<RegisterBit> {
+            self.pin
+        }
+        fn notify_tick(&mut self, tick: &PrescalerTick) {
+            self.ticks.lock().unwrap().push(*tick);
+        }
+    }
+
+    type Ticks = Arc<Mutex<Vec<PrescalerTick>>>;
+
+    fn set_up(
+        registers: &'static PrescalerRegisters,
+        pin: Option<RegisterBit>,
+    ) -> (Arc<Mutex<Memory>>, Prescaler, Ticks) {
+        let memory = Arc::new(Mutex::new(Memory::new(200, vec![]).unwrap()));
+        let ticks = Arc::new(Mutex::new(vec![]));
+
+        let mut prescaler = Prescaler::new(memory.clone(), registers);
+        prescaler.subscribe(Arc::new(Mutex::new(Box::new(MockTimer {
+            pin,
+            ticks: ticks.clone(),
+        }))));
+
+        (memory, prescaler, ticks)
+    }
+
+    fn clock_cycle(prescaler: &mut Prescaler) {
+        prescaler.notify_rising_edge();
+        prescaler.run();
+    }
+
+    #[test]
+    fn test_is_tap() {
+        let tick = PrescalerTick {
+            counter: 64,
+            external_pin_edge: PinEdge::None,
+        };
+
+        assert!(tick.is_tap(1));
+        assert!(tick.is_tap(8));
+        assert!(tick.is_tap(64));
+        assert!(!tick.is_tap(256));
+        assert!(!tick.is_tap(0));
+    }
+
+    #[test]
+    fn test_run_without_notify() {
+        let (_, mut sut, ticks) = set_up(&device::ATMEGA8.synchronous_prescaler, None);
+
+        sut.run();
+
+        assert_eq!(sut.counter, 0);
+        assert!(ticks.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_run_falling_edge() {
+        let (_, mut sut, ticks) = set_up(&device::ATMEGA8.synchronous_prescaler, None);
+
+        for _ in 0..10 {
+            sut.notify_falling_edge();
+            sut.run();
+        }
+
+        assert_eq!(sut.counter, 0);
+        assert!(ticks.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_counter_wraps_after_1024_cycles() {
+        let (_, mut sut, ticks) = set_up(&device::ATMEGA8.synchronous_prescaler, None);
+
+        for _ in 0..1025 {
+            clock_cycle(&mut sut);
+        }
+
+        assert_eq!(sut.counter, 1);
+        assert_eq!(ticks.lock().unwrap().len(), 1025);
+        assert_eq!(ticks.lock().unwrap()[1023].counter, 0);
+    }
+
+    #[test]
+    fn test_reset_clears_counter_and_reset_bit() {
+        let (memory, mut sut, ticks) = set_up(&device::ATMEGA8.synchronous_prescaler, None);
+
+        for _ in 0..10 {
+            clock_cycle(&mut sut);
+        }
+
+        memory.lock().unwrap().set_io(0x30, 0b0000_0011);
+        clock_cycle(&mut sut);
+
+        assert_eq!(sut.counter, 0);
+        assert_eq!(memory.lock().unwrap().get_io(0x30).unwrap(), 0b0000_0010);
+        assert_eq!(ticks.lock().unwrap().len(), 10);
+
+        clock_cycle(&mut sut);
+        assert_eq!(sut.counter, 1);
+    }
+
+    #[test]
+    fn test_synchronization_mode_holds_prescaler_in_reset() {
+        let (memory, mut sut, ticks) = set_up(&device::ATMEGA88.synchronous_prescaler, None);
+
+        memory.lock().unwrap().set_sram(0x43, 0b1000_0001);
+
+        for _ in 0..10 {
+            clock_cycle(&mut sut);
+        }
+
+        assert_eq!(sut.counter, 0);
+        assert!(ticks.lock().unwrap().is_empty());
+        assert_eq!(memory.lock().unwrap().get_sram(0x43).unwrap(), 0b1000_0001);
+
+        memory.lock().unwrap().set_sram(0x43, 0b0000_0001);
+        clock_cycle(&mut sut);
+        clock_cycle(&mut sut);
+
+        assert_eq!(memory.lock().unwrap().get_sram(0x43).unwrap(), 0);
+        assert_eq!(sut.counter, 1);
+    }
+
+    #[test]
+    fn test_external_pin_edges() {
+        let pin = device::ATMEGA8.timer0.external_clock_pin;
+        let (memory, mut sut, ticks) = set_up(&device::ATMEGA8.synchronous_prescaler, Some(pin));
+
+        clock_cycle(&mut sut);
+        memory.lock().unwrap().set_sram(pin.address, pin.mask());
+        clock_cycle(&mut sut);
+        clock_cycle(&mut sut);
+        memory.lock().unwrap().set_sram(pin.address, 0);
+        clock_cycle(&mut sut);
+
+        let edges: Vec<PinEdge> = ticks
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|tick| tick.external_pin_edge)
+            .collect();
+
+        assert_eq!(
+            edges,
+            vec![
+                PinEdge::None,
+                PinEdge::Rising,
+                PinEdge::None,
+                PinEdge::Falling
+            ]
+        );
+    }
+}
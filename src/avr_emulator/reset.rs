@@ -0,0 +1,258 @@
+use crate::avr_emulator::device::Device;
+use crate::avr_emulator::fuses::Fuses;
+use crate::avr_emulator::memory::Memory;
+use crate::avr_emulator::system_clock_prescaler;
+
+// MCUSR / MCUCSR
+const PORF: u8 = 1 << 0;
+const EXTRF: u8 = 1 << 1;
+const BORF: u8 = 1 << 2;
+const WDRF: u8 = 1 << 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResetKind {
+    PowerOn,
+    External,
+    BrownOut,
+    Watchdog,
+}
+
+impl ResetKind {
+    fn get_flag(&self) -> u8 {
+        match self {
+            ResetKind::PowerOn => PORF,
+            ResetKind::External => EXTRF,
+            ResetKind::BrownOut => BORF,
+            ResetKind::Watchdog => WDRF,
+        }
+    }
+}
+
+impl std::str::FromStr for ResetKind {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().as_str() {
+            "power-on" | "poweron" | "por" => Ok(ResetKind::PowerOn),
+            "external" | "ext" => Ok(ResetKind::External),
+            "brown-out" | "brownout" | "bor" => Ok(ResetKind::BrownOut),
+            "watchdog" | "wdt" => Ok(ResetKind::Watchdog),
+            _ => Err(format!("unknown reset kind: {}", name)),
+        }
+    }
+}
+
+// Puts the core into its reset state: execution restarts at the reset vector selected by BOOTRST
+// and the io registers get their datasheet reset values. A power-on reset clears the registers and sram, other resets
+// only do so when preserve_sram is false. The reset flags accumulate until a power-on reset
+// or until they are cleared by software.
+pub fn reset(
+    memory: &mut Memory,
+    device: &Device,
+    fuses: &Fuses,
+    kind: ResetKind,
+    preserve_sram: bool,
+) {
+    let reset_status = match kind {
+        ResetKind::PowerOn => 0,
+        _ => memory.peek(device.reset_status),
+    };
+
+    if kind == ResetKind::PowerOn || !preserve_sram {
+        memory.clear();
+    }
+    memory.reset(device.sram_start);
+    memory.set_pc(fuses.get_reset_address(device));
+
+    for (address, value) in device.io_reset_values {
+        memory.poke(*address, *value);
+    }
+    memory.poke(device.reset_status, reset_status | kind.get_flag());
+
+    // the system clock starts divided by 8 when CKDIV8 is programmed
+    let clock_select = if fuses.is_clock_divided(device) {
+        system_clock_prescaler::CLOCK_SELECT_DIVIDED_BY_8
+    } else {
+        0
+    };
+    if let Some(clock_prescaler) = device.clock_prescaler {
+        memory.poke(clock_prescaler, clock_select);
+    }
+    memory.set_clock_division(system_clock_prescaler::get_division(clock_select));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avr_emulator::device;
+
+    fn set_up() -> Memory {
+        let mut memory = Memory::new(0x500, vec![]).unwrap();
+        memory.set_register(1, 0x11);
+        memory.set_pc(0x42);
+        memory.poke(0x2a, 0x98);
+        memory.poke(0x200, 0x22);
+
+        memory
+    }
+
+    #[test]
+    fn test_parse_reset_kind() {
+        assert_eq!("power-on".parse::<ResetKind>(), Ok(ResetKind::PowerOn));
+        assert_eq!("External".parse::<ResetKind>(), Ok(ResetKind::External));
+        assert_eq!("bor".parse::<ResetKind>(), Ok(ResetKind::BrownOut));
+        assert_eq!("wdt".parse::<ResetKind>(), Ok(ResetKind::Watchdog));
+        assert!("jtag".parse::<ResetKind>().is_err());
+    }
+
+    #[test]
+    fn test_power_on_reset() {
+        let mut memory = set_up();
+        memory.poke(0x54, WDRF | EXTRF);
+
+        reset(
+            &mut memory,
+            &device::ATMEGA88,
+            &device::ATMEGA88.fuses.defaults,
+            ResetKind::PowerOn,
+            true,
+        );
+
+        assert_eq!(memory.get_pc(), 0);
+        assert_eq!(memory.get_register(1), Ok(0));
+        assert_eq!(memory.peek(0x200), 0);
+        assert_eq!(memory.peek(0x2a), 0);
+        assert_eq!(memory.peek(0x54), PORF);
+    }
+
+    #[test]
+    fn test_boot_reset_vector() {
+        let mut memory = set_up();
+        let mut fuses = device::ATMEGA88.fuses.defaults;
+        fuses.extended = 0xf8;
+
+        reset(
+            &mut memory,
+            &device::ATMEGA88,
+            &fuses,
+            ResetKind::External,
+            true,
+        );
+
+        assert_eq!(memory.get_pc(), 0xc00);
+    }
+
+    #[test]
+    fn test_clock_division_follows_ckdiv8() {
+        let mut memory = set_up();
+        memory.set_clock_division(4);
+        let mut fuses = device::ATMEGA88.fuses.defaults;
+
+        reset(
+            &mut memory,
+            &device::ATMEGA88,
+            &fuses,
+            ResetKind::External,
+            true,
+        );
+        assert_eq!(memory.peek(0x61), 0b0000_0011);
+        assert_eq!(memory.get_clock_division(), 8);
+
+        fuses.low |= 0x80;
+        reset(
+            &mut memory,
+            &device::ATMEGA88,
+            &fuses,
+            ResetKind::External,
+            true,
+        );
+        assert_eq!(memory.peek(0x61), 0);
+        assert_eq!(memory.get_clock_division(), 1);
+    }
+
+    #[test]
+    fn test_reset_values() {
+        let mut memory = set_up();
+
+        reset(
+            &mut memory,
+            &device::ATMEGA88,
+            &device::ATMEGA88.fuses.defaults,
+            ResetKind::PowerOn,
+            true,
+        );
+
+        // stack pointer at RAMEND, UDRE set, TWI idle
+        assert_eq!(memory.get_sp(), 0x04ff);
+        assert_eq!(memory.peek(0xc0), 0x20);
+        assert_eq!(memory.peek(0xc2), 0x06);
+        assert_eq!(memory.peek(0xb9), 0xf8);
+
+        reset(
+            &mut memory,
+            &device::ATMEGA8,
+            &device::ATMEGA8.fuses.defaults,
+            ResetKind::PowerOn,
+            true,
+        );
+
+        assert_eq!(memory.get_sp(), 0);
+        assert_eq!(memory.peek(0x2b), 0x20);
+        assert_eq!(memory.peek(0x40), 0x86);
+    }
+
+    #[test]
+    fn test_external_reset_preserving_sram() {
+        let mut memory = set_up();
+        memory.poke(0x54, PORF);
+
+        reset(
+            &mut memory,
+            &device::ATMEGA88,
+            &device::ATMEGA88.fuses.defaults,
+            ResetKind::External,
+            true,
+        );
+
+        assert_eq!(memory.get_pc(), 0);
+        assert_eq!(memory.get_register(1), Ok(0x11));
+        assert_eq!(memory.peek(0x200), 0x22);
+        assert_eq!(memory.peek(0x2a), 0);
+        assert_eq!(memory.peek(0x54), PORF | EXTRF);
+    }
+
+    #[test]
+    fn test_reset_clearing_sram() {
+        let mut memory = set_up();
+
+        reset(
+            &mut memory,
+            &device::ATMEGA8,
+            &device::ATMEGA8.fuses.defaults,
+            ResetKind::BrownOut,
+            false,
+        );
+
+        assert_eq!(memory.get_register(1), Ok(0));
+        assert_eq!(memory.peek(0x200), 0);
+        assert_eq!(memory.peek(0x54), BORF);
+    }
+
+    #[test]
+    fn test_watchdog_reset_keeps_sram_above_io() {
+        let mut memory = set_up();
+        memory.poke(0x60, 0x33);
+
+        reset(
+            &mut memory,
+            &device::ATMEGA8,
+            &device::ATMEGA8.fuses.defaults,
+            ResetKind::Watchdog,
+            true,
+        );
+
+        // 0x60 is sram on the ATmega8
+        assert_eq!(memory.peek(0x60), 0x33);
+        assert_eq!(memory.peek(0x54), WDRF);
+    }
+}
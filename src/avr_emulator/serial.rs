@@ -0,0 +1,399 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+// Host side of an emulated serial port. Frame timing is handled by the peripheral,
+// the backend only moves whole bytes.
+pub trait SerialBackend: Send {
+    fn transmit(&mut self, byte: u8);
+    fn receive(&mut self) -> Option<u8>;
+}
+
+// Creates a backend from a command line specification: stdio, none, pty[:<link>] or unix:<path>
+pub fn from_spec(spec: &str) -> Result<Box<dyn SerialBackend>, String> {
+    match spec.split_once(':') {
+        None if spec == "stdio" => Ok(Box::new(StdioSerial::new())),
+        None if spec == "none" => Ok(Box::new(Disconnected)),
+        None if spec == "pty" => Ok(Box::new(PtySerial::new(None)?)),
+        Some(("pty", link)) if !link.is_empty() => {
+            Ok(Box::new(PtySerial::new(Some(Path::new(link)))?))
+        }
+        Some(("unix", path)) if !path.is_empty() => {
+            Ok(Box::new(UnixSocketSerial::new(Path::new(path))?))
+        }
+        _ => Err(format!("invalid serial port specification: {}", spec)),
+    }
+}
+
+// Forwards bytes until the reader is closed. Returns false once the receiving side is gone.
+fn forward_bytes<R: Read>(mut reader: R, sender: &Sender<u8>) -> std::io::Result<bool> {
+    let mut buffer = [0u8; 64];
+
+    loop {
+        let count = reader.read(&mut buffer)?;
+        if count == 0 {
+            return Ok(true);
+        }
+
+        if buffer[..count]
+            .iter()
+            .any(|byte| sender.send(*byte).is_err())
+        {
+            return Ok(false);
+        }
+    }
+}
+
+// Removes what a previous run left at path, other files are never overwritten
+fn remove_stale(path: &Path, is_stale: impl Fn(&std::fs::FileType) -> bool) -> Result<(), String> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if is_stale(&metadata.file_type()) => std::fs::remove_file(path)
+            .map_err(|error| format!("failed to remove {}: {}", path.display(), error)),
+        Ok(_) => Err(format!("{} already exists", path.display())),
+        Err(_) => Ok(()),
+    }
+}
+
+// Forwards everything read by a background thread to the returned channel
+fn spawn_reader<R: Read + Send + 'static>(name: String, reader: R) -> Receiver<u8> {
+    let (sender, receiver) = mpsc::channel();
+
+    std::thread::spawn(move || {
+        if let Err(error) = forward_bytes(reader, &sender) {
+            log::warn!("failed to read serial input from {}: {}", name, error);
+        }
+    });
+
+    receiver
+}
+
+// Drops transmitted bytes and never receives anything
+#[derive(Debug, Default)]
+pub struct Disconnected;
+
+impl SerialBackend for Disconnected {
+    fn transmit(&mut self, _byte: u8) {}
+
+    fn receive(&mut self) -> Option<u8> {
+        None
+    }
+}
+
+// Transmitted bytes go to stdout, received bytes come from stdin
+pub struct StdioSerial {
+    input: Receiver<u8>,
+}
+
+impl StdioSerial {
+    pub fn new() -> Self {
+        Self {
+            input: spawn_reader("stdin".to_string(), std::io::stdin()),
+        }
+    }
+}
+
+impl Default for StdioSerial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SerialBackend for StdioSerial {
+    fn transmit(&mut self, byte: u8) {
+        let mut stdout = std::io::stdout().lock();
+
+        if let Err(error) = stdout.write_all(&[byte]).and_then(|_| stdout.flush()) {
+            log::warn!("failed to write serial output to stdout: {}", error);
+        }
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        self.input.try_recv().ok()
+    }
+}
+
+// Pseudo-terminal in raw mode, host tools open the slave side like a usb serial adapter
+pub struct PtySerial {
+    master: File,
+    // kept open so the master does not report a hang up while no host tool is connected
+    _slave: File,
+    slave_path: PathBuf,
+    link: Option<PathBuf>,
+    input: Receiver<u8>,
+}
+
+impl PtySerial {
+    pub fn new(link: Option<&Path>) -> Result<Self, String> {
+        let error = |what: &str| format!("{}: {}", what, std::io::Error::last_os_error());
+
+        // SAFETY: plain libc calls on a file descriptor owned by this function
+        let master = unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            if fd < 0 {
+                return Err(error("failed to open pty"));
+            }
+            let master = File::from_raw_fd(fd);
+
+            if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                return Err(error("failed to unlock pty"));
+            }
+
+            master
+        };
+
+        let mut name = [0 as libc::c_char; 64];
+        // SAFETY: the buffer length passed matches the buffer
+        if unsafe { libc::ptsname_r(master.as_raw_fd(), name.as_mut_ptr(), name.len()) } != 0 {
+            return Err(error("failed to get pty name"));
+        }
+        // SAFETY: ptsname_r wrote a nul terminated string into the buffer
+        let slave_path = unsafe { std::ffi::CStr::from_ptr(name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned();
+        let slave_path = PathBuf::from(slave_path);
+
+        let slave = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&slave_path)
+            .map_err(|error| format!("failed to open {}: {}", slave_path.display(), error))?;
+
+        // SAFETY: termios is only used after tcgetattr filled it in
+        unsafe {
+            let mut termios = std::mem::zeroed::<libc::termios>();
+            if libc::tcgetattr(slave.as_raw_fd(), &mut termios) != 0 {
+                return Err(error("failed to get pty attributes"));
+            }
+            libc::cfmakeraw(&mut termios);
+            if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+                return Err(error("failed to set pty attributes"));
+            }
+        }
+
+        if let Some(link) = link {
+            remove_stale(link, |file_type| file_type.is_symlink())?;
+            std::os::unix::fs::symlink(&slave_path, link)
+                .map_err(|error| format!("failed to link {}: {}", link.display(), error))?;
+        }
+
+        let reader = master
+            .try_clone()
+            .map_err(|error| format!("failed to clone pty: {}", error))?;
+
+        eprintln!(
+            "serial port available at {}",
+            link.unwrap_or(&slave_path).display()
+        );
+
+        Ok(Self {
+            master,
+            _slave: slave,
+            input: spawn_reader(slave_path.display().to_string(), reader),
+            slave_path,
+            link: link.map(Path::to_path_buf),
+        })
+    }
+
+    pub fn get_slave_path(&self) -> &Path {
+        &self.slave_path
+    }
+
+    fn is_writable(&self) -> bool {
+        let mut poll_fd = libc::pollfd {
+            fd: self.master.as_raw_fd(),
+            events: libc::POLLOUT,
+            revents: 0,
+        };
+
+        // SAFETY: a single valid pollfd is passed
+        unsafe { libc::poll(&mut poll_fd, 1, 0) == 1 && poll_fd.revents & libc::POLLOUT != 0 }
+    }
+}
+
+impl SerialBackend for PtySerial {
+    fn transmit(&mut self, byte: u8) {
+        // nobody reads the pty, drop the byte instead of stalling the emulation
+        if !self.is_writable() {
+            log::debug!("pty {} is full, dropping byte", self.slave_path.display());
+            return;
+        }
+
+        if let Err(error) = self.master.write_all(&[byte]) {
+            log::warn!("failed to write serial output to pty: {}", error);
+        }
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        self.input.try_recv().ok()
+    }
+}
+
+impl Drop for PtySerial {
+    fn drop(&mut self) {
+        if let Some(link) = &self.link {
+            let _ = std::fs::remove_file(link);
+        }
+    }
+}
+
+// Listens on a Unix domain socket, one client at a time is connected to the serial port
+pub struct UnixSocketSerial {
+    path: PathBuf,
+    client: Arc<Mutex<Option<UnixStream>>>,
+    input: Receiver<u8>,
+}
+
+impl UnixSocketSerial {
+    const WRITE_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(10);
+
+    pub fn new(path: &Path) -> Result<Self, String> {
+        remove_stale(path, |file_type| file_type.is_socket())?;
+        let listener = UnixListener::bind(path)
+            .map_err(|error| format!("failed to bind {}: {}", path.display(), error))?;
+
+        let client: Arc<Mutex<Option<UnixStream>>> = Arc::new(Mutex::new(None));
+        let (sender, input) = mpsc::channel();
+
+        let connected_client = client.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(error) => {
+                        log::warn!("failed to accept serial client: {}", error);
+                        continue;
+                    }
+                };
+
+                let reader = match stream.try_clone() {
+                    Ok(reader) => reader,
+                    Err(error) => {
+                        log::warn!("failed to clone serial client: {}", error);
+                        continue;
+                    }
+                };
+                let _ = stream.set_write_timeout(Some(Self::WRITE_TIMEOUT));
+                *connected_client.lock().unwrap() = Some(stream);
+
+                // the client is served until it disconnects, then the next one is accepted
+                let receiver_alive = forward_bytes(reader, &sender).unwrap_or(true);
+
+                *connected_client.lock().unwrap() = None;
+
+                if !receiver_alive {
+                    return;
+                }
+            }
+        });
+
+        eprintln!("serial port available at {}", path.display());
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            client,
+            input,
+        })
+    }
+}
+
+impl SerialBackend for UnixSocketSerial {
+    fn transmit(&mut self, byte: u8) {
+        let mut client = self.client.lock().unwrap();
+
+        if let Some(stream) = client.as_mut() {
+            if let Err(error) = stream.write_all(&[byte]) {
+                log::debug!("failed to write to serial client: {}", error);
+            }
+        }
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        self.input.try_recv().ok()
+    }
+}
+
+impl Drop for UnixSocketSerial {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn receive_with_timeout(serial: &mut dyn SerialBackend) -> Option<u8> {
+        for _ in 0..200 {
+            if let Some(byte) = serial.receive() {
+                return Some(byte);
+            }
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+        None
+    }
+
+    #[test]
+    fn test_from_spec_rejects_invalid_specifications() {
+        for spec in ["", "tcp:1234", "unix:", "pty:", "stdout"] {
+            assert!(from_spec(spec).is_err(), "{}", spec);
+        }
+        assert!(from_spec("none").is_ok());
+    }
+
+    #[test]
+    fn test_pty() {
+        let mut sut = PtySerial::new(None).unwrap();
+
+        let mut host = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(sut.get_slave_path())
+            .unwrap();
+
+        host.write_all(b"r").unwrap();
+        assert_eq!(receive_with_timeout(&mut sut), Some(b'r'));
+
+        sut.transmit(b't');
+        let mut byte = [0u8];
+        host.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], b't');
+    }
+
+    #[test]
+    fn test_unix_socket() {
+        let path = std::env::temp_dir().join(format!("avr-serial-{}.sock", std::process::id()));
+        let mut sut = UnixSocketSerial::new(&path).unwrap();
+
+        // nobody is connected yet, the byte is dropped
+        sut.transmit(b'x');
+
+        let mut host = UnixStream::connect(&path).unwrap();
+        host.write_all(b"r").unwrap();
+        assert_eq!(receive_with_timeout(&mut sut), Some(b'r'));
+
+        sut.transmit(b't');
+        let mut byte = [0u8];
+        host.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], b't');
+
+        drop(sut);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_existing_files_are_not_replaced() {
+        let path = std::env::temp_dir().join(format!("avr-serial-{}.txt", std::process::id()));
+        std::fs::write(&path, "firmware").unwrap();
+
+        assert!(UnixSocketSerial::new(&path).is_err());
+        assert!(PtySerial::new(Some(&path)).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "firmware");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,175 @@
+use std::sync::Arc;
+
+use crate::avr_emulator::observer::Observer;
+use crate::avr_emulator::reset::ResetKind;
+use crate::avr_emulator::symbols::{SymbolTable, DATA_OFFSET};
+
+// Tracks which bytes of the data sram were written since they were last cleared and reports
+// once per address about reads of the others. Registers and io are not tracked, they have
+// reset values or are written before being read by compiled code.
+pub struct ShadowMemory {
+    symbols: Arc<SymbolTable>,
+    sram_start: usize,
+    preserve_sram: bool,
+    initialised: Vec<bool>,
+    reported: Vec<bool>,
+    // byte address of the instruction whose accesses are being notified
+    pc: u32,
+    reads: usize,
+}
+
+impl ShadowMemory {
+    pub fn new(
+        symbols: Arc<SymbolTable>,
+        sram_start: usize,
+        sram_size: usize,
+        preserve_sram: bool,
+    ) -> Self {
+        Self {
+            symbols,
+            sram_start,
+            preserve_sram,
+            initialised: vec![false; sram_size],
+            reported: vec![false; sram_size],
+            pc: 0,
+            reads: 0,
+        }
+    }
+
+    // Reads of uninitialised bytes, including the ones already reported
+    pub fn get_reads(&self) -> usize {
+        self.reads
+    }
+
+    pub fn get_reported_addresses(&self) -> Vec<usize> {
+        (0..self.reported.len())
+            .filter(|address| self.reported[*address])
+            .collect()
+    }
+
+    fn is_tracked(&self, address: usize) -> bool {
+        address >= self.sram_start && address < self.initialised.len()
+    }
+}
+
+impl Observer for ShadowMemory {
+    fn on_instruction(&mut self, pc: u32, _opcode: u16) {
+        self.pc = pc;
+    }
+
+    fn on_mem_read(&mut self, address: usize, value: u8) {
+        if !self.is_tracked(address) || self.initialised[address] {
+            return;
+        }
+        self.reads += 1;
+
+        if !std::mem::replace(&mut self.reported[address], true) {
+            let variable = self
+                .symbols
+                .symbolize(address as u32 + DATA_OFFSET)
+                .map_or(String::new(), |symbol| format!(" <{}>", symbol));
+
+            log::warn!(
+                "read of uninitialised sram {:#06x}{} at {}: {:#04x}",
+                address,
+                variable,
+                self.symbols.format_address(self.pc),
+                value
+            );
+        }
+    }
+
+    fn on_mem_write(&mut self, address: usize, _value: u8) {
+        if self.is_tracked(address) {
+            self.initialised[address] = true;
+        }
+    }
+
+    fn on_reset(&mut self, kind: ResetKind) {
+        if kind == ResetKind::PowerOn || !self.preserve_sram {
+            self.initialised.fill(false);
+            self.reported.fill(false);
+        }
+    }
+}
+
+// The summary printed at exit
+impl std::fmt::Display for ShadowMemory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "uninitialised sram reads: {} of {} address(es)",
+            self.reads,
+            self.get_reported_addresses().len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avr_emulator::symbols::{Symbol, SymbolKind};
+
+    fn set_up(preserve_sram: bool) -> ShadowMemory {
+        let symbols = SymbolTable::new(vec![
+            Symbol {
+                name: "main".to_owned(),
+                address: 0x40,
+                size: 0x20,
+                kind: SymbolKind::Function,
+            },
+            Symbol {
+                name: "buffer".to_owned(),
+                address: DATA_OFFSET + 0x100,
+                size: 4,
+                kind: SymbolKind::Object,
+            },
+        ]);
+
+        ShadowMemory::new(Arc::new(symbols), 0x100, 0x200, preserve_sram)
+    }
+
+    #[test]
+    fn test_reads_of_unwritten_sram() {
+        let mut sut = set_up(false);
+
+        sut.on_instruction(0x42, 0);
+        sut.on_mem_write(0x100, 1);
+        sut.on_mem_read(0x100, 1);
+        // registers and io are not tracked
+        sut.on_mem_read(0x18, 0);
+        sut.on_mem_read(0x5f, 0);
+        assert_eq!(sut.get_reads(), 0);
+
+        sut.on_instruction(0x44, 0);
+        sut.on_mem_read(0x102, 0);
+        sut.on_mem_read(0x102, 0);
+        sut.on_mem_read(0x1ff, 0);
+        assert_eq!(sut.get_reads(), 3);
+        assert_eq!(sut.get_reported_addresses(), [0x102, 0x1ff]);
+        assert_eq!(
+            sut.to_string(),
+            "uninitialised sram reads: 3 of 2 address(es)\n"
+        );
+    }
+
+    #[test]
+    fn test_reset_forgets_written_sram() {
+        let mut sut = set_up(true);
+        sut.on_mem_write(0x101, 1);
+
+        sut.on_reset(ResetKind::Watchdog);
+        sut.on_mem_read(0x101, 1);
+        assert_eq!(sut.get_reads(), 0);
+
+        sut.on_reset(ResetKind::PowerOn);
+        sut.on_mem_read(0x101, 1);
+        assert_eq!(sut.get_reads(), 1);
+
+        let mut sut = set_up(false);
+        sut.on_mem_write(0x101, 1);
+        sut.on_reset(ResetKind::External);
+        sut.on_mem_read(0x101, 1);
+        assert_eq!(sut.get_reads(), 1);
+    }
+}
@@ -0,0 +1,555 @@
+use std::sync::{Arc, Mutex};
+
+use crate::avr_emulator::clock;
+use crate::avr_emulator::device::{RegisterBit, SpiRegisters};
+use crate::avr_emulator::memory::Memory;
+
+// SPCR
+const SPE: u8 = 1 << 6;
+const DORD: u8 = 1 << 5;
+const MSTR: u8 = 1 << 4;
+const SPR: u8 = 0b0000_0011;
+
+// SPSR
+const SPIF: u8 = 1 << 7;
+const WCOL: u8 = 1 << 6;
+const SPI2X: u8 = 1 << 0;
+
+// Clock cycles per bit for a slave, the fastest clock a master may use
+const SLAVE_BIT_CYCLES: u32 = 4;
+
+// Device on the other end of the SPI bus. Bytes are exchanged most significant bit first,
+// LSB first transfers (DORD) reach the device bit reversed like they would on the wire.
+pub trait SpiDevice: Send {
+    // The emulated mcu is the master: the device receives mosi and answers with miso
+    fn transfer(&mut self, mosi: u8) -> u8;
+
+    // The emulated mcu is a selected slave: the device may start a transfer by returning mosi
+    fn start_transfer(&mut self) -> Option<u8> {
+        None
+    }
+
+    // Result of a transfer started by the device
+    fn transfer_complete(&mut self, _miso: u8) {}
+}
+
+pub struct SpiSlave {
+    device: Box<dyn SpiDevice>,
+    // active low pin selecting the device, a device without one is always selected
+    chip_select: Option<RegisterBit>,
+}
+
+impl SpiSlave {
+    pub fn new(device: Box<dyn SpiDevice>, chip_select: Option<RegisterBit>) -> Self {
+        Self {
+            device,
+            chip_select,
+        }
+    }
+
+    fn is_selected(&self, memory: &Memory) -> bool {
+        self.chip_select
+            .is_none_or(|chip_select| !memory.get_bit(&chip_select))
+    }
+}
+
+// Daisy chain of 8-bit shift registers like the 74HC595, shifting out the oldest byte
+#[derive(Debug, Default)]
+pub struct ShiftRegister {
+    stages: Vec<u8>,
+}
+
+impl ShiftRegister {
+    pub fn new(length: usize) -> Self {
+        Self {
+            stages: vec![0; length],
+        }
+    }
+
+    pub fn get_outputs(&self) -> &[u8] {
+        &self.stages
+    }
+}
+
+impl SpiDevice for ShiftRegister {
+    fn transfer(&mut self, mosi: u8) -> u8 {
+        self.stages.insert(0, mosi);
+        self.stages.pop().unwrap_or(mosi)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Transfer {
+    Master {
+        data: u8,
+        remaining_cycles: u32,
+    },
+    Slave {
+        mosi: u8,
+        remaining_cycles: u32,
+        slave: usize,
+    },
+}
+
+pub struct Spi {
+    rising_edge_notified: std::sync::atomic::AtomicBool,
+    memory: Arc<Mutex<Memory>>,
+    registers: &'static SpiRegisters,
+    slaves: Arc<Mutex<Vec<SpiSlave>>>,
+    transfer: Option<Transfer>,
+    // data written to SPDR while in slave mode, shifted out with the next transfer
+    slave_data: u8,
+    // SPIF is cleared by reading SPSR with SPIF set and then accessing SPDR
+    flag_read: bool,
+}
+
+impl Spi {
+    pub fn new(
+        memory: Arc<Mutex<Memory>>,
+        registers: &'static SpiRegisters,
+        slaves: Arc<Mutex<Vec<SpiSlave>>>,
+    ) -> Self {
+        Self {
+            rising_edge_notified: std::sync::atomic::AtomicBool::new(false),
+            memory,
+            registers,
+            slaves,
+            transfer: None,
+            slave_data: 0,
+            flag_read: false,
+        }
+    }
+
+    fn get_bit_cycles(&self, memory: &Memory) -> u32 {
+        let control = memory.peek(self.registers.control);
+        let double_speed = memory.peek(self.registers.status) & SPI2X != 0;
+
+        let divisor = [4, 16, 64, 128][(control & SPR) as usize];
+
+        if double_speed {
+            divisor / 2
+        } else {
+            divisor
+        }
+    }
+
+    fn to_wire(&self, memory: &Memory, byte: u8) -> u8 {
+        if memory.peek(self.registers.control) & DORD != 0 {
+            byte.reverse_bits()
+        } else {
+            byte
+        }
+    }
+
+    fn update_status(&self, memory: &mut Memory, set: u8, clear: u8) {
+        let status = memory.peek(self.registers.status);
+        memory.poke(self.registers.status, (status & !clear) | set);
+    }
+
+    fn handle_status_access(&mut self, memory: &mut Memory) {
+        if let Some(write) = memory.take_io_write(self.registers.status) {
+            memory.poke(
+                self.registers.status,
+                (write.old & (SPIF | WCOL)) | (write.new & SPI2X),
+            );
+        }
+
+        if memory.take_io_read(self.registers.status)
+            && memory.peek(self.registers.status) & SPIF != 0
+        {
+            self.flag_read = true;
+        }
+    }
+
+    fn handle_data_access(&mut self, memory: &mut Memory) {
+        let read = memory.take_io_read(self.registers.data);
+        let write = memory.take_io_write(self.registers.data);
+
+        if (read || write.is_some()) && self.flag_read {
+            self.update_status(memory, 0, SPIF | WCOL);
+            self.flag_read = false;
+        }
+
+        let Some(write) = write else {
+            return;
+        };
+
+        let control = memory.peek(self.registers.control);
+        if control & SPE == 0 {
+            return;
+        }
+
+        if self.transfer.is_some() {
+            log::warn!("Spi write collision, {:#04x} is ignored", write.new);
+            self.update_status(memory, WCOL, 0);
+        } else if control & MSTR != 0 {
+            self.transfer = Some(Transfer::Master {
+                data: write.new,
+                remaining_cycles: 8 * self.get_bit_cycles(memory),
+            });
+        } else {
+            self.slave_data = write.new;
+        }
+    }
+
+    fn handle_slave_select(&mut self, memory: &mut Memory) {
+        let control = memory.peek(self.registers.control);
+        let selected = !memory.get_bit(&self.registers.slave_select_pin);
+
+        if control & SPE == 0 {
+            self.transfer = None;
+            return;
+        }
+
+        if control & MSTR != 0 {
+            let slave_select_is_input = !memory.get_bit(&self.registers.slave_select_direction);
+
+            if slave_select_is_input && selected {
+                log::info!("Spi SS pulled low, switching to slave mode");
+                memory.poke(self.registers.control, control & !MSTR);
+                self.update_status(memory, SPIF, 0);
+                self.transfer = None;
+            }
+        } else if !selected && self.transfer.is_some() {
+            log::debug!("Spi slave deselected during transfer");
+            self.transfer = None;
+        }
+    }
+
+    fn complete_master_transfer(&mut self, memory: &mut Memory, data: u8) {
+        let mosi = self.to_wire(memory, data);
+
+        // nobody drives MISO when no device is selected, the line floats high
+        let mut miso = None;
+        for slave in self.slaves.lock().unwrap().iter_mut() {
+            if slave.is_selected(memory) {
+                let response = slave.device.transfer(mosi);
+                miso.get_or_insert(response);
+            }
+        }
+
+        let received = self.to_wire(memory, miso.unwrap_or(0xff));
+        memory.poke(self.registers.data, received);
+        self.update_status(memory, SPIF, 0);
+        memory.raise_event("Spi", format_args!("transfer complete {:#04x}", received));
+    }
+
+    fn complete_slave_transfer(&mut self, memory: &mut Memory, mosi: u8, slave: usize) {
+        let miso = self.to_wire(memory, self.slave_data);
+
+        self.slaves.lock().unwrap()[slave]
+            .device
+            .transfer_complete(miso);
+
+        memory.poke(self.registers.data, self.to_wire(memory, mosi));
+        self.update_status(memory, SPIF, 0);
+        memory.raise_event("Spi", format_args!("slave transfer complete {:#04x}", mosi));
+    }
+
+    fn start_slave_transfer(&mut self, memory: &Memory) {
+        let control = memory.peek(self.registers.control);
+        let selected = !memory.get_bit(&self.registers.slave_select_pin);
+
+        if control & (SPE | MSTR) != SPE || !selected {
+            return;
+        }
+
+        for (index, slave) in self.slaves.lock().unwrap().iter_mut().enumerate() {
+            if slave.is_selected(memory) {
+                if let Some(mosi) = slave.device.start_transfer() {
+                    self.transfer = Some(Transfer::Slave {
+                        mosi,
+                        remaining_cycles: 8 * SLAVE_BIT_CYCLES,
+                        slave: index,
+                    });
+                    return;
+                }
+            }
+        }
+    }
+
+    fn run_transfer(&mut self, memory: &mut Memory) {
+        match self.transfer.take() {
+            Some(Transfer::Master {
+                data,
+                remaining_cycles: 1,
+            }) => self.complete_master_transfer(memory, data),
+            Some(Transfer::Slave {
+                mosi,
+                remaining_cycles: 1,
+                slave,
+            }) => self.complete_slave_transfer(memory, mosi, slave),
+            Some(Transfer::Master {
+                data,
+                remaining_cycles,
+            }) => {
+                self.transfer = Some(Transfer::Master {
+                    data,
+                    remaining_cycles: remaining_cycles - 1,
+                })
+            }
+            Some(Transfer::Slave {
+                mosi,
+                remaining_cycles,
+                slave,
+            }) => {
+                self.transfer = Some(Transfer::Slave {
+                    mosi,
+                    remaining_cycles: remaining_cycles - 1,
+                    slave,
+                })
+            }
+            None => self.start_slave_transfer(memory),
+        }
+    }
+
+    fn update(&mut self) {
+        let memory = self.memory.clone();
+        let mut memory = memory.lock().unwrap();
+
+        self.handle_status_access(&mut memory);
+        self.handle_data_access(&mut memory);
+        self.handle_slave_select(&mut memory);
+        self.run_transfer(&mut memory);
+    }
+}
+
+impl clock::Subscriber for Spi {
+    fn notify_rising_edge(&self) {
+        log::debug!("Spi rising edge notified");
+
+        if self
+            .rising_edge_notified
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            log::warn!("Spi did not finish handling previous rising edge!");
+        }
+
+        self.rising_edge_notified
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+    fn notify_falling_edge(&self) {}
+
+    fn run(&mut self) {
+        if self
+            .rising_edge_notified
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            self.update();
+
+            self.rising_edge_notified
+                .store(false, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.transfer = None;
+        self.slave_data = 0;
+        self.flag_read = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avr_emulator::device;
+    use crate::avr_emulator::test_utils::clock_cycles;
+
+    #[derive(Default)]
+    struct MockLog {
+        received: Vec<u8>,
+        completed: Vec<u8>,
+    }
+
+    struct MockDevice {
+        log: Arc<Mutex<MockLog>>,
+        response: u8,
+        to_send: Vec<u8>,
+    }
+
+    impl SpiDevice for MockDevice {
+        fn transfer(&mut self, mosi: u8) -> u8 {
+            self.log.lock().unwrap().received.push(mosi);
+            self.response
+        }
+
+        fn start_transfer(&mut self) -> Option<u8> {
+            self.to_send.pop()
+        }
+
+        fn transfer_complete(&mut self, miso: u8) {
+            self.log.lock().unwrap().completed.push(miso);
+        }
+    }
+
+    fn set_up(
+        chip_select: Option<RegisterBit>,
+        to_send: Vec<u8>,
+    ) -> (Arc<Mutex<Memory>>, Spi, Arc<Mutex<MockLog>>) {
+        let memory = Arc::new(Mutex::new(Memory::new(300, vec![]).unwrap()));
+        let log = Arc::new(Mutex::new(MockLog::default()));
+
+        let device = MockDevice {
+            log: log.clone(),
+            response: 0xa5,
+            to_send,
+        };
+        let slaves = Arc::new(Mutex::new(vec![SpiSlave::new(
+            Box::new(device),
+            chip_select,
+        )]));
+
+        let spi = Spi::new(memory.clone(), &device::ATMEGA8.spi, slaves);
+
+        (memory, spi, log)
+    }
+
+    fn enable_master(memory: &Arc<Mutex<Memory>>, control: u8) {
+        let mut memory = memory.lock().unwrap();
+        // SS as output so it can not switch the spi into slave mode
+        memory.set_sram(0x37, 0b0000_0100);
+        memory.set_sram(0x2d, SPE | MSTR | control);
+    }
+
+    #[test]
+    fn test_master_transfer() {
+        let (memory, mut sut, log) = set_up(None, vec![]);
+        enable_master(&memory, 0);
+        memory.lock().unwrap().set_sram(0x2f, 0x3c);
+
+        clock_cycles(&mut sut, 31);
+        assert_eq!(memory.lock().unwrap().peek(0x2e) & SPIF, 0);
+        assert!(log.lock().unwrap().received.is_empty());
+
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x2e) & SPIF, SPIF);
+        assert_eq!(memory.lock().unwrap().peek(0x2f), 0xa5);
+        assert_eq!(log.lock().unwrap().received, vec![0x3c]);
+    }
+
+    #[test]
+    fn test_master_clock_rate() {
+        let (memory, sut, _) = set_up(None, vec![]);
+
+        let bit_cycles = |spr: u8, spi2x: u8| {
+            let mut memory = memory.lock().unwrap();
+            memory.poke(0x2d, spr);
+            memory.poke(0x2e, spi2x);
+            sut.get_bit_cycles(&memory)
+        };
+
+        assert_eq!(bit_cycles(0, 0), 4);
+        assert_eq!(bit_cycles(1, 0), 16);
+        assert_eq!(bit_cycles(2, 0), 64);
+        assert_eq!(bit_cycles(3, 0), 128);
+        assert_eq!(bit_cycles(0, SPI2X), 2);
+        assert_eq!(bit_cycles(3, SPI2X), 64);
+    }
+
+    #[test]
+    fn test_lsb_first_is_bit_reversed_on_the_wire() {
+        let (memory, mut sut, log) = set_up(None, vec![]);
+        enable_master(&memory, DORD);
+        memory.lock().unwrap().set_sram(0x2f, 0b0000_0001);
+
+        clock_cycles(&mut sut, 33);
+
+        assert_eq!(log.lock().unwrap().received, vec![0b1000_0000]);
+        assert_eq!(memory.lock().unwrap().peek(0x2f), 0xa5);
+    }
+
+    #[test]
+    fn test_write_collision() {
+        let (memory, mut sut, log) = set_up(None, vec![]);
+        enable_master(&memory, 0);
+        memory.lock().unwrap().set_sram(0x2f, 0x01);
+        clock_cycles(&mut sut, 1);
+
+        memory.lock().unwrap().set_sram(0x2f, 0x02);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x2e) & WCOL, WCOL);
+
+        clock_cycles(&mut sut, 31);
+        assert_eq!(log.lock().unwrap().received, vec![0x01]);
+    }
+
+    #[test]
+    fn test_flag_cleared_by_reading_status_then_data() {
+        let (memory, mut sut, _) = set_up(None, vec![]);
+        enable_master(&memory, 0);
+        memory.lock().unwrap().set_sram(0x2f, 0x01);
+        clock_cycles(&mut sut, 33);
+
+        memory.lock().unwrap().get_sram(0x2f).unwrap();
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x2e) & SPIF, SPIF);
+
+        memory.lock().unwrap().get_sram(0x2e).unwrap();
+        clock_cycles(&mut sut, 1);
+        memory.lock().unwrap().get_sram(0x2f).unwrap();
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x2e) & SPIF, 0);
+    }
+
+    #[test]
+    fn test_chip_select() {
+        let chip_select = RegisterBit::new(0x36, 1);
+        let (memory, mut sut, log) = set_up(Some(chip_select), vec![]);
+        enable_master(&memory, 0);
+        memory.lock().unwrap().poke(0x36, 0b0000_0010);
+
+        memory.lock().unwrap().set_sram(0x2f, 0x01);
+        clock_cycles(&mut sut, 33);
+        assert_eq!(memory.lock().unwrap().peek(0x2f), 0xff);
+
+        memory.lock().unwrap().poke(0x36, 0);
+        memory.lock().unwrap().set_sram(0x2f, 0x02);
+        clock_cycles(&mut sut, 33);
+        assert_eq!(memory.lock().unwrap().peek(0x2f), 0xa5);
+        assert_eq!(log.lock().unwrap().received, vec![0x02]);
+    }
+
+    #[test]
+    fn test_slave_select_low_switches_master_to_slave() {
+        let (memory, mut sut, _) = set_up(None, vec![]);
+        memory.lock().unwrap().poke(0x36, 0b0000_0100);
+        memory.lock().unwrap().set_sram(0x2d, SPE | MSTR);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x2d), SPE | MSTR);
+
+        memory.lock().unwrap().poke(0x36, 0);
+        clock_cycles(&mut sut, 1);
+
+        assert_eq!(memory.lock().unwrap().peek(0x2d), SPE);
+        assert_eq!(memory.lock().unwrap().peek(0x2e) & SPIF, SPIF);
+    }
+
+    #[test]
+    fn test_slave_transfer() {
+        let (memory, mut sut, log) = set_up(None, vec![0x42]);
+        memory.lock().unwrap().poke(0x36, 0b0000_0100);
+        memory.lock().unwrap().set_sram(0x2d, SPE);
+        memory.lock().unwrap().set_sram(0x2f, 0x99);
+
+        // not selected
+        clock_cycles(&mut sut, 100);
+        assert!(log.lock().unwrap().completed.is_empty());
+
+        memory.lock().unwrap().poke(0x36, 0);
+        clock_cycles(&mut sut, 33);
+
+        assert_eq!(log.lock().unwrap().completed, vec![0x99]);
+        assert_eq!(memory.lock().unwrap().peek(0x2f), 0x42);
+        assert_eq!(memory.lock().unwrap().peek(0x2e) & SPIF, SPIF);
+    }
+
+    #[test]
+    fn test_shift_register() {
+        let mut sut = ShiftRegister::new(2);
+
+        assert_eq!(sut.transfer(0x01), 0);
+        assert_eq!(sut.transfer(0x02), 0);
+        assert_eq!(sut.transfer(0x03), 0x01);
+        assert_eq!(sut.get_outputs(), &[0x03, 0x02]);
+    }
+}
@@ -0,0 +1,380 @@
+use std::sync::{Arc, Mutex};
+
+use crate::avr_emulator::memory::Memory;
+use crate::avr_emulator::number::parse_number;
+use crate::avr_emulator::observer::Observer;
+use crate::avr_emulator::symbols::{SymbolTable, DATA_OFFSET};
+
+// Instructions pushing a return address: rcall, call, icall and eicall
+const CALLS: [(u16, u16); 4] = [
+    (0xd000, 0xf000),
+    (0x940e, 0xfe0e),
+    (0x9509, 0xffff),
+    (0x9519, 0xffff),
+];
+
+fn is_call(opcode: u16) -> bool {
+    CALLS.iter().any(|(code, mask)| opcode & mask == *code)
+}
+
+pub fn parse_address(value: &str) -> Result<u16, String> {
+    parse_number(value).ok_or_else(|| format!("invalid address: {}", value))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FrameKind {
+    // byte address of the calling instruction
+    Call(u32),
+    Interrupt(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Frame {
+    kind: FrameKind,
+    // SP with the return address pushed, the frame is gone once SP is above it
+    sp: u16,
+}
+
+// Where the data below the stack ends according to the symbols of an elf file: the end of
+// .bss, a heap end set by the linker and the current top of the heap
+#[derive(Debug, Clone, Default)]
+struct SymbolLimits {
+    bss_end: Option<u16>,
+    heap_end: Option<u16>,
+    brkval: Option<usize>,
+}
+
+impl SymbolLimits {
+    fn new(symbols: &SymbolTable) -> Self {
+        let data_address = |name: &str| {
+            symbols
+                .find(name)
+                .map(|symbol| {
+                    symbol
+                        .address
+                        .checked_sub(DATA_OFFSET)
+                        .unwrap_or(symbol.address)
+                })
+                .filter(|address| *address != 0)
+        };
+
+        Self {
+            bss_end: data_address("__heap_start")
+                .or_else(|| data_address("__bss_end"))
+                .map(|address| address as u16),
+            heap_end: data_address("__heap_end").map(|address| address as u16),
+            brkval: data_address("__brkval").map(|address| address as usize),
+        }
+    }
+
+    fn get(&self, memory: &Memory) -> Option<u16> {
+        let brkval = self
+            .brkval
+            .filter(|address| address + 1 < memory.get_sram_size())
+            .map(|address| u16::from_le_bytes([memory.peek(address), memory.peek(address + 1)]))
+            .filter(|brkval| *brkval != 0);
+
+        [self.bss_end, self.heap_end, brkval]
+            .into_iter()
+            .flatten()
+            .max()
+    }
+}
+
+// Follows the stack pointer after every instruction and interrupt. Calls and interrupts are
+// tracked to report the call stack when SP drops below the limit, the lowest SP is kept as
+// the stack's high-water mark.
+pub struct StackGuard {
+    memory: Arc<Mutex<Memory>>,
+    symbols: Arc<SymbolTable>,
+    limit: Option<u16>,
+    symbol_limits: SymbolLimits,
+    frames: Vec<Frame>,
+    max_call_depth: usize,
+    // SP is 0 on some devices until the firmware initialises it, so 0 is not tracked
+    highest_sp: Option<u16>,
+    lowest_sp: Option<u16>,
+    overflowed: bool,
+    overflows: usize,
+}
+
+impl StackGuard {
+    // Without a limit it is taken from the symbols
+    pub fn new(memory: Arc<Mutex<Memory>>, symbols: Arc<SymbolTable>, limit: Option<u16>) -> Self {
+        let symbol_limits = SymbolLimits::new(&symbols);
+
+        if limit.is_none() && symbol_limits.bss_end.is_none() && symbol_limits.brkval.is_none() {
+            log::warn!("stack limit unknown without the symbols of an elf file");
+        }
+
+        Self {
+            memory,
+            symbols,
+            limit,
+            symbol_limits,
+            frames: vec![],
+            max_call_depth: 0,
+            highest_sp: None,
+            lowest_sp: None,
+            overflowed: false,
+            overflows: 0,
+        }
+    }
+
+    fn get_limit(&self, memory: &Memory) -> Option<u16> {
+        self.limit.or_else(|| self.symbol_limits.get(memory))
+    }
+
+    pub fn get_overflows(&self) -> usize {
+        self.overflows
+    }
+
+    // Bytes between the highest and the lowest SP seen
+    pub fn get_max_depth(&self) -> u16 {
+        match (self.highest_sp, self.lowest_sp) {
+            (Some(highest), Some(lowest)) => highest - lowest,
+            _ => 0,
+        }
+    }
+
+    fn update(&mut self, pc: u32, frame: Option<FrameKind>) {
+        let memory = self.memory.clone();
+        let memory = memory.lock().unwrap();
+        let sp = memory.get_sp();
+
+        self.frames.retain(|frame| frame.sp >= sp);
+        if let Some(kind) = frame {
+            self.frames.push(Frame { kind, sp });
+            self.max_call_depth = self.max_call_depth.max(self.frames.len());
+        }
+
+        if sp == 0 {
+            return;
+        }
+
+        // an SP wrapped around below 0 is not part of the high-water mark
+        let outside = sp as usize >= memory.get_sram_size() - Memory::STACK_START;
+        if !outside {
+            self.highest_sp = self.highest_sp.max(Some(sp));
+            self.lowest_sp = Some(self.lowest_sp.map_or(sp, |lowest| lowest.min(sp)));
+        }
+
+        let limit = self.get_limit(&memory);
+        drop(memory);
+
+        match limit {
+            // counted as an overflow, the emulator reports it when it stops
+            _ if outside => {
+                self.count_overflow();
+            }
+            Some(limit) if sp < limit => {
+                if self.count_overflow() {
+                    log::error!("{}", self.get_overflow_report(pc, sp, limit));
+                }
+            }
+            _ => self.overflowed = false,
+        }
+    }
+
+    // An overflow is counted once until SP is back above the limit
+    fn count_overflow(&mut self) -> bool {
+        let first = !std::mem::replace(&mut self.overflowed, true);
+        if first {
+            self.overflows += 1;
+        }
+        first
+    }
+
+    fn get_overflow_report(&self, pc: u32, sp: u16, limit: u16) -> String {
+        let mut report = format!(
+            "stack overflow at {}: SP {:#06x} below the limit {:#06x}\ncall stack:\n  {}",
+            self.symbols.format_address(pc),
+            sp,
+            limit,
+            self.symbols.format_address(pc)
+        );
+
+        for frame in self.frames.iter().rev() {
+            let line = match frame.kind {
+                FrameKind::Call(call_site) => self.symbols.format_address(call_site),
+                FrameKind::Interrupt(vector) => format!("interrupt vector {}", vector),
+            };
+            report.push_str(&format!("\n  {}", line));
+        }
+
+        report.push_str(&format!(
+            "\nstack depth {} bytes, max call depth {}",
+            self.get_max_depth(),
+            self.max_call_depth
+        ));
+        report
+    }
+}
+
+impl Observer for StackGuard {
+    fn on_instruction(&mut self, pc: u32, opcode: u16) {
+        let frame = is_call(opcode).then_some(FrameKind::Call(pc));
+        self.update(pc, frame);
+    }
+
+    fn on_interrupt(&mut self, vector: usize) {
+        let pc = self.memory.lock().unwrap().get_pc() as u32 * 2;
+        self.update(pc, Some(FrameKind::Interrupt(vector)));
+    }
+}
+
+// The high-water mark printed at exit
+impl std::fmt::Display for StackGuard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Some(lowest_sp) = self.lowest_sp else {
+            return writeln!(f, "stack high-water mark: stack pointer never initialised");
+        };
+
+        write!(
+            f,
+            "stack high-water mark: {} bytes, lowest SP {:#06x}",
+            self.get_max_depth(),
+            lowest_sp
+        )?;
+        if let Some(limit) = self.get_limit(&self.memory.lock().unwrap()) {
+            write!(f, ", limit {:#06x}", limit)?;
+        }
+        writeln!(
+            f,
+            ", max call depth {}, {} overflow(s)",
+            self.max_call_depth, self.overflows
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avr_emulator::symbols::{Symbol, SymbolKind};
+
+    const RCALL: u16 = 0xd002;
+    const RET: u16 = 0x9508;
+    const NOP: u16 = 0x0000;
+
+    fn symbol(name: &str, address: u32, size: u32, kind: SymbolKind) -> Symbol {
+        Symbol {
+            name: name.to_owned(),
+            address,
+            size,
+            kind,
+        }
+    }
+
+    fn set_up(limit: Option<u16>) -> StackGuard {
+        let symbols = SymbolTable::new(vec![
+            symbol("main", 0x40, 0x20, SymbolKind::Function),
+            symbol("recurse", 0x80, 0x20, SymbolKind::Function),
+            symbol("__bss_end", DATA_OFFSET + 0x140, 0, SymbolKind::Other),
+            symbol("__brkval", DATA_OFFSET + 0x100, 2, SymbolKind::Object),
+            symbol("__heap_end", 0, 0, SymbolKind::Other),
+        ]);
+
+        StackGuard::new(
+            Arc::new(Mutex::new(Memory::new(0x300, vec![]).unwrap())),
+            Arc::new(symbols),
+            limit,
+        )
+    }
+
+    fn execute(sut: &mut StackGuard, pc: u32, opcode: u16, sp: u16) {
+        sut.memory.lock().unwrap().set_sp(sp);
+        sut.on_instruction(pc, opcode);
+    }
+
+    #[test]
+    fn test_parse_address() {
+        assert_eq!(parse_address("0x100"), Ok(0x100));
+        assert_eq!(parse_address("256"), Ok(256));
+        assert!(parse_address("0x10000").is_err());
+    }
+
+    #[test]
+    fn test_limit_from_symbols() {
+        let sut = set_up(None);
+        let mut memory = sut.memory.lock().unwrap();
+        assert_eq!(sut.get_limit(&memory), Some(0x140));
+
+        // a heap grown by malloc above the end of .bss
+        memory.poke(0x100, 0x80);
+        memory.poke(0x101, 0x01);
+        assert_eq!(sut.get_limit(&memory), Some(0x180));
+
+        let sut = StackGuard::new(sut.memory.clone(), Arc::default(), None);
+        assert_eq!(sut.get_limit(&memory), None);
+    }
+
+    #[test]
+    fn test_call_stack() {
+        let mut sut = set_up(Some(0x1f8));
+
+        execute(&mut sut, 0x40, NOP, 0x200);
+        execute(&mut sut, 0x42, RCALL, 0x1fe);
+        execute(&mut sut, 0x80, RCALL, 0x1fc);
+        execute(&mut sut, 0x82, RET, 0x1fe);
+        execute(&mut sut, 0x84, RCALL, 0x1fc);
+        sut.on_interrupt(3);
+        assert_eq!(sut.frames.len(), 3);
+        assert_eq!(sut.get_overflows(), 0);
+
+        execute(&mut sut, 0x86, RCALL, 0x1f6);
+        assert_eq!(sut.get_overflows(), 1);
+        assert_eq!(
+            sut.get_overflow_report(0x86, 0x1f6, 0x1f8),
+            "stack overflow at recurse+0x6: SP 0x01f6 below the limit 0x01f8\n\
+             call stack:\n  \
+             recurse+0x6\n  \
+             recurse+0x6\n  \
+             interrupt vector 3\n  \
+             recurse+0x4\n  \
+             main+0x2\n\
+             stack depth 10 bytes, max call depth 4"
+        );
+
+        // reported once until SP is back above the limit
+        execute(&mut sut, 0x88, NOP, 0x1f6);
+        assert_eq!(sut.get_overflows(), 1);
+
+        execute(&mut sut, 0x8a, RET, 0x200);
+        assert!(sut.frames.is_empty());
+        execute(&mut sut, 0x8c, NOP, 0x1f0);
+        assert_eq!(sut.get_overflows(), 2);
+    }
+
+    #[test]
+    fn test_sp_outside_the_data_space() {
+        let mut sut = set_up(None);
+
+        execute(&mut sut, 0x40, NOP, 0x200);
+        execute(&mut sut, 0x42, RCALL, 0xfffe);
+        assert_eq!(sut.get_overflows(), 1);
+        assert_eq!(sut.get_max_depth(), 0);
+
+        execute(&mut sut, 0x80, NOP, 0x200);
+        assert_eq!(sut.get_overflows(), 1);
+    }
+
+    #[test]
+    fn test_high_water_mark() {
+        let mut sut = set_up(None);
+        assert_eq!(
+            sut.to_string(),
+            "stack high-water mark: stack pointer never initialised\n"
+        );
+
+        execute(&mut sut, 0x40, NOP, 0);
+        execute(&mut sut, 0x42, NOP, 0x25f);
+        execute(&mut sut, 0x44, RCALL, 0x25d);
+        execute(&mut sut, 0x80, RET, 0x25f);
+
+        assert_eq!(sut.get_max_depth(), 2);
+        assert_eq!(
+            sut.to_string(),
+            "stack high-water mark: 2 bytes, lowest SP 0x025d, limit 0x0140, max call depth 1, 0 overflow(s)\n"
+        );
+    }
+}
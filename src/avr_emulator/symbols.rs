@@ -0,0 +1,135 @@
+// Data space addresses of symbols are offset like avr-gdb does, flash addresses are byte addresses
+pub const DATA_OFFSET: u32 = 0x80_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymbolKind {
+    Function,
+    Object,
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub address: u32,
+    pub size: u32,
+    pub kind: SymbolKind,
+}
+
+impl Symbol {
+    fn is_in_data_space(address: u32) -> bool {
+        address >= DATA_OFFSET
+    }
+
+    fn contains(&self, address: u32) -> bool {
+        address >= self.address
+            && (self.size == 0 || address - self.address < self.size)
+            && Self::is_in_data_space(address) == Self::is_in_data_space(self.address)
+    }
+}
+
+// Symbols sorted by address, looked up by name or by an address within them
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    pub fn new(mut symbols: Vec<Symbol>) -> Self {
+        // functions and objects are preferred over labels at the same address
+        symbols.sort_by_key(|symbol| (symbol.address, symbol.kind != SymbolKind::Other));
+        Self { symbols }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn get_symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    // Closest symbol at or before the address that still covers it, labels without a size
+    // cover everything up to the next symbol
+    pub fn lookup(&self, address: u32) -> Option<(&Symbol, u32)> {
+        let end = self
+            .symbols
+            .partition_point(|symbol| symbol.address <= address);
+
+        self.symbols[..end]
+            .iter()
+            .rev()
+            .find(|symbol| symbol.contains(address))
+            .map(|symbol| (symbol, address - symbol.address))
+    }
+
+    // "main+0x4" for an address within a symbol
+    pub fn symbolize(&self, address: u32) -> Option<String> {
+        match self.lookup(address)? {
+            (symbol, 0) => Some(symbol.name.clone()),
+            (symbol, offset) => Some(format!("{}+{:#x}", symbol.name, offset)),
+        }
+    }
+
+    // The symbolized address or the plain address without a symbol
+    pub fn format_address(&self, address: u32) -> String {
+        self.symbolize(address)
+            .unwrap_or_else(|| format!("{:#06x}", address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbol(name: &str, address: u32, size: u32, kind: SymbolKind) -> Symbol {
+        Symbol {
+            name: name.to_owned(),
+            address,
+            size,
+            kind,
+        }
+    }
+
+    fn set_up() -> SymbolTable {
+        SymbolTable::new(vec![
+            symbol("main", 0x80, 0x10, SymbolKind::Function),
+            symbol("__vectors", 0, 0, SymbolKind::Other),
+            symbol("__bad_interrupt", 0x7c, 0, SymbolKind::Other),
+            symbol("__ctors_end", 0x80, 0, SymbolKind::Other),
+            symbol("counter", 0x800100, 2, SymbolKind::Object),
+            symbol("delay", 0xa0, 0x08, SymbolKind::Function),
+        ])
+    }
+
+    #[test]
+    fn test_lookup() {
+        let sut = set_up();
+
+        assert_eq!(sut.format_address(0x84), "main+0x4");
+        assert_eq!(sut.format_address(0x80), "main");
+        assert_eq!(sut.format_address(0x26), "__vectors+0x26");
+        assert_eq!(sut.format_address(0x7e), "__bad_interrupt+0x2");
+
+        // behind main the label at its address covers the rest
+        assert_eq!(sut.format_address(0x92), "__ctors_end+0x12");
+
+        assert_eq!(sut.format_address(0x800101), "counter+0x1");
+        assert_eq!(sut.format_address(0x800102), "0x800102");
+        assert_eq!(sut.format_address(0x8000ff), "0x8000ff");
+        assert_eq!(sut.symbolize(0x8000ff), None);
+    }
+
+    #[test]
+    fn test_find() {
+        let sut = set_up();
+
+        assert_eq!(sut.find("delay").map(|symbol| symbol.address), Some(0xa0));
+        assert!(sut.find("loop").is_none());
+        assert_eq!(sut.get_symbols()[0].name, "__vectors");
+    }
+}
@@ -0,0 +1,191 @@
+use std::sync::{Arc, Mutex};
+
+use crate::avr_emulator::clock;
+use crate::avr_emulator::memory::Memory;
+
+// CLKPR
+const CLKPCE: u8 = 1 << 7;
+const CLKPS: u8 = 0b0000_1111;
+
+// CLKPS divides the clock source by 1 up to 256, larger values are reserved
+const MAX_CLOCK_SELECT: u8 = 8;
+
+// Initial CLKPS when the CKDIV8 fuse is programmed
+pub const CLOCK_SELECT_DIVIDED_BY_8: u8 = 3;
+
+// CLKPS has to be written within four cycles after setting CLKPCE,
+// afterwards CLKPCE is cleared by hardware
+const CHANGE_ENABLE_CYCLES: u8 = 4;
+
+pub fn get_division(clock_select: u8) -> u16 {
+    1 << clock_select
+}
+
+// The new division is picked up by the clock through Memory::get_clock_division
+pub struct SystemClockPrescaler {
+    rising_edge_notified: std::sync::atomic::AtomicBool,
+    memory: Arc<Mutex<Memory>>,
+    register: usize,
+    change_enable_cycles: u8,
+}
+
+impl SystemClockPrescaler {
+    pub fn new(memory: Arc<Mutex<Memory>>, register: usize) -> Self {
+        Self {
+            rising_edge_notified: std::sync::atomic::AtomicBool::new(false),
+            memory,
+            register,
+            change_enable_cycles: 0,
+        }
+    }
+
+    fn handle_register_write(&mut self, memory: &mut Memory) {
+        let Some(write) = memory.take_io_write(self.register) else {
+            return;
+        };
+
+        let mut value = write.old & CLKPS;
+
+        if write.new == CLKPCE {
+            self.change_enable_cycles = CHANGE_ENABLE_CYCLES;
+            value |= CLKPCE;
+        } else if self.change_enable_cycles > 0 && write.new & CLKPCE == 0 {
+            self.change_enable_cycles = 0;
+
+            let clock_select = write.new & CLKPS;
+            if clock_select > MAX_CLOCK_SELECT {
+                log::warn!(
+                    "SystemClockPrescaler reserved division {:#06b}, ignored",
+                    clock_select
+                );
+            } else {
+                value = clock_select;
+                memory.set_clock_division(get_division(clock_select));
+                log::info!(
+                    "SystemClockPrescaler clock divided by {}",
+                    get_division(clock_select)
+                );
+            }
+        } else {
+            log::warn!("SystemClockPrescaler CLKPR written without the timed sequence, ignored");
+        }
+
+        memory.poke(self.register, value);
+    }
+
+    fn update(&mut self) {
+        let memory = self.memory.clone();
+        let mut memory = memory.lock().unwrap();
+
+        self.handle_register_write(&mut memory);
+
+        if self.change_enable_cycles > 0 {
+            self.change_enable_cycles -= 1;
+
+            if self.change_enable_cycles == 0 {
+                let value = memory.peek(self.register);
+                memory.poke(self.register, value & !CLKPCE);
+            }
+        }
+    }
+}
+
+impl clock::Subscriber for SystemClockPrescaler {
+    fn notify_rising_edge(&self) {
+        log::debug!("SystemClockPrescaler rising edge notified");
+
+        if self
+            .rising_edge_notified
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            log::warn!("SystemClockPrescaler did not finish handling previous rising edge!");
+        }
+
+        self.rising_edge_notified
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+    fn notify_falling_edge(&self) {}
+
+    fn run(&mut self) {
+        if self
+            .rising_edge_notified
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            self.update();
+
+            self.rising_edge_notified
+                .store(false, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.change_enable_cycles = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avr_emulator::test_utils::clock_cycles;
+
+    fn set_up() -> (Arc<Mutex<Memory>>, SystemClockPrescaler) {
+        let memory = Arc::new(Mutex::new(Memory::new(300, vec![]).unwrap()));
+
+        let prescaler = SystemClockPrescaler::new(memory.clone(), 0x61);
+
+        (memory, prescaler)
+    }
+
+    #[test]
+    fn test_timed_sequence() {
+        let (memory, mut sut) = set_up();
+
+        memory.lock().unwrap().set_sram(0x61, CLKPCE);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x61), CLKPCE);
+
+        memory.lock().unwrap().set_sram(0x61, 0b0000_0100);
+        clock_cycles(&mut sut, 1);
+
+        assert_eq!(memory.lock().unwrap().peek(0x61), 0b0000_0100);
+        assert_eq!(memory.lock().unwrap().get_clock_division(), 16);
+    }
+
+    #[test]
+    fn test_write_without_change_enable_is_ignored() {
+        let (memory, mut sut) = set_up();
+
+        memory.lock().unwrap().set_sram(0x61, 0b0000_0011);
+        clock_cycles(&mut sut, 1);
+
+        assert_eq!(memory.lock().unwrap().peek(0x61), 0);
+        assert_eq!(memory.lock().unwrap().get_clock_division(), 1);
+    }
+
+    #[test]
+    fn test_change_enable_times_out() {
+        let (memory, mut sut) = set_up();
+
+        memory.lock().unwrap().set_sram(0x61, CLKPCE);
+        clock_cycles(&mut sut, 4);
+        assert_eq!(memory.lock().unwrap().peek(0x61), 0);
+
+        memory.lock().unwrap().set_sram(0x61, 0b0000_0001);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().get_clock_division(), 1);
+    }
+
+    #[test]
+    fn test_reserved_division_is_ignored() {
+        let (memory, mut sut) = set_up();
+        memory.lock().unwrap().poke(0x61, 0b0000_0011);
+
+        memory.lock().unwrap().set_sram(0x61, CLKPCE);
+        clock_cycles(&mut sut, 1);
+        memory.lock().unwrap().set_sram(0x61, 0b0000_1001);
+        clock_cycles(&mut sut, 1);
+
+        assert_eq!(memory.lock().unwrap().peek(0x61), 0b0000_0011);
+        assert_eq!(memory.lock().unwrap().get_clock_division(), 1);
+    }
+}
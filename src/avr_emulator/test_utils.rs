@@ -0,0 +1,36 @@
+// Helpers shared by the tests of several modules
+use std::sync::{Arc, Mutex};
+
+use crate::avr_emulator::clock::Subscriber;
+
+pub fn clock_cycles<S: Subscriber + ?Sized>(subscriber: &mut S, count: u32) {
+    for _ in 0..count {
+        subscriber.notify_rising_edge();
+        subscriber.run();
+    }
+}
+
+// Collects what is written to a trace or dump for inspection
+#[derive(Clone, Default)]
+pub struct Output(Arc<Mutex<Vec<u8>>>);
+
+impl std::io::Write for Output {
+    fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buffer);
+        Ok(buffer.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Output {
+    pub fn text(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        self.text().lines().map(str::to_owned).collect()
+    }
+}
@@ -1,79 +1,64 @@
 use std::sync::{Arc, Mutex};
 
-use crate::avr_emulator::clock::Subscriber;
+use crate::avr_emulator::device::{RegisterBit, TimerRegisters};
 use crate::avr_emulator::memory::Memory;
+use crate::avr_emulator::prescaler::{self, PinEdge, PrescalerTick};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClockSource {
+    Stopped,
+    Prescaled(u16),
+    ExternalFalling,
+    ExternalRising,
+}
 
 pub struct Timer {
-    rising_edge_notified: std::sync::atomic::AtomicBool,
     memory: Arc<Mutex<Memory>>,
-    current_clock: u32,
+    registers: &'static TimerRegisters,
 }
 
-impl<'a> Subscriber for Timer {
-    fn notify_rising_edge(&self) {
-        log::debug!("InstructionExecutor rising edge notified");
+impl prescaler::Subscriber for Timer {
+    fn get_external_clock_pin(&self) -> Option<RegisterBit> {
+        Some(self.registers.external_clock_pin)
+    }
 
-        if self
-            .rising_edge_notified
-            .load(std::sync::atomic::Ordering::Relaxed)
-        {
-            log::warn!("Timer did not finish handling previous rising edge!");
-        }
+    fn notify_tick(&mut self, tick: &PrescalerTick) {
+        let count = match self.get_clock_source() {
+            ClockSource::Stopped => false,
+            ClockSource::Prescaled(divisor) => tick.is_tap(divisor),
+            ClockSource::ExternalFalling => tick.external_pin_edge == PinEdge::Falling,
+            ClockSource::ExternalRising => tick.external_pin_edge == PinEdge::Rising,
+        };
 
-        self.rising_edge_notified
-            .store(true, std::sync::atomic::Ordering::Relaxed);
-    }
-    fn notify_falling_edge(&self) {}
-
-    fn run(&mut self) {
-        if self
-            .rising_edge_notified
-            .load(std::sync::atomic::Ordering::Relaxed)
-        {
-            let prescaler = self.get_prescaler();
-
-            if prescaler != 0 {
-                self.current_clock += 1;
-
-                if self.current_clock % prescaler == 0 || self.current_clock > prescaler {
-                    self.current_clock = 0;
-                    self.increment_tcnt0();
-                }
-            }
-
-            self.rising_edge_notified
-                .store(false, std::sync::atomic::Ordering::Relaxed)
+        if count {
+            self.increment_tcnt0();
         }
     }
 }
 
 impl Timer {
-    pub fn new(memory: Arc<Mutex<Memory>>) -> Self {
-        Self {
-            rising_edge_notified: std::sync::atomic::AtomicBool::new(false),
-            memory: memory,
-            current_clock: 0,
-        }
+    pub fn new(memory: Arc<Mutex<Memory>>, registers: &'static TimerRegisters) -> Self {
+        Self { memory, registers }
     }
 
-    fn get_prescaler(&self) -> u32 {
-        match self.memory.lock().unwrap().get_io(51).unwrap() {
-            1 => 1,
-            2 => 8,
-            3 => 64,
-            4 => 256,
-            5 => 1024,
-            _ => 0,
-        }
+    fn get_clock_source(&self) -> ClockSource {
+        let clock_select = self.memory.lock().unwrap().peek(self.registers.control) & 0b0000_0111;
+
+        self.registers.clock_sources[clock_select as usize]
     }
-    fn increment_tcnt0(&mut self) -> () {
-        if self.memory.lock().unwrap().get_io(50).unwrap() == 255 {
-            self.memory.lock().unwrap().set_io(50, 0);
-            let register_value = self.memory.lock().unwrap().get_io(56).unwrap();
-            self.memory.lock().unwrap().set_io(56, register_value | 1);
+
+    fn increment_tcnt0(&mut self) {
+        let mut memory = self.memory.lock().unwrap();
+
+        let counter = memory.peek(self.registers.counter);
+
+        if counter == 255 {
+            memory.poke(self.registers.counter, 0);
+
+            memory.set_bit(&self.registers.overflow_flag, true);
+            memory.raise_event("Timer0", format_args!("overflow"));
         } else {
-            let register_value = self.memory.lock().unwrap().get_io(50).unwrap();
-            self.memory.lock().unwrap().set_io(50, register_value + 1);
+            memory.poke(self.registers.counter, counter + 1);
         }
     }
 }
@@ -81,40 +66,54 @@ impl Timer {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::avr_emulator::device;
+    use crate::avr_emulator::prescaler::Subscriber;
+
+    fn tick(counter: u16) -> PrescalerTick {
+        PrescalerTick {
+            counter: counter & 0x03ff,
+            external_pin_edge: PinEdge::None,
+        }
+    }
 
     #[test]
-    fn test_get_prescaler() {
+    fn test_get_clock_source() {
         let memory = Arc::new(Mutex::new(Memory::new(100, vec![]).unwrap()));
 
-        let sut = Timer::new(memory.clone());
+        let sut = Timer::new(memory.clone(), &device::ATMEGA8.timer0);
 
-        assert_eq!(sut.get_prescaler(), 0);
+        assert_eq!(sut.get_clock_source(), ClockSource::Stopped);
 
         memory.lock().unwrap().set_io(51, 1);
-        assert_eq!(sut.get_prescaler(), 1);
+        assert_eq!(sut.get_clock_source(), ClockSource::Prescaled(1));
 
         memory.lock().unwrap().set_io(51, 2);
-        assert_eq!(sut.get_prescaler(), 8);
+        assert_eq!(sut.get_clock_source(), ClockSource::Prescaled(8));
 
         memory.lock().unwrap().set_io(51, 3);
-        assert_eq!(sut.get_prescaler(), 64);
+        assert_eq!(sut.get_clock_source(), ClockSource::Prescaled(64));
 
         memory.lock().unwrap().set_io(51, 4);
-        assert_eq!(sut.get_prescaler(), 256);
+        assert_eq!(sut.get_clock_source(), ClockSource::Prescaled(256));
 
         memory.lock().unwrap().set_io(51, 5);
-        assert_eq!(sut.get_prescaler(), 1024);
+        assert_eq!(sut.get_clock_source(), ClockSource::Prescaled(1024));
+
+        memory.lock().unwrap().set_io(51, 6);
+        assert_eq!(sut.get_clock_source(), ClockSource::ExternalFalling);
+
+        memory.lock().unwrap().set_io(51, 7);
+        assert_eq!(sut.get_clock_source(), ClockSource::ExternalRising);
     }
 
     #[test]
     fn test_run_prescaler_0() {
         let memory = Arc::new(Mutex::new(Memory::new(100, vec![]).unwrap()));
 
-        let mut sut = Timer::new(memory.clone());
+        let mut sut = Timer::new(memory.clone(), &device::ATMEGA8.timer0);
 
-        for _ in 0..10 {
-            sut.notify_rising_edge();
-            sut.run();
+        for i in 1..=10 {
+            sut.notify_tick(&tick(i));
         }
 
         assert_eq!(memory.lock().unwrap().get_io(50).unwrap(), 0);
@@ -126,11 +125,10 @@ mod tests {
         let memory = Arc::new(Mutex::new(Memory::new(100, vec![]).unwrap()));
         memory.lock().unwrap().set_io(51, 1);
 
-        let mut sut = Timer::new(memory.clone());
+        let mut sut = Timer::new(memory.clone(), &device::ATMEGA8.timer0);
 
-        for _ in 0..255 {
-            sut.notify_rising_edge();
-            sut.run();
+        for i in 1..=255 {
+            sut.notify_tick(&tick(i));
         }
 
         assert_eq!(memory.lock().unwrap().get_io(50).unwrap(), 255);
@@ -142,11 +140,10 @@ mod tests {
         let memory = Arc::new(Mutex::new(Memory::new(100, vec![]).unwrap()));
         memory.lock().unwrap().set_io(51, 2);
 
-        let mut sut = Timer::new(memory.clone());
+        let mut sut = Timer::new(memory.clone(), &device::ATMEGA8.timer0);
 
-        for _ in 0..(255 * 8) {
-            sut.notify_rising_edge();
-            sut.run();
+        for i in 1..=(255 * 8) {
+            sut.notify_tick(&tick(i));
         }
 
         assert_eq!(memory.lock().unwrap().get_io(50).unwrap(), 255);
@@ -157,31 +154,100 @@ mod tests {
     fn test_run_prescaler_1_overflow() {
         let memory = Arc::new(Mutex::new(Memory::new(100, vec![]).unwrap()));
         memory.lock().unwrap().set_io(51, 1);
+        memory.lock().unwrap().set_record_events(true);
 
-        let mut sut = Timer::new(memory.clone());
+        let mut sut = Timer::new(memory.clone(), &device::ATMEGA8.timer0);
 
-        for _ in 0..256 {
-            sut.notify_rising_edge();
-            sut.run();
+        for i in 1..=256 {
+            sut.notify_tick(&tick(i));
         }
 
         assert_eq!(memory.lock().unwrap().get_io(50).unwrap(), 0);
         assert_eq!(memory.lock().unwrap().get_io(56).unwrap(), 1);
+
+        let events = memory.lock().unwrap().take_events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            (events[0].peripheral, events[0].event.as_str()),
+            ("Timer0", "overflow")
+        );
     }
 
     #[test]
-    fn test_run_falling_edge() {
+    fn test_run_prescaler_reset_restarts_period() {
         let memory = Arc::new(Mutex::new(Memory::new(100, vec![]).unwrap()));
-        memory.lock().unwrap().set_io(51, 1);
+        memory.lock().unwrap().set_io(51, 3);
 
-        let mut sut = Timer::new(memory.clone());
+        let mut sut = Timer::new(memory.clone(), &device::ATMEGA8.timer0);
 
-        for _ in 0..10 {
-            sut.notify_falling_edge();
-            sut.run();
+        for i in 1..=63 {
+            sut.notify_tick(&tick(i));
+        }
+        // prescaler reset, counting starts from the beginning
+        for i in 1..=63 {
+            sut.notify_tick(&tick(i));
         }
 
         assert_eq!(memory.lock().unwrap().get_io(50).unwrap(), 0);
-        assert_eq!(memory.lock().unwrap().get_io(56).unwrap(), 0);
+
+        sut.notify_tick(&tick(64));
+        assert_eq!(memory.lock().unwrap().get_io(50).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_run_external_clock() {
+        let memory = Arc::new(Mutex::new(Memory::new(100, vec![]).unwrap()));
+
+        let mut sut = Timer::new(memory.clone(), &device::ATMEGA8.timer0);
+        assert_eq!(
+            sut.get_external_clock_pin(),
+            Some(device::ATMEGA8.timer0.external_clock_pin)
+        );
+
+        let edges = [
+            PinEdge::Rising,
+            PinEdge::None,
+            PinEdge::Falling,
+            PinEdge::Rising,
+        ];
+
+        for edge in edges {
+            sut.notify_tick(&PrescalerTick {
+                counter: 0,
+                external_pin_edge: edge,
+            });
+        }
+        assert_eq!(memory.lock().unwrap().get_io(50).unwrap(), 0);
+
+        memory.lock().unwrap().set_io(51, 6);
+        for edge in edges {
+            sut.notify_tick(&PrescalerTick {
+                counter: 0,
+                external_pin_edge: edge,
+            });
+        }
+        assert_eq!(memory.lock().unwrap().get_io(50).unwrap(), 1);
+
+        memory.lock().unwrap().set_io(51, 7);
+        for edge in edges {
+            sut.notify_tick(&PrescalerTick {
+                counter: 0,
+                external_pin_edge: edge,
+            });
+        }
+        assert_eq!(memory.lock().unwrap().get_io(50).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_run_atmega88_registers() {
+        let memory = Arc::new(Mutex::new(Memory::new(200, vec![]).unwrap()));
+        memory.lock().unwrap().set_sram(0x45, 1);
+        memory.lock().unwrap().set_sram(0x46, 255);
+
+        let mut sut = Timer::new(memory.clone(), &device::ATMEGA88.timer0);
+        sut.notify_tick(&tick(1));
+
+        assert_eq!(memory.lock().unwrap().get_sram(0x46).unwrap(), 0);
+        assert_eq!(memory.lock().unwrap().get_sram(0x35).unwrap(), 1);
     }
 }
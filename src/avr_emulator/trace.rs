@@ -0,0 +1,325 @@
+use std::io::Write;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+use crate::avr_emulator::number::parse_number;
+use crate::avr_emulator::symbols::SymbolTable;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    Text,
+    Csv,
+    JsonLines,
+}
+
+pub fn parse_format(value: &str) -> Result<TraceFormat, String> {
+    match value {
+        "text" => Ok(TraceFormat::Text),
+        "csv" => Ok(TraceFormat::Csv),
+        "jsonl" | "json" => Ok(TraceFormat::JsonLines),
+        _ => Err(format!("invalid trace format: {}", value)),
+    }
+}
+
+fn parse_address(value: &str) -> Result<u32, String> {
+    parse_number(value).ok_or_else(|| format!("invalid address: {}", value))
+}
+
+// Flash byte addresses like 0x100-0x1ff, both ends included
+pub fn parse_range(value: &str) -> Result<RangeInclusive<u32>, String> {
+    let (start, end) = value
+        .split_once('-')
+        .ok_or(format!("invalid address range: {}", value))?;
+
+    Ok(parse_address(start)?..=parse_address(end)?)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceRecord {
+    pub cycle: u64,
+    // byte address
+    pub pc: u32,
+    pub opcode: Vec<u8>,
+    pub disassembly: String,
+    // registers written by the instruction with their new values
+    pub changes: Vec<(usize, u8)>,
+    pub sreg: u8,
+}
+
+impl TraceRecord {
+    // Registers differing between the register file before and after the instruction
+    pub fn find_changes(before: &[u8], after: &[u8]) -> Vec<(usize, u8)> {
+        before
+            .iter()
+            .zip(after)
+            .enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(register, (_, new))| (register, *new))
+            .collect()
+    }
+}
+
+// Flags set in the status register as upper case letters like I-----Z-
+pub fn format_sreg(sreg: u8) -> String {
+    "ITHSVNZC"
+        .chars()
+        .enumerate()
+        .map(|(index, flag)| {
+            if sreg & (0x80 >> index) != 0 {
+                flag
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+fn format_changes(changes: &[(usize, u8)]) -> String {
+    changes
+        .iter()
+        .map(|(register, value)| format!("r{}={:#04x}", register, value))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn format_opcode(opcode: &[u8]) -> String {
+    opcode
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn quote_csv(field: &str) -> String {
+    if field.contains([',', '"']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+fn quote_json(field: &str) -> String {
+    let mut quoted = String::from("\"");
+    for character in field.chars() {
+        match character {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            character if character.is_control() => {
+                quoted.push_str(&format!("\\u{:04x}", character as u32))
+            }
+            character => quoted.push(character),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// Writes one record per executed instruction, restricted to the added address ranges and
+// functions when there are any
+pub struct Tracer {
+    writer: Box<dyn Write + Send>,
+    format: TraceFormat,
+    symbols: Arc<SymbolTable>,
+    ranges: Vec<RangeInclusive<u32>>,
+    header_written: bool,
+}
+
+impl Tracer {
+    pub fn new(
+        writer: Box<dyn Write + Send>,
+        format: TraceFormat,
+        symbols: Arc<SymbolTable>,
+    ) -> Self {
+        Self {
+            writer,
+            format,
+            symbols,
+            ranges: vec![],
+            header_written: false,
+        }
+    }
+
+    pub fn add_range(&mut self, range: RangeInclusive<u32>) {
+        self.ranges.push(range);
+    }
+
+    pub fn add_function(&mut self, name: &str) -> Result<(), String> {
+        let symbol = self
+            .symbols
+            .find(name)
+            .ok_or(format!("unknown function: {}", name))?;
+
+        if symbol.size == 0 {
+            return Err(format!("size of function {} is unknown", name));
+        }
+
+        self.ranges
+            .push(symbol.address..=symbol.address + symbol.size - 1);
+        Ok(())
+    }
+
+    pub fn is_traced(&self, pc: u32) -> bool {
+        self.ranges.is_empty() || self.ranges.iter().any(|range| range.contains(&pc))
+    }
+
+    pub fn record(&mut self, record: &TraceRecord) -> std::io::Result<()> {
+        let symbol = self.symbols.symbolize(record.pc).unwrap_or_default();
+
+        match self.format {
+            TraceFormat::Text => {
+                let line = format!(
+                    "{:>10} {:#06x} {:<24} {:<11} {:<24} {} {}",
+                    record.cycle,
+                    record.pc,
+                    symbol,
+                    format_opcode(&record.opcode),
+                    record.disassembly,
+                    format_sreg(record.sreg),
+                    format_changes(&record.changes)
+                );
+                writeln!(self.writer, "{}", line.trim_end())
+            }
+            TraceFormat::Csv => {
+                if !std::mem::replace(&mut self.header_written, true) {
+                    writeln!(
+                        self.writer,
+                        "cycle,pc,symbol,opcode,disassembly,changes,sreg"
+                    )?;
+                }
+
+                writeln!(
+                    self.writer,
+                    "{},{:#06x},{},{},{},{},{}",
+                    record.cycle,
+                    record.pc,
+                    quote_csv(&symbol),
+                    format_opcode(&record.opcode),
+                    quote_csv(&record.disassembly),
+                    format_changes(&record.changes),
+                    format_sreg(record.sreg)
+                )
+            }
+            TraceFormat::JsonLines => {
+                let changes = record
+                    .changes
+                    .iter()
+                    .map(|(register, value)| format!("\"r{}\":{}", register, value))
+                    .collect::<Vec<_>>()
+                    .join(",");
+
+                writeln!(
+                    self.writer,
+                    "{{\"cycle\":{},\"pc\":{},\"symbol\":{},\"opcode\":\"{}\",\"disassembly\":{},\"changes\":{{{}}},\"sreg\":{}}}",
+                    record.cycle,
+                    record.pc,
+                    quote_json(&symbol),
+                    format_opcode(&record.opcode).replace(' ', ""),
+                    quote_json(&record.disassembly),
+                    changes,
+                    record.sreg
+                )
+            }
+        }
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avr_emulator::symbols::{Symbol, SymbolKind};
+    use crate::avr_emulator::test_utils::Output;
+
+    fn set_up(format: TraceFormat) -> (Output, Tracer) {
+        let output = Output::default();
+        let symbols = SymbolTable::new(vec![Symbol {
+            name: "main".to_owned(),
+            address: 0x40,
+            size: 0x10,
+            kind: SymbolKind::Function,
+        }]);
+
+        let sut = Tracer::new(Box::new(output.clone()), format, Arc::new(symbols));
+        (output, sut)
+    }
+
+    fn record() -> TraceRecord {
+        TraceRecord {
+            cycle: 12,
+            pc: 0x44,
+            opcode: vec![0x85, 0xe0],
+            disassembly: "ldi r24, 5".to_owned(),
+            changes: TraceRecord::find_changes(&[0, 1, 2], &[0, 1, 5]),
+            sreg: 0b1000_0010,
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_format("csv"), Ok(TraceFormat::Csv));
+        assert!(parse_format("xml").is_err());
+        assert_eq!(parse_range("0x100-0x1ff"), Ok(0x100..=0x1ff));
+        assert_eq!(parse_range("16-32"), Ok(16..=32));
+        assert!(parse_range("0x100").is_err());
+    }
+
+    #[test]
+    fn test_text() {
+        let (output, mut sut) = set_up(TraceFormat::Text);
+
+        sut.record(&record()).unwrap();
+
+        assert_eq!(
+            output.lines(),
+            ["        12 0x0044 main+0x4                 85 e0       ldi r24, 5               I-----Z- r2=0x05"]
+        );
+    }
+
+    #[test]
+    fn test_csv() {
+        let (output, mut sut) = set_up(TraceFormat::Csv);
+
+        sut.record(&record()).unwrap();
+        sut.record(&record()).unwrap();
+
+        assert_eq!(
+            output.lines(),
+            [
+                "cycle,pc,symbol,opcode,disassembly,changes,sreg",
+                "12,0x0044,main+0x4,85 e0,\"ldi r24, 5\",r2=0x05,I-----Z-",
+                "12,0x0044,main+0x4,85 e0,\"ldi r24, 5\",r2=0x05,I-----Z-",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let (output, mut sut) = set_up(TraceFormat::JsonLines);
+
+        sut.record(&record()).unwrap();
+
+        assert_eq!(
+            output.lines(),
+            ["{\"cycle\":12,\"pc\":68,\"symbol\":\"main+0x4\",\"opcode\":\"85e0\",\"disassembly\":\"ldi r24, 5\",\"changes\":{\"r2\":5},\"sreg\":130}"]
+        );
+    }
+
+    #[test]
+    fn test_filters() {
+        let (_, mut sut) = set_up(TraceFormat::Text);
+        assert!(sut.is_traced(0x1000));
+
+        sut.add_function("main").unwrap();
+        sut.add_range(0x100..=0x101);
+        assert!(sut.add_function("loop").is_err());
+
+        assert!(sut.is_traced(0x40));
+        assert!(sut.is_traced(0x4e));
+        assert!(!sut.is_traced(0x50));
+        assert!(sut.is_traced(0x101));
+        assert!(!sut.is_traced(0x102));
+    }
+}
@@ -0,0 +1,713 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::avr_emulator::clock;
+use crate::avr_emulator::device::TwiRegisters;
+use crate::avr_emulator::i2c::{I2cBus, I2cTransaction, I2cTransactionResult};
+use crate::avr_emulator::memory::Memory;
+
+// TWCR
+const TWINT: u8 = 1 << 7;
+const TWEA: u8 = 1 << 6;
+const TWSTA: u8 = 1 << 5;
+const TWSTO: u8 = 1 << 4;
+const TWWC: u8 = 1 << 3;
+const TWEN: u8 = 1 << 2;
+
+// TWSR
+const TWPS: u8 = 0b0000_0011;
+
+// TWAR
+const TWGCE: u8 = 1 << 0;
+
+// Status codes
+const START: u8 = 0x08;
+const REPEATED_START: u8 = 0x10;
+const SLA_W_ACK: u8 = 0x18;
+const SLA_W_NACK: u8 = 0x20;
+const DATA_SENT_ACK: u8 = 0x28;
+const DATA_SENT_NACK: u8 = 0x30;
+const SLA_R_ACK: u8 = 0x40;
+const SLA_R_NACK: u8 = 0x48;
+const DATA_RECEIVED_ACK: u8 = 0x50;
+const DATA_RECEIVED_NACK: u8 = 0x58;
+const OWN_SLA_W: u8 = 0x60;
+const GENERAL_CALL: u8 = 0x70;
+const SLAVE_DATA_ACK: u8 = 0x80;
+const SLAVE_DATA_NACK: u8 = 0x88;
+const GENERAL_CALL_DATA_ACK: u8 = 0x90;
+const GENERAL_CALL_DATA_NACK: u8 = 0x98;
+const SLAVE_STOP: u8 = 0xa0;
+const OWN_SLA_R: u8 = 0xa8;
+const SLAVE_DATA_SENT_ACK: u8 = 0xb8;
+const SLAVE_DATA_SENT_NACK: u8 = 0xc0;
+const NO_STATE: u8 = 0xf8;
+
+// An external master may clock SCL at up to a sixteenth of the cpu clock
+const SLAVE_BIT_CYCLES: u32 = 16;
+
+// Bus transfers take a start bit or a byte plus acknowledge
+const START_BITS: u32 = 1;
+const BYTE_BITS: u32 = 9;
+
+#[derive(Debug, Clone, PartialEq)]
+enum State {
+    Idle,
+    MasterStarted {
+        previous_address: Option<u8>,
+    },
+    MasterTransmitter {
+        address: u8,
+    },
+    MasterReceiver {
+        address: u8,
+    },
+    SlaveReceiver {
+        data: VecDeque<u8>,
+        general_call: bool,
+    },
+    SlaveStopped,
+    SlaveTransmitter {
+        remaining: usize,
+        sent: Vec<u8>,
+    },
+    SlaveTransmitted,
+}
+
+// Operation on the bus finishing with TWINT set and the given status
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PendingOperation {
+    remaining_cycles: u32,
+    status: u8,
+    data: Option<u8>,
+}
+
+pub struct Twi {
+    rising_edge_notified: std::sync::atomic::AtomicBool,
+    memory: Arc<Mutex<Memory>>,
+    registers: &'static TwiRegisters,
+    bus: Arc<Mutex<I2cBus>>,
+    state: State,
+    pending: Option<PendingOperation>,
+}
+
+impl Twi {
+    pub fn new(
+        memory: Arc<Mutex<Memory>>,
+        registers: &'static TwiRegisters,
+        bus: Arc<Mutex<I2cBus>>,
+    ) -> Self {
+        memory.lock().unwrap().poke(registers.status, NO_STATE);
+
+        Self {
+            rising_edge_notified: std::sync::atomic::AtomicBool::new(false),
+            memory,
+            registers,
+            bus,
+            state: State::Idle,
+            pending: None,
+        }
+    }
+
+    // SCL period is 16 + 2 * TWBR * 4^TWPS cpu cycles
+    fn get_master_bit_cycles(&self, memory: &Memory) -> u32 {
+        let bit_rate = memory.peek(self.registers.bit_rate) as u32;
+        let prescaler = 1 << (2 * (memory.peek(self.registers.status) & TWPS) as u32);
+
+        16 + 2 * bit_rate * prescaler
+    }
+
+    fn set_status(&self, memory: &mut Memory, status: u8) {
+        let prescaler = memory.peek(self.registers.status) & TWPS;
+        memory.poke(self.registers.status, status | prescaler);
+    }
+
+    fn master_operation(&self, memory: &Memory, bits: u32, status: u8) -> PendingOperation {
+        PendingOperation {
+            remaining_cycles: bits * self.get_master_bit_cycles(memory),
+            status,
+            data: None,
+        }
+    }
+
+    fn slave_operation(bits: u32, status: u8, data: Option<u8>) -> PendingOperation {
+        PendingOperation {
+            remaining_cycles: bits * SLAVE_BIT_CYCLES,
+            status,
+            data,
+        }
+    }
+
+    fn get_master_address(&self) -> Option<u8> {
+        match self.state {
+            State::MasterStarted { previous_address } => previous_address,
+            State::MasterTransmitter { address } | State::MasterReceiver { address } => {
+                Some(address)
+            }
+            _ => None,
+        }
+    }
+
+    fn is_master(&self) -> bool {
+        matches!(
+            self.state,
+            State::MasterStarted { .. }
+                | State::MasterTransmitter { .. }
+                | State::MasterReceiver { .. }
+        )
+    }
+
+    fn stop(&mut self, memory: &mut Memory) {
+        if let Some(address) = self.get_master_address() {
+            self.bus.lock().unwrap().stop(address);
+        }
+
+        self.state = State::Idle;
+
+        let control = memory.peek(self.registers.control);
+        memory.poke(self.registers.control, control & !TWSTO);
+        self.set_status(memory, NO_STATE);
+    }
+
+    // Software cleared TWINT, start the next step of the transfer
+    fn start_operation(&mut self, memory: &mut Memory) -> Option<PendingOperation> {
+        let control = memory.peek(self.registers.control);
+        let data = memory.peek(self.registers.data);
+        let acknowledge = control & TWEA != 0;
+
+        if control & TWSTO != 0 && self.is_master() {
+            self.stop(memory);
+        }
+
+        if control & TWSTA != 0 && !self.is_slave() {
+            let repeated = self.is_master();
+            self.state = State::MasterStarted {
+                previous_address: self.get_master_address(),
+            };
+
+            let status = if repeated { REPEATED_START } else { START };
+            return Some(self.master_operation(memory, START_BITS, status));
+        }
+
+        let mut bus = self.bus.lock().unwrap();
+
+        match std::mem::replace(&mut self.state, State::Idle) {
+            State::Idle => None,
+            State::MasterStarted { previous_address } => {
+                let address = data >> 1;
+                let read = data & 1 != 0;
+
+                // a repeated start addressing another device ends the previous transfer
+                if let Some(previous_address) = previous_address.filter(|&a| a != address) {
+                    bus.stop(previous_address);
+                }
+                let acknowledged = bus.start(address, read);
+
+                let status = match (read, acknowledged) {
+                    (false, true) => SLA_W_ACK,
+                    (false, false) => SLA_W_NACK,
+                    (true, true) => SLA_R_ACK,
+                    (true, false) => SLA_R_NACK,
+                };
+
+                self.state = if read {
+                    State::MasterReceiver { address }
+                } else {
+                    State::MasterTransmitter { address }
+                };
+
+                Some(self.master_operation(memory, BYTE_BITS, status))
+            }
+            State::MasterTransmitter { address } => {
+                let status = if bus.write(address, data) {
+                    DATA_SENT_ACK
+                } else {
+                    DATA_SENT_NACK
+                };
+                self.state = State::MasterTransmitter { address };
+
+                Some(self.master_operation(memory, BYTE_BITS, status))
+            }
+            State::MasterReceiver { address } => {
+                let byte = bus.read(address, acknowledge);
+                let status = if acknowledge {
+                    DATA_RECEIVED_ACK
+                } else {
+                    DATA_RECEIVED_NACK
+                };
+                self.state = State::MasterReceiver { address };
+
+                Some(PendingOperation {
+                    data: Some(byte),
+                    ..self.master_operation(memory, BYTE_BITS, status)
+                })
+            }
+            State::SlaveReceiver {
+                mut data,
+                general_call,
+            } => match data.pop_front() {
+                Some(byte) => {
+                    let status = match (general_call, acknowledge) {
+                        (false, true) => SLAVE_DATA_ACK,
+                        (false, false) => SLAVE_DATA_NACK,
+                        (true, true) => GENERAL_CALL_DATA_ACK,
+                        (true, false) => GENERAL_CALL_DATA_NACK,
+                    };
+
+                    // a not acknowledged byte ends the transfer, the master sends a stop
+                    self.state = if acknowledge {
+                        State::SlaveReceiver { data, general_call }
+                    } else {
+                        bus.push_result(I2cTransactionResult {
+                            acknowledged: true,
+                            data: vec![],
+                        });
+                        State::SlaveStopped
+                    };
+
+                    Some(Self::slave_operation(BYTE_BITS, status, Some(byte)))
+                }
+                None => {
+                    bus.push_result(I2cTransactionResult {
+                        acknowledged: true,
+                        data: vec![],
+                    });
+                    self.state = State::SlaveStopped;
+
+                    Some(Self::slave_operation(START_BITS, SLAVE_STOP, None))
+                }
+            },
+            State::SlaveTransmitter {
+                remaining,
+                mut sent,
+            } => {
+                sent.push(data);
+
+                if remaining > 1 {
+                    self.state = State::SlaveTransmitter {
+                        remaining: remaining - 1,
+                        sent,
+                    };
+                    Some(Self::slave_operation(BYTE_BITS, SLAVE_DATA_SENT_ACK, None))
+                } else {
+                    bus.push_result(I2cTransactionResult {
+                        acknowledged: true,
+                        data: sent,
+                    });
+                    self.state = State::SlaveTransmitted;
+                    Some(Self::slave_operation(BYTE_BITS, SLAVE_DATA_SENT_NACK, None))
+                }
+            }
+            State::SlaveStopped | State::SlaveTransmitted => {
+                drop(bus);
+                self.set_status(memory, NO_STATE);
+                None
+            }
+        }
+    }
+
+    fn is_slave(&self) -> bool {
+        matches!(
+            self.state,
+            State::SlaveReceiver { .. }
+                | State::SlaveStopped
+                | State::SlaveTransmitter { .. }
+                | State::SlaveTransmitted
+        )
+    }
+
+    fn handle_register_writes(&mut self, memory: &mut Memory) {
+        if let Some(write) = memory.take_io_write(self.registers.status) {
+            memory.poke(
+                self.registers.status,
+                (write.old & !TWPS) | (write.new & TWPS),
+            );
+        }
+
+        let control = memory.peek(self.registers.control);
+
+        if let Some(write) = memory.take_io_write(self.registers.data) {
+            if control & TWINT == 0 && control & TWEN != 0 {
+                log::warn!("Twi write collision, TWDR written while TWINT is cleared");
+                memory.poke(self.registers.data, write.old);
+                memory.poke(self.registers.control, control | TWWC);
+            }
+        }
+
+        if let Some(write) = memory.take_io_write(self.registers.control) {
+            // TWINT is cleared by writing one to it, TWWC is read only
+            let twint = if write.new & TWINT != 0 {
+                0
+            } else {
+                write.old & TWINT
+            };
+            let twwc = if write.new & TWINT != 0 {
+                0
+            } else {
+                write.old & TWWC
+            };
+            memory.poke(
+                self.registers.control,
+                (write.new & !(TWINT | TWWC)) | twint | twwc,
+            );
+
+            if write.new & TWEN == 0 {
+                self.state = State::Idle;
+                self.pending = None;
+            } else if write.new & TWINT != 0 {
+                self.pending = self.start_operation(memory);
+            }
+        }
+    }
+
+    fn is_own_address(&self, memory: &Memory, address: u8) -> bool {
+        let own_address = memory.peek(self.registers.address);
+
+        address == own_address >> 1 || (address == 0 && own_address & TWGCE != 0)
+    }
+
+    // An external master addresses the mcu while the twi is idle
+    fn handle_external_master(&mut self, memory: &mut Memory) {
+        let control = memory.peek(self.registers.control);
+
+        if self.state != State::Idle || self.pending.is_some() || control & TWEN == 0 {
+            return;
+        }
+
+        let mut bus = self.bus.lock().unwrap();
+        let Some(transaction) = bus.next_transaction() else {
+            return;
+        };
+
+        let address = transaction.get_address();
+        if control & TWEA == 0 || !self.is_own_address(memory, address) {
+            log::debug!("Twi did not acknowledge address {:#04x}", address);
+            bus.push_result(I2cTransactionResult::default());
+            return;
+        }
+
+        let (state, status) = match transaction {
+            I2cTransaction::Write { address, data } => (
+                State::SlaveReceiver {
+                    data: data.into(),
+                    general_call: address == 0,
+                },
+                if address == 0 {
+                    GENERAL_CALL
+                } else {
+                    OWN_SLA_W
+                },
+            ),
+            I2cTransaction::Read { length, .. } => (
+                State::SlaveTransmitter {
+                    remaining: length,
+                    sent: vec![],
+                },
+                OWN_SLA_R,
+            ),
+        };
+
+        self.state = state;
+        self.pending = Some(Self::slave_operation(START_BITS + BYTE_BITS, status, None));
+    }
+
+    fn run_pending_operation(&mut self, memory: &mut Memory) {
+        let Some(mut pending) = self.pending.take() else {
+            return;
+        };
+
+        pending.remaining_cycles = pending.remaining_cycles.saturating_sub(1);
+
+        if pending.remaining_cycles > 0 {
+            self.pending = Some(pending);
+            return;
+        }
+
+        if let Some(data) = pending.data {
+            memory.poke(self.registers.data, data);
+        }
+        self.set_status(memory, pending.status);
+
+        let control = memory.peek(self.registers.control);
+        memory.poke(self.registers.control, control | TWINT);
+        memory.raise_event("Twi", format_args!("status {:#04x}", pending.status));
+    }
+
+    fn update(&mut self) {
+        let memory = self.memory.clone();
+        let mut memory = memory.lock().unwrap();
+
+        self.handle_register_writes(&mut memory);
+        self.handle_external_master(&mut memory);
+        self.run_pending_operation(&mut memory);
+    }
+}
+
+impl clock::Subscriber for Twi {
+    fn notify_rising_edge(&self) {
+        log::debug!("Twi rising edge notified");
+
+        if self
+            .rising_edge_notified
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            log::warn!("Twi did not finish handling previous rising edge!");
+        }
+
+        self.rising_edge_notified
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+    fn notify_falling_edge(&self) {}
+
+    fn run(&mut self) {
+        if self
+            .rising_edge_notified
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            self.update();
+
+            self.rising_edge_notified
+                .store(false, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.state = State::Idle;
+        self.pending = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avr_emulator::clock::Subscriber;
+    use crate::avr_emulator::device;
+    use crate::avr_emulator::i2c::I2cEeprom;
+    use crate::avr_emulator::test_utils::clock_cycles;
+
+    fn set_up() -> (Arc<Mutex<Memory>>, Twi, Arc<Mutex<I2cBus>>) {
+        let memory = Arc::new(Mutex::new(Memory::new(300, vec![]).unwrap()));
+        let bus = Arc::new(Mutex::new(I2cBus::default()));
+        bus.lock()
+            .unwrap()
+            .attach(0x50, Box::new(I2cEeprom::new(256).unwrap()));
+
+        let twi = Twi::new(memory.clone(), &device::ATMEGA8.twi, bus.clone());
+
+        (memory, twi, bus)
+    }
+
+    // Writes TWCR and runs until TWINT is set again, returns the status
+    fn command(memory: &Arc<Mutex<Memory>>, twi: &mut Twi, control: u8) -> u8 {
+        memory.lock().unwrap().set_sram(0x56, control);
+
+        for _ in 0..10_000 {
+            twi.notify_rising_edge();
+            twi.run();
+
+            if memory.lock().unwrap().peek(0x56) & TWINT != 0 {
+                return memory.lock().unwrap().peek(0x21) & !TWPS;
+            }
+        }
+
+        memory.lock().unwrap().peek(0x21) & !TWPS
+    }
+
+    fn send(memory: &Arc<Mutex<Memory>>, twi: &mut Twi, data: u8) -> u8 {
+        memory.lock().unwrap().set_sram(0x23, data);
+        command(memory, twi, TWINT | TWEN)
+    }
+
+    #[test]
+    fn test_master_bit_cycles() {
+        let (memory, sut, _) = set_up();
+        memory.lock().unwrap().poke(0x20, 32);
+        assert_eq!(sut.get_master_bit_cycles(&memory.lock().unwrap()), 80);
+
+        memory.lock().unwrap().poke(0x21, 0b0000_0010);
+        assert_eq!(
+            sut.get_master_bit_cycles(&memory.lock().unwrap()),
+            16 + 64 * 16
+        );
+    }
+
+    #[test]
+    fn test_start_timing() {
+        let (memory, mut sut, _) = set_up();
+        memory.lock().unwrap().set_sram(0x56, TWINT | TWSTA | TWEN);
+
+        clock_cycles(&mut sut, 15);
+        assert_eq!(memory.lock().unwrap().peek(0x56) & TWINT, 0);
+
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x56) & TWINT, TWINT);
+        assert_eq!(memory.lock().unwrap().peek(0x21), START);
+    }
+
+    #[test]
+    fn test_master_write_and_read() {
+        let (memory, mut sut, _) = set_up();
+
+        assert_eq!(command(&memory, &mut sut, TWINT | TWSTA | TWEN), START);
+        assert_eq!(send(&memory, &mut sut, 0x50 << 1), SLA_W_ACK);
+        assert_eq!(send(&memory, &mut sut, 0x20), DATA_SENT_ACK);
+        assert_eq!(send(&memory, &mut sut, 0x11), DATA_SENT_ACK);
+        assert_eq!(send(&memory, &mut sut, 0x22), DATA_SENT_ACK);
+
+        assert_eq!(
+            command(&memory, &mut sut, TWINT | TWSTA | TWEN),
+            REPEATED_START
+        );
+        assert_eq!(send(&memory, &mut sut, 0x50 << 1), SLA_W_ACK);
+        assert_eq!(send(&memory, &mut sut, 0x20), DATA_SENT_ACK);
+        assert_eq!(
+            command(&memory, &mut sut, TWINT | TWSTA | TWEN),
+            REPEATED_START
+        );
+        assert_eq!(send(&memory, &mut sut, 0x50 << 1 | 1), SLA_R_ACK);
+
+        assert_eq!(
+            command(&memory, &mut sut, TWINT | TWEA | TWEN),
+            DATA_RECEIVED_ACK
+        );
+        assert_eq!(memory.lock().unwrap().peek(0x23), 0x11);
+        assert_eq!(command(&memory, &mut sut, TWINT | TWEN), DATA_RECEIVED_NACK);
+        assert_eq!(memory.lock().unwrap().peek(0x23), 0x22);
+
+        memory.lock().unwrap().set_sram(0x56, TWINT | TWSTO | TWEN);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x56), TWEN);
+        assert_eq!(memory.lock().unwrap().peek(0x21), NO_STATE);
+    }
+
+    #[test]
+    fn test_missing_slave_does_not_acknowledge() {
+        let (memory, mut sut, _) = set_up();
+
+        command(&memory, &mut sut, TWINT | TWSTA | TWEN);
+        assert_eq!(send(&memory, &mut sut, 0x51 << 1), SLA_W_NACK);
+
+        command(&memory, &mut sut, TWINT | TWSTA | TWEN);
+        assert_eq!(send(&memory, &mut sut, 0x51 << 1 | 1), SLA_R_NACK);
+    }
+
+    #[test]
+    fn test_write_collision() {
+        let (memory, mut sut, _) = set_up();
+        memory.lock().unwrap().set_sram(0x23, 0x12);
+        memory.lock().unwrap().set_sram(0x56, TWINT | TWSTA | TWEN);
+        clock_cycles(&mut sut, 1);
+
+        memory.lock().unwrap().set_sram(0x23, 0x34);
+        clock_cycles(&mut sut, 1);
+
+        assert_eq!(memory.lock().unwrap().peek(0x23), 0x12);
+        assert_eq!(memory.lock().unwrap().peek(0x56) & TWWC, TWWC);
+    }
+
+    #[test]
+    fn test_slave_receiver() {
+        let (memory, mut sut, bus) = set_up();
+        memory.lock().unwrap().set_sram(0x22, 0x10 << 1);
+        bus.lock()
+            .unwrap()
+            .queue_transaction(I2cTransaction::Write {
+                address: 0x10,
+                data: vec![0xaa, 0xbb],
+            });
+
+        assert_eq!(command(&memory, &mut sut, TWEA | TWEN), OWN_SLA_W);
+        assert_eq!(
+            command(&memory, &mut sut, TWINT | TWEA | TWEN),
+            SLAVE_DATA_ACK
+        );
+        assert_eq!(memory.lock().unwrap().peek(0x23), 0xaa);
+        assert_eq!(
+            command(&memory, &mut sut, TWINT | TWEA | TWEN),
+            SLAVE_DATA_ACK
+        );
+        assert_eq!(memory.lock().unwrap().peek(0x23), 0xbb);
+        assert_eq!(command(&memory, &mut sut, TWINT | TWEA | TWEN), SLAVE_STOP);
+
+        memory.lock().unwrap().set_sram(0x56, TWINT | TWEA | TWEN);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x21), NO_STATE);
+
+        assert_eq!(
+            bus.lock().unwrap().take_results(),
+            vec![I2cTransactionResult {
+                acknowledged: true,
+                data: vec![]
+            }]
+        );
+    }
+
+    #[test]
+    fn test_slave_transmitter() {
+        let (memory, mut sut, bus) = set_up();
+        memory.lock().unwrap().set_sram(0x22, 0x10 << 1);
+        bus.lock().unwrap().queue_transaction(I2cTransaction::Read {
+            address: 0x10,
+            length: 2,
+        });
+
+        assert_eq!(command(&memory, &mut sut, TWEA | TWEN), OWN_SLA_R);
+        memory.lock().unwrap().set_sram(0x23, 0x01);
+        assert_eq!(
+            command(&memory, &mut sut, TWINT | TWEA | TWEN),
+            SLAVE_DATA_SENT_ACK
+        );
+        memory.lock().unwrap().set_sram(0x23, 0x02);
+        assert_eq!(
+            command(&memory, &mut sut, TWINT | TWEA | TWEN),
+            SLAVE_DATA_SENT_NACK
+        );
+
+        assert_eq!(
+            bus.lock().unwrap().take_results(),
+            vec![I2cTransactionResult {
+                acknowledged: true,
+                data: vec![0x01, 0x02]
+            }]
+        );
+    }
+
+    #[test]
+    fn test_slave_ignores_other_addresses() {
+        let (memory, mut sut, bus) = set_up();
+        memory.lock().unwrap().set_sram(0x22, 0x10 << 1);
+        memory.lock().unwrap().set_sram(0x56, TWEA | TWEN);
+        bus.lock()
+            .unwrap()
+            .queue_transaction(I2cTransaction::Write {
+                address: 0x11,
+                data: vec![0xaa],
+            });
+
+        clock_cycles(&mut sut, 500);
+
+        assert_eq!(memory.lock().unwrap().peek(0x56) & TWINT, 0);
+        assert_eq!(
+            bus.lock().unwrap().take_results(),
+            vec![I2cTransactionResult::default()]
+        );
+    }
+
+    #[test]
+    fn test_general_call() {
+        let (memory, mut sut, bus) = set_up();
+        memory.lock().unwrap().set_sram(0x22, 0x10 << 1 | TWGCE);
+        bus.lock()
+            .unwrap()
+            .queue_transaction(I2cTransaction::Write {
+                address: 0,
+                data: vec![0x06],
+            });
+
+        assert_eq!(command(&memory, &mut sut, TWEA | TWEN), GENERAL_CALL);
+        assert_eq!(
+            command(&memory, &mut sut, TWINT | TWEN),
+            GENERAL_CALL_DATA_NACK
+        );
+    }
+}
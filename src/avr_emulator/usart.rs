@@ -0,0 +1,511 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::avr_emulator::clock;
+use crate::avr_emulator::device::UsartRegisters;
+use crate::avr_emulator::memory::Memory;
+use crate::avr_emulator::serial::SerialBackend;
+
+// UCSRA
+const RXC: u8 = 1 << 7;
+const TXC: u8 = 1 << 6;
+const UDRE: u8 = 1 << 5;
+const FE: u8 = 1 << 4;
+const DOR: u8 = 1 << 3;
+const PE: u8 = 1 << 2;
+const U2X: u8 = 1 << 1;
+const MPCM: u8 = 1 << 0;
+
+// UCSRB
+const RXEN: u8 = 1 << 4;
+const TXEN: u8 = 1 << 3;
+const UCSZ2: u8 = 1 << 2;
+const RXB8: u8 = 1 << 1;
+const TXB8: u8 = 1 << 0;
+
+// UCSRC
+const UMSEL: u8 = 1 << 6;
+const UPM: u8 = 0b0011_0000;
+const USBS: u8 = 1 << 3;
+const UCSZ: u8 = 0b0000_0110;
+
+// 8 data bits, no parity, 1 stop bit
+const UCSRC_RESET_VALUE: u8 = 0b0000_0110;
+
+const RECEIVE_BUFFER_SIZE: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Frame {
+    data: u16,
+    remaining_cycles: u32,
+}
+
+pub struct Usart {
+    rising_edge_notified: std::sync::atomic::AtomicBool,
+    memory: Arc<Mutex<Memory>>,
+    registers: &'static UsartRegisters,
+    serial: Arc<Mutex<Box<dyn SerialBackend>>>,
+    // shadows of UBRRH and UCSRC when they share an address
+    baud_rate_high: u8,
+    frame_format: u8,
+    transmit_buffer: Option<u16>,
+    transmit_shift: Option<Frame>,
+    receive_shift: Option<Frame>,
+    receive_buffer: VecDeque<u16>,
+    data_overrun: bool,
+}
+
+impl Usart {
+    pub fn new(
+        memory: Arc<Mutex<Memory>>,
+        registers: &'static UsartRegisters,
+        serial: Arc<Mutex<Box<dyn SerialBackend>>>,
+    ) -> Self {
+        {
+            let mut memory = memory.lock().unwrap();
+            memory.poke(registers.control_status_a, UDRE);
+
+            let select = registers.register_select.map_or(0, |select| select.mask());
+            memory.poke(registers.control_status_c, UCSRC_RESET_VALUE | select);
+        }
+
+        Self {
+            rising_edge_notified: std::sync::atomic::AtomicBool::new(false),
+            memory,
+            registers,
+            serial,
+            baud_rate_high: 0,
+            frame_format: UCSRC_RESET_VALUE,
+            transmit_buffer: None,
+            transmit_shift: None,
+            receive_shift: None,
+            receive_buffer: VecDeque::new(),
+            data_overrun: false,
+        }
+    }
+
+    fn handle_shared_register_write(&mut self, memory: &mut Memory) {
+        let synchronous = self.frame_format & UMSEL != 0;
+
+        match self.registers.register_select {
+            Some(select) => {
+                if let Some(write) = memory.take_io_write(self.registers.control_status_c) {
+                    if write.new & select.mask() != 0 {
+                        self.frame_format = write.new;
+                    } else {
+                        self.baud_rate_high = write.new;
+                    }
+                }
+            }
+            None => {
+                self.frame_format = memory.peek(self.registers.control_status_c);
+                self.baud_rate_high = memory.peek(self.registers.baud_rate_high);
+            }
+        }
+
+        // warned once when the mode is selected, the frames keep the asynchronous timing
+        if !synchronous && self.frame_format & UMSEL != 0 {
+            log::warn!("Usart synchronous mode is not supported");
+        }
+    }
+
+    fn handle_status_write(&mut self, memory: &mut Memory) {
+        if let Some(write) = memory.take_io_write(self.registers.control_status_a) {
+            let read_only = write.old & (RXC | UDRE | FE | DOR | PE);
+            let cleared_by_one = write.old & TXC & !write.new;
+            let writable = write.new & (U2X | MPCM);
+
+            memory.poke(
+                self.registers.control_status_a,
+                read_only | cleared_by_one | writable,
+            );
+        }
+    }
+
+    fn handle_data_access(&mut self, memory: &mut Memory) {
+        if let Some(write) = memory.take_io_write(self.registers.data) {
+            let control = memory.peek(self.registers.control_status_b);
+
+            if control & TXEN != 0 && self.transmit_buffer.is_none() {
+                let ninth_bit = if control & TXB8 != 0 { 0x100 } else { 0 };
+                self.transmit_buffer = Some(write.new as u16 | ninth_bit);
+            } else {
+                log::debug!("Usart ignored write of {:#04x} to UDR", write.new);
+            }
+        }
+
+        if memory.take_io_read(self.registers.data) && self.receive_buffer.pop_front().is_some() {
+            self.data_overrun = false;
+        }
+    }
+
+    fn get_data_bits(&self, memory: &Memory) -> u32 {
+        let character_size = (self.frame_format & UCSZ) >> 1
+            | if memory.peek(self.registers.control_status_b) & UCSZ2 != 0 {
+                0b100
+            } else {
+                0
+            };
+
+        match character_size {
+            0..=3 => 5 + character_size as u32,
+            7 => 9,
+            _ => {
+                log::warn!("Usart reserved character size {}", character_size);
+                8
+            }
+        }
+    }
+
+    fn get_frame_cycles(&self, memory: &Memory) -> u32 {
+        let baud_rate = ((self.baud_rate_high as u32 & 0x0f) << 8)
+            | memory.peek(self.registers.baud_rate_low) as u32;
+        let double_speed = memory.peek(self.registers.control_status_a) & U2X != 0;
+
+        let bit_cycles = if double_speed { 8 } else { 16 } * (baud_rate + 1);
+
+        let parity_bits = if self.frame_format & UPM != 0 { 1 } else { 0 };
+        let stop_bits = if self.frame_format & USBS != 0 { 2 } else { 1 };
+
+        bit_cycles * (1 + self.get_data_bits(memory) + parity_bits + stop_bits)
+    }
+
+    fn data_mask(&self, memory: &Memory) -> u16 {
+        (1 << self.get_data_bits(memory)) - 1
+    }
+
+    fn run_transmitter(&mut self, memory: &mut Memory) {
+        if let Some(mut frame) = self.transmit_shift.take() {
+            frame.remaining_cycles -= 1;
+
+            if frame.remaining_cycles == 0 {
+                self.serial.lock().unwrap().transmit(frame.data as u8);
+                memory.raise_event("Usart", format_args!("transmitted {:#04x}", frame.data));
+
+                if self.transmit_buffer.is_none() {
+                    let status = memory.peek(self.registers.control_status_a);
+                    memory.poke(self.registers.control_status_a, status | TXC);
+                }
+            } else {
+                self.transmit_shift = Some(frame);
+            }
+        }
+
+        if self.transmit_shift.is_none() {
+            if let Some(data) = self.transmit_buffer.take() {
+                self.transmit_shift = Some(Frame {
+                    data: data & self.data_mask(memory),
+                    remaining_cycles: self.get_frame_cycles(memory),
+                });
+            }
+        }
+    }
+
+    fn run_receiver(&mut self, memory: &mut Memory) {
+        if memory.peek(self.registers.control_status_b) & RXEN == 0 {
+            self.receive_shift = None;
+            self.receive_buffer.clear();
+            self.data_overrun = false;
+            return;
+        }
+
+        match self.receive_shift.take() {
+            Some(mut frame) => {
+                frame.remaining_cycles -= 1;
+
+                if frame.remaining_cycles == 0 {
+                    if self.receive_buffer.len() < RECEIVE_BUFFER_SIZE {
+                        self.receive_buffer.push_back(frame.data);
+                        memory.raise_event("Usart", format_args!("received {:#04x}", frame.data));
+                    } else {
+                        log::warn!(
+                            "Usart data overrun, received byte {:#04x} is lost",
+                            frame.data
+                        );
+                        self.data_overrun = true;
+                        memory.raise_event("Usart", format_args!("data overrun"));
+                    }
+                } else {
+                    self.receive_shift = Some(frame);
+                }
+            }
+            None => {
+                if let Some(byte) = self.serial.lock().unwrap().receive() {
+                    self.receive_shift = Some(Frame {
+                        data: byte as u16 & self.data_mask(memory),
+                        remaining_cycles: self.get_frame_cycles(memory),
+                    });
+                }
+            }
+        }
+    }
+
+    fn update_registers(&self, memory: &mut Memory) {
+        let mut status = memory.peek(self.registers.control_status_a) & !(RXC | UDRE | DOR);
+        if !self.receive_buffer.is_empty() {
+            status |= RXC;
+        }
+        if self.transmit_buffer.is_none() {
+            status |= UDRE;
+        }
+        if self.data_overrun {
+            status |= DOR;
+        }
+        memory.poke(self.registers.control_status_a, status);
+
+        let received = self.receive_buffer.front().copied().unwrap_or(0);
+
+        let control = memory.peek(self.registers.control_status_b) & !RXB8;
+        let ninth_bit = if received & 0x100 != 0 { RXB8 } else { 0 };
+        memory.poke(self.registers.control_status_b, control | ninth_bit);
+
+        memory.poke(self.registers.data, received as u8);
+    }
+
+    fn update(&mut self) {
+        let memory = self.memory.clone();
+        let mut memory = memory.lock().unwrap();
+
+        self.handle_shared_register_write(&mut memory);
+        self.handle_status_write(&mut memory);
+        self.handle_data_access(&mut memory);
+
+        self.run_transmitter(&mut memory);
+        self.run_receiver(&mut memory);
+
+        self.update_registers(&mut memory);
+    }
+}
+
+impl clock::Subscriber for Usart {
+    fn notify_rising_edge(&self) {
+        log::debug!("Usart rising edge notified");
+
+        if self
+            .rising_edge_notified
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            log::warn!("Usart did not finish handling previous rising edge!");
+        }
+
+        self.rising_edge_notified
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+    fn notify_falling_edge(&self) {}
+
+    fn run(&mut self) {
+        if self
+            .rising_edge_notified
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            self.update();
+
+            self.rising_edge_notified
+                .store(false, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.baud_rate_high = 0;
+        self.frame_format = UCSRC_RESET_VALUE;
+        self.transmit_buffer = None;
+        self.transmit_shift = None;
+        self.receive_shift = None;
+        self.receive_buffer.clear();
+        self.data_overrun = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avr_emulator::device::{self, Device};
+    use crate::avr_emulator::test_utils::clock_cycles;
+
+    struct MockSerial {
+        transmitted: Arc<Mutex<Vec<u8>>>,
+        to_receive: Arc<Mutex<VecDeque<u8>>>,
+    }
+
+    impl SerialBackend for MockSerial {
+        fn transmit(&mut self, byte: u8) {
+            self.transmitted.lock().unwrap().push(byte);
+        }
+
+        fn receive(&mut self) -> Option<u8> {
+            self.to_receive.lock().unwrap().pop_front()
+        }
+    }
+
+    struct TestSetUp {
+        memory: Arc<Mutex<Memory>>,
+        usart: Usart,
+        transmitted: Arc<Mutex<Vec<u8>>>,
+        to_receive: Arc<Mutex<VecDeque<u8>>>,
+    }
+
+    fn set_up(device: &'static Device) -> TestSetUp {
+        let memory = Arc::new(Mutex::new(Memory::new(300, vec![]).unwrap()));
+        let transmitted = Arc::new(Mutex::new(vec![]));
+        let to_receive = Arc::new(Mutex::new(VecDeque::new()));
+
+        let serial: Box<dyn SerialBackend> = Box::new(MockSerial {
+            transmitted: transmitted.clone(),
+            to_receive: to_receive.clone(),
+        });
+
+        let usart = Usart::new(
+            memory.clone(),
+            &device.usarts[0],
+            Arc::new(Mutex::new(serial)),
+        );
+
+        TestSetUp {
+            memory,
+            usart,
+            transmitted,
+            to_receive,
+        }
+    }
+
+    #[test]
+    fn test_reset_state() {
+        let sut = set_up(&device::ATMEGA8);
+
+        assert_eq!(sut.memory.lock().unwrap().peek(0x2b), UDRE);
+        assert_eq!(sut.memory.lock().unwrap().peek(0x40), 0b1000_0110);
+    }
+
+    #[test]
+    fn test_frame_cycles() {
+        let mut sut = set_up(&device::ATMEGA8);
+        sut.memory.lock().unwrap().set_sram(0x29, 12);
+
+        let memory = sut.memory.clone();
+        let memory = memory.lock().unwrap();
+        assert_eq!(sut.usart.get_frame_cycles(&memory), 16 * 13 * 10);
+        drop(memory);
+
+        // double speed, 7 data bits, even parity, 2 stop bits, UBRRH = 1
+        sut.memory.lock().unwrap().set_sram(0x2b, U2X);
+        sut.memory.lock().unwrap().set_sram(0x40, 0b1010_1100);
+        clock_cycles(&mut sut.usart, 1);
+        sut.memory.lock().unwrap().set_sram(0x40, 0b0000_0001);
+        clock_cycles(&mut sut.usart, 1);
+
+        let memory = sut.memory.lock().unwrap();
+        assert_eq!(sut.usart.get_frame_cycles(&memory), 8 * 269 * 11);
+    }
+
+    #[test]
+    fn test_transmit() {
+        let mut sut = set_up(&device::ATMEGA8);
+        sut.memory.lock().unwrap().set_sram(0x2a, TXEN);
+        sut.memory.lock().unwrap().set_sram(0x2c, b'A');
+
+        clock_cycles(&mut sut.usart, 1);
+        assert_eq!(sut.memory.lock().unwrap().peek(0x2b) & (UDRE | TXC), UDRE);
+
+        clock_cycles(&mut sut.usart, 159);
+        assert!(sut.transmitted.lock().unwrap().is_empty());
+
+        clock_cycles(&mut sut.usart, 1);
+        assert_eq!(*sut.transmitted.lock().unwrap(), vec![b'A']);
+        assert_eq!(
+            sut.memory.lock().unwrap().peek(0x2b) & (UDRE | TXC),
+            UDRE | TXC
+        );
+
+        sut.memory.lock().unwrap().set_sram(0x2b, TXC);
+        clock_cycles(&mut sut.usart, 1);
+        assert_eq!(sut.memory.lock().unwrap().peek(0x2b) & TXC, 0);
+    }
+
+    #[test]
+    fn test_transmit_buffer() {
+        let mut sut = set_up(&device::ATMEGA88);
+        sut.memory.lock().unwrap().set_sram(0xc1, TXEN);
+
+        sut.memory.lock().unwrap().set_sram(0xc6, b'a');
+        clock_cycles(&mut sut.usart, 1);
+        sut.memory.lock().unwrap().set_sram(0xc6, b'b');
+        clock_cycles(&mut sut.usart, 1);
+        assert_eq!(sut.memory.lock().unwrap().peek(0xc0) & UDRE, 0);
+
+        // buffer is full, the byte is dropped
+        sut.memory.lock().unwrap().set_sram(0xc6, b'c');
+        clock_cycles(&mut sut.usart, 1);
+
+        clock_cycles(&mut sut.usart, 2 * 160);
+        assert_eq!(*sut.transmitted.lock().unwrap(), vec![b'a', b'b']);
+        assert_eq!(
+            sut.memory.lock().unwrap().peek(0xc0) & (UDRE | TXC),
+            UDRE | TXC
+        );
+    }
+
+    #[test]
+    fn test_transmit_requires_enable() {
+        let mut sut = set_up(&device::ATMEGA8);
+        sut.memory.lock().unwrap().set_sram(0x2c, b'A');
+
+        clock_cycles(&mut sut.usart, 200);
+
+        assert!(sut.transmitted.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_receive() {
+        let mut sut = set_up(&device::ATMEGA8);
+        sut.memory.lock().unwrap().set_sram(0x2a, RXEN);
+        sut.to_receive.lock().unwrap().extend([b'x', b'y']);
+
+        clock_cycles(&mut sut.usart, 160);
+        assert_eq!(sut.memory.lock().unwrap().peek(0x2b) & RXC, 0);
+
+        clock_cycles(&mut sut.usart, 1);
+        assert_eq!(sut.memory.lock().unwrap().peek(0x2b) & RXC, RXC);
+        assert_eq!(sut.memory.lock().unwrap().get_sram(0x2c).unwrap(), b'x');
+
+        // the next byte is only received after the previous frame has been shifted in
+        clock_cycles(&mut sut.usart, 1);
+        assert_eq!(sut.memory.lock().unwrap().peek(0x2b) & RXC, 0);
+
+        clock_cycles(&mut sut.usart, 159);
+        assert_eq!(sut.memory.lock().unwrap().peek(0x2b) & RXC, 0);
+
+        clock_cycles(&mut sut.usart, 1);
+        assert_eq!(sut.memory.lock().unwrap().peek(0x2b) & RXC, RXC);
+        assert_eq!(sut.memory.lock().unwrap().get_sram(0x2c).unwrap(), b'y');
+
+        clock_cycles(&mut sut.usart, 1);
+        assert_eq!(sut.memory.lock().unwrap().peek(0x2b) & RXC, 0);
+    }
+
+    #[test]
+    fn test_receive_data_overrun() {
+        let mut sut = set_up(&device::ATMEGA8);
+        sut.memory.lock().unwrap().set_sram(0x2a, RXEN);
+        sut.to_receive.lock().unwrap().extend([b'1', b'2', b'3']);
+
+        clock_cycles(&mut sut.usart, 3 * 161);
+
+        assert_eq!(sut.memory.lock().unwrap().peek(0x2b) & DOR, DOR);
+        assert_eq!(sut.memory.lock().unwrap().get_sram(0x2c).unwrap(), b'1');
+
+        clock_cycles(&mut sut.usart, 1);
+        assert_eq!(sut.memory.lock().unwrap().peek(0x2b) & DOR, 0);
+        assert_eq!(sut.memory.lock().unwrap().peek(0x2c), b'2');
+    }
+
+    #[test]
+    fn test_receive_requires_enable() {
+        let mut sut = set_up(&device::ATMEGA8);
+        sut.to_receive.lock().unwrap().push_back(b'x');
+
+        clock_cycles(&mut sut.usart, 200);
+
+        assert_eq!(sut.memory.lock().unwrap().peek(0x2b) & RXC, 0);
+        assert_eq!(sut.to_receive.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,315 @@
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use crate::avr_emulator::clock;
+use crate::avr_emulator::gpio::PortPins;
+use crate::avr_emulator::memory::Memory;
+use crate::avr_emulator::number::parse_number;
+
+// Registers to dump are given as name and data space address like OCR0A=0x47
+pub fn parse_register(value: &str) -> Result<(String, usize), String> {
+    let invalid = || format!("invalid register: {}", value);
+
+    let (name, address) = value.split_once('=').ok_or_else(invalid)?;
+    let address = parse_number(address).ok_or_else(invalid)?;
+
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err(invalid());
+    }
+
+    Ok((name.to_owned(), address))
+}
+
+enum Source {
+    Pin(Arc<Mutex<PortPins>>, u8),
+    Register(usize),
+    InterruptActive,
+}
+
+struct Signal {
+    scope: Option<String>,
+    name: String,
+    width: u8,
+    identifier: String,
+    source: Source,
+    value: Option<u8>,
+}
+
+// Short printable identifiers the signals are referred to by in the value changes
+fn identifier(mut index: usize) -> String {
+    let mut identifier = String::new();
+    loop {
+        identifier.push((b'!' + (index % 94) as u8) as char);
+        index /= 94;
+        if index == 0 {
+            return identifier;
+        }
+        index -= 1;
+    }
+}
+
+// Value change dump of pin levels, io registers and internal signals sampled every system
+// clock cycle, timestamped with the emulated time
+pub struct VcdWriter {
+    rising_edge_notified: std::sync::atomic::AtomicBool,
+    memory: Arc<Mutex<Memory>>,
+    writer: Option<Box<dyn Write + Send + Sync>>,
+    module: String,
+    frequency_hz: f64,
+    source_cycles: u64,
+    signals: Vec<Signal>,
+}
+
+impl VcdWriter {
+    pub fn new(
+        memory: Arc<Mutex<Memory>>,
+        writer: Box<dyn Write + Send + Sync>,
+        module: &str,
+        frequency_hz: f64,
+    ) -> Self {
+        Self {
+            rising_edge_notified: std::sync::atomic::AtomicBool::new(false),
+            memory,
+            writer: Some(writer),
+            module: module.to_owned(),
+            frequency_hz,
+            source_cycles: 0,
+            signals: vec![],
+        }
+    }
+
+    fn add_signal(&mut self, scope: Option<String>, name: &str, width: u8, source: Source) {
+        self.signals.push(Signal {
+            scope,
+            name: name.to_owned(),
+            width,
+            identifier: identifier(self.signals.len()),
+            source,
+            value: None,
+        });
+    }
+
+    pub fn add_port(&mut self, name: char, pins: Arc<Mutex<PortPins>>) {
+        for pin in 0..8 {
+            self.add_signal(
+                Some(format!("PORT{}", name)),
+                &format!("P{}{}", name, pin),
+                1,
+                Source::Pin(pins.clone(), pin),
+            );
+        }
+    }
+
+    pub fn add_register(&mut self, name: &str, address: usize) {
+        self.add_signal(None, name, 8, Source::Register(address));
+    }
+
+    // High while an interrupt routine is executed
+    pub fn add_interrupt_active(&mut self) {
+        self.add_signal(None, "interrupt_active", 1, Source::InterruptActive);
+    }
+
+    fn write_header(&self, writer: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(writer, "$version AvrEmulator $end")?;
+        writeln!(writer, "$timescale 1ps $end")?;
+        writeln!(writer, "$scope module {} $end", self.module)?;
+
+        let mut scope = None;
+        for signal in &self.signals {
+            if signal.scope != scope {
+                if scope.is_some() {
+                    writeln!(writer, "$upscope $end")?;
+                }
+                if let Some(name) = &signal.scope {
+                    writeln!(writer, "$scope module {} $end", name)?;
+                }
+                scope = signal.scope.clone();
+            }
+
+            let kind = if signal.width == 1 { "wire" } else { "reg" };
+            writeln!(
+                writer,
+                "$var {} {} {} {} $end",
+                kind, signal.width, signal.identifier, signal.name
+            )?;
+        }
+        if scope.is_some() {
+            writeln!(writer, "$upscope $end")?;
+        }
+
+        writeln!(writer, "$upscope $end")?;
+        writeln!(writer, "$enddefinitions $end")
+    }
+
+    fn sample(&mut self, writer: &mut dyn Write) -> std::io::Result<()> {
+        let memory = self.memory.clone();
+        let memory = memory.lock().unwrap();
+
+        let first = self.source_cycles == 0;
+        if first {
+            self.write_header(writer)?;
+        }
+
+        let mut changes = vec![];
+        for signal in &mut self.signals {
+            let value = match &signal.source {
+                Source::Pin(pins, pin) => pins.lock().unwrap().get_level(*pin) as u8,
+                Source::Register(address) => memory.peek(*address),
+                Source::InterruptActive => (memory.get_interrupt_depth() > 0) as u8,
+            };
+
+            if signal.value != Some(value) {
+                signal.value = Some(value);
+                changes.push(if signal.width == 1 {
+                    format!("{}{}", value, signal.identifier)
+                } else {
+                    format!("b{:b} {}", value, signal.identifier)
+                });
+            }
+        }
+
+        if !changes.is_empty() {
+            let time_ps = (self.source_cycles as f64 * 1e12 / self.frequency_hz).round() as u64;
+            writeln!(writer, "#{}", time_ps)?;
+
+            if first {
+                writeln!(writer, "$dumpvars")?;
+            }
+            for change in changes {
+                writeln!(writer, "{}", change)?;
+            }
+            if first {
+                writeln!(writer, "$end")?;
+            }
+        }
+
+        self.source_cycles += memory.get_clock_division() as u64;
+        Ok(())
+    }
+
+    fn update(&mut self) {
+        if let Some(mut writer) = self.writer.take() {
+            match self.sample(writer.as_mut()) {
+                Ok(()) => self.writer = Some(writer),
+                Err(error) => log::error!("failed to write value change dump: {}", error),
+            }
+        }
+    }
+}
+
+impl clock::Subscriber for VcdWriter {
+    fn notify_rising_edge(&self) {
+        log::debug!("VcdWriter rising edge notified");
+
+        if self
+            .rising_edge_notified
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            log::warn!("VcdWriter did not finish handling previous rising edge!");
+        }
+
+        self.rising_edge_notified
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+    fn notify_falling_edge(&self) {}
+
+    fn run(&mut self) {
+        if self
+            .rising_edge_notified
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            self.update();
+
+            self.rising_edge_notified
+                .store(false, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for VcdWriter {
+    fn drop(&mut self) {
+        if let Some(writer) = &mut self.writer {
+            if let Err(error) = writer.flush() {
+                log::error!("failed to write value change dump: {}", error);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avr_emulator::test_utils::{clock_cycles, Output};
+
+    #[test]
+    fn test_parse_register() {
+        assert_eq!(parse_register("OCR0A=0x47"), Ok(("OCR0A".to_owned(), 0x47)));
+        assert_eq!(parse_register("TCCR0=83"), Ok(("TCCR0".to_owned(), 83)));
+        assert!(parse_register("OCR0A").is_err());
+        assert!(parse_register("=0x47").is_err());
+        assert!(parse_register("OCR0A=0xfg").is_err());
+    }
+
+    #[test]
+    fn test_identifier() {
+        assert_eq!(identifier(0), "!");
+        assert_eq!(identifier(93), "~");
+        assert_eq!(identifier(94), "!!");
+        assert_eq!(identifier(95), "\"!");
+    }
+
+    #[test]
+    fn test_value_changes() {
+        let memory = Arc::new(Mutex::new(Memory::new(200, vec![]).unwrap()));
+        let pins = Arc::new(Mutex::new(PortPins::default()));
+        let output = Output::default();
+
+        // 8 MHz, a cycle takes 125 ns
+        let mut sut = VcdWriter::new(memory.clone(), Box::new(output.clone()), "atmega8", 8e6);
+        sut.add_port('B', pins);
+        sut.add_register("TCNT0", 0x52);
+        sut.add_interrupt_active();
+
+        clock_cycles(&mut sut, 2);
+        memory.lock().unwrap().poke(0x52, 5);
+        memory.lock().unwrap().enter_interrupt();
+        clock_cycles(&mut sut, 1);
+
+        memory.lock().unwrap().set_clock_division(2);
+        clock_cycles(&mut sut, 1);
+        memory.lock().unwrap().leave_interrupt();
+        clock_cycles(&mut sut, 1);
+
+        let dump = output.text();
+        let expected = "$version AvrEmulator $end\n\
+                        $timescale 1ps $end\n\
+                        $scope module atmega8 $end\n\
+                        $scope module PORTB $end\n\
+                        $var wire 1 ! PB0 $end\n\
+                        $var wire 1 \" PB1 $end\n\
+                        $var wire 1 # PB2 $end\n\
+                        $var wire 1 $ PB3 $end\n\
+                        $var wire 1 % PB4 $end\n\
+                        $var wire 1 & PB5 $end\n\
+                        $var wire 1 ' PB6 $end\n\
+                        $var wire 1 ( PB7 $end\n\
+                        $upscope $end\n\
+                        $var reg 8 ) TCNT0 $end\n\
+                        $var wire 1 * interrupt_active $end\n\
+                        $upscope $end\n\
+                        $enddefinitions $end\n\
+                        #0\n\
+                        $dumpvars\n\
+                        0!\n0\"\n0#\n0$\n0%\n0&\n0'\n0(\n\
+                        b0 )\n\
+                        0*\n\
+                        $end\n\
+                        #250000\n\
+                        b101 )\n\
+                        1*\n\
+                        #625000\n\
+                        0*\n";
+
+        assert_eq!(dump, expected);
+    }
+}
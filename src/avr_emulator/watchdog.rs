@@ -0,0 +1,414 @@
+use std::sync::{Arc, Mutex};
+
+use crate::avr_emulator::clock;
+use crate::avr_emulator::device::WatchdogRegisters;
+use crate::avr_emulator::memory::Memory;
+use crate::avr_emulator::reset::ResetKind;
+
+// WDTCR / WDTCSR
+const WDIF: u8 = 1 << 7;
+const WDIE: u8 = 1 << 6;
+const WDP3: u8 = 1 << 5;
+const WDCE: u8 = 1 << 4;
+const WDE: u8 = 1 << 3;
+const WDP: u8 = 0b0000_0111;
+
+// MCUSR / MCUCSR
+const WDRF: u8 = 1 << 3;
+
+// Protected changes have to be written within four cycles after setting WDCE and WDE,
+// afterwards WDCE is cleared by hardware
+const CHANGE_ENABLE_CYCLES: u8 = 4;
+
+pub struct Watchdog {
+    rising_edge_notified: std::sync::atomic::AtomicBool,
+    memory: Arc<Mutex<Memory>>,
+    registers: &'static WatchdogRegisters,
+    frequency: i64,
+    elapsed_cycles: u64,
+    change_enable_cycles: u8,
+    last_interrupt_flag: bool,
+}
+
+impl Watchdog {
+    pub fn new(
+        memory: Arc<Mutex<Memory>>,
+        registers: &'static WatchdogRegisters,
+        frequency: i64,
+    ) -> Self {
+        Self {
+            rising_edge_notified: std::sync::atomic::AtomicBool::new(false),
+            memory,
+            registers,
+            frequency,
+            elapsed_cycles: 0,
+            change_enable_cycles: 0,
+            last_interrupt_flag: false,
+        }
+    }
+
+    fn get_prescaler_mask(&self) -> u8 {
+        if self.registers.interrupt_mode {
+            WDP3 | WDP
+        } else {
+            WDP
+        }
+    }
+
+    fn get_enable_mask(&self) -> u8 {
+        if self.registers.interrupt_mode {
+            WDE | WDIE
+        } else {
+            WDE
+        }
+    }
+
+    // The watchdog runs from its own oscillator, the timeout does not depend on the cpu clock
+    fn get_timeout_cycles(&self, control: u8) -> u64 {
+        let mut select = (control & WDP) as usize;
+        if self.registers.interrupt_mode && control & WDP3 != 0 {
+            select += 8;
+        }
+
+        let oscillator_cycles = match self.registers.timeouts.get(select) {
+            Some(cycles) => *cycles,
+            None => {
+                log::warn!("Watchdog reserved prescaler selection {}", select);
+                *self.registers.timeouts.last().unwrap()
+            }
+        };
+
+        let timeout = oscillator_cycles as f64 / self.registers.oscillator_frequency;
+
+        ((timeout * self.frequency as f64).round() as u64).max(1)
+    }
+
+    fn handle_control_write(&mut self, memory: &mut Memory) {
+        let Some(write) = memory.take_io_write(self.registers.control) else {
+            return;
+        };
+
+        let change_enabled = self.change_enable_cycles > 0;
+        let prescaler = self.get_prescaler_mask();
+
+        let mut control = write.new & (WDCE | prescaler | self.get_enable_mask());
+
+        if self.registers.interrupt_mode {
+            control |= write.old & WDIF & !write.new;
+        }
+
+        if write.old & WDE != 0 && write.new & WDE == 0 && !change_enabled {
+            log::warn!("Watchdog disabled without the timed sequence, ignored");
+            control |= WDE;
+        }
+
+        if self.registers.interrupt_mode && !change_enabled {
+            control = (control & !prescaler) | (write.old & prescaler);
+        }
+
+        if self.registers.interrupt_mode && memory.peek(self.registers.reset_status) & WDRF != 0 {
+            control |= WDE;
+        }
+
+        if write.new & (WDCE | WDE) == WDCE | WDE {
+            self.change_enable_cycles = CHANGE_ENABLE_CYCLES;
+        }
+
+        memory.poke(self.registers.control, control);
+        self.last_interrupt_flag = control & WDIF != 0;
+    }
+
+    fn update_change_enable(&mut self, memory: &mut Memory) {
+        if self.change_enable_cycles > 0 {
+            self.change_enable_cycles -= 1;
+
+            if self.change_enable_cycles == 0 {
+                let control = memory.peek(self.registers.control);
+                memory.poke(self.registers.control, control & !WDCE);
+            }
+        }
+    }
+
+    // In interrupt and system reset mode executing the interrupt clears WDIE,
+    // so the next timeout resets the mcu
+    fn handle_interrupt_entry(&mut self, memory: &mut Memory) {
+        if !self.registers.interrupt_mode {
+            return;
+        }
+
+        let control = memory.peek(self.registers.control);
+        let flag = control & WDIF != 0;
+
+        if self.last_interrupt_flag && !flag && control & WDE != 0 {
+            memory.poke(self.registers.control, control & !WDIE);
+        }
+        self.last_interrupt_flag = flag;
+    }
+
+    fn update(&mut self) {
+        let memory = self.memory.clone();
+        let mut memory = memory.lock().unwrap();
+
+        if memory.take_watchdog_kick() {
+            self.elapsed_cycles = 0;
+        }
+
+        self.handle_control_write(&mut memory);
+        self.update_change_enable(&mut memory);
+        self.handle_interrupt_entry(&mut memory);
+
+        let control = memory.peek(self.registers.control);
+        if control & self.get_enable_mask() == 0 {
+            self.elapsed_cycles = 0;
+            return;
+        }
+
+        // the watchdog oscillator does not depend on the system clock prescaler
+        self.elapsed_cycles += memory.get_clock_division() as u64;
+        if self.elapsed_cycles < self.get_timeout_cycles(control) {
+            return;
+        }
+        self.elapsed_cycles = 0;
+
+        if self.registers.interrupt_mode && control & WDIE != 0 {
+            memory.poke(self.registers.control, control | WDIF);
+            self.last_interrupt_flag = true;
+            memory.raise_event("Watchdog", format_args!("timeout, interrupt"));
+        } else {
+            log::info!("Watchdog timeout, resetting the mcu");
+            memory.raise_event("Watchdog", format_args!("timeout, reset"));
+            memory.request_reset(ResetKind::Watchdog);
+        }
+    }
+}
+
+impl clock::Subscriber for Watchdog {
+    fn notify_rising_edge(&self) {
+        log::debug!("Watchdog rising edge notified");
+
+        if self
+            .rising_edge_notified
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            log::warn!("Watchdog did not finish handling previous rising edge!");
+        }
+
+        self.rising_edge_notified
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+    fn notify_falling_edge(&self) {}
+
+    fn run(&mut self) {
+        if self
+            .rising_edge_notified
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            self.update();
+
+            self.rising_edge_notified
+                .store(false, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.elapsed_cycles = 0;
+        self.change_enable_cycles = 0;
+        self.last_interrupt_flag = false;
+
+        // WDRF keeps the watchdog enabled with the shortest timeout
+        let mut memory = self.memory.lock().unwrap();
+        if self.registers.interrupt_mode && memory.peek(self.registers.reset_status) & WDRF != 0 {
+            memory.poke(self.registers.control, WDE);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avr_emulator::clock::Subscriber;
+    use crate::avr_emulator::device::{self, Device};
+    use crate::avr_emulator::reset;
+    use crate::avr_emulator::test_utils::clock_cycles;
+
+    fn set_up(device: &'static Device, frequency: i64) -> (Arc<Mutex<Memory>>, Watchdog) {
+        let memory = Arc::new(Mutex::new(Memory::new(300, vec![]).unwrap()));
+        let watchdog = Watchdog::new(memory.clone(), &device.watchdog, frequency);
+
+        (memory, watchdog)
+    }
+
+    #[test]
+    fn test_reset_timeout() {
+        // the oscillator runs at the cpu frequency, the shortest timeout is 16K cycles
+        let (memory, mut sut) = set_up(&device::ATMEGA8, 1_000_000);
+        memory.lock().unwrap().set_sram(0x41, WDE);
+
+        clock_cycles(&mut sut, 16 * 1024 - 1);
+        assert_eq!(memory.lock().unwrap().take_reset_request(), None);
+
+        clock_cycles(&mut sut, 1);
+        assert_eq!(
+            memory.lock().unwrap().take_reset_request(),
+            Some(ResetKind::Watchdog)
+        );
+    }
+
+    #[test]
+    fn test_timeout_does_not_depend_on_clock_division() {
+        // 16 ms are 128000 cycles of the 8 MHz source and 16000 cycles of the divided clock
+        let (memory, mut sut) = set_up(&device::ATMEGA88, 8_000_000);
+        memory.lock().unwrap().set_clock_division(8);
+        memory.lock().unwrap().set_sram(0x60, WDE);
+
+        clock_cycles(&mut sut, 16_000 - 1);
+        assert_eq!(memory.lock().unwrap().take_reset_request(), None);
+
+        clock_cycles(&mut sut, 1);
+        assert_eq!(
+            memory.lock().unwrap().take_reset_request(),
+            Some(ResetKind::Watchdog)
+        );
+    }
+
+    #[test]
+    fn test_timeout_does_not_depend_on_cpu_frequency() {
+        let (memory, mut sut) = set_up(&device::ATMEGA8, 2_000_000);
+        memory.lock().unwrap().set_sram(0x41, WDE | 1);
+
+        clock_cycles(&mut sut, 64 * 1024 - 1);
+        assert_eq!(memory.lock().unwrap().take_reset_request(), None);
+
+        clock_cycles(&mut sut, 1);
+        assert_eq!(
+            memory.lock().unwrap().take_reset_request(),
+            Some(ResetKind::Watchdog)
+        );
+    }
+
+    #[test]
+    fn test_kick_restarts_timeout() {
+        let (memory, mut sut) = set_up(&device::ATMEGA8, 1_000_000);
+        memory.lock().unwrap().set_sram(0x41, WDE);
+
+        clock_cycles(&mut sut, 16000);
+        memory.lock().unwrap().kick_watchdog();
+        clock_cycles(&mut sut, 16000);
+
+        assert_eq!(memory.lock().unwrap().take_reset_request(), None);
+    }
+
+    #[test]
+    fn test_disable_needs_timed_sequence() {
+        let (memory, mut sut) = set_up(&device::ATMEGA8, 1_000_000);
+        memory.lock().unwrap().set_sram(0x41, WDE);
+        clock_cycles(&mut sut, 1);
+
+        memory.lock().unwrap().set_sram(0x41, 0);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x41), WDE);
+
+        // the window closes after four cycles
+        memory.lock().unwrap().set_sram(0x41, WDCE | WDE);
+        clock_cycles(&mut sut, 4);
+        assert_eq!(memory.lock().unwrap().peek(0x41), WDE);
+        memory.lock().unwrap().set_sram(0x41, 0);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x41), WDE);
+
+        memory.lock().unwrap().set_sram(0x41, WDCE | WDE);
+        clock_cycles(&mut sut, 2);
+        memory.lock().unwrap().set_sram(0x41, 0);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x41), 0);
+    }
+
+    #[test]
+    fn test_prescaler_change_needs_timed_sequence() {
+        let (memory, mut sut) = set_up(&device::ATMEGA88, 128_000);
+
+        memory.lock().unwrap().set_sram(0x60, WDE | WDP3 | 1);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x60), WDE);
+
+        memory.lock().unwrap().set_sram(0x60, WDCE | WDE);
+        clock_cycles(&mut sut, 1);
+        memory.lock().unwrap().set_sram(0x60, WDE | WDP3 | 1);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x60), WDE | WDP3 | 1);
+
+        // 1024K cycles of the 128 kHz oscillator, 8 seconds
+        assert_eq!(sut.get_timeout_cycles(WDP3 | 1), 1024 * 1024);
+    }
+
+    #[test]
+    fn test_interrupt_mode() {
+        let (memory, mut sut) = set_up(&device::ATMEGA88, 128_000);
+        memory.lock().unwrap().set_sram(0x60, WDIE);
+
+        clock_cycles(&mut sut, 2048);
+        assert_eq!(memory.lock().unwrap().peek(0x60), WDIF | WDIE);
+
+        // writing one clears the flag
+        memory.lock().unwrap().set_sram(0x60, WDIF | WDIE);
+        clock_cycles(&mut sut, 2048);
+        assert_eq!(memory.lock().unwrap().peek(0x60), WDIF | WDIE);
+        assert_eq!(memory.lock().unwrap().take_reset_request(), None);
+    }
+
+    #[test]
+    fn test_interrupt_and_reset_mode() {
+        let (memory, mut sut) = set_up(&device::ATMEGA88, 128_000);
+        memory.lock().unwrap().set_sram(0x60, WDIE | WDE);
+
+        clock_cycles(&mut sut, 2048);
+        assert_eq!(memory.lock().unwrap().peek(0x60), WDIF | WDIE | WDE);
+
+        // entering the interrupt clears the flag and switches to reset mode
+        memory.lock().unwrap().poke(0x60, WDIE | WDE);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x60), WDE);
+
+        clock_cycles(&mut sut, 2047);
+        assert_eq!(
+            memory.lock().unwrap().take_reset_request(),
+            Some(ResetKind::Watchdog)
+        );
+    }
+
+    #[test]
+    fn test_reset_flag_keeps_watchdog_enabled() {
+        let (memory, mut sut) = set_up(&device::ATMEGA88, 128_000);
+        memory.lock().unwrap().set_sram(0x60, WDE);
+        clock_cycles(&mut sut, 2048);
+        assert_eq!(
+            memory.lock().unwrap().take_reset_request(),
+            Some(ResetKind::Watchdog)
+        );
+
+        reset::reset(
+            &mut memory.lock().unwrap(),
+            &device::ATMEGA88,
+            &device::ATMEGA88.fuses.defaults,
+            ResetKind::Watchdog,
+            true,
+        );
+        sut.reset();
+        assert_eq!(memory.lock().unwrap().peek(0x54), WDRF);
+        assert_eq!(memory.lock().unwrap().peek(0x60), WDE);
+
+        // the watchdog can not be disabled while WDRF is set
+        memory.lock().unwrap().set_sram(0x60, WDCE | WDE);
+        clock_cycles(&mut sut, 1);
+        memory.lock().unwrap().set_sram(0x60, 0);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x60), WDE);
+
+        memory.lock().unwrap().poke(0x54, 0);
+        memory.lock().unwrap().set_sram(0x60, WDCE | WDE);
+        clock_cycles(&mut sut, 1);
+        memory.lock().unwrap().set_sram(0x60, 0);
+        clock_cycles(&mut sut, 1);
+        assert_eq!(memory.lock().unwrap().peek(0x60), 0);
+    }
+}
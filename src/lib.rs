@@ -0,0 +1 @@
+pub mod avr_emulator;
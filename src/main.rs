@@ -3,22 +3,216 @@ use std::sync::{atomic, Arc};
 
 use structopt::StructOpt;
 
-mod avr_emulator;
+use avr_emulator::avr_emulator;
+use avr_emulator::elf::ElfFile;
 
 #[derive(Debug, StructOpt)]
-#[structopt(name = "AVRSimulator", about = "allows running avr hex")]
+#[structopt(
+    name = "AVRSimulator",
+    about = "allows running avr hex",
+    setting = structopt::clap::AppSettings::SubcommandsNegateReqs
+)]
 struct Opt {
     #[structopt(short, long, parse(from_occurrences))]
     /// Verbose mode (-v, -vv, -vvv, etc.)
     verbose: u8,
 
-    #[structopt(short, long, default_value = "1000000")]
-    /// clock frequency in Hz
-    frequency: i64,
+    #[structopt(short, long)]
+    /// clock source frequency in Hz (default: the internal oscillator selected by the fuses)
+    frequency: Option<i64>,
 
-    /// hex file to be "executed"
+    #[structopt(short, long)]
+    /// emulated microcontroller (atmega8, atmega88), default: the one an elf FILE was built for, else atmega8
+    mcu: Option<String>,
+
+    #[structopt(short, long, number_of_values = 1)]
+    /// serial port of each USART in order: stdio, none, pty[:<link>] or unix:<path> (default: stdio)
+    serial: Vec<String>,
+
+    #[structopt(short, long, parse(from_os_str))]
+    /// analog input script, lines of "<time_s> <input> <volts>" e.g. "0.01 ADC0 3.3"
+    analog: Option<PathBuf>,
+
+    #[structopt(short, long, parse(from_os_str))]
+    /// eeprom contents loaded at start and saved after every write, Intel HEX (.hex, .eep) or raw
+    eeprom: Option<PathBuf>,
+
+    #[structopt(long, parse(try_from_str = avr_emulator::fuses::parse_byte))]
+    /// low fuse byte, e.g. 0xe2 (default: the factory setting of the mcu)
+    lfuse: Option<u8>,
+
+    #[structopt(long, parse(try_from_str = avr_emulator::fuses::parse_byte))]
+    /// high fuse byte
+    hfuse: Option<u8>,
+
+    #[structopt(long, parse(try_from_str = avr_emulator::fuses::parse_byte))]
+    /// extended fuse byte
+    efuse: Option<u8>,
+
+    #[structopt(long, parse(try_from_str = avr_emulator::fuses::parse_byte))]
+    /// lock byte
+    lock: Option<u8>,
+
+    #[structopt(long, parse(from_os_str))]
+    /// elf file to read the .fuse and .lock sections from when FILE is a hex file, the fuse options take precedence
+    fuse_elf: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// keep registers and sram contents across external, brown-out and watchdog resets
+    preserve_sram: bool,
+
+    #[structopt(long, default_value = "zero")]
+    /// registers and sram contents at power-on: zero, random, random:<seed> or a byte e.g. 0xa5
+    sram_fill: avr_emulator::memory::SramFill,
+
+    #[structopt(long)]
+    /// warn about reads of sram never written and print how many there were when stopped
+    uninit_check: bool,
+
+    #[structopt(long)]
+    /// serve avr-gdb on localhost:<port>, execution starts halted until gdb continues it
+    gdb: Option<u16>,
+
+    #[structopt(long)]
+    /// control execution with commands on stdin, it starts halted and serial ports default to none
+    monitor: bool,
+
+    #[structopt(long)]
+    /// print for how many cycles each peripheral with a PRR bit was enabled when stopped
+    power_report: bool,
+
+    #[structopt(long)]
+    /// report SP dropping below the heap or .bss of an elf FILE and print the stack high-water mark when stopped
+    stack_check: bool,
+
+    #[structopt(long, parse(try_from_str = avr_emulator::stack::parse_address))]
+    /// lowest allowed SP, e.g. 0x300, implies --stack-check
+    stack_limit: Option<u16>,
+
+    #[structopt(long, parse(from_os_str))]
+    /// write a record of every executed instruction to this file
+    trace: Option<PathBuf>,
+
+    #[structopt(long, default_value = "text", parse(try_from_str = avr_emulator::trace::parse_format))]
+    /// format of the trace: text, csv or jsonl
+    trace_format: avr_emulator::trace::TraceFormat,
+
+    #[structopt(long, parse(try_from_str = avr_emulator::trace::parse_range))]
+    /// only trace instructions in this flash byte address range, e.g. 0x100-0x1ff
+    trace_range: Vec<std::ops::RangeInclusive<u32>>,
+
+    #[structopt(long)]
+    /// only trace instructions of this function of an elf FILE
+    trace_function: Vec<String>,
+
+    #[structopt(long, parse(from_os_str))]
+    /// write a value change dump of the pins, TCNT0, SREG and interrupt activity to this file
+    vcd: Option<PathBuf>,
+
+    #[structopt(long, parse(try_from_str = avr_emulator::vcd::parse_register))]
+    /// additional io register to dump given by its data space address, e.g. OCR0A=0x47
+    vcd_register: Vec<(String, usize)>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+
+    /// hex or elf file to be "executed"
     #[structopt(name = "FILE", parse(from_os_str))]
-    file_name: PathBuf,
+    file_name: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// print the instructions in flash as the emulator decodes them without running them
+    Disasm {
+        /// hex or elf file to disassemble, symbols of an elf file label the listing
+        #[structopt(name = "FILE", parse(from_os_str))]
+        file_name: PathBuf,
+    },
+}
+
+// Flash contents and the parsed elf file when it is not a hex file
+fn load_firmware(file_path: &Path) -> (Vec<u8>, Option<ElfFile>) {
+    let firmware = match std::fs::read(file_path) {
+        Ok(firmware) => firmware,
+        Err(error) => {
+            log::error!("failed to read {}: {}", file_path.display(), error);
+            std::process::exit(1);
+        }
+    };
+
+    if !ElfFile::is_elf(&firmware) {
+        let hex_dump = bin_file::BinFile::from_file(file_path)
+            .unwrap()
+            .to_bytes(.., None)
+            .unwrap();
+        return (hex_dump, None);
+    }
+
+    match ElfFile::parse(firmware) {
+        Ok(elf) => (elf.get_flash(), Some(elf)),
+        Err(error) => {
+            log::error!("{}: {}", file_path.display(), error);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn disassemble(file_path: &Path) {
+    let (flash, elf) = load_firmware(file_path);
+
+    let symbols = match elf.map(|elf| elf.get_symbols()).transpose() {
+        Ok(symbols) => symbols.unwrap_or_default(),
+        Err(error) => {
+            log::error!("{}: {}", file_path.display(), error);
+            std::process::exit(1);
+        }
+    };
+
+    print!(
+        "{}",
+        avr_emulator::disassembler::Disassembly::new(&flash, &symbols)
+    );
+}
+
+fn create_tracer(
+    path: &Path,
+    format: avr_emulator::trace::TraceFormat,
+    ranges: &[std::ops::RangeInclusive<u32>],
+    functions: &[String],
+    symbols: Arc<avr_emulator::symbols::SymbolTable>,
+) -> Result<avr_emulator::trace::Tracer, String> {
+    let file = std::fs::File::create(path)
+        .map_err(|error| format!("failed to create {}: {}", path.display(), error))?;
+
+    let mut tracer =
+        avr_emulator::trace::Tracer::new(Box::new(std::io::BufWriter::new(file)), format, symbols);
+
+    for range in ranges {
+        tracer.add_range(range.clone());
+    }
+    for function in functions {
+        tracer.add_function(function)?;
+    }
+
+    Ok(tracer)
+}
+
+static INTERRUPTED: atomic::AtomicBool = atomic::AtomicBool::new(false);
+
+extern "C" fn handle_interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, atomic::Ordering::Relaxed);
+}
+
+// The first SIGINT or SIGTERM stops the emulator, a second one terminates the process
+fn install_interrupt_handler() {
+    unsafe {
+        let mut action = std::mem::zeroed::<libc::sigaction>();
+        action.sa_sigaction = handle_interrupt as *const () as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESETHAND;
+        libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut());
+        libc::sigaction(libc::SIGTERM, &action, std::ptr::null_mut());
+    }
 }
 
 fn to_filter_level(verbose: u8) -> log::LevelFilter {
@@ -37,30 +231,254 @@ fn main() {
 
     let opt = Opt::from_args();
 
-    env_logger::Builder::from_default_env()
-        .filter_level(to_filter_level(opt.verbose))
-        .init();
+    let level = to_filter_level(opt.verbose);
+    let mut logger = env_logger::Builder::from_default_env();
+    logger.filter_level(level);
+    // the warnings asked for by an option are shown without -v
+    if opt.uninit_check {
+        logger.filter_module(
+            "avr_emulator::avr_emulator::shadow",
+            level.max(log::LevelFilter::Warn),
+        );
+    }
+    logger.init();
+
+    if let Some(Command::Disasm { file_name }) = &opt.command {
+        disassemble(file_name);
+        return;
+    }
+
+    let file_name = match &opt.file_name {
+        Some(file_name) => file_name.clone(),
+        None => structopt::clap::Error::with_description(
+            "The following required arguments were not provided:\n    <FILE>",
+            structopt::clap::ErrorKind::MissingRequiredArgument,
+        )
+        .exit(),
+    };
 
     let mut file_path = std::env::current_dir().unwrap();
 
-    if opt.file_name.is_relative() {
-        file_path.push(opt.file_name);
+    if file_name.is_relative() {
+        file_path.push(file_name);
     } else {
-        file_path = opt.file_name;
+        file_path = file_name;
     }
 
-    let hex_dump = bin_file::BinFile::from_file(Path::new(&file_path))
-        .unwrap()
-        .to_bytes(.., None)
-        .unwrap();
+    let (hex_dump, elf) = load_firmware(&file_path);
 
-    let avr_emulator =
-        avr_emulator::AVREmulator::new(hex_dump, 1500, opt.frequency, stop_program.clone());
+    let elf_mcu = elf.as_ref().and_then(|elf| elf.get_mcu());
+
+    if let (Some(mcu), Some(elf_mcu)) = (&opt.mcu, &elf_mcu) {
+        if mcu != elf_mcu {
+            log::warn!(
+                "{} was built for {}, running it on {}",
+                file_path.display(),
+                elf_mcu,
+                mcu
+            );
+        }
+    }
+
+    let mcu = opt.mcu.clone().or(elf_mcu).unwrap_or("atmega8".to_owned());
+
+    let device = match avr_emulator::device::find_device(&mcu) {
+        Some(device) => device,
+        None => {
+            log::error!("unsupported mcu: {}", mcu);
+            std::process::exit(1);
+        }
+    };
+
+    let mut fuses = device.fuses.defaults;
+
+    if let Some(elf) = &elf {
+        fuses.set_from_elf(elf);
+
+        if let Err(error) = avr_emulator::fuses::check_signature(device, elf) {
+            log::warn!("{}", error);
+        }
+    }
+
+    if let Some(path) = &opt.fuse_elf {
+        if let Err(error) = fuses.load_elf_sections(path) {
+            log::error!("{}", error);
+            std::process::exit(1);
+        }
+    }
+
+    fuses.low = opt.lfuse.unwrap_or(fuses.low);
+    fuses.high = opt.hfuse.unwrap_or(fuses.high);
+    fuses.extended = opt.efuse.unwrap_or(fuses.extended);
+    fuses.lock = opt.lock.unwrap_or(fuses.lock);
+
+    let frequency = match fuses.get_clock_source_frequency(device, opt.frequency) {
+        Ok(frequency) => frequency,
+        Err(error) => {
+            log::error!("{}", error);
+            std::process::exit(1);
+        }
+    };
+
+    let mut avr_emulator =
+        avr_emulator::AVREmulator::new(hex_dump, 1500, device, frequency, stop_program.clone());
+    avr_emulator.set_fuses(fuses);
+    avr_emulator.set_preserve_sram(opt.preserve_sram);
+    if opt.sram_fill != avr_emulator::memory::SramFill::Zero {
+        avr_emulator.set_sram_fill(opt.sram_fill);
+    }
+
+    let serial_specs = if !opt.serial.is_empty() {
+        opt.serial
+    } else if opt.monitor {
+        vec!["none".to_string()]
+    } else {
+        vec!["stdio".to_string()]
+    };
+
+    if opt.monitor && serial_specs.iter().any(|spec| spec == "stdio") {
+        log::error!("the monitor reads stdin, use another serial port than stdio");
+        std::process::exit(1);
+    }
+
+    if serial_specs.len() > device.usarts.len() {
+        log::error!("{} has only {} usart(s)", device.name, device.usarts.len());
+        std::process::exit(1);
+    }
+
+    for (usart, spec) in serial_specs.iter().enumerate() {
+        match avr_emulator::serial::from_spec(spec) {
+            Ok(backend) => avr_emulator.set_serial_backend(usart, backend),
+            Err(error) => {
+                log::error!("{}", error);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(script) = &opt.analog {
+        let loaded = avr_emulator
+            .get_analog_inputs()
+            .lock()
+            .unwrap()
+            .load_script(script, frequency);
+
+        if let Err(error) = loaded {
+            log::error!("{}", error);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(contents) = elf.as_ref().and_then(|elf| elf.get_eeprom()) {
+        if let Err(error) = avr_emulator.get_eeprom().lock().unwrap().load(contents) {
+            log::error!("{}", error);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(elf) = &elf {
+        match elf.get_symbols() {
+            Ok(symbols) => avr_emulator.set_symbols(symbols),
+            Err(error) => {
+                log::error!("{}: {}", file_path.display(), error);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let tracer = opt.trace.as_ref().map(|path| {
+        match create_tracer(
+            path,
+            opt.trace_format,
+            &opt.trace_range,
+            &opt.trace_function,
+            avr_emulator.get_symbols(),
+        ) {
+            Ok(tracer) => Arc::new(std::sync::Mutex::new(tracer)),
+            Err(error) => {
+                log::error!("{}", error);
+                std::process::exit(1);
+            }
+        }
+    });
+
+    if let Some(tracer) = &tracer {
+        avr_emulator.set_tracer(tracer.clone());
+    }
+
+    let stack_guard = (opt.stack_check || opt.stack_limit.is_some())
+        .then(|| avr_emulator.check_stack(opt.stack_limit));
+    let shadow = opt
+        .uninit_check
+        .then(|| avr_emulator.check_uninitialised_reads());
+
+    if let Some(path) = &opt.vcd {
+        let recorded = std::fs::File::create(path)
+            .map_err(|error| format!("failed to create {}: {}", path.display(), error))
+            .and_then(|file| {
+                avr_emulator.record_vcd(Box::new(std::io::BufWriter::new(file)), &opt.vcd_register)
+            });
+
+        if let Err(error) = recorded {
+            log::error!("{}", error);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(path) = &opt.eeprom {
+        if let Err(error) = avr_emulator
+            .get_eeprom()
+            .lock()
+            .unwrap()
+            .set_backing_file(path)
+        {
+            log::error!("{}", error);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(port) = opt.gdb {
+        if let Err(error) = avr_emulator.start_gdb_server(port) {
+            log::error!("{}", error);
+            std::process::exit(1);
+        }
+    }
+
+    if opt.monitor {
+        avr_emulator.start_monitor();
+    }
+
+    install_interrupt_handler();
 
     let mut threads_to_join = avr_emulator.run();
 
+    while !INTERRUPTED.load(atomic::Ordering::Relaxed)
+        && !threads_to_join.iter().any(|thread| thread.is_finished())
+    {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    stop_program.store(true, atomic::Ordering::Relaxed);
+
     while threads_to_join.len() > 0 {
         let cur_thread = threads_to_join.remove(0);
         cur_thread.join().unwrap();
     }
+
+    if let Some(tracer) = &tracer {
+        if let Err(error) = tracer.lock().unwrap().flush() {
+            log::error!("failed to write trace: {}", error);
+        }
+    }
+
+    if opt.power_report {
+        eprint!("{}", avr_emulator.get_power_report().lock().unwrap());
+    }
+
+    if let Some(stack_guard) = &stack_guard {
+        eprint!("{}", stack_guard.lock().unwrap());
+    }
+
+    if let Some(shadow) = &shadow {
+        eprint!("{}", shadow.lock().unwrap());
+    }
 }
@@ -3,7 +3,7 @@ import threading
 import os
 
 def test_allocation_on_heap():
-    proc = subprocess.Popen(["cargo", "run", os.path.dirname(__file__)+"/build/allocation_on_heap.hex", "-vv"], stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+    proc = subprocess.Popen(["cargo", "run", os.path.dirname(__file__)+"/build/allocation_on_heap.elf", "-vv"], stdout=subprocess.PIPE, stderr=subprocess.PIPE)
     timer = threading.Timer(5, proc.kill)
     
     try:
@@ -3,7 +3,7 @@ import threading
 import os
 
 def test_nop_in_while():
-    proc = subprocess.Popen(["cargo", "run", os.path.dirname(__file__)+"/build/if_statements.hex", "-vv"], stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+    proc = subprocess.Popen(["cargo", "run", os.path.dirname(__file__)+"/build/if_statements.elf", "-vv"], stdout=subprocess.PIPE, stderr=subprocess.PIPE)
     timer = threading.Timer(1, proc.kill)
     
     try:
@@ -3,7 +3,7 @@ import threading
 import os
 
 def test_local_variables():
-    proc = subprocess.Popen(["cargo", "run", os.path.dirname(__file__)+"/build/local_variables.hex", "-vv"], stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+    proc = subprocess.Popen(["cargo", "run", os.path.dirname(__file__)+"/build/local_variables.elf", "-vv"], stdout=subprocess.PIPE, stderr=subprocess.PIPE)
     timer = threading.Timer(5, proc.kill)
     
     try:
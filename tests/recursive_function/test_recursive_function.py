@@ -3,7 +3,7 @@ import threading
 import os
 
 def test_recursive_function():
-    proc = subprocess.Popen(["cargo", "run", os.path.dirname(__file__)+"/build/recursive_function.hex", "-vv"], stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+    proc = subprocess.Popen(["cargo", "run", os.path.dirname(__file__)+"/build/recursive_function.elf", "-vv"], stdout=subprocess.PIPE, stderr=subprocess.PIPE)
     timer = threading.Timer(5, proc.kill)
     
     try:
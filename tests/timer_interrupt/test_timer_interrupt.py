@@ -3,7 +3,7 @@ import threading
 import os
 
 def test_timer_interrupt():
-    proc = subprocess.Popen(["cargo", "run", os.path.dirname(__file__)+"/build/timer_interrupt.hex", "-vv"], stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+    proc = subprocess.Popen(["cargo", "run", os.path.dirname(__file__)+"/build/timer_interrupt.elf", "-vv"], stdout=subprocess.PIPE, stderr=subprocess.PIPE)
     timer = threading.Timer(5, proc.kill)
     
     try: